@@ -0,0 +1,72 @@
+//! Compare reading a GWAS file chunk by chunk with and without cached row
+//! offsets, and through a buffer or memory-mapped.
+//!
+//! Run with `cargo bench --bench chunked_read`.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use igwas::io::gwas::{
+    read_gwas_results, read_gwas_results_from, read_gwas_results_mmap, ColumnSpec,
+};
+
+const NUM_LINES: usize = 1_000_000;
+const CHUNKSIZE: usize = 100_000;
+
+fn write_gwas_file(path: &std::path::Path) {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+    writeln!(writer, "ID\tBETA\tSE\tOBS_CT").unwrap();
+    for i in 0..NUM_LINES {
+        let beta = ((i % 1000) as f32 - 500.0) / 1000.0;
+        writeln!(writer, "rs{}\t{}\t0.1\t1000", i, beta).unwrap();
+    }
+}
+
+fn time_chunks<F: FnMut(usize, usize)>(mut read_chunk: F) -> Duration {
+    let start = Instant::now();
+    let mut start_line = 0;
+    while start_line < NUM_LINES {
+        let end_line = std::cmp::min(NUM_LINES, start_line + CHUNKSIZE);
+        read_chunk(start_line, end_line);
+        start_line = end_line;
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("gwas.tsv");
+    write_gwas_file(&path);
+    let path = path.to_str().unwrap();
+    let column_names = ColumnSpec::default();
+
+    let scanning = time_chunks(|start_line, end_line| {
+        read_gwas_results(path, &column_names, start_line, end_line).unwrap();
+    });
+
+    let mut offset = None;
+    let seeking = time_chunks(|start_line, end_line| {
+        let (_, next) =
+            read_gwas_results_from(path, &column_names, start_line, end_line, offset).unwrap();
+        offset = next;
+    });
+
+    let mut offset = None;
+    let mapped = time_chunks(|start_line, end_line| {
+        let (_, next) =
+            read_gwas_results_mmap(path, &column_names, start_line, end_line, offset, 1).unwrap();
+        offset = next;
+    });
+
+    println!(
+        "{} lines in chunks of {}: scanning {:.2?}, cached offsets {:.2?} ({:.1}x), \
+        memory-mapped with cached offsets {:.2?} ({:.1}x)",
+        NUM_LINES,
+        CHUNKSIZE,
+        scanning,
+        seeking,
+        scanning.as_secs_f64() / seeking.as_secs_f64(),
+        mapped,
+        scanning.as_secs_f64() / mapped.as_secs_f64()
+    );
+}
@@ -0,0 +1,70 @@
+//! Time computing the final statistics of many chunks with one set of
+//! running statistics, as `process_chunks` does.
+//!
+//! Run with `cargo bench --bench final_stats`.
+
+use std::time::{Duration, Instant};
+
+use igwas::io::gwas::IntermediateResults;
+use igwas::io::matrix::LabeledMatrix;
+use igwas::stats::running::RunningSufficientStats;
+use nalgebra::{DMatrix, DVector};
+
+const NUM_CHUNKS: usize = 20;
+const CHUNKSIZE: usize = 5_000;
+const NUM_FEATURES: usize = 10;
+const NUM_PROJECTIONS: usize = 100;
+
+fn main() {
+    let features: Vec<String> = (0..NUM_FEATURES).map(|i| format!("y{}", i)).collect();
+    let proj = LabeledMatrix {
+        row_labels: features.clone(),
+        col_labels: (0..NUM_PROJECTIONS).map(|i| format!("p{}", i)).collect(),
+        matrix: DMatrix::from_fn(NUM_FEATURES, NUM_PROJECTIONS, |i, j| {
+            ((i + j) % 7) as f32 - 3.0
+        }),
+    };
+    let cov = LabeledMatrix {
+        row_labels: features.clone(),
+        col_labels: features.clone(),
+        matrix: DMatrix::identity(NUM_FEATURES, NUM_FEATURES),
+    };
+    let mut running =
+        RunningSufficientStats::<f32>::new(&proj, &cov, 0, CHUNKSIZE, None, Default::default())
+            .unwrap();
+
+    let variant_ids: Vec<String> = (0..CHUNKSIZE).map(|i| format!("rs{}", i)).collect();
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..NUM_CHUNKS {
+        running.clear_chunk(CHUNKSIZE);
+        for feature in &features {
+            running
+                .update(&IntermediateResults {
+                    phenotype_id: feature.clone(),
+                    variant_ids: variant_ids.clone(),
+                    beta_update: DMatrix::from_element(CHUNKSIZE, NUM_PROJECTIONS, 0.01),
+                    gpv_update: DVector::from_element(CHUNKSIZE, 1.0),
+                    sample_sizes: DVector::from_element(CHUNKSIZE, 1000),
+                    missing: Vec::new(),
+                    zero_weight: Vec::new(),
+                    annotations: Default::default(),
+                    heterogeneity: None,
+                    sample_size_sums: None,
+                })
+                .unwrap();
+        }
+        let start = Instant::now();
+        let results = running.compute_final_stats().unwrap();
+        elapsed += start.elapsed();
+        assert_eq!(results.beta_values.len(), CHUNKSIZE * NUM_PROJECTIONS);
+    }
+
+    println!(
+        "{} chunks of {} variants x {} projections: {:.2?} in compute_final_stats ({:.2?} per chunk)",
+        NUM_CHUNKS,
+        CHUNKSIZE,
+        NUM_PROJECTIONS,
+        elapsed,
+        elapsed / NUM_CHUNKS as u32
+    );
+}
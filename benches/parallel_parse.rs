@@ -0,0 +1,60 @@
+//! Compare parsing a chunk of a wide GWAS file sequentially and in parallel.
+//!
+//! Run with `cargo bench --bench parallel_parse`.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use igwas::io::gwas::{read_gwas_results_from, read_gwas_results_parallel, ColumnSpec};
+
+const NUM_LINES: usize = 200_000;
+const NUM_EXTRA_COLUMNS: usize = 50;
+
+fn write_wide_gwas_file(path: &std::path::Path) {
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+    write!(writer, "ID\tBETA\tSE\tOBS_CT").unwrap();
+    for j in 0..NUM_EXTRA_COLUMNS {
+        write!(writer, "\tEXTRA{}", j).unwrap();
+    }
+    writeln!(writer).unwrap();
+    for i in 0..NUM_LINES {
+        let beta = ((i % 1000) as f32 - 500.0) / 1000.0;
+        write!(writer, "rs{}\t{}\t0.1\t1000", i, beta).unwrap();
+        for j in 0..NUM_EXTRA_COLUMNS {
+            write!(writer, "\t{}", (i * j) % 9973).unwrap();
+        }
+        writeln!(writer).unwrap();
+    }
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("wide.tsv");
+    write_wide_gwas_file(&path);
+    let path = path.to_str().unwrap();
+    let column_names = ColumnSpec::default();
+
+    let sequential = time(|| {
+        read_gwas_results_from(path, &column_names, 0, NUM_LINES, None).unwrap();
+    });
+    let n_threads = rayon::current_num_threads();
+    let parallel = time(|| {
+        read_gwas_results_parallel(path, &column_names, 0, NUM_LINES, None, n_threads).unwrap();
+    });
+
+    println!(
+        "{} lines of {} columns: sequential {:.2?}, parallel in {} parts {:.2?} ({:.1}x)",
+        NUM_LINES,
+        NUM_EXTRA_COLUMNS + 4,
+        sequential,
+        n_threads,
+        parallel,
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
@@ -0,0 +1,92 @@
+//! Count the memory allocated by `RunningSufficientStats::update` for chunks
+//! of increasing numbers of GWAS files. Only the first file of a chunk stores
+//! its variant ids; the others are compared in place, so the allocation per
+//! chunk should not grow with the number of files.
+//!
+//! Run with `cargo bench --bench update_alloc`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use igwas::io::gwas::IntermediateResults;
+use igwas::io::matrix::LabeledMatrix;
+use igwas::stats::running::RunningSufficientStats;
+use nalgebra::{DMatrix, DVector};
+
+const CHUNKSIZE: usize = 10_000;
+const NUM_PROJECTIONS: usize = 2;
+
+/// The system allocator, counting the bytes allocated through it
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Bytes allocated by updating one chunk with `num_files` GWAS files
+fn allocated_by_update(num_files: usize) -> usize {
+    let features: Vec<String> = (0..num_files).map(|i| format!("y{}", i)).collect();
+    let proj = LabeledMatrix {
+        row_labels: features.clone(),
+        col_labels: (0..NUM_PROJECTIONS).map(|i| format!("p{}", i)).collect(),
+        matrix: DMatrix::from_element(num_files, NUM_PROJECTIONS, 1.0),
+    };
+    let cov = LabeledMatrix {
+        row_labels: features.clone(),
+        col_labels: features.clone(),
+        matrix: DMatrix::identity(num_files, num_files),
+    };
+    let mut running =
+        RunningSufficientStats::<f32>::new(&proj, &cov, 0, CHUNKSIZE, None, Default::default())
+            .unwrap();
+    running.clear_chunk(CHUNKSIZE);
+
+    let variant_ids: Vec<String> = (0..CHUNKSIZE).map(|i| format!("rs{}", i)).collect();
+    let updates: Vec<IntermediateResults<f32>> = features
+        .iter()
+        .map(|feature| IntermediateResults {
+            phenotype_id: feature.clone(),
+            variant_ids: variant_ids.clone(),
+            beta_update: DMatrix::from_element(CHUNKSIZE, NUM_PROJECTIONS, 0.01),
+            gpv_update: DVector::from_element(CHUNKSIZE, 1.0),
+            sample_sizes: DVector::from_element(CHUNKSIZE, 1000),
+            missing: Vec::new(),
+            zero_weight: Vec::new(),
+            annotations: Default::default(),
+            heterogeneity: None,
+            sample_size_sums: None,
+        })
+        .collect();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    for update in &updates {
+        running.update(update).unwrap();
+    }
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let baseline = allocated_by_update(1);
+    for num_files in [1, 10, 100] {
+        let allocated = allocated_by_update(num_files);
+        println!(
+            "{} files of {} variants: {} bytes allocated by update ({} more than one file)",
+            num_files,
+            CHUNKSIZE,
+            allocated,
+            allocated as isize - baseline as isize
+        );
+    }
+}
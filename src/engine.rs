@@ -0,0 +1,201 @@
+//! Indirect GWAS of results already in memory, without reading or writing
+//! any files.
+//!
+//! ```
+//! use igwas::engine::IGwasEngine;
+//! use igwas::io::gwas::GwasResults;
+//! use igwas::io::matrix::LabeledMatrix;
+//! use nalgebra::DMatrix;
+//!
+//! let phenotypes = vec!["height".to_string(), "weight".to_string()];
+//! let proj = LabeledMatrix {
+//!     row_labels: phenotypes.clone(),
+//!     col_labels: vec!["bmi_like".to_string()],
+//!     matrix: DMatrix::from_column_slice(2, 1, &[-2.0, 1.0]),
+//! };
+//! let cov = LabeledMatrix {
+//!     row_labels: phenotypes.clone(),
+//!     col_labels: phenotypes,
+//!     matrix: DMatrix::from_row_slice(2, 2, &[1.0, 0.4, 0.4, 1.0]),
+//! };
+//!
+//! let variants = vec!["rs1".to_string(), "rs2".to_string()];
+//! let height = GwasResults::new(
+//!     variants.clone(),
+//!     vec![0.1, -0.2],
+//!     vec![0.05, 0.05],
+//!     vec![1000, 1000],
+//! );
+//! let weight = GwasResults::new(variants, vec![0.3, 0.0], vec![0.05, 0.04], vec![1000, 990]);
+//!
+//! let mut engine = IGwasEngine::<f32>::new(&proj, &cov, 2)?;
+//! let results = engine
+//!     .ingest("height", &height)?
+//!     .ingest("weight", &weight)?
+//!     .finish()?;
+//! assert_eq!(results.projection_ids, ["bmi_like", "bmi_like"]);
+//! assert_eq!(results.variant_ids, ["rs1", "rs2"]);
+//! assert!((results.beta_values[0] - 0.1).abs() < 1e-6);
+//! assert_eq!(results.sample_sizes.as_slice(), [1000, 990]);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{ensure, Result};
+use nalgebra::DMatrix;
+
+use crate::io::gwas::{GwasResults, IGwasResults};
+use crate::io::matrix::LabeledMatrix;
+use crate::stats::running::{RunningSufficientStats, StatsConfig};
+use crate::stats::sumstats::Float;
+use crate::util::ProcessingStats;
+
+/// Accumulates the GWAS results of each phenotype of a projection matrix,
+/// then computes the GWAS results of the projections. All phenotypes must
+/// have results for the same variants, in the same order.
+pub struct IGwasEngine<T = f32> {
+    running: RunningSufficientStats<T>,
+    processing: Option<ProcessingStats<T>>,
+    phenotypes: Vec<String>,
+    ingested: HashSet<String>,
+}
+
+impl<T: Float> IGwasEngine<T> {
+    /// An engine for the given projection and phenotype covariance matrices,
+    /// with GWAS adjusted for `n_covar` covariates. The covariance matrix
+    /// may list the phenotypes in any order, and may have extra phenotypes.
+    pub fn new(proj: &LabeledMatrix, cov: &LabeledMatrix, n_covar: usize) -> Result<Self> {
+        Self::with_config(proj, cov, n_covar, StatsConfig::default())
+    }
+
+    pub fn with_config(
+        proj: &LabeledMatrix,
+        cov: &LabeledMatrix,
+        n_covar: usize,
+        config: StatsConfig,
+    ) -> Result<Self> {
+        let cov = cov.select(&proj.row_labels, &proj.row_labels)?;
+        Ok(IGwasEngine {
+            running: RunningSufficientStats::new(proj, &cov, n_covar, 0, None, config)?,
+            processing: None,
+            phenotypes: proj.row_labels.clone(),
+            ingested: HashSet::new(),
+        })
+    }
+
+    /// Add the GWAS results of one phenotype
+    pub fn ingest(&mut self, phenotype: &str, gwas_results: &GwasResults) -> Result<&mut Self> {
+        ensure!(
+            self.phenotypes.iter().any(|x| x == phenotype),
+            "Phenotype {} is not in the projection matrix",
+            phenotype
+        );
+        ensure!(
+            !self.ingested.contains(phenotype),
+            "Results of phenotype {} were already ingested",
+            phenotype
+        );
+        if self.processing.is_none() {
+            self.running.clear_chunk(gwas_results.variant_ids.len());
+            self.processing = Some(self.running.build_processing_stats());
+        }
+        let processing = self.processing.as_ref().unwrap();
+        ensure!(
+            gwas_results.variant_ids.len() == processing.n_variants,
+            "Results of phenotype {} have {} variants, but those ingested before have {}",
+            phenotype,
+            gwas_results.variant_ids.len(),
+            processing.n_variants
+        );
+        let update = processing.format_update(phenotype, gwas_results);
+        self.running.update(&update)?;
+        self.ingested.insert(phenotype.to_string());
+        Ok(self)
+    }
+
+    /// Compute the results of the projections, once every phenotype has
+    /// been ingested. The engine can then be reused for other variants.
+    pub fn finish(&mut self) -> Result<IGwasResults> {
+        let missing: Vec<&String> = self
+            .phenotypes
+            .iter()
+            .filter(|x| !self.ingested.contains(*x))
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "No results were ingested for phenotypes {:?}",
+            missing
+        );
+        let results = self.running.compute_final_stats()?;
+        self.processing = None;
+        self.ingested.clear();
+        Ok(results)
+    }
+}
+
+/// Compute the GWAS results of one projection, given as the weight of each
+/// phenotype, from the GWAS results of those phenotypes, without building
+/// a projection matrix. The phenotypes of `weights` and `gwas_results` must
+/// be the same, and `cov` must cover them. The results are labeled with
+/// `projection_id`.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use igwas::engine::run_single_projection;
+/// use igwas::io::gwas::GwasResults;
+/// use igwas::io::matrix::LabeledMatrix;
+/// use nalgebra::DMatrix;
+///
+/// let phenotypes = vec!["height".to_string(), "weight".to_string()];
+/// let cov = LabeledMatrix {
+///     row_labels: phenotypes.clone(),
+///     col_labels: phenotypes,
+///     matrix: DMatrix::from_row_slice(2, 2, &[1.0, 0.4, 0.4, 1.0]),
+/// };
+/// let weights = HashMap::from([("height".to_string(), -2.0), ("weight".to_string(), 1.0)]);
+///
+/// let variants = vec!["rs1".to_string(), "rs2".to_string()];
+/// let gwas_results = HashMap::from([
+///     (
+///         "height".to_string(),
+///         GwasResults::new(variants.clone(), vec![0.1, -0.2], vec![0.05, 0.05], vec![1000, 1000]),
+///     ),
+///     (
+///         "weight".to_string(),
+///         GwasResults::new(variants, vec![0.3, 0.0], vec![0.05, 0.04], vec![1000, 990]),
+///     ),
+/// ]);
+///
+/// let results = run_single_projection("bmi_like", &weights, &cov, 2, &gwas_results)?;
+/// assert_eq!(results.projection_ids, ["bmi_like", "bmi_like"]);
+/// assert_eq!(results.variant_ids, ["rs1", "rs2"]);
+/// assert!((results.beta_values[0] - 0.1).abs() < 1e-6);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn run_single_projection(
+    projection_id: &str,
+    weights: &HashMap<String, f32>,
+    cov: &LabeledMatrix,
+    n_covar: usize,
+    gwas_results: &HashMap<String, GwasResults>,
+) -> Result<IGwasResults> {
+    ensure!(!weights.is_empty(), "The projection has no phenotypes");
+    // Sorted, so that the results do not depend on the order of the map
+    let mut phenotypes: Vec<&String> = weights.keys().collect();
+    phenotypes.sort();
+    let proj = LabeledMatrix {
+        row_labels: phenotypes.iter().map(|x| x.to_string()).collect(),
+        col_labels: vec![projection_id.to_string()],
+        matrix: DMatrix::from_iterator(phenotypes.len(), 1, phenotypes.iter().map(|x| weights[*x])),
+    };
+
+    let mut engine = IGwasEngine::<f32>::new(&proj, cov, n_covar)?;
+    let mut ingested: Vec<&String> = gwas_results.keys().collect();
+    ingested.sort();
+    for phenotype in ingested {
+        engine.ingest(phenotype, &gwas_results[phenotype])?;
+    }
+    engine.finish()
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::{bail, Context, Result};
+
+/// Progress of a run writing one output file, saved after each chunk so that
+/// an interrupted run can be resumed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Parameters of the run, which a resumed run must share: the output
+    /// path, the chunk size, and a hash of each input file
+    pub fingerprint: Vec<String>,
+    /// Number of variants whose results are in the output
+    pub end_line: usize,
+    /// Size of the output after the last completed chunk. Anything past it
+    /// was written by an interrupted chunk.
+    pub output_bytes: u64,
+}
+
+/// 64-bit FNV-1a hash of the contents of a file
+pub(crate) fn hash_file(filename: &str) -> Result<u64> {
+    let mut reader = BufReader::new(
+        File::open(filename).with_context(|| format!("Failed to open file {}", filename))?,
+    );
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buffer = [0u8; 1 << 16];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for &byte in &buffer[..n] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// The fingerprint of a run, see `Checkpoint::fingerprint`
+pub fn fingerprint(
+    output_file: &str,
+    chunksize: usize,
+    input_files: &[&str],
+) -> Result<Vec<String>> {
+    let mut fingerprint = vec![
+        format!("output\t{}", output_file),
+        format!("chunksize\t{}", chunksize),
+    ];
+    for filename in input_files {
+        fingerprint.push(format!(
+            "input\t{}\t{:016x}",
+            filename,
+            hash_file(filename)?
+        ));
+    }
+    Ok(fingerprint)
+}
+
+impl Checkpoint {
+    /// Check that the checkpoint was written by a run with the given
+    /// fingerprint
+    pub fn ensure_matches(&self, fingerprint: &[String], filename: &str) -> Result<()> {
+        for i in 0..self.fingerprint.len().max(fingerprint.len()) {
+            let found = self.fingerprint.get(i);
+            let expected = fingerprint.get(i);
+            if found != expected {
+                let describe = |x: Option<&String>| {
+                    x.map_or(String::from("nothing"), |x| x.replace('\t', " "))
+                };
+                bail!(
+                    "Checkpoint {} was written by a run with different parameters or inputs: \
+                    it has '{}' where this run has '{}'. Delete it to start over",
+                    filename,
+                    describe(found),
+                    describe(expected)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a checkpoint, or `None` if there is none
+pub fn read_checkpoint(filename: &str) -> Result<Option<Checkpoint>> {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open checkpoint {}", filename)),
+    };
+    let mut fingerprint = Vec::new();
+    let mut end_line = None;
+    let mut output_bytes = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match line.split_once('\t') {
+            Some(("end_line", value)) => end_line = value.parse().ok(),
+            Some(("output_bytes", value)) => output_bytes = value.parse().ok(),
+            _ => fingerprint.push(line),
+        }
+    }
+    let (Some(end_line), Some(output_bytes)) = (end_line, output_bytes) else {
+        bail!("Checkpoint {} is incomplete or corrupt", filename);
+    };
+    Ok(Some(Checkpoint {
+        fingerprint,
+        end_line,
+        output_bytes,
+    }))
+}
+
+/// Write a checkpoint, replacing any previous one. The checkpoint is
+/// written next to its final path and then renamed, so that an interruption
+/// never leaves a partial checkpoint.
+pub fn write_checkpoint(checkpoint: &Checkpoint, filename: &str) -> Result<()> {
+    let temporary = format!("{}.tmp", filename);
+    let mut writer = File::create(&temporary)
+        .with_context(|| format!("Failed to create checkpoint {}", temporary))?;
+    for line in &checkpoint.fingerprint {
+        writeln!(writer, "{}", line)?;
+    }
+    writeln!(writer, "end_line\t{}", checkpoint.end_line)?;
+    writeln!(writer, "output_bytes\t{}", checkpoint.output_bytes)?;
+    writer.sync_all()?;
+    std::fs::rename(&temporary, filename)
+        .with_context(|| format!("Failed to write checkpoint {}", filename))
+}
@@ -1,27 +1,377 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::sync::{Mutex, OnceLock};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use nalgebra::{DMatrix, DVector};
+use rayon::prelude::*;
 
-pub fn count_lines(filename: &str) -> Result<usize> {
+use crate::io::parquet;
+use crate::io::remote;
+use crate::io::tabix::{self, Region};
+use crate::io::vcf::{self, VcfFields};
+use crate::stats::running::{HeterogeneitySums, SampleSizeSums};
+use crate::stats::sumstats::format_pvalue;
+
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+pub(crate) const PARQUET_MAGIC: [u8; 4] = *b"PAR1";
+
+/// Byte order mark that some programs, notably on Windows, write at the
+/// start of UTF-8 text. The csv reader skips it, but lines read directly
+/// must drop it, or it sticks to the first field.
+const UTF8_BOM: char = '\u{feff}';
+
+/// `text` without a leading byte order mark
+pub(crate) fn strip_bom(text: &str) -> &str {
+    text.strip_prefix(UTF8_BOM).unwrap_or(text)
+}
+
+/// Compression formats recognized for input files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// Detect the compression of a file from its magic bytes, falling back to the extension.
+/// Remote files (see `io::remote`) are never compressed.
+pub fn detect_compression(filename: &str) -> Result<Compression> {
+    if remote::is_remote(filename) {
+        return Ok(Compression::None);
+    }
+    let mut file = File::open(filename)?;
+    let mut magic = [0u8; 4];
+    let n = file.read(&mut magic)?;
+    if n == ZSTD_MAGIC.len() && magic == ZSTD_MAGIC {
+        return Ok(Compression::Zstd);
+    }
+    if n >= GZIP_MAGIC.len() && magic[..2] == GZIP_MAGIC {
+        return Ok(Compression::Gzip);
+    }
     if filename.ends_with(".zst") {
-        let file = File::open(filename)?;
-        let reader = BufReader::new(file);
-        let decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
-        let mut reader = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .from_reader(decoder);
-        return Ok(reader.records().count());
+        return Ok(Compression::Zstd);
     }
-    let file = File::open(filename)?;
-    let mut reader = BufReader::with_capacity(32768, file);
-    let mut num_lines = 0;
-    let mut string = String::new();
-    while reader.read_line(&mut string)? > 0 {
-        num_lines += 1;
+    if filename.ends_with(".gz") {
+        return Ok(Compression::Gzip);
+    }
+    Ok(Compression::None)
+}
+
+/// Open a file for reading, transparently decompressing it if needed.
+/// gzip files may have several members, as written by bgzip or by
+/// concatenating gzip files, which are read as one stream.
+pub fn open_maybe_compressed(filename: &str) -> Result<Box<dyn BufRead>> {
+    if remote::is_remote(filename) {
+        return remote::open(filename);
+    }
+    let mut file = File::open(filename)?;
+    let mut magic = [0u8; 4];
+    let n = file.read(&mut magic)?;
+    ensure!(
+        !(n == PARQUET_MAGIC.len() && magic == PARQUET_MAGIC),
+        "File {} is a Parquet file, which is not supported. Convert it to a delimited text file",
+        filename
+    );
+    file.rewind()?;
+    match detect_compression(filename)? {
+        Compression::None => Ok(Box::new(BufReader::with_capacity(32768, file))),
+        Compression::Zstd => {
+            let decoder = zstd::stream::read::Decoder::new(file)?;
+            Ok(Box::new(BufReader::with_capacity(32768, decoder)))
+        }
+        Compression::Gzip => {
+            let decoder = flate2::read::MultiGzDecoder::new(BufReader::new(file));
+            Ok(Box::new(BufReader::with_capacity(32768, decoder)))
+        }
+    }
+}
+
+/// Number of variants in a GWAS results file: its data rows, or its records
+/// if it is a GWAS-VCF, in `ColumnSpec::region` if given. Counts of local
+/// files are cached like those of `count_lines_with_progress`.
+pub fn count_variants(filename: &str, column_names: &ColumnSpec) -> Result<usize> {
+    match (&column_names.vcf, &column_names.region) {
+        (Some(_), region) => {
+            let counted = match region {
+                Some(region) => format!("VCF records in {}", region),
+                None => String::from("VCF records"),
+            };
+            cached_count(filename, counted, || {
+                vcf::count_records(filename, region.as_ref())
+            })
+        }
+        (None, Some(region)) => cached_count(filename, format!("rows in {}", region), || {
+            let mut reader = tabix::open_region(filename, region)?;
+            let mut num_lines: usize = 0;
+            let mut line = Vec::new();
+            while reader.read_until(b'\n', &mut line)? > 0 {
+                num_lines += 1;
+                line.clear();
+            }
+            // Less the header line
+            Ok(num_lines.saturating_sub(1))
+        }),
+        (None, None) => count_lines(filename),
+    }
+}
+
+/// Open a GWAS results file like `open_maybe_compressed`, or, if the column
+/// spec gives a region, read only its header and the rows in the region,
+/// with its tabix index (see `tabix::open_region`)
+pub(crate) fn open_rows(filename: &str, column_names: &ColumnSpec) -> Result<Box<dyn BufRead>> {
+    match &column_names.region {
+        Some(region) => tabix::open_region(filename, region),
+        None => open_maybe_compressed(filename),
+    }
+}
+
+/// Lines counted between the progress reports of `count_lines_with_progress`
+const COUNT_PROGRESS_INTERVAL: usize = 1 << 20;
+
+/// Number of data rows of a file, i.e. its lines after the header, or the
+/// rows of a Parquet file, from its footer
+pub fn count_lines(filename: &str) -> Result<usize> {
+    count_lines_with_progress(filename, &mut |_| {})
+}
+
+/// Like `count_lines`, calling `progress` with the number of lines read so
+/// far every 2^20 lines and once the file is read.
+///
+/// Counts of local files are cached for the rest of the process, keyed by
+/// path, modification time, and size, so counting a file again skips the
+/// scan, and reports no progress, unless it changed. Compressed files are
+/// decoded in full to be counted, but cached all the same.
+pub fn count_lines_with_progress(filename: &str, progress: &mut dyn FnMut(usize)) -> Result<usize> {
+    if remote::is_remote(filename) {
+        return remote::count_lines(filename);
+    }
+    if parquet::is_parquet(filename) {
+        return parquet::count_rows(filename);
+    }
+    cached_count(filename, String::from("lines"), || {
+        let mut reader = open_maybe_compressed(filename)?;
+        let mut num_lines = 0;
+        let mut string = String::new();
+        while reader.read_line(&mut string)? > 0 {
+            num_lines += 1;
+            if num_lines % COUNT_PROGRESS_INTERVAL == 0 {
+                progress(num_lines);
+            }
+            string.clear();
+        }
+        progress(num_lines);
+        ensure!(
+            num_lines > 0,
+            "{} is empty, without even a header line",
+            filename
+        );
+        Ok(num_lines - 1)
+    })
+}
+
+/// Return the cached result of `count` for `filename`, or count and cache
+/// it. `counted` tells apart the counts of the same file, e.g. of its lines
+/// and of its rows in a region.
+fn cached_count(
+    filename: &str,
+    counted: String,
+    count: impl FnOnce() -> Result<usize>,
+) -> Result<usize> {
+    type Key = (String, String, std::time::SystemTime, u64);
+    static CACHE: OnceLock<Mutex<HashMap<Key, usize>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    // Files whose modification time cannot be read are not cached
+    let key = std::fs::metadata(filename)
+        .and_then(|x| Ok((filename.to_string(), counted, x.modified()?, x.len())))
+        .ok();
+    if let Some(num) = key
+        .as_ref()
+        .and_then(|x| cache.lock().unwrap().get(x).copied())
+    {
+        return Ok(num);
+    }
+    let num = count()?;
+    if let Some(key) = key {
+        cache.lock().unwrap().insert(key, num);
+    }
+    Ok(num)
+}
+
+/// Ends of the runs of consecutive variants on the same chromosome, as the
+/// number of variants up to and including each run. Fails if a chromosome
+/// has more than one run, since its variants cannot then be kept together.
+#[derive(Default)]
+pub(crate) struct ChromosomeRuns {
+    seen: HashSet<String>,
+    current: Option<String>,
+    n_variants: usize,
+    ends: Vec<usize>,
+}
+
+impl ChromosomeRuns {
+    pub(crate) fn push(&mut self, chromosome: &str, filename: &str) -> Result<()> {
+        if self.current.as_deref() != Some(chromosome) {
+            if self.current.is_some() {
+                self.ends.push(self.n_variants);
+            }
+            ensure!(
+                self.seen.insert(chromosome.to_string()),
+                "The variants of chromosome {} are not together in {}, so it cannot be a \
+                chunk of its own. Sort the file by chromosome",
+                chromosome,
+                filename
+            );
+            self.current = Some(chromosome.to_string());
+        }
+        self.n_variants += 1;
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<usize> {
+        if self.current.is_some() {
+            self.ends.push(self.n_variants);
+        }
+        self.ends
+    }
+}
+
+/// Where the chromosomes of a GWAS results file end, as the number of
+/// variants up to and including each chromosome, in file order. The
+/// chromosome is read as described for `ColumnSpec::chromosome_column`, and
+/// the variants of each chromosome must be together.
+pub fn chromosome_ends(filename: &str, column_names: &ColumnSpec) -> Result<Vec<usize>> {
+    if column_names.vcf.is_some() {
+        return vcf::chromosome_ends(filename, column_names.region.as_ref());
+    }
+    if parquet::is_parquet(filename) {
+        return parquet::chromosome_ends(filename, column_names);
+    }
+    let (mut reader, _, _) = open_gwas_reader(filename, column_names, None)?;
+    let header = reader.headers()?;
+    let (column, from_id) = match (
+        &column_names.chromosome_column,
+        &column_names.variant_id_columns,
+    ) {
+        (Some(column), _) => (find_column(header, column, "Chromosome")?, false),
+        (None, Some(id_columns)) => (
+            find_column(header, &id_columns.chromosome, "Chromosome")?,
+            false,
+        ),
+        (None, None) => (
+            find_column(header, &column_names.variant_id, "Variant ID")?,
+            true,
+        ),
+    };
+    let mut runs = ChromosomeRuns::default();
+    for result in reader.records() {
+        let record = result?;
+        let value = record.get(column).unwrap_or_default();
+        let chromosome = match from_id {
+            true => value.split(':').next().unwrap_or_default(),
+            false => value,
+        };
+        runs.push(chromosome, filename)?;
+    }
+    Ok(runs.finish())
+}
+
+/// How to handle variants with a missing value (empty, `NA`, `NaN`, or `.`)
+/// in a numeric column of a GWAS file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum NaPolicy {
+    /// Return an error
+    #[default]
+    Error,
+    /// Drop the variant from the output
+    Skip,
+    /// Keep the variant, leaving this file out of its statistics
+    ZeroWeight,
+}
+
+/// Scale of the effect and standard error columns of a GWAS file. Effects
+/// are converted to betas on the log scale when read: an odds ratio `OR`
+/// becomes `beta = ln(OR)`. Standard errors of the log odds ratio are kept
+/// as they are, while standard errors of the odds ratio itself are converted
+/// by the delta method, `se(beta) = se(OR) / OR`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EffectScale {
+    /// Betas, e.g. log odds ratios, with their standard errors
+    #[default]
+    Beta,
+    /// Odds ratios with standard errors of the log odds ratio, like PLINK's
+    /// OR and LOG(OR)_SE columns
+    OddsRatio,
+    /// Odds ratios with standard errors of the odds ratio
+    OddsRatioSe,
+}
+
+impl EffectScale {
+    /// The beta and standard error of an effect and standard error on this
+    /// scale, or `None` for an odds ratio that is not positive
+    pub(crate) fn to_beta(self, effect: f32, se: f32) -> Option<(f32, f32)> {
+        match self {
+            EffectScale::Beta => Some((effect, se)),
+            _ if effect <= 0.0 => None,
+            EffectScale::OddsRatio => Some((effect.ln(), se)),
+            EffectScale::OddsRatioSe => Some((effect.ln(), se / effect)),
+        }
+    }
+}
+
+/// Column groups of a wide file holding several phenotypes, whose statistics
+/// are in columns named `<name><separator><phenotype>`, e.g. `BETA_height`
+/// for the beta column `BETA` and phenotype `height`
+#[derive(Debug, Clone)]
+pub struct WideColumns {
+    pub phenotypes: Vec<String>,
+    pub separator: String,
+}
+
+/// Columns from which variant ids are built, as
+/// `chromosome:position:ref:alt`, for files without a variant id column
+#[derive(Debug, Clone)]
+pub struct VariantIdColumns {
+    pub chromosome: String,
+    pub position: String,
+    pub ref_allele: String,
+    pub alt_allele: String,
+    /// Separator between the components of the id
+    pub separator: String,
+}
+
+impl VariantIdColumns {
+    fn names(&self) -> [&str; 4] {
+        [
+            &self.chromosome,
+            &self.position,
+            &self.ref_allele,
+            &self.alt_allele,
+        ]
+    }
+}
+
+/// Chromosomes whose variants are kept, e.g. to leave out the sex
+/// chromosomes. Variants on other chromosomes are read as placeholders and
+/// left out of the output, like missing values under the skip NA policy, so
+/// that every file stays aligned. A `chr` prefix is ignored when matching.
+/// The chromosome is read as described for `ColumnSpec::chromosome_column`.
+#[derive(Debug, Clone)]
+pub struct ChromosomeFilter {
+    pub chromosomes: Vec<String>,
+}
+
+impl ChromosomeFilter {
+    pub(crate) fn keeps(&self, chromosome: &str) -> bool {
+        fn strip(chromosome: &str) -> &str {
+            chromosome.strip_prefix("chr").unwrap_or(chromosome)
+        }
+        self.chromosomes
+            .iter()
+            .any(|x| strip(x) == strip(chromosome))
     }
-    Ok(num_lines - 1)
 }
 
 // Create a struct ColumnSpec to hold the names of columns
@@ -31,68 +381,989 @@ pub struct ColumnSpec {
     pub beta: String,
     pub se: String,
     pub sample_size: String,
+    /// Field delimiter. Detected from the header line when `None`.
+    pub delimiter: Option<u8>,
+    /// Split the header and rows on runs of spaces and tabs, as in files
+    /// whose columns are aligned with spaces, instead of on a single
+    /// delimiter. Leading and trailing whitespace is ignored, fields cannot
+    /// be quoted, and `delimiter` is unused.
+    pub whitespace_delimited: bool,
+    /// Name of the z-score column. When set, effects are read as z-scores
+    /// instead of beta/standard error pairs (see `GwasResults::genotype_variances`).
+    pub z_score: Option<String>,
+    /// Name of the allele frequency column, required in z-score mode
+    pub allele_freq: Option<String>,
+    pub na_policy: NaPolicy,
+    /// Build variant ids from these columns instead of reading `variant_id`
+    pub variant_id_columns: Option<VariantIdColumns>,
+    /// Names of the effect allele and effect allele frequency columns, which
+    /// are carried through to the output unchanged (see `Annotations`)
+    pub effect_allele: Option<String>,
+    pub effect_allele_freq: Option<String>,
+    /// Read the files as GWAS-VCF instead of delimited text, taking the
+    /// statistics from these FORMAT/INFO fields. The column names above are
+    /// then unused.
+    pub vcf: Option<VcfFields>,
+    /// Read several phenotypes from each file, from these column groups
+    pub wide: Option<WideColumns>,
+    /// Scale of the beta and standard error columns, see `EffectScale`
+    pub effect_scale: EffectScale,
+    /// Keep only the variants on these chromosomes
+    pub chromosome_filter: Option<ChromosomeFilter>,
+    /// Name of the chromosome column, used by the chromosome filter and to
+    /// chunk by chromosome. When `None`, the chromosome column of
+    /// `VariantIdColumns` is used if given, and otherwise the part of the
+    /// variant id before the first `:`. GWAS-VCF files always use `#CHROM`.
+    pub chromosome_column: Option<String>,
+    /// Leave out variants whose minor allele frequency, from the
+    /// `allele_freq` column or else the `effect_allele_freq` column, is
+    /// below this. A variant rare in any file is left out of the output
+    /// entirely, so the same variants remain in every file.
+    pub min_maf: Option<f32>,
+    /// Handle values that cannot be parsed, e.g. `abc` in a beta column, like
+    /// missing values under `na_policy`, rather than as errors, and count
+    /// them in `GwasResults::invalid`. Under `NaPolicy::Error`, they remain
+    /// errors.
+    pub invalid_as_missing: bool,
+    /// Read only the variants in this genomic region, from bgzipped files
+    /// with tabix indexes (see `tabix::open_region`). Set by `util::run`
+    /// from `RuntimeConfig::region`.
+    pub region: Option<Region>,
+}
+
+impl Default for ColumnSpec {
+    fn default() -> Self {
+        ColumnSpec {
+            variant_id: String::from("ID"),
+            beta: String::from("BETA"),
+            se: String::from("SE"),
+            sample_size: String::from("OBS_CT"),
+            delimiter: None,
+            whitespace_delimited: false,
+            z_score: None,
+            allele_freq: None,
+            na_policy: NaPolicy::Error,
+            variant_id_columns: None,
+            effect_allele: None,
+            effect_allele_freq: None,
+            vcf: None,
+            wide: None,
+            effect_scale: EffectScale::Beta,
+            chromosome_filter: None,
+            chromosome_column: None,
+            min_maf: None,
+            invalid_as_missing: false,
+            region: None,
+        }
+    }
+}
+
+/// Column names of one GWAS file that differ from those of the shared
+/// `ColumnSpec`, for mixing files from providers that name their columns
+/// differently
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnOverrides {
+    pub variant_id: Option<String>,
+    pub beta: Option<String>,
+    pub se: Option<String>,
+    pub sample_size: Option<String>,
+}
+
+impl ColumnOverrides {
+    /// Set the column named by `key`, one of `variant-id`, `beta`,
+    /// `std-error`, and `sample-size` (the names of the command line flags)
+    pub fn set(&mut self, key: &str, name: &str) -> Result<()> {
+        let column = match key {
+            "variant-id" => &mut self.variant_id,
+            "beta" => &mut self.beta,
+            "std-error" => &mut self.se,
+            "sample-size" => &mut self.sample_size,
+            _ => bail!(
+                "Unknown column {}, expected variant-id, beta, std-error, or sample-size",
+                key
+            ),
+        };
+        ensure!(column.is_none(), "Column {} is given more than once", key);
+        ensure!(!name.is_empty(), "Column {} has an empty name", key);
+        *column = Some(name.to_string());
+        Ok(())
+    }
+}
+
+impl ColumnSpec {
+    /// The columns of a file with the given overrides
+    pub fn with_overrides(&self, overrides: &ColumnOverrides) -> ColumnSpec {
+        let pick =
+            |name: &Option<String>, default: &String| name.as_ref().unwrap_or(default).clone();
+        ColumnSpec {
+            variant_id: pick(&overrides.variant_id, &self.variant_id),
+            beta: pick(&overrides.beta, &self.beta),
+            se: pick(&overrides.se, &self.se),
+            sample_size: pick(&overrides.sample_size, &self.sample_size),
+            ..self.clone()
+        }
+    }
+
+    /// The columns of one phenotype of a wide file: the beta, standard error,
+    /// sample size, z-score, and allele frequency columns of the phenotype's
+    /// group, and the shared variant id and annotation columns
+    pub fn for_phenotype(&self, phenotype: &str) -> ColumnSpec {
+        let Some(wide) = &self.wide else {
+            return self.clone();
+        };
+        let group = |name: &str| format!("{}{}{}", name, wide.separator, phenotype);
+        ColumnSpec {
+            beta: group(&self.beta),
+            se: group(&self.se),
+            sample_size: group(&self.sample_size),
+            z_score: self.z_score.as_deref().map(group),
+            allele_freq: self.allele_freq.as_deref().map(group),
+            wide: None,
+            ..self.clone()
+        }
+    }
+
+    /// The columns of each phenotype read from a file: those of every column
+    /// group of a wide file, in order, or else these columns
+    pub fn phenotype_columns(&self) -> Vec<ColumnSpec> {
+        match &self.wide {
+            Some(wide) => wide
+                .phenotypes
+                .iter()
+                .map(|x| self.for_phenotype(x))
+                .collect(),
+            None => vec![self.clone()],
+        }
+    }
+
+    fn names(&self) -> Vec<&str> {
+        let mut names = match &self.variant_id_columns {
+            Some(id_columns) => id_columns.names().to_vec(),
+            None => vec![self.variant_id.as_str()],
+        };
+        names.push(self.sample_size.as_str());
+        match &self.z_score {
+            Some(z_score) => names.push(z_score),
+            None => names.extend([self.beta.as_str(), self.se.as_str()]),
+        }
+        names.extend(self.allele_freq.as_deref());
+        names.extend(self.effect_allele.as_deref());
+        names.extend(self.effect_allele_freq.as_deref());
+        names.extend(self.chromosome_column.as_deref());
+        names
+    }
+}
+
+const CANDIDATE_DELIMITERS: [u8; 3] = [b'\t', b',', b' '];
+
+fn delimiter_name(delimiter: u8) -> String {
+    match delimiter {
+        b'\t' => String::from("tab"),
+        b' ' => String::from("space"),
+        x => format!("'{}'", x as char),
+    }
+}
+
+/// Fields of a header line split by `delimiter`, unquoting quoted fields
+/// like the data rows are
+fn split_header(header: &str, delimiter: u8) -> Vec<String> {
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .from_reader(header.as_bytes())
+        .records()
+        .next()
+        .and_then(|x| x.ok())
+        .map_or(Vec::new(), |x| x.iter().map(String::from).collect())
+}
+
+/// Pick the delimiter under which the header contains every column in the spec
+fn detect_delimiter(header: &str, spec: &ColumnSpec) -> Result<u8> {
+    let header = strip_bom(header).trim_end_matches(['\n', '\r']);
+    let matches: Vec<u8> = CANDIDATE_DELIMITERS
+        .into_iter()
+        .filter(|&d| {
+            let fields = split_header(header, d);
+            spec.names()
+                .iter()
+                .all(|name| fields.iter().any(|x| x == name))
+        })
+        .collect();
+    match matches.as_slice() {
+        [delimiter] => Ok(*delimiter),
+        [] => bail!(
+            "Could not detect the delimiter: no delimiter among tab, comma, and space splits the \
+            header into fields containing all of {:?}. Header: {}",
+            spec.names(),
+            header
+        ),
+        _ => bail!(
+            "Ambiguous delimiter: the header matches the columns when split by any of {}. \
+            Specify the delimiter explicitly",
+            matches
+                .iter()
+                .map(|&d| delimiter_name(d))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// The delimiter of a file with the given header line: the one given in the
+/// spec, or else the detected one. Whitespace-delimited files are read with
+/// their runs of whitespace collapsed to tabs (see `WhitespaceRuns`).
+fn resolve_delimiter(header: &str, column_names: &ColumnSpec) -> Result<u8> {
+    match (column_names.whitespace_delimited, column_names.delimiter) {
+        (true, _) => Ok(b'\t'),
+        (false, Some(delimiter)) => Ok(delimiter),
+        (false, None) => {
+            // The columns of a wide file are those of its column groups
+            let phenotype_columns = column_names.phenotype_columns();
+            detect_delimiter(header, phenotype_columns.first().unwrap_or(column_names))
+        }
+    }
+}
+
+/// Reads lines of text with each run of spaces and tabs replaced by a
+/// single tab, and leading and trailing whitespace removed, so that files
+/// aligned with spaces can be parsed as tab-separated
+struct WhitespaceRuns<R> {
+    inner: R,
+    line: Vec<u8>,
+    output: std::io::Cursor<Vec<u8>>,
+}
+
+impl<R: BufRead> WhitespaceRuns<R> {
+    fn new(inner: R) -> Self {
+        WhitespaceRuns {
+            inner,
+            line: Vec::new(),
+            output: Default::default(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for WhitespaceRuns<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.output.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.line.clear();
+            if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+                return Ok(0);
+            }
+            let mut output = std::mem::take(self.output.get_mut());
+            output.clear();
+            let fields = self
+                .line
+                .split(|x| matches!(x, b' ' | b'\t' | b'\r' | b'\n'))
+                .filter(|x| !x.is_empty());
+            for (i, field) in fields.enumerate() {
+                if i > 0 {
+                    output.push(b'\t');
+                }
+                output.extend_from_slice(field);
+            }
+            output.push(b'\n');
+            self.output = std::io::Cursor::new(output);
+        }
+    }
+}
+
+/// A csv reader of the header and rows of a GWAS results file, given as
+/// `source`, collapsing runs of whitespace first if the file is
+/// whitespace-delimited
+fn delimited_reader<'a>(
+    source: impl BufRead + 'a,
+    delimiter: u8,
+    column_names: &ColumnSpec,
+) -> csv::Reader<Box<dyn Read + 'a>> {
+    let source: Box<dyn Read + 'a> = match column_names.whitespace_delimited {
+        true => Box::new(WhitespaceRuns::new(source)),
+        false => Box::new(source),
+    };
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(true)
+        .from_reader(source)
+}
+
+/// Check that the header of a GWAS results file contains every column in the
+/// spec, without reading the rest of the file. The error lists all missing
+/// columns along with the available ones.
+pub fn validate_columns(filename: &str, column_names: &ColumnSpec) -> Result<()> {
+    if column_names.z_score.is_some() {
+        ensure!(
+            column_names.allele_freq.is_some(),
+            "An allele frequency column is required when reading z-scores"
+        );
+    }
+    if parquet::is_parquet(filename) {
+        parquet::validate_spec(column_names)?;
+        let fields = parquet::column_names(filename)?;
+        let missing: Vec<&str> = column_names
+            .names()
+            .into_iter()
+            .filter(|name| !fields.iter().any(|x| x == name))
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "Missing columns {:?} in {}. Available columns: {:?}",
+            missing,
+            filename,
+            fields
+        );
+        return Ok(());
+    }
+
+    let mut header = String::new();
+    open_rows(filename, column_names)?.read_line(&mut header)?;
+    let header = strip_bom(&header).trim_end_matches(['\n', '\r']);
+
+    // If no delimiter yields all the columns, use the one splitting the
+    // header into the most fields to report what is missing
+    let (fields, delimiter) = if column_names.whitespace_delimited {
+        let fields = header.split_whitespace().map(String::from).collect();
+        (fields, String::from("whitespace"))
+    } else {
+        let delimiter = resolve_delimiter(header, column_names).unwrap_or_else(|_| {
+            CANDIDATE_DELIMITERS
+                .into_iter()
+                .max_by_key(|&d| split_header(header, d).len())
+                .unwrap()
+        });
+        (split_header(header, delimiter), delimiter_name(delimiter))
+    };
+    let missing: Vec<&str> = column_names
+        .names()
+        .into_iter()
+        .filter(|name| !fields.iter().any(|x| x == name))
+        .collect();
+    ensure!(
+        missing.is_empty(),
+        "Missing columns {:?} in {}. Available columns (split by {}): {:?}",
+        missing,
+        filename,
+        delimiter,
+        fields
+    );
+    Ok(())
 }
 
 struct MappedColumns {
-    variant_id: usize,
+    variant_id: Vec<usize>, // The id column, or the columns the id is built from
+    id_separator: String,
     beta: usize,
-    se: usize,
+    se: Option<usize>,
     sample_size: usize,
+    allele_freq: Option<usize>,
+    effect_allele: Option<usize>,
+    effect_allele_freq: Option<usize>,
+    chromosome: Option<usize>, // Chromosome column, if not read from the id
+    maf_freq: Option<usize>,   // Frequency column of the minimum MAF filter
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GwasResults {
     pub variant_ids: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub beta_values: DVector<f32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub se_values: DVector<f32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub sample_sizes: DVector<i32>,
+    /// Genotype variances, 2p(1 - p), known when reading z-scores. In that
+    /// case `beta_values` holds the z-scores and `se_values` is all ones, so
+    /// that `beta / se` is the z-score, and the effects are rescaled later
+    /// using the phenotype partial variance.
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::option_dvector")
+    )]
+    pub genotype_variances: Option<DVector<f32>>,
+    /// Indices of variants with no data in this file. Their values are
+    /// placeholders, and the variants are excluded from the output.
+    pub missing: Vec<usize>,
+    /// Indices of variants with missing values, under `NaPolicy::Skip`. Like
+    /// `missing`, these are placeholders excluded from the output.
+    pub skipped: Vec<usize>,
+    /// Indices of variants with missing values, under `NaPolicy::ZeroWeight`.
+    /// Their values are placeholders, and this file is left out of their
+    /// statistics.
+    pub zero_weight: Vec<usize>,
+    pub annotations: Annotations,
+    /// Rows whose values could not be parsed, with
+    /// `ColumnSpec::invalid_as_missing`. They are also listed in `skipped`
+    /// or `zero_weight`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub invalid: InvalidRows,
+}
+
+/// Number of rows of `InvalidRows` described in full
+const MAX_INVALID_EXAMPLES: usize = 5;
+
+/// Count of the rows of a GWAS file whose values could not be parsed, with
+/// the errors of the first few
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InvalidRows {
+    pub count: usize,
+    /// Errors of the first `MAX_INVALID_EXAMPLES` (5) rows, e.g. "Invalid
+    /// value 'abc' on line 3: invalid float literal"
+    pub examples: Vec<String>,
 }
 
-pub struct IntermediateResults {
+impl InvalidRows {
+    fn add(&mut self, error: &anyhow::Error) {
+        self.count += 1;
+        if self.examples.len() < MAX_INVALID_EXAMPLES {
+            self.examples.push(format!("{:#}", error));
+        }
+    }
+
+    fn extend(&mut self, other: InvalidRows) {
+        self.count += other.count;
+        let n_more = MAX_INVALID_EXAMPLES - self.examples.len();
+        self.examples
+            .extend(other.examples.into_iter().take(n_more));
+    }
+}
+
+impl GwasResults {
+    /// Results of one GWAS with beta, standard error, and sample size for
+    /// every variant, without missing values or annotations
+    pub fn new(
+        variant_ids: Vec<String>,
+        beta_values: Vec<f32>,
+        se_values: Vec<f32>,
+        sample_sizes: Vec<i32>,
+    ) -> GwasResults {
+        GwasResults {
+            variant_ids,
+            beta_values: DVector::from_vec(beta_values),
+            se_values: DVector::from_vec(se_values),
+            sample_sizes: DVector::from_vec(sample_sizes),
+            genotype_variances: None,
+            missing: Vec::new(),
+            skipped: Vec::new(),
+            zero_weight: Vec::new(),
+            annotations: Annotations::default(),
+            invalid: InvalidRows::default(),
+        }
+    }
+
+    /// Concatenate the results of consecutive rows of the same file
+    fn concat(parts: Vec<GwasResults>) -> GwasResults {
+        let n: usize = parts.iter().map(|x| x.variant_ids.len()).sum();
+        let first = &parts[0];
+        let mut results = GwasResults {
+            variant_ids: Vec::with_capacity(n),
+            beta_values: DVector::zeros(n),
+            se_values: DVector::zeros(n),
+            sample_sizes: DVector::zeros(n),
+            genotype_variances: first.genotype_variances.as_ref().map(|_| DVector::zeros(n)),
+            missing: Vec::new(),
+            skipped: Vec::new(),
+            zero_weight: Vec::new(),
+            annotations: first.annotations.map(|_| Vec::with_capacity(n)),
+            invalid: InvalidRows::default(),
+        };
+        for part in parts {
+            let offset = results.variant_ids.len();
+            let rows = offset..offset + part.variant_ids.len();
+            results.variant_ids.extend(part.variant_ids);
+            results
+                .beta_values
+                .rows_range_mut(rows.clone())
+                .copy_from(&part.beta_values);
+            results
+                .se_values
+                .rows_range_mut(rows.clone())
+                .copy_from(&part.se_values);
+            results
+                .sample_sizes
+                .rows_range_mut(rows.clone())
+                .copy_from(&part.sample_sizes);
+            if let (Some(to), Some(from)) =
+                (&mut results.genotype_variances, &part.genotype_variances)
+            {
+                to.rows_range_mut(rows).copy_from(from);
+            }
+            results
+                .missing
+                .extend(part.missing.iter().map(|i| i + offset));
+            results
+                .skipped
+                .extend(part.skipped.iter().map(|i| i + offset));
+            results
+                .zero_weight
+                .extend(part.zero_weight.iter().map(|i| i + offset));
+            results.invalid.extend(part.invalid);
+            for (to, from) in [
+                (
+                    &mut results.annotations.effect_allele,
+                    part.annotations.effect_allele,
+                ),
+                (
+                    &mut results.annotations.effect_allele_freq,
+                    part.annotations.effect_allele_freq,
+                ),
+            ] {
+                if let (Some(to), Some(from)) = (to, from) {
+                    to.extend(from);
+                }
+            }
+        }
+        results
+    }
+}
+
+/// Per-variant columns carried from the GWAS files to the output unchanged.
+/// Each is `None` unless its column was named in the `ColumnSpec`.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Annotations {
+    pub effect_allele: Option<Vec<String>>,
+    pub effect_allele_freq: Option<Vec<String>>,
+}
+
+impl Annotations {
+    /// Annotation columns that are present, with their output names
+    pub fn columns(&self) -> Vec<(&'static str, &[String])> {
+        [
+            ("effect_allele", &self.effect_allele),
+            ("effect_allele_freq", &self.effect_allele_freq),
+        ]
+        .into_iter()
+        .filter_map(|(name, values)| values.as_deref().map(|x| (name, x)))
+        .collect()
+    }
+
+    fn map(&self, f: impl Fn(&[String]) -> Vec<String>) -> Annotations {
+        Annotations {
+            effect_allele: self.effect_allele.as_deref().map(&f),
+            effect_allele_freq: self.effect_allele_freq.as_deref().map(&f),
+        }
+    }
+
+    /// Repeat the annotations once for each of `n` projections
+    pub fn repeat(&self, n: usize) -> Annotations {
+        self.map(|x| std::iter::repeat_n(x, n).flatten().cloned().collect())
+    }
+}
+
+/// The contribution of one GWAS file to the running statistics, in the
+/// precision the statistics are computed in
+pub struct IntermediateResults<T = f32> {
+    pub phenotype_id: String,
     pub variant_ids: Vec<String>,
-    pub beta_update: DMatrix<f32>,
-    pub gpv_update: DVector<f32>,
+    pub beta_update: DMatrix<T>,
+    pub gpv_update: DVector<T>,
     pub sample_sizes: DVector<i32>,
+    /// Variants to exclude from the output
+    pub missing: Vec<usize>,
+    /// Variants to which this update contributes nothing
+    pub zero_weight: Vec<usize>,
+    pub annotations: Annotations,
+    /// Heterogeneity sums of this feature, if computing the statistic
+    pub heterogeneity: Option<HeterogeneitySums<T>>,
+    /// Effective sample size sums of this feature, if computing it
+    pub sample_size_sums: Option<SampleSizeSums<T>>,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IGwasResults {
     pub projection_ids: Vec<String>,
     pub variant_ids: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub beta_values: DVector<f32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub se_values: DVector<f32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub t_stat_values: DVector<f32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub p_values: DVector<f32>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::dvector"))]
     pub sample_sizes: DVector<i32>,
+    /// Lower and upper confidence interval bounds, if requested
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::option_dvector")
+    )]
+    pub ci_lower: Option<DVector<f32>>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::option_dvector")
+    )]
+    pub ci_upper: Option<DVector<f32>>,
+    /// Heterogeneity statistic of each result, if requested
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::option_dvector")
+    )]
+    pub heterogeneity: Option<DVector<f32>>,
+    /// Effective sample size of each result, if requested
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "crate::serialization::option_dvector")
+    )]
+    pub effective_sample_size: Option<DVector<f32>>,
+    pub annotations: Annotations,
+}
+
+impl IGwasResults {
+    /// Keep only the rows for which `keep` is true
+    pub fn select_rows(self, keep: &[bool]) -> IGwasResults {
+        let indices: Vec<usize> = (0..keep.len()).filter(|&i| keep[i]).collect();
+        self.take_rows(&indices)
+    }
+
+    /// The rows at `indices`, in that order
+    pub fn take_rows(&self, indices: &[usize]) -> IGwasResults {
+        fn take<T: Clone>(values: &[T], indices: &[usize]) -> Vec<T> {
+            indices.iter().map(|&i| values[i].clone()).collect()
+        }
+        let take_vector = |x: &DVector<f32>| DVector::from_vec(take(x.as_slice(), indices));
+        IGwasResults {
+            projection_ids: take(&self.projection_ids, indices),
+            variant_ids: take(&self.variant_ids, indices),
+            beta_values: take_vector(&self.beta_values),
+            se_values: take_vector(&self.se_values),
+            t_stat_values: take_vector(&self.t_stat_values),
+            p_values: take_vector(&self.p_values),
+            sample_sizes: DVector::from_vec(take(self.sample_sizes.as_slice(), indices)),
+            ci_lower: self.ci_lower.as_ref().map(take_vector),
+            ci_upper: self.ci_upper.as_ref().map(take_vector),
+            heterogeneity: self.heterogeneity.as_ref().map(take_vector),
+            effective_sample_size: self.effective_sample_size.as_ref().map(take_vector),
+            annotations: self.annotations.map(|x| take(x, indices)),
+        }
+    }
+
+    /// Concatenate the results of consecutive chunks. Returns `None` if
+    /// there are no chunks.
+    pub fn concat(chunks: &[IGwasResults]) -> Option<IGwasResults> {
+        let first = chunks.first()?;
+        let strings = |f: fn(&IGwasResults) -> &[String]| -> Vec<String> {
+            chunks.iter().flat_map(|x| f(x).iter().cloned()).collect()
+        };
+        let vector = |f: fn(&IGwasResults) -> &DVector<f32>| -> DVector<f32> {
+            DVector::from_iterator(
+                chunks.iter().map(|x| f(x).len()).sum(),
+                chunks.iter().flat_map(|x| f(x).iter().cloned()),
+            )
+        };
+        Some(IGwasResults {
+            projection_ids: strings(|x| &x.projection_ids),
+            variant_ids: strings(|x| &x.variant_ids),
+            beta_values: vector(|x| &x.beta_values),
+            se_values: vector(|x| &x.se_values),
+            t_stat_values: vector(|x| &x.t_stat_values),
+            p_values: vector(|x| &x.p_values),
+            sample_sizes: DVector::from_iterator(
+                chunks.iter().map(|x| x.sample_sizes.len()).sum(),
+                chunks.iter().flat_map(|x| x.sample_sizes.iter().cloned()),
+            ),
+            ci_lower: first
+                .ci_lower
+                .as_ref()
+                .map(|_| vector(|x| x.ci_lower.as_ref().unwrap())),
+            ci_upper: first
+                .ci_upper
+                .as_ref()
+                .map(|_| vector(|x| x.ci_upper.as_ref().unwrap())),
+            heterogeneity: first
+                .heterogeneity
+                .as_ref()
+                .map(|_| vector(|x| x.heterogeneity.as_ref().unwrap())),
+            effective_sample_size: first
+                .effective_sample_size
+                .as_ref()
+                .map(|_| vector(|x| x.effective_sample_size.as_ref().unwrap())),
+            annotations: Annotations {
+                effect_allele: first
+                    .annotations
+                    .effect_allele
+                    .as_ref()
+                    .map(|_| strings(|x| x.annotations.effect_allele.as_deref().unwrap())),
+                effect_allele_freq: first
+                    .annotations
+                    .effect_allele_freq
+                    .as_ref()
+                    .map(|_| strings(|x| x.annotations.effect_allele_freq.as_deref().unwrap())),
+            },
+        })
+    }
+
+    /// Sort the rows by projection, in order of first appearance, then by
+    /// genomic position. Positions are parsed from variant ids of the form
+    /// `chromosome<separator>position<separator>...`. Rows at the same
+    /// position keep their order.
+    pub fn sort_by_position(&self, separator: &str) -> Result<IGwasResults> {
+        let mut projection_rank: HashMap<&str, usize> = HashMap::new();
+        let mut keys = Vec::with_capacity(self.variant_ids.len());
+        for (projection_id, variant_id) in self.projection_ids.iter().zip(&self.variant_ids) {
+            let n_projections = projection_rank.len();
+            let rank = *projection_rank
+                .entry(projection_id)
+                .or_insert(n_projections);
+            keys.push((rank, parse_position(variant_id, separator)?));
+        }
+        let mut indices: Vec<usize> = (0..keys.len()).collect();
+        indices.sort_by(|&i, &j| keys[i].cmp(&keys[j]));
+        Ok(self.take_rows(&indices))
+    }
+}
+
+/// Sort key of a chromosome: numbered chromosomes in numeric order, then the
+/// others (X, Y, MT, ...) alphabetically, ignoring a `chr` prefix
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum ChromosomeKey {
+    Numbered(u32),
+    Named(String),
+}
+
+/// Parse the chromosome and position of a variant id of the form
+/// `chromosome<separator>position<separator>...`
+fn parse_position(variant_id: &str, separator: &str) -> Result<(ChromosomeKey, u64)> {
+    let mut parts = variant_id.split(separator);
+    let (Some(chromosome), Some(position)) = (parts.next(), parts.next()) else {
+        bail!(
+            "Cannot sort by position: variant id {} is not of the form \
+            chromosome{}position{}...",
+            variant_id,
+            separator,
+            separator
+        );
+    };
+    let position = position.parse().map_err(|_| {
+        anyhow!(
+            "Cannot sort by position: invalid position '{}' in variant id {}",
+            position,
+            variant_id
+        )
+    })?;
+    let chromosome = chromosome.strip_prefix("chr").unwrap_or(chromosome);
+    let chromosome = match chromosome.parse() {
+        Ok(number) => ChromosomeKey::Numbered(number),
+        Err(_) => ChromosomeKey::Named(chromosome.to_string()),
+    };
+    Ok((chromosome, position))
+}
+
+fn find_column(header: &csv::StringRecord, name: &str, description: &str) -> Result<usize> {
+    header
+        .iter()
+        .position(|x| x == name)
+        .with_context(|| format!("{} column not found", description))
 }
 
 fn map_column_names(header: &csv::StringRecord, spec: &ColumnSpec) -> Result<MappedColumns> {
     // Find the indices of the columns we want. If any of them are not found, return an error,
     // specifying which column was not found.
+    let (beta, se) = match &spec.z_score {
+        Some(z_score) => {
+            ensure!(
+                spec.allele_freq.is_some(),
+                "An allele frequency column is required when reading z-scores"
+            );
+            ensure!(
+                spec.effect_scale == EffectScale::Beta,
+                "Odds ratios cannot be read when reading z-scores"
+            );
+            (find_column(header, z_score, "Z-score")?, None)
+        }
+        None => (
+            find_column(header, &spec.beta, "Beta")?,
+            Some(find_column(header, &spec.se, "Standard error")?),
+        ),
+    };
+    let variant_id = match &spec.variant_id_columns {
+        Some(id_columns) => id_columns
+            .names()
+            .into_iter()
+            .zip([
+                "Chromosome",
+                "Position",
+                "Reference allele",
+                "Alternate allele",
+            ])
+            .map(|(name, description)| find_column(header, name, description))
+            .collect::<Result<Vec<usize>>>()?,
+        None => vec![find_column(header, &spec.variant_id, "Variant ID")?],
+    };
+    let chromosome = match &spec.chromosome_column {
+        Some(column) => Some(find_column(header, column, "Chromosome")?),
+        None if spec.variant_id_columns.is_some() => Some(variant_id[0]),
+        None => None,
+    };
+    let allele_freq = spec
+        .allele_freq
+        .as_ref()
+        .map(|x| find_column(header, x, "Allele frequency"))
+        .transpose()?;
+    let effect_allele_freq = spec
+        .effect_allele_freq
+        .as_ref()
+        .map(|x| find_column(header, x, "Effect allele frequency"))
+        .transpose()?;
+    let maf_freq = match spec.min_maf {
+        Some(min_maf) => {
+            ensure!(
+                (0.0..=0.5).contains(&min_maf),
+                "Minimum minor allele frequency must be between 0 and 0.5, got {}",
+                min_maf
+            );
+            Some(allele_freq.or(effect_allele_freq).context(
+                "An allele frequency or effect allele frequency column is required to filter by \
+                minor allele frequency",
+            )?)
+        }
+        None => None,
+    };
     Ok(MappedColumns {
-        variant_id: header
-            .iter()
-            .position(|x| x == spec.variant_id)
-            .context("Variant ID column not found")?,
-        beta: header
-            .iter()
-            .position(|x| x == spec.beta)
-            .context("Beta column not found")?,
-        se: header
-            .iter()
-            .position(|x| x == spec.se)
-            .context("Standard error column not found")?,
-        sample_size: header
-            .iter()
-            .position(|x| x == spec.sample_size)
-            .context("Sample size column not found")?,
+        variant_id,
+        id_separator: spec
+            .variant_id_columns
+            .as_ref()
+            .map_or(String::new(), |x| x.separator.clone()),
+        beta,
+        se,
+        sample_size: find_column(header, &spec.sample_size, "Sample size")?,
+        allele_freq,
+        effect_allele: spec
+            .effect_allele
+            .as_ref()
+            .map(|x| find_column(header, x, "Effect allele"))
+            .transpose()?,
+        effect_allele_freq,
+        chromosome,
+        maf_freq,
     })
 }
 
-fn read_from_record<T: std::str::FromStr>(record: &csv::StringRecord, index: usize) -> T
+impl MappedColumns {
+    fn read_variant_id(&self, record: &csv::StringRecord) -> Result<String> {
+        match self.variant_id.as_slice() {
+            [column] => read_from_record(record, *column),
+            columns => Ok(columns
+                .iter()
+                .map(|&c| read_from_record(record, c))
+                .collect::<Result<Vec<String>>>()?
+                .join(&self.id_separator)),
+        }
+    }
+}
+
+/// Whether a field holds a missing value
+pub(crate) fn is_missing_value(value: &str) -> bool {
+    matches!(value.trim(), "" | "NA" | "na" | "NaN" | "nan" | ".")
+}
+
+/// The value of `result`, or, with `ColumnSpec::invalid_as_missing` and a
+/// policy allowing missing values, `None` after counting its error in
+/// `invalid`
+pub(crate) fn tolerate_invalid<T>(
+    result: Result<T>,
+    column_names: &ColumnSpec,
+    invalid: &mut InvalidRows,
+) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if column_names.invalid_as_missing && column_names.na_policy != NaPolicy::Error => {
+            invalid.add(&e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The values of a row of a GWAS results file
+enum ParsedRow {
+    /// A variant below the minimum minor allele frequency, whose other
+    /// values are not read
+    Rare,
+    Values {
+        beta: f32,
+        se: f32,
+        sample_size: i32,
+        genotype_variance: Option<f32>,
+    },
+}
+
+fn read_from_record<T: std::str::FromStr>(record: &csv::StringRecord, index: usize) -> Result<T>
 where
-    <T as std::str::FromStr>::Err: std::fmt::Debug,
+    <T as std::str::FromStr>::Err: std::fmt::Display,
 {
-    // TODO: Error handling here.
-    record.get(index).unwrap().parse::<T>().unwrap()
+    let line = record.position().map_or(0, |x| x.line());
+    let value = record
+        .get(index)
+        .with_context(|| format!("Line {} has no field {}", line, index + 1))?;
+    value
+        .parse::<T>()
+        .map_err(|e| anyhow!("Invalid value '{}' on line {}: {}", value, line, e))
+}
+
+/// Which rows of a GWAS results file to read
+pub(crate) enum RowSelection<'a> {
+    /// Data rows from start (inclusive) to end (exclusive)
+    Range(usize, usize),
+    /// Rows whose variant id is in the set
+    Ids(&'a HashSet<&'a str>),
+}
+
+/// A data row of a GWAS results file and the byte offset at which it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowOffset {
+    pub line: usize,
+    pub byte: u64,
+}
+
+/// Open a GWAS results file and read its header line. The returned reader
+/// is positioned at the first data row, or at byte offset `data_start` if
+/// given. Also returns the delimiter and whether the byte offsets of the
+/// stream correspond to the file on disk (i.e. it is uncompressed, and read
+/// in full rather than for a region).
+fn open_data_rows(
+    filename: &str,
+    column_names: &ColumnSpec,
+    data_start: Option<u64>,
+) -> Result<(Box<dyn BufRead>, String, u8, bool)> {
+    let mut reader = open_rows(filename, column_names)?;
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let delimiter = resolve_delimiter(&header, column_names)
+        .with_context(|| format!("Error reading header of {}", filename))?;
+    let seekable = column_names.region.is_none()
+        && !remote::is_remote(filename)
+        && detect_compression(filename)? == Compression::None;
+    if let Some(offset) = data_start {
+        ensure!(seekable, "Cannot seek in compressed file {}", filename);
+        let mut file = File::open(filename)?;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        reader = Box::new(BufReader::with_capacity(32768, file));
+    }
+    Ok((reader, header, delimiter, seekable))
+}
+
+/// Open a GWAS results file for csv parsing, like `open_data_rows`. Also
+/// returns the length of the header line and whether the file is
+/// uncompressed.
+fn open_gwas_reader(
+    filename: &str,
+    column_names: &ColumnSpec,
+    data_start: Option<u64>,
+) -> Result<(csv::Reader<impl std::io::Read>, u64, bool)> {
+    let (reader, header, delimiter, seekable) = open_data_rows(filename, column_names, data_start)?;
+    let header_len = header.len() as u64;
+    let reader = delimited_reader(
+        std::io::Cursor::new(header).chain(reader),
+        delimiter,
+        column_names,
+    );
+    Ok((reader, header_len, seekable))
 }
 
 /// Read GWAS summary statistics from a file
@@ -103,66 +1374,712 @@ pub fn read_gwas_results(
     start_line: usize,
     end_line: usize,
 ) -> Result<GwasResults> {
-    if filename.ends_with(".zst") {
-        let file = File::open(filename)?;
-        let reader = BufReader::new(file);
-        let decoder = zstd::stream::read::Decoder::with_buffer(reader)?;
-        // Can't (easily) use csv_sniffer with zstd. Assume tab delimiter.
-        let mut reader = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .has_headers(true)
-            .from_reader(decoder);
-        return read_gwas_rows(&mut reader, column_names, start_line, end_line);
+    Ok(read_gwas_results_from(filename, column_names, start_line, end_line, None)?.0)
+}
+
+/// The results of a file read for a single phenotype
+pub(crate) fn single(results: Vec<GwasResults>) -> Result<GwasResults> {
+    let n = results.len();
+    let [results] = <[GwasResults; 1]>::try_from(results).map_err(|_| {
+        anyhow!(
+            "Expected the columns of one phenotype, found {}. Read wide files with \
+            read_wide_gwas_results",
+            n
+        )
+    })?;
+    Ok(results)
+}
+
+/// Read GWAS summary statistics of every phenotype of a wide file (see
+/// `WideColumns`), like `read_gwas_results`. Each row is parsed once for all
+/// of the phenotypes.
+pub fn read_wide_gwas_results(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+) -> Result<Vec<(String, GwasResults)>> {
+    let wide = column_names
+        .wide
+        .as_ref()
+        .context("No phenotype column groups given for a wide file")?;
+    let (results, _) = read_phenotypes_from(filename, column_names, start_line, end_line, None)?;
+    Ok(wide.phenotypes.iter().cloned().zip(results).collect())
+}
+
+/// Read GWAS summary statistics from a file, like `read_gwas_results`.
+///
+/// If `start` gives the byte offset of a data row at or before `start_line`,
+/// reading seeks there instead of scanning from the top of the file. The
+/// offset of `end_line` is returned, so that consecutive chunks each read
+/// only their own rows. Offsets are only available for uncompressed files.
+pub fn read_gwas_results_from(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+    start: Option<RowOffset>,
+) -> Result<(GwasResults, Option<RowOffset>)> {
+    let (results, end) = read_phenotypes_from(filename, column_names, start_line, end_line, start)?;
+    Ok((single(results)?, end))
+}
+
+/// Read the GWAS summary statistics of each phenotype of a file (see
+/// `ColumnSpec::phenotype_columns`), like `read_gwas_results_from`
+pub(crate) fn read_phenotypes_from(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+    start: Option<RowOffset>,
+) -> Result<(Vec<GwasResults>, Option<RowOffset>)> {
+    if remote::is_remote(filename) {
+        let results = read_remote_rows(filename, column_names, start_line, end_line, 1)?;
+        return Ok((results, None));
     }
-    let mut reader = csv_sniffer::Sniffer::new().open_path(filename)?;
-    read_gwas_rows(&mut reader, column_names, start_line, end_line)
+    if parquet::is_parquet(filename) {
+        let selection = RowSelection::Range(start_line, end_line);
+        return Ok((
+            parquet::read_phenotypes(filename, column_names, selection)?,
+            None,
+        ));
+    }
+    // Positions in the collapsed text of a whitespace-delimited file are not
+    // those of the file, so read its lines first to know where they end
+    if column_names.whitespace_delimited {
+        return read_phenotypes_parallel(filename, column_names, start_line, end_line, start, 1);
+    }
+    let start = start.filter(|x| x.line <= start_line);
+    let (mut reader, header_len, seekable) =
+        open_gwas_reader(filename, column_names, start.map(|x| x.byte))?;
+    let (skipped, data_start) = match start {
+        Some(offset) => (offset.line, offset.byte),
+        None => (0, header_len),
+    };
+    let results = read_gwas_rows(
+        &mut reader,
+        column_names,
+        RowSelection::Range(start_line - skipped, end_line - skipped),
+    )?;
+
+    // Positions in the stream count the header, followed by the data rows
+    // from data_start onward
+    let n_rows = results.first().map_or(0, |x| x.variant_ids.len());
+    let end = (seekable && n_rows == end_line - start_line).then(|| RowOffset {
+        line: end_line,
+        byte: data_start + reader.position().byte() - header_len,
+    });
+    Ok((results, end))
 }
 
-fn read_gwas_rows<T: std::io::Read>(
-    reader: &mut csv::Reader<T>,
+/// Read rows `start_line..end_line` of a remote file (see `io::remote`),
+/// fetching only those rows and the header, and parse them in `n_parts`
+/// parts in parallel
+fn read_remote_rows(
+    path: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+    n_parts: usize,
+) -> Result<Vec<GwasResults>> {
+    let (header, rows) = remote::read_lines(path, start_line, end_line)?;
+    let delimiter = std::str::from_utf8(&header)
+        .map_err(anyhow::Error::from)
+        .and_then(|header| resolve_delimiter(header, column_names))
+        .with_context(|| format!("Error reading header of {}", path))?;
+    parse_rows_parallel(&header, &rows, delimiter, column_names, n_parts)
+}
+
+/// Byte offset of the start of the line `n_lines` lines after `from` in
+/// `data`, or the end of `data` if it has fewer lines
+pub(crate) fn skip_lines(data: &[u8], from: usize, n_lines: usize) -> usize {
+    let mut position = from;
+    for _ in 0..n_lines {
+        match data[position..].iter().position(|&x| x == b'\n') {
+            Some(i) => position += i + 1,
+            None => return data.len(),
+        }
+    }
+    position
+}
+
+/// Read GWAS summary statistics from a file, like `read_gwas_results_from`,
+/// but by memory-mapping the file rather than reading it through a buffer.
+/// The rows of the chunk are found by scanning the mapped file for newlines
+/// from `start` (or the header), and only those rows are parsed, in
+/// `n_parts` parts in parallel (see `read_gwas_results_parallel`).
+///
+/// This is often faster for large uncompressed files; compressed files
+/// cannot be mapped. The file must not be modified while it is read.
+pub fn read_gwas_results_mmap(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+    start: Option<RowOffset>,
+    n_parts: usize,
+) -> Result<(GwasResults, Option<RowOffset>)> {
+    let (results, end) =
+        read_phenotypes_mmap(filename, column_names, start_line, end_line, start, n_parts)?;
+    Ok((single(results)?, end))
+}
+
+/// Read the GWAS summary statistics of each phenotype of a file, like
+/// `read_gwas_results_mmap`
+pub(crate) fn read_phenotypes_mmap(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+    start: Option<RowOffset>,
+    n_parts: usize,
+) -> Result<(Vec<GwasResults>, Option<RowOffset>)> {
+    ensure!(
+        !remote::is_remote(filename),
+        "Cannot memory-map {}, which is not a local file",
+        filename
+    );
+    // Parquet is not text, and its row groups are read as needed instead
+    if parquet::is_parquet(filename) {
+        let selection = RowSelection::Range(start_line, end_line);
+        return Ok((
+            parquet::read_phenotypes(filename, column_names, selection)?,
+            None,
+        ));
+    }
+    ensure!(
+        detect_compression(filename)? == Compression::None,
+        "Cannot memory-map compressed file {}",
+        filename
+    );
+    ensure!(
+        column_names.region.is_none(),
+        "Cannot memory-map {} to read a region, which needs a bgzipped file",
+        filename
+    );
+    let file = File::open(filename)?;
+    // Safety: the map is only read, and the file is not expected to change
+    // during the run, as with the other readers
+    let data = unsafe { memmap2::Mmap::map(&file) }
+        .with_context(|| format!("Failed to memory-map {}", filename))?;
+
+    let header_len = skip_lines(&data, 0, 1);
+    let header = std::str::from_utf8(&data[..header_len])
+        .with_context(|| format!("Header of {} is not valid UTF-8", filename))?;
+    let delimiter = resolve_delimiter(header, column_names)
+        .with_context(|| format!("Error reading header of {}", filename))?;
+
+    let start = start.filter(|x| x.line <= start_line && x.byte as usize <= data.len());
+    let (skipped, data_start) = match start {
+        Some(offset) => (offset.line, offset.byte as usize),
+        None => (0, header_len),
+    };
+    let chunk_start = skip_lines(&data, data_start, start_line - skipped);
+    let chunk_end = skip_lines(&data, chunk_start, end_line - start_line);
+
+    let results = parse_rows_parallel(
+        &data[..header_len],
+        &data[chunk_start..chunk_end],
+        delimiter,
+        column_names,
+        n_parts,
+    )?;
+    let n_rows = results.first().map_or(0, |x| x.variant_ids.len());
+    let end = (n_rows == end_line - start_line).then_some(RowOffset {
+        line: end_line,
+        byte: chunk_end as u64,
+    });
+    Ok((results, end))
+}
+
+/// Parse `rows`, whole lines of a GWAS results file with the given header
+/// line, in up to `n_parts` parts of about equal size, in parallel in the
+/// current rayon pool. The results of each phenotype's parts are
+/// concatenated in order.
+fn parse_rows_parallel(
+    header: &[u8],
+    rows: &[u8],
+    delimiter: u8,
+    column_names: &ColumnSpec,
+    n_parts: usize,
+) -> Result<Vec<GwasResults>> {
+    // Move each split forward to the start of the next line
+    let mut bounds = vec![0];
+    for k in 1..n_parts.max(1) {
+        let split = skip_lines(rows, rows.len() * k / n_parts, 1);
+        if split > *bounds.last().unwrap() && split < rows.len() {
+            bounds.push(split);
+        }
+    }
+    bounds.push(rows.len());
+
+    let parts = bounds
+        .par_windows(2)
+        .map(|range| {
+            let mut reader = delimited_reader(
+                header.chain(&rows[range[0]..range[1]]),
+                delimiter,
+                column_names,
+            );
+            read_gwas_rows(
+                &mut reader,
+                column_names,
+                RowSelection::Range(0, usize::MAX),
+            )
+        })
+        .collect::<Result<Vec<Vec<GwasResults>>>>()?;
+
+    let mut phenotypes: Vec<Vec<GwasResults>> = parts[0].iter().map(|_| Vec::new()).collect();
+    for part in parts {
+        for (phenotype, results) in phenotypes.iter_mut().zip(part) {
+            phenotype.push(results);
+        }
+    }
+    Ok(phenotypes.into_iter().map(GwasResults::concat).collect())
+}
+
+/// Read GWAS summary statistics from a file, like `read_gwas_results_from`,
+/// but parse the rows in `n_parts` parts in parallel, in the current rayon
+/// pool. The rows of the chunk are first read into memory, then split at
+/// line boundaries, so this helps most with wide files, whose parsing
+/// rather than reading takes most of the time. The results are the same as
+/// `read_gwas_results_from`.
+pub fn read_gwas_results_parallel(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+    start: Option<RowOffset>,
+    n_parts: usize,
+) -> Result<(GwasResults, Option<RowOffset>)> {
+    let (results, end) =
+        read_phenotypes_parallel(filename, column_names, start_line, end_line, start, n_parts)?;
+    Ok((single(results)?, end))
+}
+
+/// Read the GWAS summary statistics of each phenotype of a file, like
+/// `read_gwas_results_parallel`
+pub(crate) fn read_phenotypes_parallel(
+    filename: &str,
     column_names: &ColumnSpec,
     start_line: usize,
     end_line: usize,
+    start: Option<RowOffset>,
+    n_parts: usize,
+) -> Result<(Vec<GwasResults>, Option<RowOffset>)> {
+    if remote::is_remote(filename) {
+        let results = read_remote_rows(filename, column_names, start_line, end_line, n_parts)?;
+        return Ok((results, None));
+    }
+    if parquet::is_parquet(filename) {
+        let selection = RowSelection::Range(start_line, end_line);
+        return Ok((
+            parquet::read_phenotypes(filename, column_names, selection)?,
+            None,
+        ));
+    }
+    let start = start.filter(|x| x.line <= start_line);
+    let (mut reader, header, delimiter, seekable) =
+        open_data_rows(filename, column_names, start.map(|x| x.byte))?;
+    let (skipped, data_start) = match start {
+        Some(offset) => (offset.line, offset.byte),
+        None => (0, header.len() as u64),
+    };
+
+    // Skip to the chunk, then read its lines
+    let mut rows = Vec::new();
+    let mut n_skipped_bytes = 0;
+    for _ in skipped..start_line {
+        let n = reader.read_until(b'\n', &mut rows)?;
+        if n == 0 {
+            break;
+        }
+        n_skipped_bytes += n as u64;
+        rows.clear();
+    }
+    for _ in start_line..end_line {
+        if reader.read_until(b'\n', &mut rows)? == 0 {
+            break;
+        }
+    }
+
+    let results = parse_rows_parallel(header.as_bytes(), &rows, delimiter, column_names, n_parts)?;
+    let n_rows = results.first().map_or(0, |x| x.variant_ids.len());
+    let end = (seekable && n_rows == end_line - start_line).then(|| RowOffset {
+        line: end_line,
+        byte: data_start + n_skipped_bytes + rows.len() as u64,
+    });
+    Ok((results, end))
+}
+
+/// Read GWAS summary statistics for the given variants, in the given order,
+/// regardless of where they appear in the file. Variants absent from the file
+/// are listed in `GwasResults::missing`.
+pub fn read_gwas_results_by_id(
+    filename: &str,
+    column_names: &ColumnSpec,
+    variant_ids: &[String],
 ) -> Result<GwasResults> {
-    // Get the indices of the columns we want
+    single(read_phenotypes_by_id(filename, column_names, variant_ids)?)
+}
+
+/// Read the GWAS summary statistics of each phenotype of a file for the
+/// given variants, like `read_gwas_results_by_id`
+pub(crate) fn read_phenotypes_by_id(
+    filename: &str,
+    column_names: &ColumnSpec,
+    variant_ids: &[String],
+) -> Result<Vec<GwasResults>> {
+    if parquet::is_parquet(filename) {
+        return parquet::read_phenotypes_by_id(filename, column_names, variant_ids);
+    }
+    let wanted: HashSet<&str> = variant_ids.iter().map(|x| x.as_str()).collect();
+    let (mut reader, _, _) = open_gwas_reader(filename, column_names, None)?;
+    read_gwas_rows(&mut reader, column_names, RowSelection::Ids(&wanted))?
+        .into_iter()
+        .map(|found| align_to_ids(found, filename, variant_ids))
+        .collect()
+}
+
+/// Reorder the variants read from `filename` to `variant_ids`, marking those
+/// it lacks as missing
+pub(crate) fn align_to_ids(
+    found: GwasResults,
+    filename: &str,
+    variant_ids: &[String],
+) -> Result<GwasResults> {
+    let mut found_idx: HashMap<&str, usize> = HashMap::with_capacity(found.variant_ids.len());
+    for (i, variant_id) in found.variant_ids.iter().enumerate() {
+        ensure!(
+            found_idx.insert(variant_id, i).is_none(),
+            "Variant {} appears more than once in {}",
+            variant_id,
+            filename
+        );
+    }
+
+    let n = variant_ids.len();
+    let mut results = GwasResults {
+        variant_ids: variant_ids.to_vec(),
+        beta_values: DVector::zeros(n),
+        se_values: DVector::from_element(n, 1.0),
+        sample_sizes: DVector::from_element(n, i32::MAX),
+        genotype_variances: found
+            .genotype_variances
+            .as_ref()
+            .map(|_| DVector::from_element(n, 1.0)),
+        missing: Vec::new(),
+        skipped: Vec::new(),
+        zero_weight: Vec::new(),
+        annotations: found.annotations.map(|values| {
+            // Variants absent from the file have empty annotations
+            variant_ids
+                .iter()
+                .map(|x| {
+                    found_idx
+                        .get(x.as_str())
+                        .map_or(String::new(), |&j| values[j].clone())
+                })
+                .collect()
+        }),
+        invalid: found.invalid.clone(),
+    };
+    let skipped: HashSet<usize> = found.skipped.iter().cloned().collect();
+    let zero_weight: HashSet<usize> = found.zero_weight.iter().cloned().collect();
+    for (i, variant_id) in variant_ids.iter().enumerate() {
+        match found_idx.get(variant_id.as_str()) {
+            Some(&j) => {
+                if skipped.contains(&j) {
+                    results.skipped.push(i);
+                }
+                if zero_weight.contains(&j) {
+                    results.zero_weight.push(i);
+                }
+                results.beta_values[i] = found.beta_values[j];
+                results.se_values[i] = found.se_values[j];
+                results.sample_sizes[i] = found.sample_sizes[j];
+                if let (Some(to), Some(from)) =
+                    (&mut results.genotype_variances, &found.genotype_variances)
+                {
+                    to[i] = from[j];
+                }
+            }
+            None => results.missing.push(i),
+        }
+    }
+    Ok(results)
+}
+
+/// The values of one phenotype of a file, collected row by row. Its variant
+/// ids and annotations are shared with the file's other phenotypes.
+#[derive(Default)]
+pub(crate) struct PhenotypeRows {
+    beta_values: Vec<f32>,
+    se_values: Vec<f32>,
+    sample_sizes: Vec<i32>,
+    genotype_variances: Vec<f32>,
+    skipped: Vec<usize>,
+    zero_weight: Vec<usize>,
+    pub(crate) invalid: InvalidRows,
+}
+
+impl PhenotypeRows {
+    pub(crate) fn push(
+        &mut self,
+        beta: f32,
+        se: f32,
+        sample_size: i32,
+        genotype_variance: Option<f32>,
+    ) {
+        self.beta_values.push(beta);
+        self.se_values.push(se);
+        self.sample_sizes.push(sample_size);
+        self.genotype_variances.extend(genotype_variance);
+    }
+
+    /// Add placeholder values for a row without values, left out of the
+    /// output (`skip`) or only of this phenotype's statistics
+    pub(crate) fn push_missing(&mut self, skip: bool, z_score_mode: bool) {
+        let row = self.beta_values.len();
+        match skip {
+            true => self.skipped.push(row),
+            false => self.zero_weight.push(row),
+        }
+        self.push(0.0, 1.0, i32::MAX, z_score_mode.then_some(1.0));
+    }
+
+    /// The results of each phenotype, sharing `variant_ids` and `annotations`
+    pub(crate) fn finish_all(
+        phenotypes: Vec<PhenotypeRows>,
+        mut variant_ids: Vec<String>,
+        annotations: Annotations,
+        z_score_mode: bool,
+    ) -> Vec<GwasResults> {
+        let mut results = Vec::with_capacity(phenotypes.len());
+        let mut phenotypes = phenotypes.into_iter().peekable();
+        while let Some(rows) = phenotypes.next() {
+            // Move the variant ids to the last phenotype rather than copy them
+            let variant_ids = match phenotypes.peek() {
+                Some(_) => variant_ids.clone(),
+                None => std::mem::take(&mut variant_ids),
+            };
+            results.push(rows.finish(variant_ids, annotations.clone(), z_score_mode));
+        }
+        results
+    }
+
+    fn finish(
+        self,
+        variant_ids: Vec<String>,
+        annotations: Annotations,
+        z_score_mode: bool,
+    ) -> GwasResults {
+        GwasResults {
+            variant_ids,
+            beta_values: DVector::from_vec(self.beta_values),
+            se_values: DVector::from_vec(self.se_values),
+            sample_sizes: DVector::from_vec(self.sample_sizes),
+            genotype_variances: z_score_mode.then(|| DVector::from_vec(self.genotype_variances)),
+            missing: Vec::new(),
+            skipped: self.skipped,
+            zero_weight: self.zero_weight,
+            annotations,
+            invalid: self.invalid,
+        }
+    }
+}
+
+/// Read the selected rows of a GWAS results file, parsing each row once for
+/// all the phenotypes of `column_names` (see `ColumnSpec::phenotype_columns`)
+fn read_gwas_rows<T: std::io::Read>(
+    reader: &mut csv::Reader<T>,
+    column_names: &ColumnSpec,
+    selection: RowSelection,
+) -> Result<Vec<GwasResults>> {
+    // Get the indices of the columns we want. The variant id and annotation
+    // columns are shared by all phenotypes.
     let header = reader.headers()?;
-    let mapped_columns = map_column_names(header, column_names)?;
+    let phenotype_columns = column_names.phenotype_columns();
+    let mapped_columns = phenotype_columns
+        .iter()
+        .map(|x| map_column_names(header, x))
+        .collect::<Result<Vec<MappedColumns>>>()?;
+    let Some(shared) = mapped_columns.first() else {
+        return Ok(Vec::new());
+    };
 
     let mut variant_ids: Vec<String> = Vec::new();
-    let mut beta_values: Vec<f32> = Vec::new();
-    let mut se_values: Vec<f32> = Vec::new();
-    let mut sample_sizes: Vec<i32> = Vec::new();
+    let z_score_mode = shared.se.is_none();
+    let mut phenotypes: Vec<PhenotypeRows> =
+        mapped_columns.iter().map(|_| Default::default()).collect();
+    let mut effect_alleles: Vec<String> = Vec::new();
+    let mut effect_allele_freqs: Vec<String> = Vec::new();
+
+    let value_columns: Vec<Vec<usize>> = mapped_columns
+        .iter()
+        .map(|x| {
+            let mut columns = vec![x.beta, x.sample_size];
+            columns.extend(x.se);
+            columns.extend(x.allele_freq);
+            columns.extend(x.maf_freq);
+            columns
+        })
+        .collect();
 
-    for (i, result) in reader.records().enumerate() {
+    // Stop right after the last row of a range, so that the reader position
+    // is the start of the next row
+    let limit = match selection {
+        RowSelection::Range(_, end_line) => end_line,
+        RowSelection::Ids(_) => usize::MAX,
+    };
+    for (i, result) in reader.records().take(limit).enumerate() {
         let record = result?;
-        if i < start_line {
-            continue;
+        match selection {
+            RowSelection::Range(start_line, _) => {
+                if i < start_line {
+                    continue;
+                }
+            }
+            RowSelection::Ids(wanted) => {
+                let is_wanted = match shared.variant_id.as_slice() {
+                    [column] => record.get(*column).is_some_and(|x| wanted.contains(x)),
+                    _ => shared
+                        .read_variant_id(&record)
+                        .is_ok_and(|x| wanted.contains(x.as_str())),
+                };
+                if !is_wanted {
+                    continue;
+                }
+            }
         }
-        if i >= end_line {
-            break;
+        let variant_id = shared.read_variant_id(&record)?;
+        if let Some(column) = shared.effect_allele {
+            effect_alleles.push(read_from_record(&record, column)?);
+        }
+        if let Some(column) = shared.effect_allele_freq {
+            effect_allele_freqs.push(read_from_record(&record, column)?);
         }
-        variant_ids.push(read_from_record(&record, mapped_columns.variant_id));
-        beta_values.push(read_from_record(&record, mapped_columns.beta));
-        se_values.push(read_from_record(&record, mapped_columns.se));
-        sample_sizes.push(read_from_record(&record, mapped_columns.sample_size));
+        let off_chromosome = column_names
+            .chromosome_filter
+            .as_ref()
+            .is_some_and(|filter| {
+                let chromosome = match shared.chromosome {
+                    Some(column) => record.get(column).unwrap_or_default(),
+                    None => variant_id.split(':').next().unwrap_or_default(),
+                };
+                !filter.keeps(chromosome)
+            });
+        for ((rows, columns), value_columns) in phenotypes
+            .iter_mut()
+            .zip(&mapped_columns)
+            .zip(&value_columns)
+        {
+            let has_missing_value = value_columns
+                .iter()
+                .any(|&c| record.get(c).is_some_and(is_missing_value));
+            // Off-chromosome variants and those with missing values are not
+            // parsed, and invalid ones are handled like missing values
+            let parsed = match off_chromosome || has_missing_value {
+                true => None,
+                false => tolerate_invalid(
+                    parse_row(&record, columns, column_names),
+                    column_names,
+                    &mut rows.invalid,
+                )?,
+            };
+            let Some(ParsedRow::Values {
+                beta,
+                se,
+                sample_size,
+                genotype_variance,
+            }) = parsed
+            else {
+                let is_rare = matches!(parsed, Some(ParsedRow::Rare));
+                match column_names.na_policy {
+                    _ if off_chromosome || is_rare => rows.push_missing(true, z_score_mode),
+                    NaPolicy::Error => bail!(
+                        "Missing value for variant {} on line {}. Use the skip or zero-weight NA \
+                        policy to allow missing values",
+                        variant_id,
+                        record.position().map_or(0, |x| x.line())
+                    ),
+                    NaPolicy::Skip => rows.push_missing(true, z_score_mode),
+                    NaPolicy::ZeroWeight => rows.push_missing(false, z_score_mode),
+                }
+                continue;
+            };
+            rows.push(beta, se, sample_size, genotype_variance);
+        }
+        variant_ids.push(variant_id);
     }
 
-    // Return the results
-    Ok(GwasResults {
+    let annotations = Annotations {
+        effect_allele: shared.effect_allele.map(|_| effect_alleles),
+        effect_allele_freq: shared.effect_allele_freq.map(|_| effect_allele_freqs),
+    };
+    Ok(PhenotypeRows::finish_all(
+        phenotypes,
         variant_ids,
-        beta_values: DVector::from_vec(beta_values),
-        se_values: DVector::from_vec(se_values),
-        sample_sizes: DVector::from_vec(sample_sizes),
-    })
+        annotations,
+        z_score_mode,
+    ))
+}
+
+/// Parse the values of a row without missing values
+fn parse_row(
+    record: &csv::StringRecord,
+    mapped_columns: &MappedColumns,
+    column_names: &ColumnSpec,
+) -> Result<ParsedRow> {
+    if let (Some(min_maf), Some(column)) = (column_names.min_maf, mapped_columns.maf_freq) {
+        let freq: f32 = read_from_record(record, column)?;
+        if freq.min(1.0 - freq) < min_maf {
+            return Ok(ParsedRow::Rare);
+        }
+    }
+    let effect: f32 = read_from_record(record, mapped_columns.beta)?;
+    let sample_size = read_from_record(record, mapped_columns.sample_size)?;
+    let row = match mapped_columns.se {
+        Some(se) => {
+            let (beta, se) = column_names
+                .effect_scale
+                .to_beta(effect, read_from_record(record, se)?)
+                .with_context(|| {
+                    format!(
+                        "Invalid odds ratio {} on line {}: odds ratios must be positive",
+                        effect,
+                        record.position().map_or(0, |x| x.line())
+                    )
+                })?;
+            ParsedRow::Values {
+                beta,
+                se,
+                sample_size,
+                genotype_variance: None,
+            }
+        }
+        None => {
+            let freq: f32 = read_from_record(record, mapped_columns.allele_freq.unwrap())?;
+            ParsedRow::Values {
+                beta: effect,
+                se: 1.0,
+                sample_size,
+                genotype_variance: Some(2.0 * freq * (1.0 - freq)),
+            }
+        }
+    };
+    Ok(row)
 }
 
 fn write_rows<W: std::io::Write>(
     writer: &mut csv::Writer<W>,
     results: &IGwasResults,
     add_header: bool,
+    config: &OutputConfig,
 ) -> Result<()> {
+    let annotations = results.annotations.columns();
     if add_header {
-        writer.write_record([
+        let mut header = vec![
             "phenotype_id",
             "variant_id",
             "beta",
@@ -170,11 +2087,31 @@ fn write_rows<W: std::io::Write>(
             "t_stat",
             "p_value",
             "sample_size",
-        ])?;
+        ];
+        if config.raw_p_values {
+            header.push("raw_p_value");
+        }
+        if config.chi_square {
+            header.push("chi_square");
+        }
+        if results.ci_lower.is_some() {
+            header.extend(["ci_lower", "ci_upper"]);
+        }
+        if results.heterogeneity.is_some() {
+            header.push("heterogeneity_q");
+        }
+        if results.effective_sample_size.is_some() {
+            header.push("effective_sample_size");
+        }
+        header.extend(annotations.iter().map(|(name, _)| *name));
+        writer.write_record(header)?;
     }
 
     for i in 0..results.variant_ids.len() {
-        writer.write_record(&[
+        if !passes_threshold(results, i, config) {
+            continue;
+        }
+        let mut record = vec![
             results.projection_ids[i].clone(),
             results.variant_ids[i].clone(),
             results.beta_values[i].to_string(),
@@ -182,42 +2119,406 @@ fn write_rows<W: std::io::Write>(
             results.t_stat_values[i].to_string(),
             results.p_values[i].to_string(),
             results.sample_sizes[i].to_string(),
-        ])?;
+        ];
+        if config.raw_p_values {
+            record.push(format_pvalue(results.p_values[i]));
+        }
+        if config.chi_square {
+            record.push(results.t_stat_values[i].powi(2).to_string());
+        }
+        if let (Some(lower), Some(upper)) = (&results.ci_lower, &results.ci_upper) {
+            record.push(lower[i].to_string());
+            record.push(upper[i].to_string());
+        }
+        if let Some(heterogeneity) = &results.heterogeneity {
+            record.push(heterogeneity[i].to_string());
+        }
+        if let Some(effective_sample_size) = &results.effective_sample_size {
+            record.push(effective_sample_size[i].to_string());
+        }
+        for (_, values) in &annotations {
+            record.push(values[i].clone());
+        }
+        writer.write_record(&record)?;
     }
 
     Ok(())
 }
 
+/// Whether row `i` passes the `min_neg_log_p` threshold, which NaN p-values
+/// never do
+pub(crate) fn passes_threshold(results: &IGwasResults, i: usize, config: &OutputConfig) -> bool {
+    match config.min_neg_log_p {
+        Some(threshold) => !results.p_values[i].is_nan() && results.p_values[i] >= threshold,
+        None => true,
+    }
+}
+
+/// Write `value` as a JSON string, escaping quotes, backslashes, and control
+/// characters
+fn write_json_string<W: std::io::Write>(writer: &mut W, value: &str) -> std::io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if c.is_control() => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    writer.write_all(b"\"")
+}
+
+/// Write `value` as a JSON number, or null if it is NaN or infinite, which
+/// JSON cannot represent
+fn write_json_number<W: std::io::Write>(writer: &mut W, value: f32) -> std::io::Result<()> {
+    if value.is_finite() {
+        write!(writer, "{}", value)
+    } else {
+        writer.write_all(b"null")
+    }
+}
+
+/// Write each row as a JSON object on its own line (NDJSON), with keys
+/// `variant_id`, `projection_id`, `beta`, `se`, `t_stat`, `neg_log_p`, and
+/// `n`, followed by the optional columns under their names in the delimited
+/// output. There is no header.
+fn write_ndjson_rows<W: std::io::Write>(
+    writer: &mut W,
+    results: &IGwasResults,
+    config: &OutputConfig,
+) -> Result<()> {
+    let annotations = results.annotations.columns();
+    for i in 0..results.variant_ids.len() {
+        if !passes_threshold(results, i, config) {
+            continue;
+        }
+        writer.write_all(b"{\"variant_id\":")?;
+        write_json_string(writer, &results.variant_ids[i])?;
+        writer.write_all(b",\"projection_id\":")?;
+        write_json_string(writer, &results.projection_ids[i])?;
+        let mut numbers = vec![
+            ("beta", results.beta_values[i]),
+            ("se", results.se_values[i]),
+            ("t_stat", results.t_stat_values[i]),
+            ("neg_log_p", results.p_values[i]),
+        ];
+        if config.chi_square {
+            numbers.push(("chi_square", results.t_stat_values[i].powi(2)));
+        }
+        if let (Some(lower), Some(upper)) = (&results.ci_lower, &results.ci_upper) {
+            numbers.extend([("ci_lower", lower[i]), ("ci_upper", upper[i])]);
+        }
+        if let Some(heterogeneity) = &results.heterogeneity {
+            numbers.push(("heterogeneity_q", heterogeneity[i]));
+        }
+        if let Some(effective_sample_size) = &results.effective_sample_size {
+            numbers.push(("effective_sample_size", effective_sample_size[i]));
+        }
+        for (key, value) in numbers {
+            write!(writer, ",\"{}\":", key)?;
+            write_json_number(writer, value)?;
+        }
+        write!(writer, ",\"n\":{}", results.sample_sizes[i])?;
+        if config.raw_p_values {
+            // Written as text, since it may be below the range of f64
+            writer.write_all(b",\"raw_p_value\":")?;
+            write_json_string(writer, &format_pvalue(results.p_values[i]))?;
+        }
+        for (name, values) in &annotations {
+            write!(writer, ",\"{}\":", name)?;
+            write_json_string(writer, &values[i])?;
+        }
+        writer.write_all(b"}\n")?;
+    }
+    Ok(())
+}
+
+/// Format of the output files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Delimited text with a header line, e.g. tab-separated
+    #[default]
+    Delimited,
+    /// Newline-delimited JSON, one object per row
+    Ndjson,
+    /// Typed Parquet table, with one row group per chunk (see
+    /// `io::parquet::ParquetWriter`). Requires the `parquet` feature.
+    Parquet,
+}
+
+/// Options controlling how results are written
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    /// Format of the output file
+    pub format: OutputFormat,
+    /// Field delimiter of the output file, if delimited
+    pub delimiter: u8,
+    /// Whether to compress the output using zstd
+    pub compress: bool,
+    /// Whether to add a column with the p-value itself, next to -log10 p
+    pub raw_p_values: bool,
+    /// Whether to add a column with the chi-square statistic, the square of
+    /// the t-statistic
+    pub chi_square: bool,
+    /// Whether to sort each projection's rows by genomic position, parsed
+    /// from the variant ids (see `IGwasResults::sort_by_position`). Since the
+    /// output is computed chunk by chunk, all results are then held in memory
+    /// and written at the end, instead of as each chunk finishes.
+    pub sort_by_position: bool,
+    /// Only write rows whose -log10 p-value is at least this threshold
+    pub min_neg_log_p: Option<f32>,
+    /// Whether to treat the output path as a directory and write each
+    /// projection to its own file there (see
+    /// `write_gwas_results_by_projection`)
+    pub split_by_projection: bool,
+    /// Path of a tab-separated file to write the genomic inflation factor
+    /// (lambda GC) of each projection to
+    pub lambda_gc_file: Option<String>,
+    /// Path of a tab-separated file to write the most significant variant of
+    /// each projection to
+    pub top_hits_file: Option<String>,
+    /// Write a manifest of the run's inputs and parameters (see
+    /// `io::manifest::Manifest`) to `<output>.manifest.json`
+    pub manifest: bool,
+    /// Whether to append the results to an existing output, without a
+    /// header, rather than replacing it
+    pub append: bool,
+    /// Whether to fsync each output file once it is written, so that the
+    /// results already written survive a crash of the machine, not only of
+    /// the process. Each write is flushed either way.
+    pub sync: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            format: OutputFormat::Delimited,
+            delimiter: b'\t',
+            compress: false,
+            raw_p_values: false,
+            chi_square: false,
+            sort_by_position: false,
+            min_neg_log_p: None,
+            split_by_projection: false,
+            lambda_gc_file: None,
+            top_hits_file: None,
+            manifest: false,
+            append: false,
+            sync: false,
+        }
+    }
+}
+
+/// Write a small tab-separated table, such as a summary of the results
+pub fn write_summary(
+    filename: &str,
+    header: &[&str],
+    rows: impl IntoIterator<Item = Vec<String>>,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(filename)?;
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(&row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Path that `write_gwas_results` takes to mean standard output
+pub const STDOUT_PATH: &str = "-";
+
+/// How `write_gwas_results` opens its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Create the file, or truncate an existing one, and write a header
+    Create,
+    /// Append to an existing file, without a header
+    Append,
+}
+
+/// Compression of the output written to `filename`: zstd if configured,
+/// otherwise gzip if the name ends in `.gz`
+pub fn output_compression(filename: &str, config: &OutputConfig) -> Compression {
+    if config.compress {
+        Compression::Zstd
+    } else if filename.ends_with(".gz") {
+        Compression::Gzip
+    } else {
+        Compression::None
+    }
+}
+
+/// Write the results to `filename`, or to standard output if it is
+/// `STDOUT_PATH`, which gets a header in `WriteMode::Create`.
+///
+/// Parquet output is written as a complete file in `WriteMode::Create`, with
+/// its column names in the schema rather than a header, and cannot be
+/// appended to. To write chunk by chunk, keep an `io::parquet::ParquetWriter`
+/// open instead.
 pub fn write_gwas_results(
     results: IGwasResults,
     filename: &str,
-    add_header: bool,
-    compress: bool,
+    mode: WriteMode,
+    config: &OutputConfig,
 ) -> Result<()> {
-    let file = if add_header {
-        OpenOptions::new()
+    if config.format == OutputFormat::Parquet {
+        ensure!(
+            filename != STDOUT_PATH,
+            "Cannot write Parquet to standard output"
+        );
+        ensure!(
+            mode == WriteMode::Create,
+            "Cannot append to Parquet output {}, which is complete once written",
+            filename
+        );
+        let mut writer = crate::io::parquet::ParquetWriter::create(filename, config)?;
+        writer.write(&results)?;
+        return writer.close();
+    }
+
+    let add_header = mode == WriteMode::Create;
+    if filename == STDOUT_PATH {
+        let compression = output_compression(filename, config);
+        return write_to(
+            std::io::stdout().lock(),
+            &results,
+            add_header,
+            config,
+            compression,
+        );
+    }
+
+    let mut file = match mode {
+        WriteMode::Create => OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(filename)?
-    } else {
-        OpenOptions::new().append(true).open(filename)?
+            .open(filename)?,
+        WriteMode::Append => OpenOptions::new()
+            .append(true)
+            .open(filename)
+            .with_context(|| format!("Cannot append to {}", filename))?,
     };
+    let compression = output_compression(filename, config);
+    write_to(&mut file, &results, add_header, config, compression)?;
+    if config.sync {
+        file.sync_data()
+            .with_context(|| format!("Failed to sync {} to disk", filename))?;
+    }
+    Ok(())
+}
 
-    if compress {
-        let zstd_writer = zstd::stream::write::Encoder::new(file, 0)?.auto_finish();
-        let mut writer = csv::WriterBuilder::new()
-            .delimiter(b'\t')
-            .buffer_capacity(8 * (1 << 13))
-            .from_writer(zstd_writer);
-        write_rows(&mut writer, &results, add_header)?;
-    } else {
-        let mut writer = csv::WriterBuilder::new()
-            .delimiter(b'\t')
-            .buffer_capacity(8 * (1 << 13))
-            .from_writer(file);
-        write_rows(&mut writer, &results, add_header)?;
+/// Write the results to `output`, compressed with `compression`. Each call
+/// writes a complete zstd frame or gzip member, so appended chunks
+/// decompress as one stream.
+fn write_to<W: std::io::Write>(
+    output: W,
+    results: &IGwasResults,
+    add_header: bool,
+    config: &OutputConfig,
+    compression: Compression,
+) -> Result<()> {
+    match compression {
+        Compression::None => write_uncompressed(output, results, add_header, config),
+        Compression::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(output, 0)?;
+            write_uncompressed(&mut encoder, results, add_header, config)?;
+            encoder.finish()?.flush()?;
+            Ok(())
+        }
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(output, Default::default());
+            write_uncompressed(&mut encoder, results, add_header, config)?;
+            encoder.finish()?.flush()?;
+            Ok(())
+        }
+    }
+}
+
+fn write_uncompressed<W: std::io::Write>(
+    output: W,
+    results: &IGwasResults,
+    add_header: bool,
+    config: &OutputConfig,
+) -> Result<()> {
+    if config.format == OutputFormat::Ndjson {
+        let mut writer = std::io::BufWriter::with_capacity(8 * (1 << 13), output);
+        write_ndjson_rows(&mut writer, results, config)?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(config.delimiter)
+        .buffer_capacity(8 * (1 << 13))
+        .from_writer(output);
+    write_rows(&mut writer, results, add_header, config)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Name of the file that `write_gwas_results_by_projection` writes a
+/// projection to, e.g. `p1.tsv`, `p1.ndjson`, or `p1.tsv.zst` if compressed
+pub fn projection_file_name(projection_id: &str, config: &OutputConfig) -> String {
+    let extension = match (config.format, config.delimiter) {
+        (OutputFormat::Ndjson, _) => "ndjson",
+        (OutputFormat::Parquet, _) => "parquet",
+        (_, b'\t') => "tsv",
+        (_, b',') => "csv",
+        _ => "txt",
     };
+    let mut name = format!("{}.{}", projection_id, extension);
+    if config.compress {
+        name.push_str(".zst");
+    }
+    name
+}
+
+/// Write the rows of each projection to its own file in `directory`, named
+/// by `projection_file_name`, opening each file like `write_gwas_results`
+pub fn write_gwas_results_by_projection(
+    results: IGwasResults,
+    directory: &str,
+    mode: WriteMode,
+    config: &OutputConfig,
+) -> Result<()> {
+    if mode == WriteMode::Create {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Error creating output directory: {}", directory))?;
+    }
+
+    // Rows of each projection, in order of first appearance
+    let mut projection_rows: Vec<(&str, Vec<usize>)> = Vec::new();
+    let mut projection_index: HashMap<&str, usize> = HashMap::new();
+    for (i, projection_id) in results.projection_ids.iter().enumerate() {
+        let index = *projection_index.entry(projection_id).or_insert_with(|| {
+            projection_rows.push((projection_id, Vec::new()));
+            projection_rows.len() - 1
+        });
+        projection_rows[index].1.push(i);
+    }
+
+    for (projection_id, rows) in projection_rows {
+        ensure!(
+            !projection_id.is_empty()
+                && !projection_id.contains(['/', '\\'])
+                && projection_id != "."
+                && projection_id != "..",
+            "Projection {:?} cannot be used as a file name",
+            projection_id
+        );
+        let path =
+            std::path::Path::new(directory).join(projection_file_name(projection_id, config));
+        let path = path.to_str().unwrap();
+        write_gwas_results(results.take_rows(&rows), path, mode, config)
+            .with_context(|| format!("Error writing GWAS results to file: {}", path))?;
+    }
 
     Ok(())
 }
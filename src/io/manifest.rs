@@ -0,0 +1,101 @@
+//! JSON manifest of a run, written next to its output. Writing it requires
+//! the `manifest` feature; without it, asking for a manifest is an error.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+/// What a run read and wrote, recorded next to its output for
+/// reproducibility
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "manifest", derive(serde::Serialize))]
+pub struct Manifest {
+    pub igwas_version: String,
+    /// When the run finished, written in RFC 3339 format
+    #[cfg_attr(feature = "manifest", serde(serialize_with = "json::rfc3339"))]
+    pub timestamp: SystemTime,
+    pub projection_matrix: HashedFile,
+    pub covariance_matrix: HashedFile,
+    /// The phenotypes used and their GWAS files, in the order of the
+    /// projection matrix
+    pub gwas_files: Vec<PhenotypeFile>,
+    pub output: String,
+    pub num_covar: usize,
+    pub chunksize: usize,
+    pub num_threads: usize,
+    pub num_variants: usize,
+    pub num_projections: usize,
+}
+
+/// Path of an input file and its contents' hash
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "manifest", derive(serde::Serialize))]
+pub struct HashedFile {
+    pub path: String,
+    /// 64-bit FNV-1a hash of the file, as for checkpoints, written in
+    /// hexadecimal
+    #[cfg_attr(
+        feature = "manifest",
+        serde(rename = "fnv1a_64", serialize_with = "json::hex")
+    )]
+    pub hash: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "manifest", derive(serde::Serialize))]
+pub struct PhenotypeFile {
+    pub phenotype: String,
+    pub path: String,
+}
+
+/// Write a manifest as a JSON object, replacing any previous one
+pub fn write_manifest(manifest: &Manifest, filename: &str) -> Result<()> {
+    json::write(manifest, filename)
+}
+
+#[cfg(feature = "manifest")]
+mod json {
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use std::time::SystemTime;
+
+    use anyhow::{Context, Result};
+    use serde::Serializer;
+
+    use super::Manifest;
+
+    pub fn rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_rfc3339_seconds(*time))
+    }
+
+    pub fn hex<S: Serializer>(hash: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:016x}", hash))
+    }
+
+    pub fn write(manifest: &Manifest, filename: &str) -> Result<()> {
+        let file =
+            File::create(filename).with_context(|| format!("Failed to create {}", filename))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, manifest)
+            .with_context(|| format!("Failed to write {}", filename))?;
+        writeln!(writer)?;
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write {}", filename))
+    }
+}
+
+#[cfg(not(feature = "manifest"))]
+mod json {
+    use anyhow::{bail, Result};
+
+    use super::Manifest;
+
+    pub fn write(_manifest: &Manifest, filename: &str) -> Result<()> {
+        bail!(
+            "Cannot write {}: writing a manifest requires igwas to be built with the manifest \
+            feature",
+            filename
+        )
+    }
+}
@@ -1,49 +1,511 @@
-use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Read};
+
+use anyhow::{bail, ensure, Context, Result};
 use nalgebra::DMatrix;
 
+use crate::io::gwas::{open_maybe_compressed, strip_bom};
+use crate::io::parquet;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
 pub struct LabeledMatrix {
     pub row_labels: Vec<String>,
     pub col_labels: Vec<String>,
     pub matrix: DMatrix<f32>,
 }
 
+impl LabeledMatrix {
+    /// The submatrix with the given rows and columns, in the given order
+    pub fn select(&self, row_labels: &[String], col_labels: &[String]) -> Result<LabeledMatrix> {
+        let find = |labels: &[String], label: &String, kind: &str| {
+            labels
+                .iter()
+                .position(|x| x == label)
+                .with_context(|| format!("No {} labeled {} in the matrix", kind, label))
+        };
+        let rows = row_labels
+            .iter()
+            .map(|x| find(&self.row_labels, x, "row"))
+            .collect::<Result<Vec<usize>>>()?;
+        let cols = col_labels
+            .iter()
+            .map(|x| find(&self.col_labels, x, "column"))
+            .collect::<Result<Vec<usize>>>()?;
+        Ok(LabeledMatrix {
+            row_labels: row_labels.to_vec(),
+            col_labels: col_labels.to_vec(),
+            matrix: DMatrix::from_fn(rows.len(), cols.len(), |i, j| {
+                self.matrix[(rows[i], cols[j])]
+            }),
+        })
+    }
+
+    /// Check that every entry is finite, naming the first that is not, e.g.
+    /// a NaN left by a failed upstream computation
+    pub fn ensure_finite(&self, name: &str) -> Result<()> {
+        for (i, row) in self.matrix.row_iter().enumerate() {
+            if let Some((j, value)) = row.iter().enumerate().find(|(_, x)| !x.is_finite()) {
+                bail!(
+                    "The {} matrix has a non-finite value, {}, in row {} and column {}",
+                    name,
+                    value,
+                    self.row_labels[i],
+                    self.col_labels[j]
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the matrix is square, with the same row and column labels
+    /// in the same order, as a covariance matrix must be
+    pub fn ensure_square(&self) -> Result<()> {
+        let (n_rows, n_cols) = self.matrix.shape();
+        ensure!(
+            n_rows == n_cols,
+            "The matrix is not square: it has {} rows and {} columns",
+            n_rows,
+            n_cols
+        );
+        if let Some((row, col)) = self
+            .row_labels
+            .iter()
+            .zip(&self.col_labels)
+            .find(|(row, col)| row != col)
+        {
+            bail!(
+                "The row and column labels of the matrix differ: a row labeled {} is in the \
+                place of the column labeled {}",
+                row,
+                col
+            );
+        }
+        Ok(())
+    }
+}
+
 /// Read a matrix from a file
+/// Delimited files have labels on both axes: column labels in the first row
+/// and row labels in the first column. The first row may start with a corner
+/// cell, which is ignored, or hold only the column labels; which of these it
+/// is follows from the number of fields of the first row and of the others.
+/// Like GWAS results files, delimited files may be compressed with gzip or
+/// zstd, and the delimiter follows from the name without `.gz` or `.zst`.
+/// NumPy `.npy` files take their labels from `<name>.rows.txt` and
+/// `<name>.cols.txt` next to `<name>.npy`. Parquet files, detected from
+/// their first bytes, are read with `read_labeled_matrix_parquet`.
 pub fn read_labeled_matrix(filename: &str) -> Result<LabeledMatrix> {
-    let mut reader = csv::ReaderBuilder::new()
-        .delimiter(if filename.ends_with(".csv") {
-            b','
-        } else {
-            b'\t'
+    read_labeled_matrix_with_layout(filename).map(|(matrix, _)| matrix)
+}
+
+/// Where the labels of a matrix file are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixLayout {
+    /// Delimited, with a corner cell before the column labels
+    CornerCell,
+    /// Delimited, with only the column labels in the first row
+    HeaderOnly,
+    /// NumPy `.npy`, with labels in separate files
+    Npy,
+    /// Parquet, with row labels in the first column
+    Parquet,
+}
+
+impl std::fmt::Display for MatrixLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            MatrixLayout::CornerCell => "delimited, with a corner cell",
+            MatrixLayout::HeaderOnly => "delimited, without a corner cell",
+            MatrixLayout::Npy => "NumPy .npy, with label files",
+            MatrixLayout::Parquet => "Parquet, with row labels in the first column",
         })
-        .from_path(filename)
-        .with_context(|| format!("Failed to open file {}", filename))?;
+    }
+}
+
+/// Read a matrix from a file, like `read_labeled_matrix`, and the layout it
+/// was found in
+fn read_labeled_matrix_with_layout(filename: &str) -> Result<(LabeledMatrix, MatrixLayout)> {
+    if let Some(stem) = filename.strip_suffix(".npy") {
+        let matrix = read_labeled_matrix_npy(
+            filename,
+            &format!("{}.rows.txt", stem),
+            &format!("{}.cols.txt", stem),
+        )?;
+        return Ok((matrix, MatrixLayout::Npy));
+    }
+    if parquet::is_parquet(filename) {
+        return Ok((
+            read_labeled_matrix_parquet(filename)?,
+            MatrixLayout::Parquet,
+        ));
+    }
+    let file = open_maybe_compressed(filename)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(
+            if filename
+                .trim_end_matches(".zst")
+                .trim_end_matches(".gz")
+                .ends_with(".csv")
+            {
+                b','
+            } else {
+                b'\t'
+            },
+        )
+        .flexible(true)
+        .from_reader(file);
 
-    let mut row_labels = Vec::new();
-    let mut matrix = Vec::new();
+    let header = reader.headers()?.clone();
+    let records = reader
+        .records()
+        .collect::<Result<Vec<csv::StringRecord>, _>>()
+        .with_context(|| format!("Failed to read matrix {}", filename))?;
+    ensure!(
+        !records.is_empty(),
+        "Matrix {} has no rows, so whether its first row starts with a corner cell is \
+        ambiguous",
+        filename
+    );
+    let n_fields = records[0].len();
+    if let Some((i, record)) = records
+        .iter()
+        .enumerate()
+        .find(|(_, x)| x.len() != n_fields)
+    {
+        bail!(
+            "Row {} of matrix {} has {} fields, but the row before it has {}",
+            i + 2,
+            filename,
+            record.len(),
+            n_fields
+        );
+    }
 
-    // Read the col_labels from the reader header property
-    let col_labels: Vec<String> = reader
-        .headers()?
-        .clone()
+    // With a corner cell, the first row is as long as the others, and
+    // without, it is one field shorter
+    let layout = match header.len() {
+        x if x == n_fields => MatrixLayout::CornerCell,
+        x if x + 1 == n_fields => MatrixLayout::HeaderOnly,
+        x => bail!(
+            "The first row of matrix {} has {} fields, but the other rows have {}, so it \
+            neither starts with a corner cell nor holds only the column labels",
+            filename,
+            x,
+            n_fields
+        ),
+    };
+    let col_labels: Vec<String> = header
         .iter()
-        .skip(1)
+        .skip((layout == MatrixLayout::CornerCell) as usize)
         .map(|x| x.to_string())
         .collect();
 
-    for result in reader.records() {
-        let record = result?;
-        for (i, value) in record.iter().enumerate() {
-            if i == 0 {
-                row_labels.push(value.to_string());
-            } else {
-                matrix.push(value.parse::<f32>().unwrap());
-            }
+    let mut row_labels = Vec::with_capacity(records.len());
+    let mut matrix = Vec::with_capacity(records.len() * col_labels.len());
+    for (i, record) in records.iter().enumerate() {
+        row_labels.push(record[0].to_string());
+        for value in record.iter().skip(1) {
+            matrix.push(value.parse::<f32>().with_context(|| {
+                format!(
+                    "Invalid value '{}' in row {} of matrix {}",
+                    value,
+                    i + 2,
+                    filename
+                )
+            })?);
         }
     }
 
     // Convert the matrix to a DMatrix
     let matrix = DMatrix::from_row_slice(row_labels.len(), col_labels.len(), &matrix);
 
+    let matrix = LabeledMatrix {
+        row_labels,
+        col_labels,
+        matrix,
+    };
+    Ok((matrix, layout))
+}
+
+/// Labels and entries shown by a `MatrixSummary`, from the start of each axis
+const SUMMARY_LABELS: usize = 5;
+const SUMMARY_ENTRIES: usize = 3;
+
+/// Overview of a matrix file, to check it without loading it elsewhere
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixSummary {
+    pub layout: MatrixLayout,
+    pub n_rows: usize,
+    pub n_cols: usize,
+    /// The first few row and column labels
+    pub row_labels: Vec<String>,
+    pub col_labels: Vec<String>,
+    /// The top-left entries, by row
+    pub entries: Vec<Vec<f32>>,
+    pub square: bool,
+    /// Whether the matrix is square, has the same row and column labels in
+    /// the same order, and equals its transpose up to a relative tolerance
+    /// of 1e-5, as a covariance matrix should
+    pub symmetric: bool,
+}
+
+impl std::fmt::Display for MatrixSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Layout: {}", self.layout)?;
+        writeln!(f, "Shape: {} x {}", self.n_rows, self.n_cols)?;
+        let labels = |labels: &[String], n: usize| match n > labels.len() {
+            true => format!("{}, ...", labels.join(", ")),
+            false => labels.join(", "),
+        };
+        writeln!(f, "Rows: {}", labels(&self.row_labels, self.n_rows))?;
+        writeln!(f, "Columns: {}", labels(&self.col_labels, self.n_cols))?;
+        writeln!(f, "Square: {}", if self.square { "yes" } else { "no" })?;
+        writeln!(
+            f,
+            "Symmetric: {}",
+            if self.symmetric { "yes" } else { "no" }
+        )?;
+        writeln!(f, "Top-left entries:")?;
+        for row in &self.entries {
+            let row: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+            writeln!(f, "{}", row.join("\t"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a matrix file with `read_labeled_matrix` and summarize it: its
+/// layout, shape, first labels and entries, and whether it is square and
+/// symmetric
+pub fn describe_labeled_matrix(filename: &str) -> Result<MatrixSummary> {
+    let (labeled, layout) = read_labeled_matrix_with_layout(filename)?;
+    let matrix = &labeled.matrix;
+    let (n_rows, n_cols) = matrix.shape();
+    let square = n_rows == n_cols;
+    let symmetric = square
+        && labeled.row_labels == labeled.col_labels
+        && (0..n_rows).all(|i| {
+            (0..i).all(|j| {
+                let (x, y) = (matrix[(i, j)], matrix[(j, i)]);
+                (x - y).abs() <= 1e-5 * x.abs().max(y.abs())
+            })
+        });
+    let first = |labels: &[String]| labels.iter().take(SUMMARY_LABELS).cloned().collect();
+    Ok(MatrixSummary {
+        layout,
+        n_rows,
+        n_cols,
+        row_labels: first(&labeled.row_labels),
+        col_labels: first(&labeled.col_labels),
+        entries: (0..n_rows.min(SUMMARY_ENTRIES))
+            .map(|i| {
+                (0..n_cols.min(SUMMARY_ENTRIES))
+                    .map(|j| matrix[(i, j)])
+                    .collect()
+            })
+            .collect(),
+        square,
+        symmetric,
+    })
+}
+
+/// Read labels from a file with one label per line
+fn read_labels(filename: &str) -> Result<Vec<String>> {
+    let file = std::fs::File::open(filename)
+        .with_context(|| format!("Failed to open label file {}", filename))?;
+    let mut labels = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let label = strip_bom(&line).trim();
+        if !label.is_empty() {
+            labels.push(label.to_string());
+        }
+    }
+    Ok(labels)
+}
+
+/// Find the value for a key in the Python dict literal of an .npy header
+fn npy_header_value<'a>(header: &'a str, key: &str) -> Result<&'a str> {
+    let pattern = format!("'{}':", key);
+    let start = header
+        .find(&pattern)
+        .with_context(|| format!("Missing '{}' in .npy header", key))?
+        + pattern.len();
+    let value = header[start..].trim_start();
+    let end = if value.starts_with('(') {
+        value.find(')').map(|x| x + 1)
+    } else {
+        value.find(',')
+    }
+    .with_context(|| format!("Malformed '{}' in .npy header", key))?;
+    Ok(value[..end].trim())
+}
+
+/// Read a 2-dimensional float array from a NumPy .npy file
+fn read_npy(filename: &str) -> Result<DMatrix<f32>> {
+    let mut bytes = Vec::new();
+    std::fs::File::open(filename)
+        .with_context(|| format!("Failed to open file {}", filename))?
+        .read_to_end(&mut bytes)?;
+
+    ensure!(
+        bytes.len() >= 10 && bytes.starts_with(NPY_MAGIC),
+        "File {} is not a valid .npy file",
+        filename
+    );
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 => {
+            ensure!(bytes.len() >= 12, "File {} is truncated", filename);
+            (
+                u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+                12,
+            )
+        }
+        v => bail!("Unsupported .npy format version {} in {}", v, filename),
+    };
+    let data_start = header_start + header_len;
+    ensure!(bytes.len() >= data_start, "File {} is truncated", filename);
+    let header = std::str::from_utf8(&bytes[header_start..data_start])?;
+
+    let descr = npy_header_value(header, "descr")?.trim_matches(|c| c == '\'' || c == '"');
+    let fortran_order = match npy_header_value(header, "fortran_order")? {
+        "True" => true,
+        "False" => false,
+        x => bail!("Malformed 'fortran_order' in .npy header: {}", x),
+    };
+    let shape = npy_header_value(header, "shape")?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .context("Malformed 'shape' in .npy header")?;
+    ensure!(
+        shape.len() == 2,
+        "Expected a 2-dimensional array in {}, got shape {:?}",
+        filename,
+        shape
+    );
+    let (nrows, ncols) = (shape[0], shape[1]);
+
+    let data = &bytes[data_start..];
+    let values: Vec<f32> = match descr {
+        "<f4" => data
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+            .collect(),
+        ">f4" => data
+            .chunks_exact(4)
+            .map(|x| f32::from_be_bytes(x.try_into().unwrap()))
+            .collect(),
+        "<f8" => data
+            .chunks_exact(8)
+            .map(|x| f64::from_le_bytes(x.try_into().unwrap()) as f32)
+            .collect(),
+        ">f8" => data
+            .chunks_exact(8)
+            .map(|x| f64::from_be_bytes(x.try_into().unwrap()) as f32)
+            .collect(),
+        x => bail!(
+            "Unsupported .npy dtype {} in {}, expected f4 or f8",
+            x,
+            filename
+        ),
+    };
+    ensure!(
+        values.len() == nrows * ncols,
+        "File {} has {} values, expected {} for shape ({}, {})",
+        filename,
+        values.len(),
+        nrows * ncols,
+        nrows,
+        ncols
+    );
+
+    Ok(if fortran_order {
+        DMatrix::from_column_slice(nrows, ncols, &values)
+    } else {
+        DMatrix::from_row_slice(nrows, ncols, &values)
+    })
+}
+
+/// Read a matrix from a NumPy .npy file, with row and column labels from
+/// files holding one label per line
+pub fn read_labeled_matrix_npy(
+    filename: &str,
+    row_labels_path: &str,
+    col_labels_path: &str,
+) -> Result<LabeledMatrix> {
+    let matrix = read_npy(filename)?;
+    let row_labels = read_labels(row_labels_path)?;
+    let col_labels = read_labels(col_labels_path)?;
+
+    ensure!(
+        matrix.nrows() == row_labels.len(),
+        "Matrix {} has {} rows, but {} has {} labels",
+        filename,
+        matrix.nrows(),
+        row_labels_path,
+        row_labels.len()
+    );
+    ensure!(
+        matrix.ncols() == col_labels.len(),
+        "Matrix {} has {} columns, but {} has {} labels",
+        filename,
+        matrix.ncols(),
+        col_labels_path,
+        col_labels.len()
+    );
+
+    Ok(LabeledMatrix {
+        row_labels,
+        col_labels,
+        matrix,
+    })
+}
+
+/// Read a matrix from a Parquet file, with row labels in its first column
+/// and column labels from the names of the others, which hold numbers. This
+/// requires the `parquet` feature.
+pub fn read_labeled_matrix_parquet(filename: &str) -> Result<LabeledMatrix> {
+    let names = parquet::column_names(filename)?;
+    ensure!(
+        names.len() > 1,
+        "Matrix {} has no columns besides the row labels",
+        filename
+    );
+    let names: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
+    let mut columns = parquet::read_columns(filename, &names, 0, usize::MAX)?.into_iter();
+    let row_labels = columns
+        .next()
+        .expect("a column is read for each name")
+        .into_text()
+        .into_iter()
+        .enumerate()
+        .map(|(i, label)| {
+            label.with_context(|| format!("Missing label of row {} of matrix {}", i + 1, filename))
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let col_labels: Vec<String> = names[1..].iter().map(|x| x.to_string()).collect();
+    let columns = columns
+        .zip(&col_labels)
+        .map(|(values, col_label)| values.into_numbers(col_label, filename))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut matrix = DMatrix::zeros(row_labels.len(), col_labels.len());
+    for (j, values) in columns.iter().enumerate() {
+        for (i, value) in values.iter().enumerate() {
+            matrix[(i, j)] = value.with_context(|| {
+                format!(
+                    "Missing value in row {} and column {} of matrix {}",
+                    row_labels[i], col_labels[j], filename
+                )
+            })? as f32;
+        }
+    }
+
     Ok(LabeledMatrix {
         row_labels,
         col_labels,
@@ -1,2 +1,8 @@
+pub mod checkpoint;
 pub mod gwas;
+pub mod manifest;
 pub mod matrix;
+pub mod parquet;
+pub mod remote;
+pub mod tabix;
+pub mod vcf;
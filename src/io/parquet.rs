@@ -0,0 +1,744 @@
+//! Parquet input and output, a typed alternative to delimited text that
+//! e.g. DuckDB loads without parsing. Reading or writing it requires the
+//! `parquet` feature; without it, either is an error.
+//!
+//! GWAS results are read from Parquet by the column names of the
+//! `ColumnSpec`, directly as typed values. Reading a chunk of rows only
+//! reads the row groups holding them, and the number of rows is taken from
+//! the footer.
+//!
+//! A Parquet file ends in a footer describing all of its row groups, so it
+//! cannot be reopened to add to. `ParquetWriter` instead stays open for the
+//! whole run and writes each chunk as a row group. The column names are part
+//! of the schema, so unlike delimited text there is no header to include or
+//! leave out.
+
+use std::collections::HashSet;
+use std::io::Read;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::io::gwas::{
+    align_to_ids, single, tolerate_invalid, Annotations, ChromosomeRuns, ColumnSpec, GwasResults,
+    IGwasResults, NaPolicy, OutputConfig, PhenotypeRows, RowSelection, PARQUET_MAGIC,
+};
+use crate::io::remote;
+
+/// Writes results to a Parquet file, one row group per call to `write`
+pub struct ParquetWriter {
+    inner: imp::Writer,
+}
+
+impl ParquetWriter {
+    /// Create `filename`, replacing any previous file. The columns are those
+    /// of the delimited output for the same `config`, typed: strings for the
+    /// ids and annotations, 32-bit integers for the sample sizes, a 64-bit
+    /// float for the raw p-value, and 32-bit floats for the rest.
+    pub fn create(filename: &str, config: &OutputConfig) -> Result<Self> {
+        Ok(ParquetWriter {
+            inner: imp::Writer::create(filename, config)?,
+        })
+    }
+
+    /// Write the rows of `results` that pass the configured threshold as one
+    /// row group. Every call must have the same columns as the first.
+    pub fn write(&mut self, results: &IGwasResults) -> Result<()> {
+        self.inner.write(results)
+    }
+
+    /// Write the footer, without which the file cannot be read, and sync the
+    /// file to disk if configured
+    pub fn close(self) -> Result<()> {
+        self.inner.close()
+    }
+}
+
+/// The values of a column of a Parquet file, `None` where null
+pub enum ColumnValues {
+    Strings(Vec<Option<String>>),
+    /// Integers and floats, which are read as the 32-bit floats and integers
+    /// of the statistics, so lose nothing as 64-bit floats
+    Numbers(Vec<Option<f64>>),
+}
+
+impl ColumnValues {
+    pub(crate) fn into_strings(self, name: &str, filename: &str) -> Result<Vec<Option<String>>> {
+        match self {
+            ColumnValues::Strings(x) => Ok(x),
+            ColumnValues::Numbers(_) => {
+                bail!(
+                    "Column {} of {} holds numbers, expected strings",
+                    name,
+                    filename
+                )
+            }
+        }
+    }
+
+    pub(crate) fn into_numbers(self, name: &str, filename: &str) -> Result<Vec<Option<f64>>> {
+        match self {
+            ColumnValues::Numbers(x) => Ok(x),
+            ColumnValues::Strings(_) => {
+                bail!(
+                    "Column {} of {} holds strings, expected numbers",
+                    name,
+                    filename
+                )
+            }
+        }
+    }
+
+    /// The values as strings, e.g. for a chromosome column of numbers
+    pub(crate) fn into_text(self) -> Vec<Option<String>> {
+        match self {
+            ColumnValues::Strings(x) => x,
+            ColumnValues::Numbers(x) => x.into_iter().map(|x| x.map(|x| x.to_string())).collect(),
+        }
+    }
+}
+
+/// Whether `filename` is a local Parquet file, from its magic bytes. Files
+/// that cannot be read are not, so that opening them reports why.
+pub fn is_parquet(filename: &str) -> bool {
+    let mut magic = [0; PARQUET_MAGIC.len()];
+    !remote::is_remote(filename)
+        && std::fs::File::open(filename)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+        && magic == PARQUET_MAGIC
+}
+
+/// Names of the columns of a Parquet file, from its footer
+pub fn column_names(filename: &str) -> Result<Vec<String>> {
+    imp::column_names(filename)
+}
+
+/// Number of rows of a Parquet file, from its footer
+pub fn count_rows(filename: &str) -> Result<usize> {
+    imp::count_rows(filename)
+}
+
+/// The values of the columns `names` in rows `start_row..end_row` of a
+/// Parquet file, in the order of `names`. Only the row groups holding the
+/// rows are read.
+pub fn read_columns(
+    filename: &str,
+    names: &[&str],
+    start_row: usize,
+    end_row: usize,
+) -> Result<Vec<ColumnValues>> {
+    imp::read_columns(filename, names, start_row, end_row)
+}
+
+/// Check that a column spec can be used to read Parquet GWAS results files
+pub fn validate_spec(column_names: &ColumnSpec) -> Result<()> {
+    ensure!(
+        column_names.z_score.is_none()
+            && column_names.effect_allele.is_none()
+            && column_names.effect_allele_freq.is_none(),
+        "Z-scores and annotation columns are not supported for Parquet files"
+    );
+    ensure!(
+        column_names.variant_id_columns.is_none(),
+        "Building variant ids from several columns is not supported for Parquet files"
+    );
+    ensure!(
+        column_names.min_maf.is_none(),
+        "Filtering by minor allele frequency is not supported for Parquet files"
+    );
+    ensure!(
+        column_names.region.is_none(),
+        "Reading a region is not supported for Parquet files, which have no tabix index"
+    );
+    Ok(())
+}
+
+/// Read GWAS summary statistics from rows `start_line..end_line` of a
+/// Parquet file, like `gwas::read_gwas_results`
+pub fn read_gwas_results_parquet(
+    filename: &str,
+    column_names: &ColumnSpec,
+    start_line: usize,
+    end_line: usize,
+) -> Result<GwasResults> {
+    single(read_phenotypes(
+        filename,
+        column_names,
+        RowSelection::Range(start_line, end_line),
+    )?)
+}
+
+/// Read the statistics of each phenotype of a Parquet file for the given
+/// variants, like `gwas::read_gwas_results_by_id`
+pub(crate) fn read_phenotypes_by_id(
+    filename: &str,
+    column_names: &ColumnSpec,
+    variant_ids: &[String],
+) -> Result<Vec<GwasResults>> {
+    let wanted: HashSet<&str> = variant_ids.iter().map(|x| x.as_str()).collect();
+    read_phenotypes(filename, column_names, RowSelection::Ids(&wanted))?
+        .into_iter()
+        .map(|found| align_to_ids(found, filename, variant_ids))
+        .collect()
+}
+
+/// Where the chromosomes of a Parquet file end, like `gwas::chromosome_ends`
+pub(crate) fn chromosome_ends(filename: &str, column_names: &ColumnSpec) -> Result<Vec<usize>> {
+    let (name, from_id) = match &column_names.chromosome_column {
+        Some(column) => (column.as_str(), false),
+        None => (column_names.variant_id.as_str(), true),
+    };
+    let values = read_columns(filename, &[name], 0, usize::MAX)?;
+    let mut runs = ChromosomeRuns::default();
+    for value in values.into_iter().flat_map(ColumnValues::into_text) {
+        let value = value.unwrap_or_default();
+        let chromosome = match from_id {
+            true => value.split(':').next().unwrap_or_default(),
+            false => &value,
+        };
+        runs.push(chromosome, filename)?;
+    }
+    Ok(runs.finish())
+}
+
+/// Read the selected rows of a Parquet GWAS results file for each phenotype
+/// of `column_names` (see `ColumnSpec::phenotype_columns`). Nulls and NaNs
+/// are missing values.
+pub(crate) fn read_phenotypes(
+    filename: &str,
+    column_names: &ColumnSpec,
+    selection: RowSelection,
+) -> Result<Vec<GwasResults>> {
+    validate_spec(column_names)?;
+    let phenotype_columns = column_names.phenotype_columns();
+    let mut names = vec![column_names.variant_id.as_str()];
+    names.extend(column_names.chromosome_column.as_deref());
+    for columns in &phenotype_columns {
+        names.extend([
+            columns.beta.as_str(),
+            columns.se.as_str(),
+            columns.sample_size.as_str(),
+        ]);
+    }
+    let (start_row, end_row) = match selection {
+        RowSelection::Range(start_line, end_line) => (start_line, end_line),
+        RowSelection::Ids(_) => (0, usize::MAX),
+    };
+    let mut values = read_columns(filename, &names, start_row, end_row)?
+        .into_iter()
+        .zip(&names);
+    let mut next = || values.next().expect("a column is read for each name");
+
+    let (variant_ids, name) = next();
+    let variant_ids = variant_ids.into_strings(name, filename)?;
+    let chromosomes = column_names
+        .chromosome_column
+        .as_ref()
+        .map(|_| next().0.into_text());
+    let statistics = phenotype_columns
+        .iter()
+        .map(|_| {
+            let [beta, se, sample_size] = [next(), next(), next()];
+            Ok([
+                beta.0.into_numbers(beta.1, filename)?,
+                se.0.into_numbers(se.1, filename)?,
+                sample_size.0.into_numbers(sample_size.1, filename)?,
+            ])
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut kept_ids = Vec::new();
+    let mut phenotypes: Vec<PhenotypeRows> = phenotype_columns
+        .iter()
+        .map(|_| Default::default())
+        .collect();
+    for (i, variant_id) in variant_ids.into_iter().enumerate() {
+        let row = start_row + i + 1;
+        let variant_id = variant_id
+            .with_context(|| format!("Missing variant id in row {} of {}", row, filename))?;
+        if let RowSelection::Ids(wanted) = selection {
+            if !wanted.contains(variant_id.as_str()) {
+                continue;
+            }
+        }
+        let off_chromosome = column_names
+            .chromosome_filter
+            .as_ref()
+            .is_some_and(|filter| {
+                let chromosome = match &chromosomes {
+                    Some(chromosomes) => chromosomes[i].as_deref().unwrap_or_default(),
+                    None => variant_id.split(':').next().unwrap_or_default(),
+                };
+                !filter.keeps(chromosome)
+            });
+
+        for (rows, [beta, se, sample_size]) in phenotypes.iter_mut().zip(&statistics) {
+            let values = [beta[i], se[i], sample_size[i]];
+            // Off-chromosome variants and those with missing values are not
+            // converted, and invalid odds ratios are handled like missing
+            // values
+            let parsed = match off_chromosome || values.iter().any(|x| x.is_none_or(f64::is_nan)) {
+                true => None,
+                false => {
+                    let [effect, se, sample_size] = values.map(|x| x.unwrap());
+                    let converted = column_names
+                        .effect_scale
+                        .to_beta(effect as f32, se as f32)
+                        .map(|(beta, se)| (beta, se, sample_size.round() as i32))
+                        .with_context(|| {
+                            format!(
+                                "Invalid odds ratio {} in row {} of {}: odds ratios must be \
+                                positive",
+                                effect, row, filename
+                            )
+                        });
+                    tolerate_invalid(converted, column_names, &mut rows.invalid)?
+                }
+            };
+            let Some((beta, se, sample_size)) = parsed else {
+                match column_names.na_policy {
+                    _ if off_chromosome => rows.push_missing(true, false),
+                    NaPolicy::Error => bail!(
+                        "Missing value for variant {} in row {} of {}. Use the skip or \
+                        zero-weight NA policy to allow missing values",
+                        variant_id,
+                        row,
+                        filename
+                    ),
+                    NaPolicy::Skip => rows.push_missing(true, false),
+                    NaPolicy::ZeroWeight => rows.push_missing(false, false),
+                }
+                continue;
+            };
+            rows.push(beta, se, sample_size, None);
+        }
+        kept_ids.push(variant_id);
+    }
+
+    Ok(PhenotypeRows::finish_all(
+        phenotypes,
+        kept_ids,
+        Annotations::default(),
+        false,
+    ))
+}
+
+#[cfg(feature = "parquet")]
+mod imp {
+    use std::fs::File;
+    use std::sync::Arc;
+
+    use anyhow::{bail, ensure, Context, Result};
+    use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+    use parquet::column::reader::{ColumnReader, ColumnReaderImpl};
+    use parquet::data_type::{
+        ByteArray, ByteArrayType, DataType, DoubleType, FloatType, Int32Type,
+    };
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::types::Type;
+
+    use super::ColumnValues;
+    use crate::io::gwas::{passes_threshold, IGwasResults, OutputConfig};
+
+    fn open(filename: &str) -> Result<SerializedFileReader<File>> {
+        let file = File::open(filename).with_context(|| format!("Failed to open {}", filename))?;
+        SerializedFileReader::new(file)
+            .with_context(|| format!("Failed to read Parquet file {}", filename))
+    }
+
+    pub fn column_names(filename: &str) -> Result<Vec<String>> {
+        let reader = open(filename)?;
+        let schema = reader.metadata().file_metadata().schema_descr();
+        Ok(schema
+            .root_schema()
+            .get_fields()
+            .iter()
+            .map(|field| field.name().to_string())
+            .collect())
+    }
+
+    pub fn count_rows(filename: &str) -> Result<usize> {
+        Ok(open(filename)?.metadata().file_metadata().num_rows() as usize)
+    }
+
+    /// The values of `n_rows` rows of a column after skipping `skip` rows,
+    /// `None` where null. Only the values of non-null rows are stored, so
+    /// nullable columns are spread out by their definition levels.
+    fn read_values<T: DataType>(
+        mut reader: ColumnReaderImpl<T>,
+        nullable: bool,
+        skip: usize,
+        n_rows: usize,
+    ) -> Result<Vec<Option<T::T>>> {
+        reader.skip_records(skip)?;
+        let mut def_levels = Vec::new();
+        let mut values = Vec::new();
+        reader.read_records(n_rows, Some(&mut def_levels), None, &mut values)?;
+        let mut values = values.into_iter();
+        Ok(match nullable {
+            true => def_levels
+                .into_iter()
+                .map(|level| match level {
+                    0 => None,
+                    _ => values.next(),
+                })
+                .collect(),
+            false => values.map(Some).collect(),
+        })
+    }
+
+    fn numbers<T: DataType>(
+        reader: ColumnReaderImpl<T>,
+        nullable: bool,
+        skip: usize,
+        n_rows: usize,
+        to_f64: fn(T::T) -> f64,
+    ) -> Result<ColumnValues> {
+        let values = read_values(reader, nullable, skip, n_rows)?;
+        Ok(ColumnValues::Numbers(
+            values.into_iter().map(|x| x.map(to_f64)).collect(),
+        ))
+    }
+
+    pub fn read_columns(
+        filename: &str,
+        names: &[&str],
+        start_row: usize,
+        end_row: usize,
+    ) -> Result<Vec<ColumnValues>> {
+        let reader = open(filename)?;
+        let schema = reader.metadata().file_metadata().schema_descr();
+        let indices = names
+            .iter()
+            .map(|&name| {
+                let index = schema
+                    .columns()
+                    .iter()
+                    .position(|x| x.path().parts() == [name])
+                    .with_context(|| format!("Column {} not found in {}", name, filename))?;
+                ensure!(
+                    schema.column(index).max_rep_level() == 0,
+                    "Column {} of {} is repeated, expected one value per row",
+                    name,
+                    filename
+                );
+                Ok(index)
+            })
+            .collect::<Result<Vec<usize>>>()?;
+
+        let mut columns: Vec<Option<ColumnValues>> = names.iter().map(|_| None).collect();
+        let mut first_row = 0;
+        for i in 0..reader.num_row_groups() {
+            let n_rows = reader.metadata().row_group(i).num_rows() as usize;
+            let (start, end) = (start_row.max(first_row), end_row.min(first_row + n_rows));
+            let skip = start.saturating_sub(first_row);
+            first_row += n_rows;
+            if start >= end {
+                if first_row >= end_row {
+                    break;
+                }
+                continue;
+            }
+            let row_group = reader.get_row_group(i)?;
+            for ((&index, &name), column) in indices.iter().zip(names).zip(&mut columns) {
+                let nullable = schema.column(index).max_def_level() > 0;
+                let n = end - start;
+                let values = match row_group.get_column_reader(index)? {
+                    ColumnReader::ByteArrayColumnReader(reader) => ColumnValues::Strings(
+                        read_values(reader, nullable, skip, n)?
+                            .into_iter()
+                            .map(|x| x.map(|x| x.as_utf8().map(str::to_string)).transpose())
+                            .collect::<Result<_, _>>()
+                            .with_context(|| {
+                                format!("Column {} of {} is not valid UTF-8", name, filename)
+                            })?,
+                    ),
+                    ColumnReader::Int32ColumnReader(reader) => {
+                        numbers(reader, nullable, skip, n, f64::from)?
+                    }
+                    ColumnReader::Int64ColumnReader(reader) => {
+                        numbers(reader, nullable, skip, n, |x| x as f64)?
+                    }
+                    ColumnReader::FloatColumnReader(reader) => {
+                        numbers(reader, nullable, skip, n, f64::from)?
+                    }
+                    ColumnReader::DoubleColumnReader(reader) => {
+                        numbers(reader, nullable, skip, n, |x| x)?
+                    }
+                    _ => bail!(
+                        "Column {} of {} has unsupported type {}, expected strings or numbers",
+                        name,
+                        filename,
+                        schema.column(index).physical_type()
+                    ),
+                };
+                match (column.as_mut(), values) {
+                    (None, values) => *column = Some(values),
+                    (Some(ColumnValues::Strings(x)), ColumnValues::Strings(y)) => x.extend(y),
+                    (Some(ColumnValues::Numbers(x)), ColumnValues::Numbers(y)) => x.extend(y),
+                    _ => unreachable!("a column has the same type in every row group"),
+                }
+            }
+        }
+        // Columns of a file without rows in the range are empty
+        Ok(columns
+            .into_iter()
+            .map(|x| x.unwrap_or(ColumnValues::Numbers(Vec::new())))
+            .collect())
+    }
+
+    enum Values {
+        Utf8(Vec<ByteArray>),
+        Float(Vec<f32>),
+        Double(Vec<f64>),
+        Int(Vec<i32>),
+    }
+
+    impl Values {
+        fn physical_type(&self) -> PhysicalType {
+            match self {
+                Values::Utf8(_) => PhysicalType::BYTE_ARRAY,
+                Values::Float(_) => PhysicalType::FLOAT,
+                Values::Double(_) => PhysicalType::DOUBLE,
+                Values::Int(_) => PhysicalType::INT32,
+            }
+        }
+    }
+
+    /// The columns of the rows of `results` that pass the threshold, in the
+    /// order of `io::gwas::write_rows`
+    fn columns(results: &IGwasResults, config: &OutputConfig) -> Vec<(&'static str, Values)> {
+        let rows: Vec<usize> = (0..results.variant_ids.len())
+            .filter(|&i| passes_threshold(results, i, config))
+            .collect();
+        let strings =
+            |x: &[String]| Values::Utf8(rows.iter().map(|&i| x[i].as_str().into()).collect());
+        let floats = |x: &[f32]| Values::Float(rows.iter().map(|&i| x[i]).collect());
+
+        let mut columns = vec![
+            ("phenotype_id", strings(&results.projection_ids)),
+            ("variant_id", strings(&results.variant_ids)),
+            ("beta", floats(results.beta_values.as_slice())),
+            ("std_error", floats(results.se_values.as_slice())),
+            ("t_stat", floats(results.t_stat_values.as_slice())),
+            ("p_value", floats(results.p_values.as_slice())),
+            (
+                "sample_size",
+                Values::Int(rows.iter().map(|&i| results.sample_sizes[i]).collect()),
+            ),
+        ];
+        if config.raw_p_values {
+            // Double precision, as p-values below 1e-38 underflow a float
+            columns.push((
+                "raw_p_value",
+                Values::Double(
+                    rows.iter()
+                        .map(|&i| 10_f64.powf(-results.p_values[i] as f64))
+                        .collect(),
+                ),
+            ));
+        }
+        if config.chi_square {
+            columns.push((
+                "chi_square",
+                Values::Float(
+                    rows.iter()
+                        .map(|&i| results.t_stat_values[i].powi(2))
+                        .collect(),
+                ),
+            ));
+        }
+        if let (Some(lower), Some(upper)) = (&results.ci_lower, &results.ci_upper) {
+            columns.push(("ci_lower", floats(lower.as_slice())));
+            columns.push(("ci_upper", floats(upper.as_slice())));
+        }
+        if let Some(heterogeneity) = &results.heterogeneity {
+            columns.push(("heterogeneity_q", floats(heterogeneity.as_slice())));
+        }
+        if let Some(effective_sample_size) = &results.effective_sample_size {
+            columns.push((
+                "effective_sample_size",
+                floats(effective_sample_size.as_slice()),
+            ));
+        }
+        for (name, values) in results.annotations.columns() {
+            columns.push((name, strings(values)));
+        }
+        columns
+    }
+
+    /// The columns written for any results, without rows
+    fn empty_columns(config: &OutputConfig) -> Vec<(&'static str, Values)> {
+        let mut columns = vec![
+            ("phenotype_id", Values::Utf8(Vec::new())),
+            ("variant_id", Values::Utf8(Vec::new())),
+            ("beta", Values::Float(Vec::new())),
+            ("std_error", Values::Float(Vec::new())),
+            ("t_stat", Values::Float(Vec::new())),
+            ("p_value", Values::Float(Vec::new())),
+            ("sample_size", Values::Int(Vec::new())),
+        ];
+        if config.raw_p_values {
+            columns.push(("raw_p_value", Values::Double(Vec::new())));
+        }
+        if config.chi_square {
+            columns.push(("chi_square", Values::Float(Vec::new())));
+        }
+        columns
+    }
+
+    fn schema(columns: &[(&'static str, Values)]) -> Result<Type> {
+        let fields = columns
+            .iter()
+            .map(|(name, values)| {
+                let mut field = Type::primitive_type_builder(name, values.physical_type())
+                    .with_repetition(Repetition::REQUIRED);
+                if let Values::Utf8(_) = values {
+                    field = field.with_logical_type(Some(LogicalType::String));
+                }
+                Ok(Arc::new(field.build()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Type::group_type_builder("igwas")
+            .with_fields(fields)
+            .build()?)
+    }
+
+    pub struct Writer {
+        filename: String,
+        config: OutputConfig,
+        /// Created with the schema of the first results written
+        writer: Option<(SerializedFileWriter<File>, Vec<&'static str>)>,
+    }
+
+    impl Writer {
+        pub fn create(filename: &str, config: &OutputConfig) -> Result<Self> {
+            // Replace any previous output now rather than at the first write
+            File::create(filename).with_context(|| format!("Failed to create {}", filename))?;
+            Ok(Writer {
+                filename: filename.to_string(),
+                config: config.clone(),
+                writer: None,
+            })
+        }
+
+        pub fn write(&mut self, results: &IGwasResults) -> Result<()> {
+            let columns = columns(results, &self.config);
+            let names: Vec<&'static str> = columns.iter().map(|&(name, _)| name).collect();
+            let (writer, first_names) = match &mut self.writer {
+                Some(writer) => writer,
+                None => {
+                    let file = File::create(&self.filename)
+                        .with_context(|| format!("Failed to create {}", self.filename))?;
+                    let properties = WriterProperties::builder()
+                        .set_compression(Compression::SNAPPY)
+                        .build();
+                    let writer = SerializedFileWriter::new(
+                        file,
+                        Arc::new(schema(&columns)?),
+                        Arc::new(properties),
+                    )?;
+                    self.writer.insert((writer, names.clone()))
+                }
+            };
+            ensure!(
+                names == *first_names,
+                "Cannot write columns {:?} to Parquet output {}, which has columns {:?}",
+                names,
+                self.filename,
+                first_names
+            );
+
+            let mut row_group = writer.next_row_group()?;
+            for (_, values) in &columns {
+                let mut column = row_group
+                    .next_column()?
+                    .expect("the schema has a column for each of the values");
+                match values {
+                    Values::Utf8(x) => {
+                        column.typed::<ByteArrayType>().write_batch(x, None, None)?
+                    }
+                    Values::Float(x) => column.typed::<FloatType>().write_batch(x, None, None)?,
+                    Values::Double(x) => column.typed::<DoubleType>().write_batch(x, None, None)?,
+                    Values::Int(x) => column.typed::<Int32Type>().write_batch(x, None, None)?,
+                };
+                column.close()?;
+            }
+            row_group.close()?;
+            Ok(())
+        }
+
+        pub fn close(mut self) -> Result<()> {
+            let file = match self.writer.take() {
+                Some((writer, _)) => writer.into_inner(),
+                // No chunks were written, so write a file without rows
+                None => SerializedFileWriter::new(
+                    File::create(&self.filename)?,
+                    Arc::new(schema(&empty_columns(&self.config))?),
+                    Default::default(),
+                )
+                .and_then(|writer| writer.into_inner()),
+            }
+            .with_context(|| format!("Failed to write {}", self.filename))?;
+            if self.config.sync {
+                file.sync_data()
+                    .with_context(|| format!("Failed to sync {} to disk", self.filename))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+mod imp {
+    use anyhow::{bail, Result};
+
+    use super::ColumnValues;
+    use crate::io::gwas::{IGwasResults, OutputConfig};
+
+    fn unsupported<T>(filename: &str) -> Result<T> {
+        bail!(
+            "Cannot read {}: Parquet input requires igwas to be built with the parquet feature",
+            filename
+        )
+    }
+
+    pub fn column_names(filename: &str) -> Result<Vec<String>> {
+        unsupported(filename)
+    }
+
+    pub fn count_rows(filename: &str) -> Result<usize> {
+        unsupported(filename)
+    }
+
+    pub fn read_columns(
+        filename: &str,
+        _names: &[&str],
+        _start_row: usize,
+        _end_row: usize,
+    ) -> Result<Vec<ColumnValues>> {
+        unsupported(filename)
+    }
+
+    pub struct Writer;
+
+    impl Writer {
+        pub fn create(filename: &str, _config: &OutputConfig) -> Result<Self> {
+            bail!(
+                "Cannot write {}: Parquet output requires igwas to be built with the parquet \
+                feature",
+                filename
+            )
+        }
+
+        pub fn write(&mut self, _results: &IGwasResults) -> Result<()> {
+            unreachable!("a Writer cannot be created without the parquet feature")
+        }
+
+        pub fn close(self) -> Result<()> {
+            unreachable!("a Writer cannot be created without the parquet feature")
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Reading GWAS results files that are not on local disk: `http://` or
+//! `https://` URLs, and `s3://bucket/key` objects. Rows are fetched with
+//! range requests, so a chunk only downloads its own lines. The line offsets
+//! this needs come from one full pass over each file, whose index is cached
+//! for the rest of the process.
+//!
+//! Each source requires its feature, `http` or `s3`; without it, its paths
+//! are an error. S3 credentials and region come from the standard AWS chain
+//! (environment variables, shared config and credential files, instance
+//! roles). An endpoint set with `AWS_ENDPOINT_URL`, e.g. of MinIO or
+//! LocalStack, is addressed with path-style requests.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::io::gwas::{skip_lines, GZIP_MAGIC, PARQUET_MAGIC, ZSTD_MAGIC};
+
+/// Lines between the offsets kept in a `LineIndex`. A chunk fetches at most
+/// this many lines more than it needs on each side.
+const INDEX_STRIDE: usize = 1024;
+
+/// Bytes fetched at a time by the reader of `open`
+const BLOCK_SIZE: u64 = 1 << 20;
+
+fn is_http(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+fn is_s3(path: &str) -> bool {
+    path.starts_with("s3://")
+}
+
+/// Whether a path is an HTTP(S) URL or an S3 object rather than a local file
+pub fn is_remote(path: &str) -> bool {
+    is_http(path) || is_s3(path)
+}
+
+/// Byte offsets of every `INDEX_STRIDE`-th line of a file, counting the
+/// header as line 0, so that the offsets of all lines take little memory
+#[derive(Debug)]
+struct LineIndex {
+    /// Start of lines 0, `INDEX_STRIDE`, `2 * INDEX_STRIDE`, ...
+    offsets: Vec<u64>,
+    n_lines: usize,
+    header_len: u64,
+    len: u64,
+}
+
+impl LineIndex {
+    /// Byte range starting at an indexed line at or before `start` and
+    /// ending at an indexed line at or after `end` (or the end of the file),
+    /// and the number of lines before `start` in it
+    fn covering(&self, start: usize, end: usize) -> (u64, u64, usize) {
+        let first = start / INDEX_STRIDE;
+        let last = end.div_ceil(INDEX_STRIDE);
+        let end_byte = self.offsets.get(last).copied().unwrap_or(self.len);
+        (self.offsets[first], end_byte, start - first * INDEX_STRIDE)
+    }
+}
+
+/// The contents of a file, or of bytes `start..end` of it
+fn get(path: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read>> {
+    if is_s3(path) {
+        s3::get(path, range)
+    } else {
+        http::get(path, range)
+    }
+}
+
+#[cfg(feature = "http")]
+mod http {
+    use std::io::Read;
+    use std::sync::OnceLock;
+
+    use anyhow::{bail, Context, Result};
+
+    fn client() -> &'static reqwest::blocking::Client {
+        static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+        CLIENT.get_or_init(reqwest::blocking::Client::new)
+    }
+
+    pub fn get(url: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read>> {
+        let mut request = client().get(url);
+        if let Some((start, end)) = range {
+            request = request.header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", start, end - 1),
+            );
+        }
+        let response = request
+            .send()
+            .and_then(|x| x.error_for_status())
+            .with_context(|| format!("Failed to fetch {}", url))?;
+        if range.is_some() && response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            bail!(
+                "The server of {} does not support range requests (status {})",
+                url,
+                response.status()
+            );
+        }
+        Ok(Box::new(response))
+    }
+}
+
+#[cfg(not(feature = "http"))]
+mod http {
+    use std::io::Read;
+
+    use anyhow::{bail, Result};
+
+    pub fn get(url: &str, _range: Option<(u64, u64)>) -> Result<Box<dyn Read>> {
+        bail!(
+            "Cannot read {}: reading files over HTTP requires igwas to be built with the http \
+            feature",
+            url
+        )
+    }
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use std::io::Read;
+    use std::sync::OnceLock;
+
+    use anyhow::{anyhow, Context, Result};
+    use aws_sdk_s3::error::DisplayErrorContext;
+    use aws_sdk_s3::primitives::ByteStream;
+
+    /// Runtime in which the asynchronous S3 client runs, blocked on by the
+    /// readers
+    fn runtime() -> &'static tokio::runtime::Runtime {
+        static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to start the runtime of the S3 client")
+        })
+    }
+
+    fn client() -> &'static aws_sdk_s3::Client {
+        static CLIENT: OnceLock<aws_sdk_s3::Client> = OnceLock::new();
+        CLIENT.get_or_init(|| {
+            let config = runtime().block_on(aws_config::load_defaults(
+                aws_config::BehaviorVersion::latest(),
+            ));
+            let s3_config = aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(config.endpoint_url().is_some())
+                .build();
+            aws_sdk_s3::Client::from_conf(s3_config)
+        })
+    }
+
+    /// An object's body, read one part at a time as it arrives
+    struct Body {
+        stream: ByteStream,
+        part: std::io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for Body {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            loop {
+                let n = self.part.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                match runtime().block_on(self.stream.try_next()) {
+                    Ok(Some(part)) => self.part = std::io::Cursor::new(part.to_vec()),
+                    Ok(None) => return Ok(0),
+                    Err(e) => return Err(std::io::Error::other(e)),
+                }
+            }
+        }
+    }
+
+    pub fn get(path: &str, range: Option<(u64, u64)>) -> Result<Box<dyn Read>> {
+        let (bucket, key) = path
+            .strip_prefix("s3://")
+            .and_then(|x| x.split_once('/'))
+            .with_context(|| format!("{} is not of the form s3://bucket/key", path))?;
+        let output = runtime()
+            .block_on(
+                client()
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_range(range.map(|(start, end)| format!("bytes={}-{}", start, end - 1)))
+                    .send(),
+            )
+            .map_err(|e| anyhow!("Failed to fetch {}: {}", path, DisplayErrorContext(e)))?;
+        Ok(Box::new(Body {
+            stream: output.body,
+            part: Default::default(),
+        }))
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+mod s3 {
+    use std::io::Read;
+
+    use anyhow::{bail, Result};
+
+    pub fn get(path: &str, _range: Option<(u64, u64)>) -> Result<Box<dyn Read>> {
+        bail!(
+            "Cannot read {}: reading files from S3 requires igwas to be built with the s3 \
+            feature",
+            path
+        )
+    }
+}
+
+/// The line index of a file, read in full on first use and then cached
+fn line_index(path: &str) -> Result<Arc<LineIndex>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Arc<LineIndex>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(index) = cache.lock().unwrap().get(path) {
+        return Ok(index.clone());
+    }
+
+    let mut body = get(path, None)?;
+    let mut offsets = vec![0];
+    let mut n_lines = 0;
+    let mut header_len = None;
+    let mut len: u64 = 0;
+    let mut last_byte = b'\n';
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let n = body
+            .read(&mut buffer)
+            .with_context(|| format!("Failed to read {}", path))?;
+        if n == 0 {
+            break;
+        }
+        if len == 0 {
+            let magic = &buffer[..n];
+            ensure!(
+                !(magic.starts_with(&ZSTD_MAGIC) || magic.starts_with(&GZIP_MAGIC)),
+                "Remote file {} is compressed, which is not supported, since rows are fetched \
+                by their byte offsets in the uncompressed text",
+                path
+            );
+            ensure!(
+                !magic.starts_with(&PARQUET_MAGIC),
+                "File {} is a Parquet file, which is not supported. Convert it to a delimited \
+                text file",
+                path
+            );
+        }
+        for (i, &byte) in buffer[..n].iter().enumerate() {
+            if byte == b'\n' {
+                n_lines += 1;
+                header_len.get_or_insert(len + i as u64 + 1);
+                if n_lines % INDEX_STRIDE == 0 {
+                    offsets.push(len + i as u64 + 1);
+                }
+            }
+        }
+        len += n as u64;
+        last_byte = buffer[n - 1];
+    }
+    // A last line without a newline still counts
+    if last_byte != b'\n' {
+        n_lines += 1;
+    }
+
+    let index = Arc::new(LineIndex {
+        offsets,
+        n_lines,
+        header_len: header_len.unwrap_or(len),
+        len,
+    });
+    cache
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), index.clone());
+    Ok(index)
+}
+
+/// Bytes `start..end` of a file, by a range request
+fn fetch(path: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+    if start == end {
+        return Ok(Vec::new());
+    }
+    let mut bytes = Vec::new();
+    get(path, Some((start, end)))?
+        .read_to_end(&mut bytes)
+        .with_context(|| format!("Failed to read {}", path))?;
+    ensure!(
+        bytes.len() as u64 == end - start,
+        "Expected {} bytes from {}, got {}. Was it modified during the run?",
+        end - start,
+        path,
+        bytes.len()
+    );
+    Ok(bytes)
+}
+
+/// Number of data rows of a file, like `gwas::count_lines`
+pub fn count_lines(path: &str) -> Result<usize> {
+    let index = line_index(path)?;
+    ensure!(
+        index.n_lines > 0,
+        "{} is empty, without even a header line",
+        path
+    );
+    Ok(index.n_lines - 1)
+}
+
+/// The header line and data rows `start_line..end_line` of a file, fetched
+/// without the rest of the file
+pub fn read_lines(path: &str, start_line: usize, end_line: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+    let index = line_index(path)?;
+    let header = fetch(path, 0, index.header_len)?;
+
+    // Data row i is line i + 1
+    let (start, end, n_before) = index.covering(start_line + 1, end_line + 1);
+    let rows = fetch(path, start, end)?;
+    let rows_start = skip_lines(&rows, 0, n_before);
+    let rows_end = skip_lines(&rows, rows_start, end_line - start_line);
+    Ok((header, rows[rows_start..rows_end].to_vec()))
+}
+
+/// Reads a file sequentially, one range request of `BLOCK_SIZE` bytes at a
+/// time
+struct RangeReader {
+    path: String,
+    len: u64,
+    position: u64,
+    block: std::io::Cursor<Vec<u8>>,
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.block.read(buf)?;
+        if n > 0 || self.position == self.len {
+            return Ok(n);
+        }
+        let end = (self.position + BLOCK_SIZE).min(self.len);
+        let block = fetch(&self.path, self.position, end).map_err(std::io::Error::other)?;
+        self.position = end;
+        self.block = std::io::Cursor::new(block);
+        self.block.read(buf)
+    }
+}
+
+/// Open a file for reading from the start, like
+/// `gwas::open_maybe_compressed`, e.g. to read its header
+pub fn open(path: &str) -> Result<Box<dyn BufRead>> {
+    let index = line_index(path)?;
+    Ok(Box::new(BufReader::new(RangeReader {
+        path: path.to_string(),
+        len: index.len,
+        position: 0,
+        block: Default::default(),
+    })))
+}
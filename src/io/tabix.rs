@@ -0,0 +1,422 @@
+//! Reading the variants of a genomic region from bgzipped, tabix-indexed
+//! files. The index gives where in the compressed file the region starts,
+//! so only the blocks holding its rows are decompressed, rather than the
+//! whole file.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::io::remote;
+
+/// A genomic interval of 1-based positions, both ends included, as tabix
+/// takes them: `chr2:1-50000000`, `chr2:1000000-` or `chr2:1000000` to the
+/// end of the chromosome, or `chr2` for all of it. Commas in the positions
+/// are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub chromosome: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl std::str::FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let (chromosome, interval) = match text.rsplit_once(':') {
+            Some((chromosome, interval)) => (chromosome, Some(interval)),
+            None => (text, None),
+        };
+        ensure!(
+            !chromosome.is_empty(),
+            "Region {:?} has no chromosome",
+            text
+        );
+        let parse = |position: &str| {
+            position
+                .replace(',', "")
+                .parse::<u64>()
+                .with_context(|| format!("Invalid position {:?} in region {:?}", position, text))
+        };
+        let (start, end) = match interval {
+            None => (1, u64::MAX),
+            Some(interval) => match interval.split_once('-') {
+                None => (parse(interval)?, u64::MAX),
+                Some((start, "")) => (parse(start)?, u64::MAX),
+                Some((start, end)) => (parse(start)?, parse(end)?),
+            },
+        };
+        ensure!(start <= end, "Region {:?} ends before it starts", text);
+        Ok(Region {
+            chromosome: chromosome.to_string(),
+            // Position 0 is taken to mean the start of the chromosome
+            start: start.max(1),
+            end,
+        })
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.end {
+            u64::MAX => write!(f, "{}:{}-", self.chromosome, self.start),
+            end => write!(f, "{}:{}-{}", self.chromosome, self.start, end),
+        }
+    }
+}
+
+/// Positions past the last that a tabix index can hold
+const MAX_POSITION: u64 = 1 << 29;
+
+/// Flag of the index format for 0-based, half-open positions, as in BED
+const ZERO_BASED: i32 = 0x10000;
+
+/// The parts of a tabix (`.tbi`) index needed to find a region
+struct TabixIndex {
+    /// 1-based columns of the chromosome and the position
+    col_seq: usize,
+    col_beg: usize,
+    zero_based: bool,
+    /// Lines starting with this character are header lines
+    meta: u8,
+    /// Number of header lines at the start of the file, besides those
+    /// starting with `meta`
+    skip: usize,
+    names: Vec<String>,
+    references: Vec<ReferenceIndex>,
+}
+
+/// Index of the rows of one chromosome
+struct ReferenceIndex {
+    /// Virtual offsets of the start and end of the chunks of rows in each bin
+    bins: HashMap<u32, Vec<(u64, u64)>>,
+    /// Virtual offset of the first row overlapping each 16 kb window
+    windows: Vec<u64>,
+}
+
+/// Little-endian reader of the binary fields of an index
+struct Fields<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Fields<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8]> {
+        ensure!(
+            self.position + n <= self.data.len(),
+            "The index ends unexpectedly"
+        );
+        self.position += n;
+        Ok(&self.data[self.position - n..self.position])
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn count(&mut self) -> Result<usize> {
+        let n = self.i32()?;
+        usize::try_from(n).with_context(|| format!("Invalid count {} in the index", n))
+    }
+}
+
+fn read_index(filename: &str) -> Result<TabixIndex> {
+    let mut data = Vec::new();
+    flate2::read::MultiGzDecoder::new(BufReader::new(File::open(filename)?))
+        .read_to_end(&mut data)?;
+    let mut fields = Fields {
+        data: &data,
+        position: 0,
+    };
+    ensure!(fields.bytes(4)? == b"TBI\x01", "Not a tabix index");
+    let n_references = fields.count()?;
+    let format = fields.i32()?;
+    let col_seq = fields.count()?;
+    let col_beg = fields.count()?;
+    let _col_end = fields.i32()?;
+    let meta = fields.i32()? as u8;
+    let skip = fields.count()?;
+    let names_length = fields.count()?;
+    let names = fields
+        .bytes(names_length)?
+        .split(|&x| x == 0)
+        .filter(|x| !x.is_empty())
+        .map(|x| String::from_utf8_lossy(x).into_owned())
+        .collect::<Vec<_>>();
+    ensure!(
+        names.len() == n_references && col_seq > 0 && col_beg > 0,
+        "Invalid tabix index header"
+    );
+
+    let mut references = Vec::with_capacity(n_references);
+    for _ in 0..n_references {
+        let mut bins = HashMap::new();
+        for _ in 0..fields.count()? {
+            let bin = fields.i32()? as u32;
+            let chunks = (0..fields.count()?)
+                .map(|_| Ok((fields.u64()?, fields.u64()?)))
+                .collect::<Result<Vec<_>>>()?;
+            bins.insert(bin, chunks);
+        }
+        let windows = (0..fields.count()?)
+            .map(|_| fields.u64())
+            .collect::<Result<Vec<_>>>()?;
+        references.push(ReferenceIndex { bins, windows });
+    }
+    Ok(TabixIndex {
+        col_seq,
+        col_beg,
+        zero_based: format & ZERO_BASED != 0,
+        meta,
+        skip,
+        names,
+        references,
+    })
+}
+
+/// The bins of the tabix binning scheme that may hold rows overlapping the
+/// 0-based, half-open interval `start..end`
+fn overlapping_bins(start: u64, end: u64) -> Vec<u32> {
+    let end = end - 1;
+    let mut bins = vec![0];
+    for (first_bin, shift) in [(1, 26), (9, 23), (73, 20), (585, 17), (4681, 14)] {
+        bins.extend(first_bin + (start >> shift) as u32..=first_bin + (end >> shift) as u32);
+    }
+    bins
+}
+
+impl TabixIndex {
+    /// Virtual offset at which to start reading the rows of `region` on the
+    /// `reference`-th chromosome, or `None` if it has no rows there
+    fn start(&self, reference: usize, region: &Region) -> Option<u64> {
+        let index = &self.references[reference];
+        let start = (region.start - 1).min(MAX_POSITION - 1);
+        let end = region.end.min(MAX_POSITION).max(start + 1);
+        // No row overlapping the region starts before the first one
+        // overlapping its first window
+        let window = (start >> 14) as usize;
+        let min_offset = index
+            .windows
+            .get(window)
+            .or(index.windows.last())
+            .copied()
+            .unwrap_or(0);
+        overlapping_bins(start, end)
+            .iter()
+            .filter_map(|bin| index.bins.get(bin))
+            .flatten()
+            .filter(|&&(_, chunk_end)| chunk_end > min_offset)
+            .map(|&(chunk_start, _)| chunk_start.max(min_offset))
+            .min()
+    }
+
+    /// Index of the chromosome of `region` in the index. As with
+    /// `ChromosomeFilter`, a `chr` prefix is ignored if the name does not
+    /// match exactly.
+    fn reference(&self, region: &Region) -> Option<usize> {
+        fn strip(chromosome: &str) -> &str {
+            chromosome.strip_prefix("chr").unwrap_or(chromosome)
+        }
+        self.names
+            .iter()
+            .position(|x| *x == region.chromosome)
+            .or_else(|| {
+                self.names
+                    .iter()
+                    .position(|x| strip(x) == strip(&region.chromosome))
+            })
+    }
+}
+
+/// Open a bgzipped file and decompress it from virtual offset `offset`: the
+/// offset in the file of a BGZF block, times 2^16, plus an offset in the
+/// block's decompressed data
+fn open_at(filename: &str, offset: u64) -> Result<Box<dyn BufRead>> {
+    let mut file = File::open(filename)?;
+    file.seek(std::io::SeekFrom::Start(offset >> 16))?;
+    let mut reader = BufReader::with_capacity(
+        32768,
+        flate2::read::MultiGzDecoder::new(BufReader::new(file)),
+    );
+    let in_block = offset & 0xffff;
+    let skipped = std::io::copy(&mut (&mut reader).take(in_block), &mut std::io::sink())?;
+    ensure!(
+        skipped == in_block,
+        "The tabix index of {} points past the end of the file",
+        filename
+    );
+    Ok(Box::new(reader))
+}
+
+/// The rows of a region, read from where the index says it starts. Rows
+/// before the region are skipped, and reading stops at the first row past
+/// it, since the rows of an indexed file are sorted by position.
+struct RegionRows {
+    reader: Box<dyn BufRead>,
+    region: Region,
+    /// Name of the region's chromosome in the file
+    chromosome: String,
+    col_seq: usize,
+    col_beg: usize,
+    zero_based: bool,
+    meta: u8,
+    /// The current row, and how much of it was consumed
+    line: Vec<u8>,
+    consumed: usize,
+    done: bool,
+}
+
+enum Row {
+    Keep,
+    Skip,
+    Stop,
+}
+
+impl RegionRows {
+    fn classify(&self) -> std::io::Result<Row> {
+        let line = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line[0] == self.meta {
+            return Ok(Row::Skip);
+        }
+        let fields: Vec<&[u8]> = line.split(|&x| x == b'\t').collect();
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Row {:?} has no chromosome and position in the columns given by the \
+                    tabix index",
+                    String::from_utf8_lossy(line)
+                ),
+            )
+        };
+        let chromosome = *fields.get(self.col_seq - 1).ok_or_else(invalid)?;
+        if chromosome != self.chromosome.as_bytes() {
+            return Ok(Row::Stop);
+        }
+        let position = fields
+            .get(self.col_beg - 1)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| x.parse::<u64>().ok())
+            .ok_or_else(invalid)?
+            + self.zero_based as u64;
+        Ok(match position {
+            x if x < self.region.start => Row::Skip,
+            x if x > self.region.end => Row::Stop,
+            _ => Row::Keep,
+        })
+    }
+}
+
+impl Read for RegionRows {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for RegionRows {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.consumed == self.line.len() && !self.done {
+            self.line.clear();
+            self.consumed = 0;
+            if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                self.done = true;
+                break;
+            }
+            match self.classify()? {
+                Row::Keep => {}
+                Row::Skip => self.line.clear(),
+                Row::Stop => {
+                    self.line.clear();
+                    self.done = true;
+                }
+            }
+        }
+        Ok(&self.line[self.consumed..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed += amt;
+    }
+}
+
+/// Open a bgzipped file with a tabix index at `<filename>.tbi`, reading its
+/// header lines followed by only its rows in `region`, i.e. those whose
+/// position lies in the region. Without an index, this is an error, rather
+/// than falling back to reading the whole file.
+pub fn open_region(filename: &str, region: &Region) -> Result<Box<dyn BufRead>> {
+    ensure!(
+        !remote::is_remote(filename),
+        "Cannot read region {} of {}, which is not a local file",
+        region,
+        filename
+    );
+    let index_path = format!("{}.tbi", filename);
+    if !Path::new(&index_path).exists() {
+        bail!(
+            "Cannot read region {} of {}, which has no tabix index {}. Compress the file \
+            with bgzip and index it with tabix, or leave out the region to read all of it",
+            region,
+            filename,
+            index_path
+        );
+    }
+    let index = read_index(&index_path)
+        .with_context(|| format!("Error reading tabix index {}", index_path))?;
+
+    // The header lines are those tabix skips at the start of the file
+    let mut reader = open_at(filename, 0)?;
+    let mut header = Vec::new();
+    let mut line = Vec::new();
+    let mut n_lines = 0;
+    loop {
+        line.clear();
+        if reader
+            .fill_buf()?
+            .first()
+            .is_none_or(|&x| n_lines >= index.skip && x != index.meta)
+        {
+            break;
+        }
+        reader.read_until(b'\n', &mut line)?;
+        header.extend_from_slice(&line);
+        n_lines += 1;
+    }
+    ensure!(
+        !header.is_empty(),
+        "{} has no header line. Index it with tabix -S 1 to skip the header",
+        filename
+    );
+
+    let start = index
+        .reference(region)
+        .and_then(|reference| Some((reference, index.start(reference, region)?)));
+    let rows: Box<dyn BufRead> = match start {
+        None => Box::new(std::io::empty()),
+        Some((reference, offset)) => Box::new(RegionRows {
+            reader: open_at(filename, offset)?,
+            region: region.clone(),
+            chromosome: index.names[reference].clone(),
+            col_seq: index.col_seq,
+            col_beg: index.col_beg,
+            zero_based: index.zero_based,
+            meta: index.meta,
+            line: Vec::new(),
+            consumed: 0,
+            done: false,
+        }),
+    };
+    Ok(Box::new(Cursor::new(header).chain(rows)))
+}
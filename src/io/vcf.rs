@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use std::io::BufRead;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use crate::io::gwas::{
+    align_to_ids, is_missing_value, open_maybe_compressed, strip_bom, tolerate_invalid,
+    Annotations, ChromosomeRuns, ColumnSpec, EffectScale, GwasResults, NaPolicy, PhenotypeRows,
+    RowSelection,
+};
+use crate::io::tabix::{self, Region};
+
+/// Fixed columns of a VCF, before the FORMAT column and the samples
+const FIXED_COLUMNS: [&str; 8] = [
+    "#CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO",
+];
+
+/// Names of the GWAS-VCF fields holding the statistics. Each field is looked
+/// up in the FORMAT values of the sample, then in the INFO column.
+#[derive(Debug, Clone)]
+pub struct VcfFields {
+    pub beta: String,
+    pub se: String,
+    pub sample_size: String,
+}
+
+impl Default for VcfFields {
+    /// The field names of the GWAS-VCF specification
+    fn default() -> Self {
+        VcfFields {
+            beta: String::from("ES"),
+            se: String::from("SE"),
+            sample_size: String::from("SS"),
+        }
+    }
+}
+
+/// Open a VCF and read its header, up to and including the `#CHROM` line.
+/// Returns the reader, positioned at the first record, or only reading the
+/// records in `region` if given (see `tabix::open_region`), the fields of
+/// the `#CHROM` line, and the number of header lines.
+fn open_vcf(
+    filename: &str,
+    region: Option<&Region>,
+) -> Result<(Box<dyn BufRead>, Vec<String>, usize)> {
+    let mut reader = match region {
+        Some(region) => tabix::open_region(filename, region)?,
+        None => open_maybe_compressed(filename)?,
+    };
+    let mut line = String::new();
+    let mut n_lines = 0;
+    loop {
+        line.clear();
+        ensure!(
+            reader.read_line(&mut line)? > 0,
+            "{} has no #CHROM header line",
+            filename
+        );
+        n_lines += 1;
+        let line = if n_lines == 1 {
+            strip_bom(&line)
+        } else {
+            &line
+        };
+        if line.starts_with("##") {
+            continue;
+        }
+        let fields: Vec<String> = line
+            .trim_end_matches(['\n', '\r'])
+            .split('\t')
+            .map(|x| x.to_string())
+            .collect();
+        ensure!(
+            fields.len() > FIXED_COLUMNS.len() + 1
+                && fields.iter().zip(FIXED_COLUMNS).all(|(x, y)| x == y)
+                && fields[FIXED_COLUMNS.len()] == "FORMAT",
+            "{} is not a VCF with samples: expected the line after the meta-information to \
+            start with {} FORMAT and at least one sample, found {}",
+            filename,
+            FIXED_COLUMNS.join(" "),
+            line.trim_end()
+        );
+        return Ok((reader, fields, n_lines));
+    }
+}
+
+/// Names of the samples of a VCF, i.e. the phenotypes of a GWAS-VCF. With
+/// `region`, the header is read through the tabix index.
+pub fn read_samples(filename: &str, region: Option<&Region>) -> Result<Vec<String>> {
+    let (_, fields, _) = open_vcf(filename, region)?;
+    Ok(fields[FIXED_COLUMNS.len() + 1..].to_vec())
+}
+
+/// Number of records of a VCF, in `region` if given
+pub fn count_records(filename: &str, region: Option<&Region>) -> Result<usize> {
+    let (mut reader, _, _) = open_vcf(filename, region)?;
+    let mut n_records = 0;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if !line.trim_end().is_empty() {
+            n_records += 1;
+        }
+        line.clear();
+    }
+    Ok(n_records)
+}
+
+/// Where the chromosomes of a VCF end, like `gwas::chromosome_ends`, from
+/// the #CHROM column
+pub fn chromosome_ends(filename: &str, region: Option<&Region>) -> Result<Vec<usize>> {
+    let (mut reader, _, _) = open_vcf(filename, region)?;
+    let mut runs = ChromosomeRuns::default();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if !line.trim_end().is_empty() {
+            let chromosome = line.split('\t').next().unwrap_or_default();
+            runs.push(chromosome, filename)?;
+        }
+        line.clear();
+    }
+    Ok(runs.finish())
+}
+
+/// Check that a column spec can be used to read GWAS-VCF files
+pub fn validate_spec(column_names: &ColumnSpec) -> Result<()> {
+    ensure!(
+        column_names.z_score.is_none()
+            && column_names.effect_allele.is_none()
+            && column_names.effect_allele_freq.is_none(),
+        "Z-scores and annotation columns are not supported for GWAS-VCF files"
+    );
+    ensure!(
+        column_names.effect_scale == EffectScale::Beta,
+        "Odds ratios are not supported for GWAS-VCF files, whose effects are betas"
+    );
+    ensure!(
+        column_names.min_maf.is_none(),
+        "Filtering by minor allele frequency is not supported for GWAS-VCF files"
+    );
+    ensure!(
+        column_names.chromosome_column.is_none(),
+        "A chromosome column is not supported for GWAS-VCF files, whose chromosomes are read \
+        from #CHROM"
+    );
+    Ok(())
+}
+
+/// Read the statistics of the given samples from the records of a GWAS-VCF
+/// from `start_line` (inclusive) to `end_line` (exclusive), in the order of
+/// `samples`. Each record is parsed once for all of them. Variant ids are
+/// taken from the ID column, or built as `chromosome:position:ref:alt` where
+/// it is missing.
+pub fn read_vcf_results(
+    filename: &str,
+    column_names: &ColumnSpec,
+    samples: &[String],
+    start_line: usize,
+    end_line: usize,
+) -> Result<Vec<GwasResults>> {
+    read_vcf_rows(
+        filename,
+        column_names,
+        samples,
+        RowSelection::Range(start_line, end_line),
+    )
+}
+
+/// Read the statistics of the given samples for the given variants, in the
+/// given order, like `read_gwas_results_by_id`
+pub fn read_vcf_results_by_id(
+    filename: &str,
+    column_names: &ColumnSpec,
+    samples: &[String],
+    variant_ids: &[String],
+) -> Result<Vec<GwasResults>> {
+    let wanted: HashSet<&str> = variant_ids.iter().map(|x| x.as_str()).collect();
+    read_vcf_rows(filename, column_names, samples, RowSelection::Ids(&wanted))?
+        .into_iter()
+        .map(|found| align_to_ids(found, filename, variant_ids))
+        .collect()
+}
+
+/// The value of `key` in the FORMAT values of a sample, or else in INFO
+fn lookup<'a>(format: &[&str], values: &[&'a str], info: &'a str, key: &str) -> Option<&'a str> {
+    match format.iter().position(|&x| x == key) {
+        // Trailing FORMAT values may be dropped
+        Some(i) => Some(values.get(i).copied().unwrap_or(".")),
+        None => info
+            .split(';')
+            .find_map(|x| x.strip_prefix(key)?.strip_prefix('=')),
+    }
+}
+
+fn read_vcf_rows(
+    filename: &str,
+    column_names: &ColumnSpec,
+    samples: &[String],
+    selection: RowSelection,
+) -> Result<Vec<GwasResults>> {
+    let fields = column_names.vcf.clone().unwrap_or_default();
+    let (mut reader, header, n_header_lines) = open_vcf(filename, column_names.region.as_ref())?;
+    let sample_columns = samples
+        .iter()
+        .map(|sample| {
+            header
+                .iter()
+                .skip(FIXED_COLUMNS.len() + 1)
+                .position(|x| x == sample)
+                .map(|x| x + FIXED_COLUMNS.len() + 1)
+                .with_context(|| format!("Sample {} not found in {}", sample, filename))
+        })
+        .collect::<Result<Vec<usize>>>()?;
+
+    let mut variant_ids: Vec<String> = Vec::new();
+    let mut phenotypes: Vec<PhenotypeRows> = samples.iter().map(|_| Default::default()).collect();
+
+    let mut line = String::new();
+    let mut line_number = n_header_lines;
+    let mut i = 0;
+    loop {
+        if let RowSelection::Range(_, end_line) = selection {
+            if i >= end_line {
+                break;
+            }
+        }
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let record = line.trim_end_matches(['\n', '\r']);
+        if record.trim().is_empty() {
+            continue;
+        }
+        i += 1;
+        if let RowSelection::Range(start_line, _) = selection {
+            if i <= start_line {
+                continue;
+            }
+        }
+
+        let columns: Vec<&str> = record.split('\t').collect();
+        ensure!(
+            columns.len() == header.len(),
+            "Line {} of {} has {} fields, but the header has {}",
+            line_number,
+            filename,
+            columns.len(),
+            header.len()
+        );
+        let variant_id = match columns[2] {
+            "." => columns[..2]
+                .iter()
+                .chain(&columns[3..5])
+                .cloned()
+                .collect::<Vec<&str>>()
+                .join(":"),
+            id => id.to_string(),
+        };
+        if let RowSelection::Ids(wanted) = selection {
+            if !wanted.contains(variant_id.as_str()) {
+                continue;
+            }
+        }
+
+        let format: Vec<&str> = columns[FIXED_COLUMNS.len()].split(':').collect();
+        let info = columns[FIXED_COLUMNS.len() - 1];
+        let off_chromosome = column_names
+            .chromosome_filter
+            .as_ref()
+            .is_some_and(|filter| !filter.keeps(columns[0]));
+
+        let parse_error = |key: &str, value: &str| {
+            anyhow!(
+                "Invalid {} '{}' on line {} of {}",
+                key,
+                value,
+                line_number,
+                filename
+            )
+        };
+        let parse = |[beta, se, sample_size]: [&str; 3]| -> Result<(f32, f32, i32)> {
+            let beta = beta.parse().map_err(|_| parse_error(&fields.beta, beta))?;
+            let se = se.parse().map_err(|_| parse_error(&fields.se, se))?;
+            // Sample sizes may be written as floats
+            let sample_size: f64 = sample_size
+                .parse()
+                .map_err(|_| parse_error(&fields.sample_size, sample_size))?;
+            Ok((beta, se, sample_size.round() as i32))
+        };
+        for (rows, &sample_column) in phenotypes.iter_mut().zip(&sample_columns) {
+            let values: Vec<&str> = columns[sample_column].split(':').collect();
+            let get = |key: &str| lookup(&format, &values, info, key);
+            let statistics = [&fields.beta, &fields.se, &fields.sample_size].map(|x| get(x));
+            // Off-chromosome variants and those with missing values are not
+            // parsed, and invalid ones are handled like missing values
+            let parsed =
+                match off_chromosome || statistics.iter().any(|x| x.is_none_or(is_missing_value)) {
+                    true => None,
+                    false => tolerate_invalid(
+                        parse(statistics.map(Option::unwrap)),
+                        column_names,
+                        &mut rows.invalid,
+                    )?,
+                };
+            let Some((beta, se, sample_size)) = parsed else {
+                match column_names.na_policy {
+                    _ if off_chromosome => rows.push_missing(true, false),
+                    NaPolicy::Error => bail!(
+                        "Missing {}, {}, or {} for variant {} on line {} of {}. Use the skip or \
+                        zero-weight NA policy to allow missing values",
+                        fields.beta,
+                        fields.se,
+                        fields.sample_size,
+                        variant_id,
+                        line_number,
+                        filename
+                    ),
+                    NaPolicy::Skip => rows.push_missing(true, false),
+                    NaPolicy::ZeroWeight => rows.push_missing(false, false),
+                }
+                continue;
+            };
+            rows.push(beta, se, sample_size, None);
+        }
+        variant_ids.push(variant_id);
+    }
+
+    Ok(PhenotypeRows::finish_all(
+        phenotypes,
+        variant_ids,
+        Annotations::default(),
+        false,
+    ))
+}
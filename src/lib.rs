@@ -1,9 +1,12 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::info;
 use std::time::Duration;
 
+pub mod engine;
 pub mod io;
+#[cfg(feature = "serde")]
+mod serialization;
 pub mod stats;
 pub mod util;
 
@@ -11,8 +14,17 @@ pub mod util;
 /// See our preprint for more details:
 /// biorxiv.org/content/10.1101/2023.11.20.567948v1
 #[derive(Parser, Debug)]
-#[command(author, version)]
+#[command(
+    author,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct InputArguments {
+    /// Other commands, instead of a run
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to the projection matrix
     #[arg(short, long)]
     pub projection_matrix: String,
@@ -25,22 +37,63 @@ pub struct InputArguments {
     #[arg(num_args(1..), short, long)]
     pub gwas_results: Vec<String>,
 
-    /// Path to the output file
+    /// Tab-separated file mapping GWAS result file paths to phenotype names,
+    /// for files whose names do not match the matrix labels, and optionally
+    /// to their own column names (e.g. variant-id=SNP)
+    #[arg(long)]
+    pub phenotype_map: Option<String>,
+
+    /// Path to the output file ("-" for standard output), or to the output
+    /// directory with --split-by-projection
     #[arg(short, long)]
     pub output_file: String,
 
+    /// Also compute the projections with another covariance matrix, e.g. of
+    /// another ancestry group, and write them to another output. The GWAS
+    /// files are read once for all covariance matrices. May be repeated
+    #[arg(long, num_args = 2, value_names = ["COVARIANCE", "OUTPUT"])]
+    pub extra_covariance: Vec<String>,
+
     /// Number of covariates
     #[arg(long)]
     pub num_covar: usize,
 
+    /// Tab-separated file of phenotype names and numbers of covariates, for
+    /// phenotypes adjusted for a different number of covariates than
+    /// --num-covar
+    #[arg(long)]
+    pub num_covar_file: Option<String>,
+
     /// Number of variants to read per chunk
     #[arg(long, default_value_t = 100000)]
     pub chunksize: usize,
 
+    /// Make each chunk one whole chromosome instead of --chunksize variants.
+    /// The GWAS files must have the variants of each chromosome together.
+    #[arg(long)]
+    pub chunk_by_chromosome: bool,
+
+    /// Only process the variants in this genomic region, e.g.
+    /// chr2:1-50000000, seeking to it with the tabix index of each GWAS
+    /// file. The files must be bgzipped and tabix-indexed.
+    #[arg(long)]
+    pub region: Option<String>,
+
     /// Name of the variant ID column
     #[arg(short, long, default_value_t = String::from("ID"))]
     pub variant_id: String,
 
+    /// Build variant IDs from the chromosome, position, reference allele, and
+    /// alternate allele columns with these names (comma-separated), instead
+    /// of reading the variant ID column
+    #[arg(long, value_delimiter = ',')]
+    pub variant_id_columns: Option<Vec<String>>,
+
+    /// Separator between the components of variant IDs built with
+    /// --variant-id-columns
+    #[arg(long, default_value_t = String::from(":"))]
+    pub variant_id_separator: String,
+
     /// Name of the beta column
     #[arg(short, long, default_value_t = String::from("BETA"))]
     pub beta: String,
@@ -53,11 +106,102 @@ pub struct InputArguments {
     #[arg(long, default_value_t = String::from("OBS_CT"))]
     pub sample_size: String,
 
-    /// Number of threads to use
+    /// Name of the z-score column. If given, GWAS results are read as
+    /// z-scores rather than beta and standard error.
+    #[arg(long)]
+    pub z_score: Option<String>,
+
+    /// Name of the allele frequency column (required with --z-score)
+    #[arg(long)]
+    pub allele_freq: Option<String>,
+
+    /// Name of an effect allele column to copy to the output
+    #[arg(long)]
+    pub effect_allele: Option<String>,
+
+    /// Name of an effect allele frequency column to copy to the output
+    #[arg(long)]
+    pub effect_allele_freq: Option<String>,
+
+    /// Read these phenotypes from each GWAS results file (comma-separated),
+    /// from column groups such as BETA_<phenotype>, SE_<phenotype>, and
+    /// OBS_CT_<phenotype>
+    #[arg(long, value_delimiter = ',')]
+    pub wide_phenotypes: Option<Vec<String>>,
+
+    /// Separator between the column names and phenotypes of
+    /// --wide-phenotypes column groups
+    #[arg(long, default_value_t = String::from("_"))]
+    pub wide_separator: String,
+
+    /// Read the GWAS results as GWAS-VCF files, each sample of which is a
+    /// phenotype named after the sample
+    #[arg(long, default_value_t = false)]
+    pub gwas_vcf: bool,
+
+    /// Names of the GWAS-VCF FORMAT or INFO fields holding the beta, standard
+    /// error, and sample size (comma-separated)
+    #[arg(long, value_delimiter = ',', default_value = "ES,SE,SS")]
+    pub vcf_fields: Vec<String>,
+
+    /// Keep only the variants on these chromosomes (comma-separated, e.g.
+    /// 1,2,...,22 for the autosomes), ignoring any chr prefix. Variants on
+    /// other chromosomes are left out of the output.
+    #[arg(long, value_delimiter = ',')]
+    pub chromosomes: Option<Vec<String>>,
+
+    /// Name of the chromosome column used by --chromosomes and
+    /// --chunk-by-chromosome. By default, the chromosome is taken from
+    /// --variant-id-columns if given, and otherwise from the variant ID, up
+    /// to its first ':'
+    #[arg(long)]
+    pub chromosome_column: Option<String>,
+
+    /// Leave out variants whose minor allele frequency is below this in any
+    /// GWAS file, read from --allele-freq or else --effect-allele-freq
+    #[arg(long)]
+    pub min_maf: Option<f32>,
+
+    /// How to handle effect alleles or frequencies that differ between GWAS
+    /// files
+    #[arg(long, value_enum, default_value_t = stats::running::AnnotationPolicy::TakeFirst)]
+    pub annotation_mismatch: stats::running::AnnotationPolicy,
+
+    /// Scale of the beta and standard error columns: betas, odds ratios with
+    /// standard errors of the log odds ratio, or odds ratios with standard
+    /// errors of the odds ratio. Odds ratios are log-transformed when read
+    #[arg(long, value_enum, default_value_t = io::gwas::EffectScale::Beta)]
+    pub effect_scale: io::gwas::EffectScale,
+
+    /// How to handle missing values (empty, NA, NaN, or .) in GWAS results
+    #[arg(long, value_enum, default_value_t = io::gwas::NaPolicy::Error)]
+    pub na_policy: io::gwas::NaPolicy,
+
+    /// Handle values that cannot be parsed like missing values, under
+    /// --na-policy skip or zero-weight, logging how many there were
+    #[arg(long)]
+    pub invalid_as_missing: bool,
+
+    /// Delimiter of the GWAS results files (tab, comma, space, or a single
+    /// character). Detected from the header line if not given.
+    #[arg(long, value_parser = parse_delimiter)]
+    pub delimiter: Option<u8>,
+
+    /// Split the GWAS results files on runs of spaces and tabs, for files
+    /// whose columns are aligned with spaces
+    #[arg(long, conflicts_with = "delimiter")]
+    pub whitespace_delimited: bool,
+
+    /// Number of threads to use. 0 uses all available cores
     #[arg(short, long, default_value_t = 1)]
     pub num_threads: usize,
 
-    /// Number of GWAS results chunks to hold in memory at once
+    /// Number of threads reading GWAS result files concurrently
+    #[arg(long, default_value_t = 1)]
+    pub num_readers: usize,
+
+    /// Maximum number of GWAS results chunks queued between the reader,
+    /// workers, and updater. Bounds peak memory use
     #[arg(long, default_value_t = 25)]
     pub capacity: usize,
 
@@ -65,46 +209,405 @@ pub struct InputArguments {
     #[arg(long, default_value_t = false)]
     pub compress: bool,
 
+    /// Format of the output file: delimited text, newline-delimited JSON, or
+    /// Parquet (which requires the parquet feature)
+    #[arg(long, value_enum, default_value_t = io::gwas::OutputFormat::Delimited)]
+    pub output_format: io::gwas::OutputFormat,
+
+    /// Delimiter of the output file (tab, comma, space, or a single character)
+    #[arg(long, default_value = "tab", value_parser = parse_delimiter)]
+    pub output_delimiter: u8,
+
+    /// Add a raw_p_value column with the p-value itself, next to -log10 p
+    #[arg(long, default_value_t = false)]
+    pub raw_p_values: bool,
+
+    /// Add a chi_square column with the square of the t-statistic, which for
+    /// large degrees of freedom is a chi-square statistic with one degree of
+    /// freedom
+    #[arg(long, default_value_t = false)]
+    pub chi_square: bool,
+
+    /// Sort each projection's output by chromosome and position, parsed from
+    /// variant ids of the form chromosome:position:... (or built with
+    /// --variant-id-columns). Holds all results in memory until the end
+    #[arg(long, default_value_t = false)]
+    pub sort_by_position: bool,
+
+    /// Only write variants whose -log10 p-value is at least this threshold
+    #[arg(long)]
+    pub min_neg_log_p: Option<f32>,
+
+    /// Write each projection to its own file, named after the projection, in
+    /// the directory given by --output-file
+    #[arg(long, default_value_t = false)]
+    pub split_by_projection: bool,
+
+    /// Append the results to an existing output file (or the files of
+    /// --split-by-projection), without a header, instead of replacing it
+    #[arg(long, default_value_t = false)]
+    pub append: bool,
+
+    /// Fsync the output after each chunk is written, so that the rows of
+    /// finished chunks survive a crash of the machine. Implied by --checkpoint
+    #[arg(long, default_value_t = false)]
+    pub sync_output: bool,
+
+    /// Write the genomic inflation factor (lambda GC) of each projection to
+    /// this tab-separated file
+    #[arg(long = "lambda-gc", value_name = "FILE")]
+    pub lambda_gc_file: Option<String>,
+
+    /// Write the most significant variant of each projection and its -log10
+    /// p-value to this tab-separated file
+    #[arg(long = "top-hits", value_name = "FILE")]
+    pub top_hits_file: Option<String>,
+
+    /// Write a JSON manifest of the run's inputs, parameters, and igwas
+    /// version to <output>.manifest.json
+    #[arg(long, default_value_t = false)]
+    pub manifest: bool,
+
+    /// Save progress to this file after each chunk. If it exists, resume an
+    /// interrupted run with the same inputs and parameters from it
+    #[arg(long)]
+    pub checkpoint: Option<String>,
+
+    /// Add ci_lower and ci_upper columns with confidence intervals for beta
+    #[arg(long, default_value_t = false)]
+    pub confidence_intervals: bool,
+
+    /// Confidence level of the intervals added by --confidence-intervals
+    #[arg(long, default_value_t = 0.95)]
+    pub confidence_level: f32,
+
+    /// Floating point precision of the accumulated statistics. f64 reduces
+    /// rounding error with many phenotypes, at twice the memory
+    #[arg(long, value_enum, default_value_t = stats::running::Precision::F32)]
+    pub precision: stats::running::Precision,
+
+    /// How p-values are computed. log-tail stays accurate for p-values below
+    /// the f64 range (about 1e-308), which cdf reports as 0
+    #[arg(long, value_enum, default_value_t = stats::sumstats::PValueMethod::Cdf)]
+    pub pvalue_method: stats::sumstats::PValueMethod,
+
+    /// Compute p-values from the standard normal distribution instead of the
+    /// t-distribution for variants with more degrees of freedom than this.
+    /// 0 always uses the t-distribution
+    #[arg(long, default_value_t = 10000)]
+    pub normal_dof_threshold: i32,
+
+    /// Report -log10 p-values above this as this, e.g. the infinite ones of
+    /// variants with a zero standard error or a p-value that underflows,
+    /// which are otherwise written as p-values of 0
+    #[arg(long)]
+    pub max_neg_log_p: Option<f32>,
+
+    /// Fail when more than this fraction of the results of a chunk have a
+    /// negative residual variance, which suggests that the covariance matrix
+    /// does not match the GWAS results. They are otherwise taken as zero,
+    /// with a warning
+    #[arg(long)]
+    pub max_negative_variance_fraction: Option<f64>,
+
+    /// Add a heterogeneity_q column with a Cochran's Q style statistic of
+    /// how much the features of each projection disagree about the effect of
+    /// the variant. This roughly quadruples the memory of each chunk.
+    #[arg(long)]
+    pub heterogeneity: bool,
+
+    /// Add an effective_sample_size column with the harmonic mean of the
+    /// sample sizes of the features of each projection, weighted by their
+    /// share of its partial variance
+    #[arg(long)]
+    pub effective_sample_size: bool,
+
+    /// How to combine the sample sizes of a variant across GWAS files. The
+    /// degrees of freedom of its p-value are combined the same way
+    #[arg(long, value_enum, default_value_t = stats::running::SampleSizePolicy::Min)]
+    pub sample_size_policy: stats::running::SampleSizePolicy,
+
+    /// Replace the covariance matrix C by (C + C^T) / 2 before computing
+    /// statistics, removing asymmetry from numerical noise
+    #[arg(long)]
+    pub symmetrize_covariance: bool,
+
+    /// Fail when the covariance matrix is more asymmetric than this, with the
+    /// asymmetry of an entry scaled like a correlation. Asymmetry above 1e-5
+    /// is otherwise a warning, unless symmetrizing
+    #[arg(long)]
+    pub max_covariance_asymmetry: Option<f32>,
+
+    /// Scale each projection to unit L2 norm before computing statistics, so
+    /// that results are comparable across projections
+    #[arg(long)]
+    pub normalize_projections: bool,
+
+    /// With --normalize-projections, scale beta, its standard error, and the
+    /// confidence bounds back to the original projection matrix
+    #[arg(long, requires = "normalize_projections")]
+    pub restore_projection_scale: bool,
+
+    /// Store the projection matrix sparse, which saves memory and time when
+    /// each projection loads on only a few phenotypes
+    #[arg(long, default_value_t = false)]
+    pub sparse_projection: bool,
+
+    /// Only compute the projections with these ids (comma-separated), in this
+    /// order, rather than every column of the projection matrix
+    #[arg(long, value_delimiter = ',')]
+    pub projections: Option<Vec<String>>,
+
+    /// Accumulate beta and its variance with compensated (Neumaier)
+    /// summation, reducing rounding error at a small cost in memory
+    #[arg(long, default_value_t = false)]
+    pub compensated_summation: bool,
+
+    /// Match variants across GWAS files by id rather than by position. The
+    /// first file defines the variants and their order.
+    #[arg(long, default_value_t = false)]
+    pub align_by_id: bool,
+
+    /// With --align-by-id, how to handle variants missing from some files
+    #[arg(long, value_enum, default_value_t = util::MissingVariantPolicy::Error)]
+    pub missing_variants: util::MissingVariantPolicy,
+
+    /// Read uncompressed GWAS files by memory-mapping them, which is often
+    /// faster for large files
+    #[arg(long, default_value_t = false)]
+    pub mmap: bool,
+
+    /// Number of threads parsing the rows of each GWAS file in parallel,
+    /// which helps with wide files with many columns
+    #[arg(long, default_value_t = 1)]
+    pub parse_threads: usize,
+
+    /// Trust that all GWAS files have the same variants in the same order,
+    /// skipping the check of their variant ids. Misaligned files then give
+    /// wrong results rather than an error
+    #[arg(long, default_value_t = false)]
+    pub assume_aligned: bool,
+
+    /// Only check that the inputs are consistent (matrix labels, a GWAS file
+    /// for every phenotype, columns, and numbers of variants), without
+    /// computing anything, and print what was found
+    #[arg(long, default_value_t = false)]
+    pub check: bool,
+
     /// Suppress output
     #[arg(short, long)]
     pub quiet: bool,
 }
 
+/// A command alone, without the arguments of a run. The parser of
+/// `InputArguments` requires those even with a command, so commands are
+/// parsed with this first.
+#[derive(Parser, Debug)]
+#[command(author, version)]
+pub struct CommandArguments {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// Commands other than an indirect GWAS run
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print the layout, shape, first labels and entries of a matrix file,
+    /// and whether it is square and symmetric
+    DescribeMatrix {
+        /// Path to the matrix, as given to --projection-matrix or
+        /// --covariance-matrix
+        path: String,
+    },
+}
+
+fn parse_delimiter(value: &str) -> Result<u8, String> {
+    match value {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        "comma" => Ok(b','),
+        "space" => Ok(b' '),
+        x if x.len() == 1 && x.is_ascii() => Ok(x.as_bytes()[0]),
+        x => Err(format!("Invalid delimiter: {}", x)),
+    }
+}
+
+/// Run a command other than an indirect GWAS
+pub fn run_command(command: &Command) -> Result<()> {
+    match command {
+        Command::DescribeMatrix { path } => {
+            print!("{}", io::matrix::describe_labeled_matrix(path)?);
+        }
+    }
+    Ok(())
+}
+
 pub fn run_cli(args: InputArguments) -> Result<()> {
+    if let Some(command) = &args.command {
+        return run_command(command);
+    }
+
     info!("Received arguments: {:#?}", &args);
 
     info!("Starting Indirect GWAS");
     let start = std::time::Instant::now();
 
+    if let Some(columns) = &args.variant_id_columns {
+        anyhow::ensure!(
+            columns.len() == 4,
+            "--variant-id-columns takes four comma-separated column names \
+            (chromosome, position, reference allele, alternate allele), got {:?}",
+            columns
+        );
+    }
+
+    anyhow::ensure!(
+        args.vcf_fields.len() == 3,
+        "--vcf-fields takes three comma-separated field names (beta, standard error, sample \
+        size), got {:?}",
+        args.vcf_fields
+    );
+
+    anyhow::ensure!(
+        args.chromosome_column.is_none() || args.chromosomes.is_some() || args.chunk_by_chromosome,
+        "--chromosome-column is only used with --chromosomes or --chunk-by-chromosome"
+    );
+
     let column_names = io::gwas::ColumnSpec {
         variant_id: args.variant_id,
         beta: args.beta,
         se: args.std_error,
         sample_size: args.sample_size,
+        delimiter: args.delimiter,
+        whitespace_delimited: args.whitespace_delimited,
+        z_score: args.z_score,
+        allele_freq: args.allele_freq,
+        na_policy: args.na_policy,
+        invalid_as_missing: args.invalid_as_missing,
+        effect_scale: args.effect_scale,
+        variant_id_columns: args.variant_id_columns.map(|x| io::gwas::VariantIdColumns {
+            chromosome: x[0].clone(),
+            position: x[1].clone(),
+            ref_allele: x[2].clone(),
+            alt_allele: x[3].clone(),
+            separator: args.variant_id_separator,
+        }),
+        effect_allele: args.effect_allele,
+        effect_allele_freq: args.effect_allele_freq,
+        vcf: args.gwas_vcf.then(|| io::vcf::VcfFields {
+            beta: args.vcf_fields[0].clone(),
+            se: args.vcf_fields[1].clone(),
+            sample_size: args.vcf_fields[2].clone(),
+        }),
+        wide: args
+            .wide_phenotypes
+            .map(|phenotypes| io::gwas::WideColumns {
+                phenotypes,
+                separator: args.wide_separator,
+            }),
+        chromosome_filter: args
+            .chromosomes
+            .map(|chromosomes| io::gwas::ChromosomeFilter { chromosomes }),
+        chromosome_column: args.chromosome_column,
+        min_maf: args.min_maf,
+        // Set from the runtime config's region
+        region: None,
     };
 
     let runtime_config = util::RuntimeConfig {
         num_threads: args.num_threads,
+        num_readers: args.num_readers,
         chunksize: args.chunksize,
-        compress: args.compress,
+        chunk_by_chromosome: args.chunk_by_chromosome,
         capacity: args.capacity,
+        output: io::gwas::OutputConfig {
+            format: args.output_format,
+            delimiter: args.output_delimiter,
+            compress: args.compress,
+            raw_p_values: args.raw_p_values,
+            chi_square: args.chi_square,
+            sort_by_position: args.sort_by_position,
+            min_neg_log_p: args.min_neg_log_p,
+            split_by_projection: args.split_by_projection,
+            lambda_gc_file: args.lambda_gc_file,
+            top_hits_file: args.top_hits_file,
+            manifest: args.manifest,
+            append: args.append,
+            sync: args.sync_output,
+        },
+        stats: stats::running::StatsConfig {
+            confidence_level: args.confidence_intervals.then_some(args.confidence_level),
+            precision: args.precision,
+            compensated_summation: args.compensated_summation,
+            annotation_mismatch: args.annotation_mismatch,
+            pvalue_method: args.pvalue_method,
+            normal_dof_threshold: (args.normal_dof_threshold > 0)
+                .then_some(args.normal_dof_threshold),
+            sparse_projection: args.sparse_projection,
+            projection_ids: args.projections,
+            max_neg_log_p: args.max_neg_log_p,
+            max_negative_variance_fraction: args.max_negative_variance_fraction,
+            heterogeneity: args.heterogeneity,
+            effective_sample_size: args.effective_sample_size,
+            sample_size_policy: args.sample_size_policy,
+            symmetrize_covariance: args.symmetrize_covariance,
+            max_covariance_asymmetry: args.max_covariance_asymmetry,
+            normalize_projections: args.normalize_projections,
+            restore_projection_scale: args.restore_projection_scale,
+        },
+        align_by_id: args.align_by_id,
+        missing_variants: args.missing_variants,
+        mmap: args.mmap,
+        parse_threads: args.parse_threads,
+        assume_aligned: args.assume_aligned,
+        phenotype_map: args.phenotype_map,
+        num_covar_by_phenotype: match &args.num_covar_file {
+            Some(filename) => util::read_num_covar_map(filename)?,
+            None => Default::default(),
+        },
+        progress: None,
+        timing: None,
+        checkpoint: args.checkpoint,
+        region: args.region,
     };
 
-    let _pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(args.num_threads)
-        .build()
-        .unwrap();
+    let mut outputs = vec![(args.covariance_matrix.as_str(), args.output_file.as_str())];
+    outputs.extend(
+        args.extra_covariance
+            .chunks(2)
+            .map(|x| (x[0].as_str(), x[1].as_str())),
+    );
 
-    util::run(
+    if args.check {
+        // The report is the same for every covariance matrix
+        let mut report = None;
+        for &(covariance_matrix, _) in &outputs {
+            report = Some(util::validate_inputs(
+                &args.projection_matrix,
+                covariance_matrix,
+                &args.gwas_results,
+                &runtime_config,
+                &column_names,
+            )?);
+        }
+        print!("{}", report.unwrap());
+        return Ok(());
+    }
+    let summary = util::run_covariances(
         &args.projection_matrix,
-        &args.covariance_matrix,
+        &outputs,
         &args.gwas_results,
-        &args.output_file,
         args.num_covar,
         runtime_config,
         column_names,
     )?;
 
+    info!(
+        "Computed {} projections of {} phenotypes for {} variants in {} chunks",
+        summary.num_projections,
+        summary.phenotypes.len(),
+        summary.num_variants,
+        summary.num_chunks
+    );
     let duration = Duration::new(start.elapsed().as_secs(), 0);
     info!(
         "Finished Indirect GWAS in {}",
@@ -3,12 +3,16 @@ use clap::Parser;
 use log::LevelFilter::{Error, Info};
 
 fn main() -> Result<()> {
+    if let Ok(args) = igwas::CommandArguments::try_parse() {
+        return igwas::run_command(&args.command);
+    }
     let args = igwas::InputArguments::parse();
 
     env_logger::Builder::from_default_env()
         .filter_level(if args.quiet { Error } else { Info })
         .format_target(false)
-        .target(env_logger::Target::Stdout)
+        // Keep standard output free for results written to it
+        .target(env_logger::Target::Stderr)
         .init();
 
     igwas::run_cli(args)
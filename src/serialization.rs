@@ -0,0 +1,192 @@
+//! Serde (de)serialization of nalgebra vectors and matrices as their shape
+//! plus their values in column-major order, for use with
+//! `#[serde(with = ...)]`. This does not depend on nalgebra's own serde
+//! support.
+
+use std::sync::Arc;
+
+use nalgebra::{DMatrix, DVector, Scalar};
+use nalgebra_sparse::CsrMatrix;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::stats::running::Projection;
+
+#[derive(Serialize, Deserialize)]
+struct Shaped<T> {
+    nrows: usize,
+    ncols: usize,
+    data: Vec<T>,
+}
+
+impl<T> Shaped<T> {
+    fn check<E: Error>(&self) -> Result<(), E> {
+        if self.data.len() != self.nrows * self.ncols {
+            return Err(E::custom(format!(
+                "expected {} x {} = {} values, found {}",
+                self.nrows,
+                self.ncols,
+                self.nrows * self.ncols,
+                self.data.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub mod dmatrix {
+    use super::*;
+
+    pub fn serialize<T, S>(matrix: &DMatrix<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Scalar + Serialize,
+        S: Serializer,
+    {
+        Shaped {
+            nrows: matrix.nrows(),
+            ncols: matrix.ncols(),
+            data: matrix.as_slice().to_vec(),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<DMatrix<T>, D::Error>
+    where
+        T: Scalar + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let shaped = Shaped::deserialize(deserializer)?;
+        shaped.check()?;
+        Ok(DMatrix::from_vec(shaped.nrows, shaped.ncols, shaped.data))
+    }
+}
+
+pub mod dvector {
+    use super::*;
+
+    pub fn serialize<T, S>(vector: &DVector<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Scalar + Serialize,
+        S: Serializer,
+    {
+        Shaped {
+            nrows: vector.nrows(),
+            ncols: 1,
+            data: vector.as_slice().to_vec(),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<DVector<T>, D::Error>
+    where
+        T: Scalar + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let shaped = Shaped::deserialize(deserializer)?;
+        if shaped.ncols != 1 {
+            return Err(D::Error::custom(format!(
+                "expected a vector, found {} columns",
+                shaped.ncols
+            )));
+        }
+        shaped.check()?;
+        Ok(DVector::from_vec(shaped.data))
+    }
+}
+
+pub mod option_dvector {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(bound(
+        serialize = "T: Scalar + Serialize",
+        deserialize = "T: Scalar + Deserialize<'de>"
+    ))]
+    struct Wrapper<T: Scalar>(#[serde(with = "super::dvector")] DVector<T>);
+
+    pub fn serialize<T, S>(vector: &Option<DVector<T>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Scalar + Serialize,
+        S: Serializer,
+    {
+        vector.clone().map(Wrapper).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<DVector<T>>, D::Error>
+    where
+        T: Scalar + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Wrapper<T>>::deserialize(deserializer)?.map(|x| x.0))
+    }
+}
+
+pub mod arc_dvector {
+    use super::*;
+
+    pub fn serialize<T, S>(vector: &Arc<DVector<T>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Scalar + Serialize,
+        S: Serializer,
+    {
+        super::dvector::serialize(vector.as_ref(), serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Arc<DVector<T>>, D::Error>
+    where
+        T: Scalar + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        super::dvector::deserialize(deserializer).map(Arc::new)
+    }
+}
+
+/// A projection matrix, dense or in compressed sparse row form
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Scalar + Serialize",
+    deserialize = "T: Scalar + Deserialize<'de>"
+))]
+enum ProjectionRepr<T: Scalar> {
+    Dense(#[serde(with = "dmatrix")] DMatrix<T>),
+    Sparse {
+        nrows: usize,
+        ncols: usize,
+        row_offsets: Vec<usize>,
+        col_indices: Vec<usize>,
+        values: Vec<T>,
+    },
+}
+
+impl<T: Scalar + Serialize> Serialize for Projection<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Projection::Dense(proj) => ProjectionRepr::Dense(proj.clone()),
+            Projection::Sparse(proj) => ProjectionRepr::Sparse {
+                nrows: proj.nrows(),
+                ncols: proj.ncols(),
+                row_offsets: proj.row_offsets().to_vec(),
+                col_indices: proj.col_indices().to_vec(),
+                values: proj.values().to_vec(),
+            },
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Scalar + Deserialize<'de>> Deserialize<'de> for Projection<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match ProjectionRepr::deserialize(deserializer)? {
+            ProjectionRepr::Dense(proj) => Ok(Projection::Dense(proj)),
+            ProjectionRepr::Sparse {
+                nrows,
+                ncols,
+                row_offsets,
+                col_indices,
+                values,
+            } => CsrMatrix::try_from_csr_data(nrows, ncols, row_offsets, col_indices, values)
+                .map(Projection::Sparse)
+                .map_err(D::Error::custom),
+        }
+    }
+}
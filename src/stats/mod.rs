@@ -1,2 +1,3 @@
 pub mod running;
+pub mod summary;
 pub mod sumstats;
@@ -1,60 +1,589 @@
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
 
+use anyhow::{bail, ensure, Context, Result};
+use log::warn;
 use nalgebra::{Const, DMatrix, DVector, Dyn};
+use nalgebra_sparse::CsrMatrix;
 use rayon::prelude::*;
 
-use crate::io::{gwas::IGwasResults, gwas::IntermediateResults, matrix::LabeledMatrix};
-use crate::stats::sumstats::compute_neg_log_pvalue;
+use crate::io::gwas::{Annotations, IGwasResults, IntermediateResults};
+use crate::io::matrix::LabeledMatrix;
+use crate::stats::sumstats::{
+    compute_neg_log_pvalue_normal, compute_neg_log_pvalue_with, compute_t_critical_value, Float,
+    PValueMethod,
+};
 use crate::util::ProcessingStats;
 
+/// Floating point precision in which the statistics are accumulated and
+/// computed. Inputs are parsed and outputs are written as f32 either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Precision {
+    #[default]
+    F32,
+    F64,
+}
+
+/// How to reconcile per-variant annotations, such as the effect allele, when
+/// GWAS files disagree on them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnnotationPolicy {
+    /// Keep the values of the phenotype listed first in the projection matrix
+    #[default]
+    TakeFirst,
+    /// Return an error
+    Error,
+}
+
+/// How to combine the sample sizes of a variant across GWAS files. The
+/// degrees of freedom, from which p-values and confidence intervals are
+/// computed, are combined the same way from those of each file, its sample
+/// size minus 2 and its number of covariates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SampleSizePolicy {
+    /// The smallest sample size, conservative when the samples overlap
+    #[default]
+    Min,
+    /// The largest sample size
+    Max,
+    /// The mean sample size, rounded to the nearest integer
+    Mean,
+    /// The total sample size, e.g. of files with disjoint samples
+    Sum,
+    /// The sample size of the phenotype listed first in the projection matrix
+    First,
+}
+
+impl SampleSizePolicy {
+    /// The combination of no sample sizes
+    fn identity(self) -> i32 {
+        match self {
+            SampleSizePolicy::Min => i32::MAX,
+            SampleSizePolicy::Max => i32::MIN,
+            SampleSizePolicy::Mean | SampleSizePolicy::Sum | SampleSizePolicy::First => 0,
+        }
+    }
+
+    /// The position of the policy in the saved state
+    fn code(self) -> u64 {
+        self as u64
+    }
+}
+
+/// Options controlling which statistics are computed
+#[derive(Debug, Clone)]
+pub struct StatsConfig {
+    /// Confidence level of the intervals around beta (e.g. 0.95). No
+    /// intervals are computed when `None`.
+    pub confidence_level: Option<f32>,
+    pub precision: Precision,
+    /// Accumulate beta and gpv with compensated (Neumaier) summation, which
+    /// reduces rounding error without the memory cost of f64 throughout
+    pub compensated_summation: bool,
+    pub annotation_mismatch: AnnotationPolicy,
+    pub pvalue_method: PValueMethod,
+    /// Variants with more degrees of freedom than this get p-values from the
+    /// standard normal distribution rather than the t-distribution, which is
+    /// numerically the same there but slower. `None` always uses the t.
+    pub normal_dof_threshold: Option<i32>,
+    /// Store the projection matrix sparse
+    pub sparse_projection: bool,
+    /// Only compute these projections, in this order, rather than every
+    /// column of the projection matrix
+    pub projection_ids: Option<Vec<String>>,
+    /// Report -log10 p-values above this as this, e.g. the infinite ones of
+    /// variants with a zero standard error or a p-value that underflows.
+    /// NaN p-values stay NaN.
+    pub max_neg_log_p: Option<f32>,
+    /// Fail when more than this fraction of the results of a chunk have a
+    /// negative residual variance, a sign that the covariance matrix does
+    /// not match the GWAS results. They are otherwise taken as zero, with a
+    /// warning.
+    pub max_negative_variance_fraction: Option<f64>,
+    /// Compute the heterogeneity statistic of each variant and projection
+    /// (see `HeterogeneitySums`). This keeps three more matrices of the size
+    /// of beta in the running statistics and in each update, so it roughly
+    /// quadruples their memory use and adds as much work to each update.
+    pub heterogeneity: bool,
+    /// Scale each column of the projection matrix to unit L2 norm before
+    /// computing statistics, so that the results of projections are
+    /// comparable (see `RunningSufficientStats::new`)
+    pub normalize_projections: bool,
+    /// With `normalize_projections`, scale the results back to the original
+    /// projection matrix (see `RunningSufficientStats::compute_final_stats`)
+    pub restore_projection_scale: bool,
+    /// Compute the effective sample size of each variant and projection (see
+    /// `SampleSizeSums`). This keeps two more matrices of the size of beta in
+    /// the running statistics and in each update.
+    pub effective_sample_size: bool,
+    /// How to combine the sample sizes and degrees of freedom of a variant
+    /// across files
+    pub sample_size_policy: SampleSizePolicy,
+    /// Replace the covariance matrix C by (C + C^T) / 2, removing the
+    /// asymmetry of numerical noise (see `covariance_asymmetry`)
+    pub symmetrize_covariance: bool,
+    /// Fail when the asymmetry of the covariance matrix is above this, even
+    /// when symmetrizing it. Above `COVARIANCE_ASYMMETRY_TOLERANCE`, it is
+    /// otherwise a warning, unless symmetrizing.
+    pub max_covariance_asymmetry: Option<f32>,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            confidence_level: None,
+            precision: Default::default(),
+            compensated_summation: false,
+            annotation_mismatch: Default::default(),
+            pvalue_method: Default::default(),
+            normal_dof_threshold: Some(10000),
+            sparse_projection: false,
+            projection_ids: None,
+            max_neg_log_p: None,
+            max_negative_variance_fraction: None,
+            heterogeneity: false,
+            normalize_projections: false,
+            restore_projection_scale: false,
+            effective_sample_size: false,
+            sample_size_policy: Default::default(),
+            symmetrize_covariance: false,
+            max_covariance_asymmetry: None,
+        }
+    }
+}
+
+/// Asymmetry of a covariance matrix above which it is reported
+pub const COVARIANCE_ASYMMETRY_TOLERANCE: f32 = 1e-5;
+
+/// The largest asymmetry of a square matrix and the row and column of the
+/// entry where it is found. The asymmetry of `C[i, j]` is
+/// `|C[i, j] - C[j, i]| / sqrt(C[i, i] C[j, j])`, i.e. on the scale of a
+/// correlation, or the absolute difference where the diagonal is not
+/// positive.
+pub fn covariance_asymmetry(matrix: &DMatrix<f32>) -> (f32, usize, usize) {
+    let mut max = (0.0, 0, 0);
+    for i in 0..matrix.nrows() {
+        for j in 0..i {
+            let scale = (matrix[(i, i)] * matrix[(j, j)]).sqrt();
+            let difference = (matrix[(i, j)] - matrix[(j, i)]).abs();
+            let asymmetry = match scale > 0.0 {
+                true => difference / scale,
+                false => difference,
+            };
+            if asymmetry > max.0 {
+                max = (asymmetry, i, j);
+            }
+        }
+    }
+    max
+}
+
+/// Add `values` to `sum` elementwise, tracking the lost low-order bits of
+/// each sum in `compensation` (Neumaier's variant of Kahan summation)
+fn compensated_add<T: Float>(sum: &mut [T], compensation: &mut [T], values: &[T]) {
+    for ((s, c), &x) in sum.iter_mut().zip(compensation.iter_mut()).zip(values) {
+        let t = *s + x;
+        if s.abs() >= x.abs() {
+            *c += (*s - t) + x;
+        } else {
+            *c += (x - t) + *s;
+        }
+        *s = t;
+    }
+}
+
+/// Running sums over features of a heterogeneity statistic in the manner of
+/// Cochran's Q, for each variant (rows) and projection (columns). A large
+/// statistic means that the features loading on a projection disagree about
+/// the effect of the variant, which can point to a misspecified projection.
+///
+/// Each feature's standardized effect `b = beta / sqrt(fpv)`, with inverse
+/// variance `w = fpv / se^2`, is treated as an estimate of a shared effect,
+/// aligned to the sign of the feature's coefficient `c` in the projection and
+/// weighted by `|c|`. With the sums below, the statistic is
+/// `Q = sum |c| w b^2 - (sum c w b)^2 / sum |c| w`, the weighted sum of
+/// squared deviations from the weighted mean effect. For `k` independent
+/// features with coefficients of equal magnitude and a shared effect, Q is
+/// approximately chi-square with `k - 1` degrees of freedom; correlated
+/// features make it only a relative measure.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeterogeneitySums<T = f32> {
+    /// Sum of `|c| w`
+    pub weights: DMatrix<T>,
+    /// Sum of `c w b`
+    pub effects: DMatrix<T>,
+    /// Sum of `|c| w b^2`, where `w b^2` is the squared t-statistic
+    pub squares: DMatrix<T>,
+}
+
+impl<T: Float> HeterogeneitySums<T> {
+    pub fn zeros(n_variants: usize, n_projections: usize) -> Self {
+        HeterogeneitySums {
+            weights: DMatrix::zeros(n_variants, n_projections),
+            effects: DMatrix::zeros(n_variants, n_projections),
+            squares: DMatrix::zeros(n_variants, n_projections),
+        }
+    }
+
+    /// Sums of one feature, with coefficients `proj` and their magnitudes
+    /// `abs_proj`, given the beta, standard error, and partial variance of
+    /// each variant
+    pub fn for_feature(
+        proj: &Projection<T>,
+        abs_proj: &Projection<T>,
+        feature_idx: usize,
+        beta: &DVector<T>,
+        se: &DVector<T>,
+        fpv: T,
+    ) -> Self {
+        let weights = se.map(|x| fpv / x.powi(2));
+        let effects = beta.zip_map(se, |b, s| fpv.sqrt() * b / s.powi(2));
+        let squares = beta.zip_map(se, |b, s| (b / s).powi(2));
+        HeterogeneitySums {
+            weights: abs_proj.outer_row(&weights, feature_idx),
+            effects: proj.outer_row(&effects, feature_idx),
+            squares: abs_proj.outer_row(&squares, feature_idx),
+        }
+    }
+
+    /// Leave out variant `i`, e.g. one a feature does not contribute to
+    pub fn clear_row(&mut self, i: usize) {
+        self.weights.row_mut(i).fill(T::zero());
+        self.effects.row_mut(i).fill(T::zero());
+        self.squares.row_mut(i).fill(T::zero());
+    }
+
+    fn add(&mut self, other: &HeterogeneitySums<T>) {
+        self.weights += &other.weights;
+        self.effects += &other.effects;
+        self.squares += &other.squares;
+    }
+
+    /// The statistic Q, or zero where no feature contributes
+    pub fn statistic(&self) -> DMatrix<T> {
+        let mut q = self.squares.clone();
+        for ((q, &w), &e) in q.iter_mut().zip(&self.weights).zip(&self.effects) {
+            *q = if w > T::zero() {
+                *q - e.powi(2) / w
+            } else {
+                T::zero()
+            };
+        }
+        q
+    }
+}
+
+/// Running sums over features of the effective sample size of each variant
+/// (rows) and projection (columns).
+///
+/// Each feature is weighted by its share of the partial variance of the
+/// projection, `w = c^2 fpv` for its coefficient `c` and partial variance
+/// `fpv`, and the effective sample size is the weighted harmonic mean of the
+/// sample sizes `N` of the features, `N_eff = sum w / sum (w / N)`. It is the
+/// sample size of a feature with a coefficient of 1, and depends neither on
+/// the scale of the features nor on that of the projection. Since the
+/// features are usually measured in the same samples, it is a mean rather
+/// than a sum, and features that do not contribute to a variant are left out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleSizeSums<T = f32> {
+    /// Sum of `w`
+    pub weights: DMatrix<T>,
+    /// Sum of `w / N`
+    pub inverse: DMatrix<T>,
+}
+
+impl<T: Float> SampleSizeSums<T> {
+    pub fn zeros(n_variants: usize, n_projections: usize) -> Self {
+        SampleSizeSums {
+            weights: DMatrix::zeros(n_variants, n_projections),
+            inverse: DMatrix::zeros(n_variants, n_projections),
+        }
+    }
+
+    /// Sums of one feature, with weights `c^2 fpv` in `variance_proj`, given
+    /// the sample size of each variant
+    pub fn for_feature(
+        variance_proj: &Projection<T>,
+        feature_idx: usize,
+        sample_sizes: &DVector<i32>,
+    ) -> Self {
+        let ones = DVector::repeat(sample_sizes.len(), T::one());
+        let inverse = sample_sizes.map(|n| T::one() / T::cast_f64(n as f64));
+        SampleSizeSums {
+            weights: variance_proj.outer_row(&ones, feature_idx),
+            inverse: variance_proj.outer_row(&inverse, feature_idx),
+        }
+    }
+
+    /// Leave out variant `i`, e.g. one a feature does not contribute to
+    pub fn clear_row(&mut self, i: usize) {
+        self.weights.row_mut(i).fill(T::zero());
+        self.inverse.row_mut(i).fill(T::zero());
+    }
+
+    fn add(&mut self, other: &SampleSizeSums<T>) {
+        self.weights += &other.weights;
+        self.inverse += &other.inverse;
+    }
+
+    /// The effective sample size, or NaN where no feature contributes
+    pub fn effective_sample_size(&self) -> DMatrix<T> {
+        self.weights.zip_map(&self.inverse, |w, inverse| {
+            if inverse > T::zero() {
+                w / inverse
+            } else {
+                T::cast_f64(f64::NAN)
+            }
+        })
+    }
+}
+
+/// Projection coefficients, features x projections. Projections that each
+/// load on a few features are best stored sparse, which skips the zeros in
+/// both memory and the products below.
 #[derive(Clone)]
-pub struct RunningSufficientStats {
-    pub beta: DMatrix<f32>,
-    pub gpv: DVector<f32>,
+pub enum Projection<T = f32> {
+    Dense(DMatrix<T>),
+    Sparse(CsrMatrix<T>),
+}
+
+impl<T: Float> Projection<T> {
+    pub fn new(matrix: &DMatrix<f32>, sparse: bool) -> Self {
+        let matrix = matrix.map(T::cast_f32);
+        if sparse {
+            Projection::Sparse(CsrMatrix::from(&matrix))
+        } else {
+            Projection::Dense(matrix)
+        }
+    }
+
+    /// The outer product of `values` (one per variant) with the coefficients
+    /// of one feature, i.e. its contribution to the projected betas
+    pub fn outer_row(&self, values: &DVector<T>, feature_idx: usize) -> DMatrix<T> {
+        match self {
+            Projection::Dense(proj) => values * proj.row(feature_idx),
+            Projection::Sparse(proj) => {
+                let mut result = DMatrix::zeros(values.len(), proj.ncols());
+                let row = proj.row(feature_idx);
+                for (&j, &coef) in row.col_indices().iter().zip(row.values()) {
+                    result.set_column(j, &(values * coef));
+                }
+                result
+            }
+        }
+    }
+
+    /// The magnitudes of the coefficients
+    pub fn abs(&self) -> Self {
+        match self {
+            Projection::Dense(proj) => Projection::Dense(proj.abs()),
+            Projection::Sparse(proj) => {
+                let mut proj = proj.clone();
+                for x in proj.values_mut() {
+                    *x = x.abs();
+                }
+                Projection::Sparse(proj)
+            }
+        }
+    }
+
+    /// 64-bit FNV-1a hash of the nonzero coefficients and their positions
+    fn coefficient_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut add = |x: u64| {
+            for byte in x.to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        let mut add_coefficient = |i: usize, j: usize, x: T| {
+            if x != T::zero() {
+                add(i as u64);
+                add(j as u64);
+                add(x.to_f64().to_bits());
+            }
+        };
+        match self {
+            Projection::Dense(proj) => {
+                for i in 0..proj.nrows() {
+                    for j in 0..proj.ncols() {
+                        add_coefficient(i, j, proj[(i, j)]);
+                    }
+                }
+            }
+            Projection::Sparse(proj) => {
+                for (i, j, &x) in proj.triplet_iter() {
+                    add_coefficient(i, j, x);
+                }
+            }
+        }
+        hash
+    }
+
+    /// The diagonal of `proj^T cov proj`, i.e. the partial variance of each
+    /// projection
+    pub fn quadratic_form_diagonal(&self, cov: &DMatrix<T>) -> DVector<T> {
+        match self {
+            // Only the diagonal is needed, so skip the off-diagonal
+            // products of the projections x projections matrix
+            Projection::Dense(proj) => DVector::from_vec(
+                (0..proj.ncols())
+                    .into_par_iter()
+                    .map(|j| {
+                        let col = proj.column(j);
+                        col.dot(&(cov * col))
+                    })
+                    .collect(),
+            ),
+            Projection::Sparse(proj) => {
+                let proj_t = proj.transpose();
+                DVector::from_iterator(
+                    proj_t.nrows(),
+                    proj_t.row_iter().map(|col| {
+                        let mut total = T::zero();
+                        for (&a, &x) in col.col_indices().iter().zip(col.values()) {
+                            for (&b, &y) in col.col_indices().iter().zip(col.values()) {
+                                total += x * cov[(a, b)] * y;
+                            }
+                        }
+                        total
+                    }),
+                )
+            }
+        }
+    }
+}
+
+/// Multiply the beta, standard error, confidence bounds, and heterogeneity
+/// statistic of each projection by its scale, in results ordered by
+/// projection with `n_variants` rows each
+fn scale_projections(results: &mut IGwasResults, scales: &DVector<f32>, n_variants: usize) {
+    let mut columns = vec![&mut results.beta_values, &mut results.se_values];
+    columns.extend(results.ci_lower.as_mut());
+    columns.extend(results.ci_upper.as_mut());
+    columns.extend(results.heterogeneity.as_mut());
+    for column in columns {
+        for (rows, &scale) in column
+            .as_mut_slice()
+            .chunks_mut(n_variants)
+            .zip(scales.iter())
+        {
+            for x in rows.iter_mut() {
+                *x *= scale;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RunningSufficientStats<T = f32> {
+    pub beta: DMatrix<T>,
+    pub gpv: DVector<T>,
     pub sample_sizes: DVector<i32>,
+    dof: DVector<i32>, // Degrees of freedom of each variant, combined across features
+    // Feature each sample size was taken from, with SampleSizePolicy::First
+    sample_size_source: Vec<usize>,
 
-    cov: DMatrix<f32>,  // Partial covariance matrix of the features
-    fpv: DVector<f32>,  // Partial variance vector of the features
-    proj: DMatrix<f32>, // Matrix of the projection coefficients
+    // Shared with the ProcessingStats of each chunk rather than copied
+    fpv: Arc<DVector<T>>,     // Partial variance vector of the features
+    proj: Arc<Projection<T>>, // Matrix of the projection coefficients
+    ppv: DVector<T>,          // Partial variance of each projection
+    // Magnitudes of the coefficients, with the heterogeneity statistic
+    abs_proj: Option<Arc<Projection<T>>>,
+    // L2 norms of the projection columns, when they are normalized
+    projection_scales: Option<DVector<f32>>,
+    // Squared coefficients times the partial variances of the features, with
+    // the effective sample size
+    variance_proj: Option<Arc<Projection<T>>>,
 
-    n_covar: usize,
+    n_covar: Arc<Vec<usize>>, // Number of covariates of each feature
     chunksize: usize,
 
     n_features: usize,
     n_projections: usize,
-    phenotype_id_to_idx: HashMap<String, usize>,
+    phenotype_id_to_idx: Arc<HashMap<String, usize>>,
 
     variant_ids: Option<Vec<String>>,
     projection_ids: Vec<String>,
+    assume_aligned: bool, // Skip checking the variant ids of each feature
 
     n_features_seen: usize,
+    excluded: Vec<bool>, // Variants missing from some file, left out of the output
+    zero_weight_counts: Vec<usize>, // Number of files not contributing to each variant
+
+    annotations: Annotations,
+    annotation_source: Option<(usize, String)>, // Feature the annotations were taken from
+
+    // Compensation terms of beta and gpv, with compensated summation
+    compensation: Option<(DMatrix<T>, DVector<T>)>,
+    heterogeneity: Option<HeterogeneitySums<T>>,
+    sample_size_sums: Option<SampleSizeSums<T>>,
+
+    // Scratch buffers of compute_final_stats for se, t, and p, reused across
+    // chunks of the same size
+    se: DMatrix<T>,
+    t_stat: DMatrix<T>,
+    p_values: DMatrix<T>,
+
+    config: StatsConfig,
 }
 
 // Add a method on RunningSufficientStats that takes some GWAS summary statistics and updates the
 // state
-impl RunningSufficientStats {
+impl<T: Float> RunningSufficientStats<T> {
+    /// Statistics of the projections in `proj` of the features with
+    /// covariance `cov`. The rows and columns of `cov` are matched to the
+    /// rows of `proj` by their labels, so they may be in any order, e.g.
+    /// that of a matrix written transposed.
+    ///
+    /// `feature_weights`, one per row of `proj`, scale each feature before it
+    /// is projected, so that a projection is the sum over features of
+    /// coefficient * weight * feature. This is the same as multiplying each
+    /// row of `proj` by its weight, which is applied to both the projected
+    /// betas and the projected partial variances. `cov` remains the covariance
+    /// of the unweighted features: the weighted features have covariance
+    /// W * cov * W, for W the diagonal matrix of the weights. The genotype
+    /// partial variance does not depend on the scale of a feature, so it is
+    /// unaffected by the weights.
+    ///
+    /// With `StatsConfig::normalize_projections`, each column of the
+    /// weighted `proj` is then divided by its L2 norm, its scale factor
+    /// (see `projection_scales`). Columns of zeros are left as they are,
+    /// with a scale factor of 1. A projection divided by `s` has its beta,
+    /// standard error, and confidence bounds divided by `s`, its partial
+    /// variance by `s^2`, and its heterogeneity statistic by `s`, while its
+    /// t-statistic and p-value are unchanged.
     pub fn new(
         proj: &LabeledMatrix,
         cov: &LabeledMatrix,
         n_covar: usize,
         chunksize: usize,
-    ) -> Self {
+        feature_weights: Option<&DVector<f32>>,
+        config: StatsConfig,
+    ) -> Result<Self> {
+        let selected;
+        let proj = match &config.projection_ids {
+            Some(projection_ids) => {
+                ensure!(!projection_ids.is_empty(), "No projections selected");
+                selected = proj
+                    .select(&proj.row_labels, projection_ids)
+                    .context("Error selecting projections")?;
+                &selected
+            }
+            None => proj,
+        };
         let n_features = proj.matrix.nrows();
         let n_projections = proj.matrix.ncols();
 
+        // Non-finite values would make every statistic non-finite
+        proj.ensure_finite("projection")?;
+        cov.ensure_finite("covariance")?;
+
         // Check that cov is n_features x n_features
-        assert_eq!(
-            cov.matrix.nrows(),
-            n_features,
-            "Covariance matrix has wrong shape, expected {} x {}, got {} x {}",
-            n_features,
-            n_features,
-            cov.matrix.nrows(),
-            cov.matrix.ncols()
-        );
-        assert_eq!(
-            cov.matrix.ncols(),
-            n_features,
+        ensure!(
+            cov.matrix.shape() == (n_features, n_features),
             "Covariance matrix has wrong shape, expected {} x {}, got {} x {}",
             n_features,
             n_features,
@@ -62,6 +591,44 @@ impl RunningSufficientStats {
             cov.matrix.ncols()
         );
 
+        // The statistics use the covariance matrix positionally, so orient
+        // it to the features of the projection by its labels, which also
+        // undoes a matrix written transposed or with its own feature order
+        let oriented;
+        let cov = if cov.row_labels == proj.row_labels && cov.col_labels == proj.row_labels {
+            cov
+        } else {
+            oriented = cov.select(&proj.row_labels, &proj.row_labels).context(
+                "The labels of the covariance matrix do not match the features of the \
+                projection matrix",
+            )?;
+            &oriented
+        };
+
+        // The statistics assume a symmetric covariance matrix
+        let (asymmetry, i, j) = covariance_asymmetry(&cov.matrix);
+        let worst = || {
+            format!(
+                "{} between rows {} and {}",
+                asymmetry, cov.row_labels[i], cov.row_labels[j]
+            )
+        };
+        if let Some(max_asymmetry) = config.max_covariance_asymmetry {
+            ensure!(
+                asymmetry <= max_asymmetry,
+                "The covariance matrix is asymmetric, up to {}, more than the maximum {}",
+                worst(),
+                max_asymmetry
+            );
+        }
+        if asymmetry > COVARIANCE_ASYMMETRY_TOLERANCE && !config.symmetrize_covariance {
+            warn!(
+                "The covariance matrix is asymmetric, up to {}. It can be symmetrized as \
+                (C + C^T) / 2",
+                worst()
+            );
+        }
+
         // Phenotype_id_to_idx is a hashmap basically of an enumeration of the phenotype ids
         let phenotype_id_to_idx = proj
             .row_labels
@@ -70,22 +637,98 @@ impl RunningSufficientStats {
             .map(|(i, x)| (x.clone(), i))
             .collect();
 
-        RunningSufficientStats {
+        ensure!(
+            config.normalize_projections || !config.restore_projection_scale,
+            "Restoring the projection scale requires normalizing the projections"
+        );
+        let mut matrix = match feature_weights {
+            Some(weights) => {
+                ensure!(
+                    weights.len() == n_features,
+                    "Expected {} feature weights, one per row of the projection matrix, got {}",
+                    n_features,
+                    weights.len()
+                );
+                ensure!(
+                    weights.iter().all(|x| x.is_finite()),
+                    "Feature weights must be finite"
+                );
+                let mut matrix = proj.matrix.clone();
+                for (mut row, &weight) in matrix.row_iter_mut().zip(weights.iter()) {
+                    row *= weight;
+                }
+                matrix
+            }
+            None => proj.matrix.clone(),
+        };
+        let projection_scales = config.normalize_projections.then(|| {
+            let scales = DVector::from_iterator(
+                n_projections,
+                matrix.column_iter().map(|col| match col.norm() {
+                    0.0 => 1.0,
+                    norm => norm,
+                }),
+            );
+            for (mut col, &scale) in matrix.column_iter_mut().zip(scales.iter()) {
+                col /= scale;
+            }
+            scales
+        });
+        let projection = Projection::new(&matrix, config.sparse_projection);
+        let variance_proj = config.effective_sample_size.then(|| {
+            let mut matrix = matrix.map(|x| x.powi(2));
+            for (mut row, &fpv) in matrix.row_iter_mut().zip(cov.matrix.diagonal().iter()) {
+                row *= fpv;
+            }
+            Arc::new(Projection::new(&matrix, config.sparse_projection))
+        });
+        let cov = match config.symmetrize_covariance {
+            true => (&cov.matrix + cov.matrix.transpose()) / 2.0,
+            false => cov.matrix.clone(),
+        }
+        .map(T::cast_f32);
+        Ok(RunningSufficientStats {
             beta: DMatrix::zeros(chunksize, n_projections),
             gpv: DVector::zeros(chunksize),
             sample_sizes: DVector::zeros(chunksize),
-            cov: cov.matrix.clone(),
-            fpv: cov.matrix.diagonal(),
-            proj: proj.matrix.clone(),
-            n_covar,
+            dof: DVector::zeros(chunksize),
+            sample_size_source: vec![usize::MAX; chunksize],
+            fpv: Arc::new(cov.diagonal()),
+            ppv: projection.quadratic_form_diagonal(&cov),
+            abs_proj: config.heterogeneity.then(|| Arc::new(projection.abs())),
+            proj: Arc::new(projection),
+            projection_scales,
+            variance_proj,
+            n_covar: Arc::new(vec![n_covar; n_features]),
             n_features,
             n_projections,
             chunksize,
-            phenotype_id_to_idx,
+            phenotype_id_to_idx: Arc::new(phenotype_id_to_idx),
             variant_ids: None,
             projection_ids: proj.col_labels.clone(),
+            assume_aligned: false,
             n_features_seen: 0,
-        }
+            excluded: vec![false; chunksize],
+            zero_weight_counts: vec![0; chunksize],
+            annotations: Annotations::default(),
+            annotation_source: None,
+            compensation: config.compensated_summation.then(|| {
+                (
+                    DMatrix::zeros(chunksize, n_projections),
+                    DVector::zeros(chunksize),
+                )
+            }),
+            heterogeneity: config
+                .heterogeneity
+                .then(|| HeterogeneitySums::zeros(chunksize, n_projections)),
+            sample_size_sums: config
+                .effective_sample_size
+                .then(|| SampleSizeSums::zeros(chunksize, n_projections)),
+            se: DMatrix::zeros(chunksize, n_projections),
+            t_stat: DMatrix::zeros(chunksize, n_projections),
+            p_values: DMatrix::zeros(chunksize, n_projections),
+            config,
+        })
     }
 
     pub fn clear_chunk(&mut self, new_chunksize: usize) {
@@ -93,84 +736,394 @@ impl RunningSufficientStats {
             self.beta = DMatrix::zeros(new_chunksize, self.n_projections);
             self.gpv = DVector::zeros(new_chunksize);
             self.sample_sizes = DVector::zeros(new_chunksize);
+            self.dof = DVector::zeros(new_chunksize);
+            self.se = DMatrix::zeros(new_chunksize, self.n_projections);
+            self.t_stat = DMatrix::zeros(new_chunksize, self.n_projections);
+            self.p_values = DMatrix::zeros(new_chunksize, self.n_projections);
             self.chunksize = new_chunksize;
         } else {
-            self.beta.fill(0.0);
-            self.gpv.fill(0.0);
+            self.beta.fill(T::zero());
+            self.gpv.fill(T::zero());
             self.sample_sizes.fill(0);
+            self.dof.fill(0);
         }
         self.n_features_seen = 0;
+        self.excluded = vec![false; new_chunksize];
+        self.sample_size_source = vec![usize::MAX; new_chunksize];
+        self.zero_weight_counts = vec![0; new_chunksize];
+        self.annotations = Annotations::default();
+        self.annotation_source = None;
+        self.compensation = self.config.compensated_summation.then(|| {
+            (
+                DMatrix::zeros(new_chunksize, self.n_projections),
+                DVector::zeros(new_chunksize),
+            )
+        });
+        self.heterogeneity = self
+            .config
+            .heterogeneity
+            .then(|| HeterogeneitySums::zeros(new_chunksize, self.n_projections));
+        self.sample_size_sums = self
+            .config
+            .effective_sample_size
+            .then(|| SampleSizeSums::zeros(new_chunksize, self.n_projections));
     }
 
-    pub fn build_processing_stats(&self) -> ProcessingStats {
+    /// The per-feature inputs of the workers. The projection and the other
+    /// inputs are shared, not copied.
+    pub fn build_processing_stats(&self) -> ProcessingStats<T> {
         ProcessingStats {
             n_variants: self.beta.nrows(),
             proj: self.proj.clone(),
+            abs_proj: self.abs_proj.clone(),
+            variance_proj: self.variance_proj.clone(),
             fpv: self.fpv.clone(),
             phenotype_id_to_idx: self.phenotype_id_to_idx.clone(),
-            n_covar: self.n_covar,
+            n_covar: self.n_covar.clone(),
         }
     }
 
-    pub fn update(&mut self, gwas_results: &IntermediateResults) {
+    /// Set the number of covariates of one feature, when it differs from
+    /// the number given to `new`
+    pub fn set_num_covar(&mut self, phenotype_id: &str, n_covar: usize) -> Result<()> {
+        let Some(&idx) = self.phenotype_id_to_idx.get(phenotype_id) else {
+            bail!(
+                "Cannot set the number of covariates of unknown phenotype {}",
+                phenotype_id
+            );
+        };
+        Arc::make_mut(&mut self.n_covar)[idx] = n_covar;
+        Ok(())
+    }
+
+    /// Trust that every feature has the same variants, in the same order, as
+    /// the first of each chunk. Their variant ids are then neither compared
+    /// nor kept, saving time with many features, but misaligned inputs give
+    /// wrong results instead of an error.
+    pub fn set_assume_aligned(&mut self, assume_aligned: bool) {
+        self.assume_aligned = assume_aligned;
+    }
+
+    /// Combine the sample sizes and degrees of freedom of one feature with
+    /// those seen so far, following the `SampleSizePolicy`. Variants the
+    /// feature does not contribute to, or is missing, are left out, since
+    /// their sample sizes are placeholders.
+    fn fold_sample_sizes(&mut self, phenotype_idx: usize, gwas_results: &IntermediateResults<T>) {
+        let n_covar = self.n_covar[phenotype_idx] as i32;
+        let sample_sizes = &gwas_results.sample_sizes;
+        let left_out = || gwas_results.zero_weight.iter().chain(&gwas_results.missing);
+        let skip = left_out().next().is_some().then(|| {
+            let mut skip = vec![false; sample_sizes.len()];
+            for &i in left_out() {
+                skip[i] = true;
+            }
+            skip
+        });
+        for (i, &n) in sample_sizes.iter().enumerate() {
+            if skip.as_ref().is_some_and(|x| x[i]) {
+                continue;
+            }
+            let dof = n - 2 - n_covar;
+            let (x, d) = (&mut self.sample_sizes[i], &mut self.dof[i]);
+            match self.config.sample_size_policy {
+                SampleSizePolicy::Min => (*x, *d) = ((*x).min(n), (*d).min(dof)),
+                SampleSizePolicy::Max => (*x, *d) = ((*x).max(n), (*d).max(dof)),
+                // The mean is taken in compute_final_stats
+                SampleSizePolicy::Mean | SampleSizePolicy::Sum => {
+                    (*x, *d) = (x.saturating_add(n), d.saturating_add(dof))
+                }
+                SampleSizePolicy::First => {
+                    if phenotype_idx < self.sample_size_source[i] {
+                        (*x, *d) = (n, dof);
+                        self.sample_size_source[i] = phenotype_idx;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ids of the projections computed, in the order of the results
+    pub fn projection_ids(&self) -> &[String] {
+        &self.projection_ids
+    }
+
+    /// The L2 norm of each column of the projection matrix, which the
+    /// columns were divided by, if normalized
+    pub fn projection_scales(&self) -> Option<&DVector<f32>> {
+        self.projection_scales.as_ref()
+    }
+
+    pub fn update(&mut self, gwas_results: &IntermediateResults<T>) -> Result<()> {
+        let Some(&phenotype_idx) = self.phenotype_id_to_idx.get(&gwas_results.phenotype_id) else {
+            bail!("Unknown phenotype {}", gwas_results.phenotype_id);
+        };
+
+        // Only the first feature of a chunk allocates; the others are
+        // compared and folded in place
         if self.n_features_seen == 0 {
-            self.sample_sizes = gwas_results.sample_sizes.clone();
+            let n_variants = gwas_results.sample_sizes.len();
+            let identity = self.config.sample_size_policy.identity();
+            self.sample_sizes = DVector::from_element(n_variants, identity);
+            self.dof = DVector::from_element(n_variants, identity);
+            self.sample_size_source = vec![usize::MAX; n_variants];
             self.variant_ids = Some(gwas_results.variant_ids.clone());
-        } else {
-            self.sample_sizes = self.sample_sizes.inf(&gwas_results.sample_sizes);
-            // Check that the variant ids match. Add a runtime error message if not
-            assert_eq!(
-                self.variant_ids.clone().unwrap(),
-                gwas_results.variant_ids,
-                "Mismatched variant ids"
+        } else if !self.assume_aligned {
+            // Check that the variant ids match
+            let expected = self.variant_ids.as_ref().unwrap();
+            let found = &gwas_results.variant_ids;
+            if let Some(i) = (0..expected.len().min(found.len())).find(|&i| expected[i] != found[i])
+            {
+                bail!(
+                    "Mismatched variant ids for phenotype {}: at index {} of the chunk, expected {} but found {}",
+                    gwas_results.phenotype_id,
+                    i,
+                    expected[i],
+                    found[i]
+                );
+            }
+            ensure!(
+                expected.len() == found.len(),
+                "Mismatched variant ids for phenotype {}: expected {} variants in the chunk, found {}",
+                gwas_results.phenotype_id,
+                expected.len(),
+                found.len()
             );
         }
+        self.fold_sample_sizes(phenotype_idx, gwas_results);
+
+        for &i in &gwas_results.missing {
+            self.excluded[i] = true;
+        }
+        for &i in &gwas_results.zero_weight {
+            self.zero_weight_counts[i] += 1;
+        }
+        self.update_annotations(phenotype_idx, gwas_results)?;
+        if let Some(sums) = &mut self.heterogeneity {
+            let update = gwas_results.heterogeneity.as_ref().with_context(|| {
+                format!(
+                    "The update of phenotype {} has no heterogeneity sums",
+                    gwas_results.phenotype_id
+                )
+            })?;
+            sums.add(update);
+        }
+        if let Some(sums) = &mut self.sample_size_sums {
+            let update = gwas_results.sample_size_sums.as_ref().with_context(|| {
+                format!(
+                    "The update of phenotype {} has no effective sample size sums",
+                    gwas_results.phenotype_id
+                )
+            })?;
+            sums.add(update);
+        }
 
-        self.beta += &gwas_results.beta_update;
-        self.gpv += &gwas_results.gpv_update;
+        match &mut self.compensation {
+            Some((beta, gpv)) => {
+                compensated_add(
+                    self.beta.as_mut_slice(),
+                    beta.as_mut_slice(),
+                    gwas_results.beta_update.as_slice(),
+                );
+                compensated_add(
+                    self.gpv.as_mut_slice(),
+                    gpv.as_mut_slice(),
+                    gwas_results.gpv_update.as_slice(),
+                );
+            }
+            None => {
+                self.beta += &gwas_results.beta_update;
+                self.gpv += &gwas_results.gpv_update;
+            }
+        }
         self.n_features_seen += 1;
+        Ok(())
     }
 
-    pub fn compute_final_stats(&mut self) -> IGwasResults {
-        if self.n_features_seen != self.n_features {
-            panic!(
-                "Too few features seen. Expected {}, got {}",
-                self.n_features, self.n_features_seen
-            );
+    /// Reconcile the annotations of one feature with those seen so far.
+    /// Variants excluded from the output are not compared.
+    fn update_annotations(
+        &mut self,
+        phenotype_idx: usize,
+        gwas_results: &IntermediateResults<T>,
+    ) -> Result<()> {
+        if let Some((source_idx, source_phenotype)) = &self.annotation_source {
+            if self.config.annotation_mismatch == AnnotationPolicy::Error {
+                let variant_ids = self.variant_ids.as_ref().unwrap();
+                for ((name, expected), (_, found)) in self
+                    .annotations
+                    .columns()
+                    .into_iter()
+                    .zip(gwas_results.annotations.columns())
+                {
+                    if let Some(i) =
+                        (0..expected.len()).find(|&i| !self.excluded[i] && expected[i] != found[i])
+                    {
+                        bail!(
+                            "Mismatched {} of variant {}: {} for phenotype {} but {} for \
+                            phenotype {}. Use the take-first annotation policy to keep the \
+                            values of the first phenotype",
+                            name,
+                            variant_ids[i],
+                            expected[i],
+                            source_phenotype,
+                            found[i],
+                            gwas_results.phenotype_id
+                        );
+                    }
+                }
+            }
+            if *source_idx < phenotype_idx {
+                return Ok(());
+            }
         }
+        self.annotations = gwas_results.annotations.clone();
+        self.annotation_source = Some((phenotype_idx, gwas_results.phenotype_id.clone()));
+        Ok(())
+    }
 
-        self.gpv /= self.n_features_seen as f32;
-        let dof = self.sample_sizes.map(|x| x - 2 - self.n_covar as i32);
-        let ppv = (self.proj.transpose() * &self.cov * &self.proj).diagonal();
-        let mut se = DMatrix::zeros(self.gpv.nrows(), ppv.nrows());
-        se.par_column_iter_mut()
+    /// Results of the chunk, one row per variant and projection, ordered by
+    /// projection. With `StatsConfig::restore_projection_scale`, the beta,
+    /// standard error, confidence bounds, and heterogeneity statistic of
+    /// each normalized projection are multiplied back by its scale factor,
+    /// giving the results of the original projection matrix.
+    pub fn compute_final_stats(&mut self) -> Result<IGwasResults> {
+        ensure!(
+            self.n_features_seen == self.n_features,
+            "Too few features seen. Expected {}, got {}",
+            self.n_features,
+            self.n_features_seen
+        );
+
+        if let Some((beta, gpv)) = self.compensation.take() {
+            self.beta += beta;
+            self.gpv += gpv;
+        }
+        if self.zero_weight_counts.iter().all(|&x| x == 0) {
+            self.gpv /= T::cast_f64(self.n_features_seen as f64);
+        } else {
+            // Average the genotype partial variance over the contributing files
+            for (i, &count) in self.zero_weight_counts.iter().enumerate() {
+                let n_contributing = self.n_features_seen - count;
+                if n_contributing == 0 {
+                    self.excluded[i] = true;
+                } else {
+                    self.gpv[i] /= T::cast_f64(n_contributing as f64);
+                }
+            }
+        }
+        if self.config.sample_size_policy == SampleSizePolicy::Mean {
+            for i in 0..self.sample_sizes.len() {
+                let n_contributing = (self.n_features_seen - self.zero_weight_counts[i]).max(1);
+                let mean = |x: i32| (x as f64 / n_contributing as f64).round() as i32;
+                self.sample_sizes[i] = mean(self.sample_sizes[i]);
+                self.dof[i] = mean(self.dof[i]);
+            }
+        }
+        let dof = &self.dof;
+        let ppv = &self.ppv;
+        let gpv = &self.gpv;
+        let beta = &self.beta;
+        let se = &mut self.se;
+        let n_negative: usize = se
+            .par_column_iter_mut()
             .enumerate()
-            .for_each(|(j, mut col)| {
+            .map(|(j, mut col)| {
+                let mut n_negative = 0;
                 for i in 0..col.len() {
-                    col[i] =
-                        ((ppv[j] / self.gpv[i] - self.beta[(i, j)].powi(2)) / dof[i] as f32).sqrt();
+                    // Rounding can make the residual variance of a perfect
+                    // fit slightly negative, which is taken as zero. The
+                    // t-statistic is then infinite, or NaN if beta is zero.
+                    let residual = ppv[j] / gpv[i] - beta[(i, j)].powi(2);
+                    let residual = if residual < T::zero() {
+                        n_negative += 1;
+                        T::zero()
+                    } else {
+                        residual
+                    };
+                    col[i] = (residual / T::cast_f64(dof[i] as f64)).sqrt();
                 }
-            });
-        let t_stat = self.beta.component_div(&se);
-        let mut p_values = DMatrix::zeros(t_stat.nrows(), t_stat.ncols());
+                n_negative
+            })
+            .sum();
+        if n_negative > 0 {
+            let n_total = self.se.len();
+            let fraction = n_negative as f64 / n_total as f64;
+            if let Some(max_fraction) = self.config.max_negative_variance_fraction {
+                ensure!(
+                    fraction <= max_fraction,
+                    "{} of {} results had a negative residual variance, more than the maximum \
+                    fraction {}. Check that the covariance matrix matches the GWAS results",
+                    n_negative,
+                    n_total,
+                    max_fraction
+                );
+            }
+            warn!(
+                "{} of {} results had a negative residual variance, taken as zero. The \
+                covariance matrix may not match the GWAS results",
+                n_negative, n_total
+            );
+        }
+        let se = &self.se;
+        let t_stat = &mut self.t_stat;
+        t_stat.copy_from(beta);
+        t_stat.component_div_assign(se);
+        let t_stat = &self.t_stat;
+        let pvalue_method = self.config.pvalue_method;
+        let normal_dof_threshold = self.config.normal_dof_threshold;
+        let max_neg_log_p = self.config.max_neg_log_p.map(T::cast_f32);
+        let p_values = &mut self.p_values;
         p_values
             .par_column_iter_mut()
             .enumerate()
             .for_each(|(j, mut col)| {
                 for i in 0..col.len() {
-                    col[i] = compute_neg_log_pvalue(t_stat[(i, j)], dof[i]);
+                    col[i] = match normal_dof_threshold {
+                        Some(threshold) if dof[i] > threshold => {
+                            compute_neg_log_pvalue_normal(t_stat[(i, j)], pvalue_method)
+                        }
+                        _ => compute_neg_log_pvalue_with(t_stat[(i, j)], dof[i], pvalue_method),
+                    };
+                    if let Some(max) = max_neg_log_p {
+                        if col[i] > max {
+                            col[i] = max;
+                        }
+                    }
                 }
             });
 
+        let p_values = &self.p_values;
+
         let n_elements = self.beta.nrows() * self.beta.ncols();
 
+        let confidence_intervals = self.config.confidence_level.map(|level| {
+            // Critical values depend only on the dof, which is shared by many variants
+            let mut critical_values: HashMap<i32, T> = HashMap::new();
+            let q = dof.map(|x| {
+                *critical_values
+                    .entry(x)
+                    .or_insert_with(|| T::cast_f32(compute_t_critical_value(level, x)))
+            });
+            let half_width = DMatrix::from_fn(se.nrows(), se.ncols(), |i, j| se[(i, j)] * q[i]);
+            let lower = &self.beta - &half_width;
+            let upper = &self.beta + &half_width;
+            (
+                lower
+                    .map(T::to_f32)
+                    .reshape_generic(Dyn(n_elements), Const::<1>),
+                upper
+                    .map(T::to_f32)
+                    .reshape_generic(Dyn(n_elements), Const::<1>),
+            )
+        });
+        let (ci_lower, ci_upper) = match confidence_intervals {
+            Some((lower, upper)) => (Some(lower), Some(upper)),
+            None => (None, None),
+        };
+
         let existing_variant_ids = self.variant_ids.clone().unwrap();
         let mut variant_ids = Vec::with_capacity(self.n_projections * existing_variant_ids.len());
-        variant_ids.extend(
-            std::iter::repeat(existing_variant_ids)
-                .take(self.n_projections)
-                .flatten(),
-        );
+        variant_ids.extend(std::iter::repeat_n(existing_variant_ids, self.n_projections).flatten());
 
         let sample_sizes: DVector<i32> = DVector::from_vec(
             self.sample_sizes
@@ -185,20 +1138,417 @@ impl RunningSufficientStats {
         let projection_ids: Vec<String> = self
             .projection_ids
             .iter()
-            .flat_map(|x| std::iter::repeat(x.clone()).take(self.chunksize))
+            .flat_map(|x| std::iter::repeat_n(x.clone(), self.chunksize))
             .collect();
 
-        IGwasResults {
+        let mut results = IGwasResults {
             projection_ids,
             variant_ids,
             beta_values: self
                 .beta
-                .clone()
+                .map(T::to_f32)
+                .reshape_generic(Dyn(n_elements), Const::<1>),
+            se_values: se
+                .map(T::to_f32)
+                .reshape_generic(Dyn(n_elements), Const::<1>),
+            t_stat_values: t_stat
+                .map(T::to_f32)
+                .reshape_generic(Dyn(n_elements), Const::<1>),
+            p_values: p_values
+                .map(T::to_f32)
                 .reshape_generic(Dyn(n_elements), Const::<1>),
-            se_values: se.reshape_generic(Dyn(n_elements), Const::<1>),
-            t_stat_values: t_stat.reshape_generic(Dyn(n_elements), Const::<1>),
-            p_values: p_values.reshape_generic(Dyn(n_elements), Const::<1>),
             sample_sizes,
+            ci_lower,
+            ci_upper,
+            heterogeneity: self.heterogeneity.as_ref().map(|sums| {
+                sums.statistic()
+                    .map(T::to_f32)
+                    .reshape_generic(Dyn(n_elements), Const::<1>)
+            }),
+            effective_sample_size: self.sample_size_sums.as_ref().map(|sums| {
+                sums.effective_sample_size()
+                    .map(T::to_f32)
+                    .reshape_generic(Dyn(n_elements), Const::<1>)
+            }),
+            annotations: self.annotations.repeat(self.n_projections),
+        };
+        if let (Some(scales), true) = (
+            &self.projection_scales,
+            self.config.restore_projection_scale,
+        ) {
+            scale_projections(&mut results, scales, self.chunksize);
+        }
+
+        if self.excluded.contains(&true) {
+            let keep: Vec<bool> = self
+                .excluded
+                .iter()
+                .cycle()
+                .take(n_elements)
+                .map(|x| !x)
+                .collect();
+            return Ok(results.select_rows(&keep));
+        }
+        Ok(results)
+    }
+    /// Save the accumulated statistics of the current chunk, so that more
+    /// features can be added later with `load_state` and `update`.
+    ///
+    /// The state is a binary file of little-endian values: unsigned
+    /// integers as u64, signed integers as i64, floats as f64 bits, flags as
+    /// one byte, strings as their length followed by UTF-8 bytes, and lists
+    /// as their length followed by the items. After the magic bytes
+    /// `IGWASSTATE` and the format version (1), it holds
+    ///
+    /// 1. the size of the floats the statistics are computed in (4 or 8),
+    ///    the features and the projections, the number of covariates, the
+    ///    partial variances of the features and the projections, and a hash
+    ///    of the projection coefficients, which must match on load;
+    /// 2. the chunk: the number of variants, the variant ids (if any
+    ///    features were seen), beta (column-major), gpv, sample sizes,
+    ///    degrees of freedom, the number of features seen, exclusions,
+    ///    zero-weight counts, annotations, the feature the annotations came
+    ///    from, the compensation terms (if any), the heterogeneity sums (if
+    ///    any), the effective sample size sums (if any), and the
+    ///    `SampleSizePolicy` with the feature each sample size was taken
+    ///    from, of which the policy must match on load.
+    pub fn save_state(&self, path: &str) -> Result<()> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+        let mut w = StateWriter(BufWriter::new(file));
+        w.0.write_all(STATE_MAGIC)?;
+        w.u64(STATE_VERSION)?;
+
+        w.u64(std::mem::size_of::<T>() as u64)?;
+        w.strings(&self.feature_ids())?;
+        w.strings(&self.projection_ids)?;
+        w.u64s(self.n_covar.iter().map(|&x| x as u64))?;
+        w.floats(self.fpv.iter())?;
+        w.floats(self.ppv.iter())?;
+        w.u64(self.proj.coefficient_hash())?;
+
+        w.u64(self.chunksize as u64)?;
+        w.optional_strings(self.variant_ids.as_deref())?;
+        w.floats(self.beta.iter())?;
+        w.floats(self.gpv.iter())?;
+        w.i64s(self.sample_sizes.iter().map(|&x| x as i64))?;
+        w.i64s(self.dof.iter().map(|&x| x as i64))?;
+        w.u64(self.n_features_seen as u64)?;
+        w.u64s(self.excluded.iter().map(|&x| x as u64))?;
+        w.u64s(self.zero_weight_counts.iter().map(|&x| x as u64))?;
+        w.optional_strings(self.annotations.effect_allele.as_deref())?;
+        w.optional_strings(self.annotations.effect_allele_freq.as_deref())?;
+        w.flag(self.annotation_source.is_some())?;
+        if let Some((idx, phenotype)) = &self.annotation_source {
+            w.u64(*idx as u64)?;
+            w.string(phenotype)?;
+        }
+        w.flag(self.compensation.is_some())?;
+        if let Some((beta, gpv)) = &self.compensation {
+            w.floats(beta.iter())?;
+            w.floats(gpv.iter())?;
+        }
+        w.flag(self.heterogeneity.is_some())?;
+        if let Some(sums) = &self.heterogeneity {
+            w.floats(sums.weights.iter())?;
+            w.floats(sums.effects.iter())?;
+            w.floats(sums.squares.iter())?;
+        }
+        w.flag(self.sample_size_sums.is_some())?;
+        if let Some(sums) = &self.sample_size_sums {
+            w.floats(sums.weights.iter())?;
+            w.floats(sums.inverse.iter())?;
+        }
+        w.u64(self.config.sample_size_policy.code())?;
+        w.u64s(self.sample_size_source.iter().map(|&x| x as u64))?;
+        w.0.flush()?;
+        Ok(())
+    }
+
+    /// Restore the accumulated statistics saved by `save_state`. The
+    /// statistics must have been created from the same projection and
+    /// covariance matrices, number of covariates, and precision. Features
+    /// added later must have the same variants as the saved ones.
+    pub fn load_state(&mut self, path: &str) -> Result<()> {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let mut r = StateReader(BufReader::new(file));
+        let mut magic = [0; STATE_MAGIC.len()];
+        r.0.read_exact(&mut magic)
+            .ok()
+            .filter(|_| magic == *STATE_MAGIC)
+            .with_context(|| format!("{} is not a saved igwas state", path))?;
+        let version = r.u64()?;
+        ensure!(
+            version == STATE_VERSION,
+            "{} has state format version {}, but only version {} is supported",
+            path,
+            version,
+            STATE_VERSION
+        );
+
+        let mismatch = |what: &str| {
+            anyhow::anyhow!(
+                "The state in {} was saved with different {} than these statistics",
+                path,
+                what
+            )
+        };
+        if r.u64()? != std::mem::size_of::<T>() as u64 {
+            return Err(mismatch("precision"));
+        }
+        if r.strings()? != self.feature_ids() {
+            return Err(mismatch("features"));
+        }
+        if r.strings()? != self.projection_ids {
+            return Err(mismatch("projections"));
+        }
+        let n_covar = r.u64s()?;
+        if !n_covar
+            .iter()
+            .map(|&x| x as usize)
+            .eq(self.n_covar.iter().cloned())
+        {
+            return Err(mismatch("numbers of covariates"));
+        }
+        if r.floats::<T>()? != self.fpv.as_slice() || r.floats::<T>()? != self.ppv.as_slice() {
+            return Err(mismatch("covariance or projection matrices"));
+        }
+        if r.u64()? != self.proj.coefficient_hash() {
+            return Err(mismatch("projection coefficients"));
+        }
+
+        let chunksize = r.u64()? as usize;
+        let variant_ids = r.optional_strings()?;
+        let beta = r.floats()?;
+        let gpv = r.floats()?;
+        let sample_sizes = r.i64s()?;
+        let dof = r.i64s()?;
+        let n_features_seen = r.u64()? as usize;
+        let excluded = r.u64s()?;
+        let zero_weight_counts = r.u64s()?;
+        let annotations = Annotations {
+            effect_allele: r.optional_strings()?,
+            effect_allele_freq: r.optional_strings()?,
+        };
+        let annotation_source = match r.flag()? {
+            true => Some((r.u64()? as usize, r.string()?)),
+            false => None,
+        };
+        let compensation: Option<(Vec<T>, Vec<T>)> = match r.flag()? {
+            true => Some((r.floats()?, r.floats()?)),
+            false => None,
+        };
+        let heterogeneity: Option<[Vec<T>; 3]> = match r.flag()? {
+            true => Some([r.floats()?, r.floats()?, r.floats()?]),
+            false => None,
+        };
+        if heterogeneity.is_some() != self.heterogeneity.is_some() {
+            return Err(mismatch("heterogeneity settings"));
+        }
+        let sample_size_sums: Option<[Vec<T>; 2]> = match r.flag()? {
+            true => Some([r.floats()?, r.floats()?]),
+            false => None,
+        };
+        if sample_size_sums.is_some() != self.sample_size_sums.is_some() {
+            return Err(mismatch("effective sample size settings"));
+        }
+        if r.u64()? != self.config.sample_size_policy.code() {
+            return Err(mismatch("sample size policies"));
+        }
+        let sample_size_source = r.u64s()?;
+        let per_variant = [
+            gpv.len(),
+            sample_sizes.len(),
+            dof.len(),
+            excluded.len(),
+            zero_weight_counts.len(),
+            sample_size_source.len(),
+        ];
+        ensure!(
+            beta.len() == chunksize * self.n_projections
+                && per_variant.iter().all(|&x| x == chunksize)
+                && variant_ids.as_ref().is_none_or(|x| x.len() == chunksize)
+                && n_features_seen <= self.n_features,
+            "The state in {} is corrupt",
+            path
+        );
+        let compensation = match compensation {
+            Some((b, g)) => {
+                ensure!(
+                    b.len() == beta.len() && g.len() == chunksize,
+                    "The state in {} is corrupt",
+                    path
+                );
+                Some((
+                    DMatrix::from_vec(chunksize, self.n_projections, b),
+                    DVector::from_vec(g),
+                ))
+            }
+            None => None,
+        };
+        let heterogeneity = match heterogeneity {
+            Some([weights, effects, squares]) => {
+                ensure!(
+                    [&weights, &effects, &squares]
+                        .iter()
+                        .all(|x| x.len() == beta.len()),
+                    "The state in {} is corrupt",
+                    path
+                );
+                let matrix = |x| DMatrix::from_vec(chunksize, self.n_projections, x);
+                Some(HeterogeneitySums {
+                    weights: matrix(weights),
+                    effects: matrix(effects),
+                    squares: matrix(squares),
+                })
+            }
+            None => None,
+        };
+        let sample_size_sums = match sample_size_sums {
+            Some([weights, inverse]) => {
+                ensure!(
+                    weights.len() == beta.len() && inverse.len() == beta.len(),
+                    "The state in {} is corrupt",
+                    path
+                );
+                let matrix = |x| DMatrix::from_vec(chunksize, self.n_projections, x);
+                Some(SampleSizeSums {
+                    weights: matrix(weights),
+                    inverse: matrix(inverse),
+                })
+            }
+            None => None,
+        };
+
+        self.clear_chunk(chunksize);
+        self.beta = DMatrix::from_vec(chunksize, self.n_projections, beta);
+        self.gpv = DVector::from_vec(gpv);
+        self.sample_sizes =
+            DVector::from_iterator(chunksize, sample_sizes.iter().map(|&x| x as i32));
+        self.dof = DVector::from_iterator(chunksize, dof.iter().map(|&x| x as i32));
+        self.variant_ids = variant_ids;
+        self.n_features_seen = n_features_seen;
+        self.excluded = excluded.iter().map(|&x| x != 0).collect();
+        self.zero_weight_counts = zero_weight_counts.iter().map(|&x| x as usize).collect();
+        self.sample_size_source = sample_size_source.iter().map(|&x| x as usize).collect();
+        self.annotations = annotations;
+        self.annotation_source = annotation_source;
+        self.compensation = compensation;
+        self.heterogeneity = heterogeneity;
+        self.sample_size_sums = sample_size_sums;
+        Ok(())
+    }
+
+    /// Feature ids, in the order of their indices
+    fn feature_ids(&self) -> Vec<String> {
+        let mut ids = vec![String::new(); self.n_features];
+        for (id, &idx) in self.phenotype_id_to_idx.iter() {
+            ids[idx] = id.clone();
+        }
+        ids
+    }
+}
+
+const STATE_MAGIC: &[u8; 10] = b"IGWASSTATE";
+const STATE_VERSION: u64 = 1;
+
+/// Writes the values of a saved state, see `RunningSufficientStats::save_state`
+struct StateWriter<W: Write>(W);
+
+impl<W: Write> StateWriter<W> {
+    fn u64(&mut self, x: u64) -> Result<()> {
+        self.0.write_all(&x.to_le_bytes())?;
+        Ok(())
+    }
+
+    fn flag(&mut self, x: bool) -> Result<()> {
+        self.0.write_all(&[x as u8])?;
+        Ok(())
+    }
+
+    fn u64s(&mut self, xs: impl ExactSizeIterator<Item = u64>) -> Result<()> {
+        self.u64(xs.len() as u64)?;
+        xs.into_iter().try_for_each(|x| self.u64(x))
+    }
+
+    fn i64s(&mut self, xs: impl ExactSizeIterator<Item = i64>) -> Result<()> {
+        self.u64s(xs.map(|x| x as u64))
+    }
+
+    fn floats<'a, T: Float>(&mut self, xs: impl ExactSizeIterator<Item = &'a T>) -> Result<()> {
+        self.u64s(xs.map(|x| x.to_f64().to_bits()))
+    }
+
+    fn string(&mut self, x: &str) -> Result<()> {
+        self.u64(x.len() as u64)?;
+        self.0.write_all(x.as_bytes())?;
+        Ok(())
+    }
+
+    fn strings(&mut self, xs: &[String]) -> Result<()> {
+        self.u64(xs.len() as u64)?;
+        xs.iter().try_for_each(|x| self.string(x))
+    }
+
+    fn optional_strings(&mut self, xs: Option<&[String]>) -> Result<()> {
+        self.flag(xs.is_some())?;
+        xs.map_or(Ok(()), |xs| self.strings(xs))
+    }
+}
+
+/// Reads the values written by `StateWriter`
+struct StateReader<R: Read>(R);
+
+impl<R: Read> StateReader<R> {
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        self.0
+            .read_exact(&mut bytes)
+            .context("Saved state ended unexpectedly")?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn flag(&mut self) -> Result<bool> {
+        let mut byte = [0; 1];
+        self.0
+            .read_exact(&mut byte)
+            .context("Saved state ended unexpectedly")?;
+        Ok(byte[0] != 0)
+    }
+
+    fn u64s(&mut self) -> Result<Vec<u64>> {
+        let n = self.u64()?;
+        (0..n).map(|_| self.u64()).collect()
+    }
+
+    fn i64s(&mut self) -> Result<Vec<i64>> {
+        Ok(self.u64s()?.into_iter().map(|x| x as i64).collect())
+    }
+
+    fn floats<T: Float>(&mut self) -> Result<Vec<T>> {
+        Ok(self
+            .u64s()?
+            .into_iter()
+            .map(|x| T::cast_f64(f64::from_bits(x)))
+            .collect())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let n = self.u64()?;
+        let mut bytes = Vec::new();
+        (&mut self.0).take(n).read_to_end(&mut bytes)?;
+        ensure!(bytes.len() as u64 == n, "Saved state ended unexpectedly");
+        String::from_utf8(bytes).context("Saved state has an invalid string")
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        let n = self.u64()?;
+        (0..n).map(|_| self.string()).collect()
+    }
+
+    fn optional_strings(&mut self) -> Result<Option<Vec<String>>> {
+        match self.flag()? {
+            true => self.strings().map(Some),
+            false => Ok(None),
         }
     }
 }
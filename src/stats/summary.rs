@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use crate::io::gwas::IGwasResults;
+
+/// Median of the chi-square distribution with one degree of freedom
+const CHI2_1_MEDIAN: f64 = 0.454_936_423_119_572_8;
+
+/// Width and number of the histogram bins of chi-square statistics. Medians
+/// beyond the last bin (lambda above about 22) are reported as infinite.
+const BIN_WIDTH: f64 = 1e-3;
+const N_BINS: usize = 10_000;
+
+/// Genomic inflation factor (lambda_GC) of each projection, the median
+/// chi-square statistic (t^2) divided by the median of the chi-square
+/// distribution with one degree of freedom.
+///
+/// Chunks of results are added one at a time, so rather than holding every
+/// statistic to find the exact median, each projection keeps a histogram of
+/// its statistics in bins of width 0.001. The median is interpolated within
+/// the bin holding it, so it is accurate to well within a bin width, while
+/// memory stays constant in the number of variants.
+#[derive(Debug, Clone, Default)]
+pub struct GenomicInflation {
+    projection_ids: Vec<String>,
+    projection_idx: HashMap<String, usize>,
+    histograms: Vec<Vec<u64>>,
+}
+
+impl GenomicInflation {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add the statistics of a chunk of results. Variants with an undefined
+    /// statistic are left out.
+    pub fn add(&mut self, results: &IGwasResults) {
+        for (projection_id, &t) in results.projection_ids.iter().zip(&results.t_stat_values) {
+            if t.is_nan() {
+                continue;
+            }
+            let idx = match self.projection_idx.get(projection_id) {
+                Some(&idx) => idx,
+                None => {
+                    let idx = self.projection_ids.len();
+                    self.projection_ids.push(projection_id.clone());
+                    self.projection_idx.insert(projection_id.clone(), idx);
+                    // The last bin counts everything past the others
+                    self.histograms.push(vec![0; N_BINS + 1]);
+                    idx
+                }
+            };
+            let chi2 = (t as f64).powi(2);
+            let bin = ((chi2 / BIN_WIDTH) as usize).min(N_BINS);
+            self.histograms[idx][bin] += 1;
+        }
+    }
+
+    /// Lambda of each projection, in the order the projections were first
+    /// seen
+    pub fn lambdas(&self) -> Vec<(String, f32)> {
+        self.projection_ids
+            .iter()
+            .zip(&self.histograms)
+            .map(|(id, histogram)| (id.clone(), (median(histogram) / CHI2_1_MEDIAN) as f32))
+            .collect()
+    }
+}
+
+/// Median of the values binned in `histogram`, assuming they are spread
+/// evenly within each bin
+fn median(histogram: &[u64]) -> f64 {
+    let half = histogram.iter().sum::<u64>() as f64 / 2.0;
+    let mut below = 0.0;
+    for (bin, &count) in histogram[..N_BINS].iter().enumerate() {
+        let count = count as f64;
+        if count > 0.0 && below + count >= half {
+            return (bin as f64 + (half - below) / count) * BIN_WIDTH;
+        }
+        below += count;
+    }
+    match histogram[N_BINS] {
+        0 => f64::NAN,
+        _ => f64::INFINITY,
+    }
+}
+
+/// The most significant variant of a projection
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopHit {
+    pub projection_id: String,
+    pub variant_id: String,
+    pub neg_log_p: f32,
+}
+
+/// Most significant variant of each projection, i.e. the one with the
+/// largest -log10 p-value, across all chunks added. The first of tied
+/// variants is kept.
+#[derive(Debug, Clone, Default)]
+pub struct TopHits {
+    projection_idx: HashMap<String, usize>,
+    hits: Vec<TopHit>,
+}
+
+impl TopHits {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a chunk of results. Variants with an undefined p-value are left
+    /// out.
+    pub fn add(&mut self, results: &IGwasResults) {
+        let rows = results
+            .projection_ids
+            .iter()
+            .zip(&results.variant_ids)
+            .zip(&results.p_values);
+        for ((projection_id, variant_id), &neg_log_p) in rows {
+            if neg_log_p.is_nan() {
+                continue;
+            }
+            match self.projection_idx.get(projection_id) {
+                Some(&idx) => {
+                    let hit = &mut self.hits[idx];
+                    if neg_log_p > hit.neg_log_p {
+                        hit.variant_id.clone_from(variant_id);
+                        hit.neg_log_p = neg_log_p;
+                    }
+                }
+                None => {
+                    self.projection_idx
+                        .insert(projection_id.clone(), self.hits.len());
+                    self.hits.push(TopHit {
+                        projection_id: projection_id.clone(),
+                        variant_id: variant_id.clone(),
+                        neg_log_p,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Top hit of each projection, in the order the projections were first
+    /// seen
+    pub fn hits(&self) -> &[TopHit] {
+        &self.hits
+    }
+}
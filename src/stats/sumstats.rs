@@ -1,12 +1,201 @@
-use statrs::distribution::ContinuousCDF;
+use nalgebra::RealField;
 use statrs::distribution::StudentsT;
+use statrs::distribution::{Continuous, ContinuousCDF};
 
-pub fn compute_neg_log_pvalue(t_statistic: f32, degrees_of_freedom: i32) -> f32 {
-    let t = t_statistic as f64;
+/// Floating point types that the running statistics can be computed in
+pub trait Float: RealField + Copy {
+    fn to_f64(self) -> f64;
+
+    fn cast_f64(x: f64) -> Self {
+        nalgebra::convert(x)
+    }
+
+    fn cast_f32(x: f32) -> Self {
+        Self::cast_f64(x as f64)
+    }
+
+    fn to_f32(self) -> f32 {
+        self.to_f64() as f32
+    }
+}
+
+impl Float for f32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Float for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Two-sided critical value of the t-distribution for a confidence level,
+/// i.e. the quantile q such that P(-q < T < q) = confidence_level. NaN if
+/// there are no degrees of freedom.
+pub fn compute_t_critical_value(confidence_level: f32, degrees_of_freedom: i32) -> f32 {
+    let Ok(t_dist) = StudentsT::new(0.0, 1.0, degrees_of_freedom as f64) else {
+        return f32::NAN;
+    };
+    let p = 0.5 + confidence_level as f64 / 2.0;
+    // The inverse CDF loses accuracy for large dof, so polish with Newton steps
+    let mut q = t_dist.inverse_cdf(p);
+    for _ in 0..2 {
+        q -= (t_dist.cdf(q) - p) / t_dist.pdf(q);
+    }
+    q as f32
+}
+
+/// How two-sided p-values are computed from t-statistics. Both compute in
+/// f64, whatever the precision of the statistics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PValueMethod {
+    /// The t-distribution CDF. Fast, but p-values below the f64 range (about
+    /// 1e-308, e.g. |t| > 38 for large dof) underflow to -log10 p = inf.
+    #[default]
+    Cdf,
+    /// Compute log p directly in the tail, so that -log10 p stays accurate
+    /// far beyond the f64 range, at some cost in speed
+    LogTail,
+}
+
+pub fn compute_neg_log_pvalue<T: Float>(t_statistic: T, degrees_of_freedom: i32) -> T {
+    compute_neg_log_pvalue_with(t_statistic, degrees_of_freedom, PValueMethod::Cdf)
+}
+
+/// -log10 of the two-sided p-value of a t-statistic, computed with `method`.
+/// A NaN t-statistic, or one without degrees of freedom, gives NaN. An
+/// infinite t-statistic, e.g. from a zero standard error, gives p = 0, i.e.
+/// infinity (see `StatsConfig::max_neg_log_p` to cap it).
+pub fn compute_neg_log_pvalue_with<T: Float>(
+    t_statistic: T,
+    degrees_of_freedom: i32,
+    method: PValueMethod,
+) -> T {
+    let t = t_statistic.to_f64();
     let dof = degrees_of_freedom as f64;
 
-    let t_dist = StudentsT::new(0.0, 1.0, dof).unwrap();
+    if t.is_nan() || degrees_of_freedom <= 0 {
+        return T::cast_f64(f64::NAN);
+    }
+    if t.is_infinite() {
+        return T::cast_f64(f64::INFINITY);
+    }
+
+    if method == PValueMethod::LogTail && t.is_finite() {
+        if let Some(ln_p) = ln_t_tail_pvalue(t, dof) {
+            return T::cast_f64(-ln_p / std::f64::consts::LN_10);
+        }
+    }
+
+    let Ok(t_dist) = StudentsT::new(0.0, 1.0, dof) else {
+        return T::cast_f64(f64::NAN);
+    };
     let p = 2.0 * t_dist.cdf(-t.abs());
 
-    -p.log10() as f32
+    T::cast_f64(-p.log10())
+}
+
+/// -log10 of the two-sided p-value of a z-statistic under the standard
+/// normal distribution, which the t-distribution approaches for large dof.
+/// NaN and infinite statistics are handled like `compute_neg_log_pvalue_with`.
+pub fn compute_neg_log_pvalue_normal<T: Float>(z_statistic: T, method: PValueMethod) -> T {
+    let z = z_statistic.to_f64().abs();
+    if z.is_nan() {
+        return T::cast_f64(f64::NAN);
+    }
+    if z.is_infinite() {
+        return T::cast_f64(f64::INFINITY);
+    }
+
+    // Beyond this, erfc loses relative accuracy and soon underflows
+    if method == PValueMethod::LogTail && z > 5.0 {
+        // p = 2 phi(z) R(z), with the Mills ratio R(z) from its continued
+        // fraction 1 / (z + 1 / (z + 2 / (z + 3 / ...))), evaluated backward
+        let mut f = z;
+        for k in (1..=100).rev() {
+            f = z + k as f64 / f;
+        }
+        let ln_p =
+            std::f64::consts::LN_2 - z * z / 2.0 - 0.5 * (2.0 * std::f64::consts::PI).ln() - f.ln();
+        return T::cast_f64(-ln_p / std::f64::consts::LN_10);
+    }
+
+    let p = statrs::function::erf::erfc(z / std::f64::consts::SQRT_2);
+    T::cast_f64(-p.log10())
+}
+
+/// Natural log of the two-sided p-value of a t-statistic in the tail, where
+/// it may be too small for f64. The p-value is the regularized incomplete
+/// beta function I_x(dof / 2, 1 / 2) with x = dof / (dof + t^2), evaluated
+/// with its continued fraction and a prefactor computed in log space.
+/// Returns `None` outside the tail, where the continued fraction converges
+/// slowly and the CDF is accurate anyway.
+fn ln_t_tail_pvalue(t: f64, dof: f64) -> Option<f64> {
+    const MAX_ITERATIONS: usize = 300;
+    const EPSILON: f64 = 1e-15;
+    const TINY: f64 = 1e-300;
+
+    let a = dof / 2.0;
+    let b = 0.5;
+    let ln_denominator = (dof + t * t).ln();
+    let x = dof / (dof + t * t);
+    if x >= (a + 1.0) / (a + b + 2.0) {
+        return None;
+    }
+
+    // ln(x^a (1 - x)^b / (a B(a, b))), with 1 - x = t^2 / (dof + t^2)
+    let ln_prefactor = a * (dof.ln() - ln_denominator) + b * (2.0 * t.abs().ln() - ln_denominator)
+        - a.ln()
+        - statrs::function::beta::ln_beta(a, b);
+
+    // Modified Lentz's method
+    let clamp = |x: f64| if x.abs() < TINY { TINY } else { x };
+    let mut c = 1.0;
+    let mut d = 1.0 / clamp(1.0 - (a + b) * x / (a + 1.0));
+    let mut h = d;
+    for m in 1..=MAX_ITERATIONS {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+        let even = m * (b - m) * x / ((a - 1.0 + m2) * (a + m2));
+        d = 1.0 / clamp(1.0 + even * d);
+        c = clamp(1.0 + even / c);
+        h *= d * c;
+        let odd = -(a + m) * (a + b + m) * x / ((a + m2) * (a + 1.0 + m2));
+        d = 1.0 / clamp(1.0 + odd * d);
+        c = clamp(1.0 + odd / c);
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < EPSILON {
+            break;
+        }
+    }
+
+    Some(ln_prefactor + h.ln())
+}
+
+/// Format a p-value given as -log10(p). Small p-values are written in
+/// scientific notation, with the exponent computed in f64 so that values far
+/// below the f32 (or even f64) range do not underflow to zero.
+pub fn format_pvalue(neg_log_pvalue: f32) -> String {
+    if neg_log_pvalue.is_nan() {
+        return String::from("NaN");
+    }
+    if neg_log_pvalue == f32::INFINITY {
+        return String::from("0");
+    }
+
+    let log_p = -(neg_log_pvalue as f64);
+    if log_p >= -4.0 {
+        return (10f64.powf(log_p) as f32).to_string();
+    }
+
+    let mut exponent = log_p.floor();
+    let mut mantissa = 10f64.powf(log_p - exponent) as f32;
+    if mantissa >= 10.0 {
+        mantissa /= 10.0;
+        exponent += 1.0;
+    }
+    format!("{}e{}", mantissa, exponent)
 }
@@ -1,18 +1,29 @@
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, bail, ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use crossbeam_channel::Sender;
-use log::info;
-use nalgebra::{DMatrix, DVector};
+use log::{info, warn};
+use nalgebra::DVector;
 
 use crate::io;
-use crate::io::gwas::{GwasResults, IntermediateResults};
-use crate::stats::running::RunningSufficientStats;
+use crate::io::gwas::{GwasResults, IGwasResults, IntermediateResults, RowOffset, WriteMode};
+use crate::stats::running::{
+    HeterogeneitySums, Precision, Projection, RunningSufficientStats, SampleSizeSums, StatsConfig,
+};
+use crate::stats::summary::{GenomicInflation, TopHits};
+use crate::stats::sumstats::Float;
 
+/// The phenotype of a GWAS file named after it: its file name, or for a
+/// remote file, the basename of its URL or S3 key
 fn gwas_path_to_phenotype(filename: &str) -> String {
+    if io::remote::is_remote(filename) {
+        return filename.rsplit('/').next().unwrap().to_string();
+    }
     Path::new(filename)
         .file_name()
         .unwrap()
@@ -21,145 +32,866 @@ fn gwas_path_to_phenotype(filename: &str) -> String {
         .to_string()
 }
 
+/// A GWAS result file and the phenotype it holds
+#[derive(Debug, Clone)]
+struct GwasFile {
+    path: String,
+    phenotype: String,
+    /// The sample column (GWAS-VCF) or column group (wide files) holding the
+    /// phenotype, for files holding several phenotypes
+    sample: Option<String>,
+    /// Column names of this file that differ from the shared ones
+    columns: io::gwas::ColumnOverrides,
+}
+
+impl GwasFile {
+    /// The column names of this file: the shared ones with the file's
+    /// overrides, and for wide files, those of the file's phenotype
+    fn column_names(&self, column_names: &io::gwas::ColumnSpec) -> io::gwas::ColumnSpec {
+        let column_names = column_names.with_overrides(&self.columns);
+        match &self.sample {
+            Some(phenotype) => column_names.for_phenotype(phenotype),
+            None => column_names,
+        }
+    }
+}
+
+/// Read a file of tab-separated key/value pairs, one per line. `description`
+/// names the keys and values in error messages.
+fn read_tsv_map(filename: &str, description: (&str, &str)) -> Result<HashMap<String, String>> {
+    let text =
+        std::fs::read_to_string(filename).with_context(|| format!("Error reading {}", filename))?;
+    let mut map = HashMap::new();
+    for (i, line) in io::gwas::strip_bom(&text).lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once('\t').with_context(|| {
+            format!(
+                "Line {} of {} is not a tab-separated {} and {}",
+                i + 1,
+                filename,
+                description.0,
+                description.1
+            )
+        })?;
+        ensure!(
+            map.insert(key.to_string(), value.to_string()).is_none(),
+            "{} {} appears more than once in {}",
+            description.0,
+            key,
+            filename
+        );
+    }
+    Ok(map)
+}
+
+/// Read a mapping from GWAS result file paths to phenotype names. Each line
+/// holds a path and a phenotype name, separated by a tab, optionally followed
+/// by tab-separated overrides of the file's column names, such as
+/// `variant-id=SNP`.
+fn read_phenotype_map(
+    filename: &str,
+) -> Result<HashMap<String, (String, io::gwas::ColumnOverrides)>> {
+    read_tsv_map(filename, ("path", "phenotype name"))?
+        .into_iter()
+        .map(|(path, value)| {
+            let mut fields = value.split('\t');
+            let phenotype = fields.next().unwrap().to_string();
+            let mut overrides = io::gwas::ColumnOverrides::default();
+            for field in fields {
+                let (key, name) = field.split_once('=').with_context(|| {
+                    format!(
+                        "Invalid column name '{}' for {} in {}, expected column=name",
+                        field, path, filename
+                    )
+                })?;
+                overrides.set(key, name).with_context(|| {
+                    format!("Invalid column names for {} in {}", path, filename)
+                })?;
+            }
+            Ok((path, (phenotype, overrides)))
+        })
+        .collect()
+}
+
+/// Read the number of covariates of each phenotype. Each line holds a
+/// phenotype name and a number of covariates, separated by a tab.
+pub fn read_num_covar_map(filename: &str) -> Result<HashMap<String, usize>> {
+    read_tsv_map(filename, ("phenotype", "number of covariates"))?
+        .into_iter()
+        .map(|(phenotype, n)| {
+            let n = n.trim().parse().with_context(|| {
+                format!(
+                    "Invalid number of covariates '{}' for phenotype {} in {}",
+                    n, phenotype, filename
+                )
+            })?;
+            Ok((phenotype, n))
+        })
+        .collect()
+}
+
+/// Name the phenotypes of the GWAS result files. Phenotype names are taken
+/// from `phenotype_map` if the path is listed there, and from the file name
+/// otherwise. Files holding several phenotypes, GWAS-VCF files and wide
+/// files, instead hold one phenotype per sample or column group, named after
+/// it.
+fn name_gwas_files(
+    gwas_result_files: &[String],
+    phenotype_map: &HashMap<String, (String, io::gwas::ColumnOverrides)>,
+    column_names: &io::gwas::ColumnSpec,
+) -> Result<Vec<GwasFile>> {
+    let mut gwas_files = Vec::new();
+    for gwas_path in gwas_result_files {
+        if let Some(wide) = &column_names.wide {
+            gwas_files.extend(wide.phenotypes.iter().map(|phenotype| GwasFile {
+                path: gwas_path.clone(),
+                phenotype: phenotype.clone(),
+                sample: Some(phenotype.clone()),
+                columns: Default::default(),
+            }));
+            continue;
+        }
+        if column_names.vcf.is_some() {
+            let samples = io::vcf::read_samples(gwas_path, column_names.region.as_ref())
+                .with_context(|| format!("Error reading GWAS-VCF header: {}", gwas_path))?;
+            gwas_files.extend(samples.into_iter().map(|sample| GwasFile {
+                path: gwas_path.clone(),
+                phenotype: sample.clone(),
+                sample: Some(sample),
+                columns: Default::default(),
+            }));
+            continue;
+        }
+        let (phenotype, columns) = match phenotype_map.get(gwas_path) {
+            Some((phenotype, columns)) => (phenotype.clone(), columns.clone()),
+            None => (gwas_path_to_phenotype(gwas_path), Default::default()),
+        };
+        gwas_files.push(GwasFile {
+            path: gwas_path.clone(),
+            phenotype,
+            sample: None,
+            columns,
+        });
+    }
+    Ok(gwas_files)
+}
+
 /// Check that a GWAS result file has been provided for every phenotype in the
-/// projection and covariance matrices. Filter out all GWAS result files that
-/// are not needed.
+/// projection matrix, and that the covariance matrix covers them all. Filter
+/// out all GWAS result files that are not needed.
 fn check_filter_inputs(
     projection_labels: &[String],
     covariance_labels: &[String],
-    gwas_result_files: &[String],
-) -> Result<Vec<String>> {
+    gwas_result_files: Vec<GwasFile>,
+) -> Result<Vec<GwasFile>> {
+    // The covariance matrix may cover more phenotypes than the projection
+    let only_projection: Vec<&String> = projection_labels
+        .iter()
+        .filter(|label| !covariance_labels.contains(label))
+        .collect();
     ensure!(
-        projection_labels == covariance_labels,
-        "Projection and covariance matrices have different labels"
+        only_projection.is_empty(),
+        "Covariance matrix has no labels for phenotypes {:?} of the projection matrix",
+        only_projection
     );
+    if covariance_labels.len() > projection_labels.len() {
+        info!(
+            "Using {} of the {} phenotypes of the covariance matrix",
+            projection_labels.len(),
+            covariance_labels.len()
+        );
+    }
 
-    let mut phenotype_to_gwas_path: HashMap<String, String> = HashMap::new();
-    for gwas_path in gwas_result_files {
-        let phenotype = gwas_path_to_phenotype(gwas_path);
-        if phenotype_to_gwas_path.contains_key(&phenotype) {
-            bail!("Multiple GWAS files provided for phenotype {}", phenotype);
+    let paths: Vec<&String> = gwas_result_files.iter().map(|x| &x.path).collect();
+    info!("Found GWAS result files: {:?}", paths);
+
+    let mut phenotype_to_gwas_file: HashMap<String, GwasFile> = HashMap::new();
+    for gwas_file in gwas_result_files {
+        if let Some(other) = phenotype_to_gwas_file.get(&gwas_file.phenotype) {
+            bail!(
+                "Multiple GWAS files provided for phenotype {}: {} and {}",
+                gwas_file.phenotype,
+                other.path,
+                gwas_file.path
+            );
+        }
+        phenotype_to_gwas_file.insert(gwas_file.phenotype.clone(), gwas_file);
+    }
+
+    let missing_phenotypes: Vec<&String> = projection_labels
+        .iter()
+        .filter(|x| !phenotype_to_gwas_file.contains_key(*x))
+        .collect();
+    if !missing_phenotypes.is_empty() {
+        let mut unused_paths: Vec<&String> = phenotype_to_gwas_file
+            .iter()
+            .filter(|(phenotype, _)| !projection_labels.contains(phenotype))
+            .map(|(_, gwas_file)| &gwas_file.path)
+            .collect();
+        unused_paths.sort();
+        unused_paths.dedup();
+        if unused_paths.is_empty() {
+            bail!(
+                "No GWAS result file provided for phenotypes {:?}",
+                missing_phenotypes
+            );
         }
-        phenotype_to_gwas_path.insert(phenotype, gwas_path.to_string());
+        // Most likely the file names do not match the labels
+        bail!(
+            "No GWAS result file provided for phenotypes {:?}, while files {:?} match no \
+            phenotype. Rename the files or use a phenotype map",
+            missing_phenotypes,
+            unused_paths
+        );
     }
 
-    info!("Found GWAS result files: {:?}", gwas_result_files);
+    let final_gwas_files = projection_labels
+        .iter()
+        .map(|phenotype| phenotype_to_gwas_file.remove(phenotype).unwrap())
+        .collect();
 
-    let mut final_gwas_paths = Vec::new();
-    for phenotype in projection_labels {
-        let path = phenotype_to_gwas_path.remove(phenotype).ok_or(anyhow!(
-            "No GWAS result file provided for phenotype {}",
-            phenotype
-        ))?;
-        final_gwas_paths.push(path);
+    Ok(final_gwas_files)
+}
+
+/// How to handle variants of the first GWAS file that another file lacks,
+/// when aligning variants by id
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MissingVariantPolicy {
+    /// Return an error
+    #[default]
+    Error,
+    /// Drop the variant from the output
+    Skip,
+}
+
+/// What a progress event reports the completion of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStage {
+    /// Lines of the first GWAS file were counted, before any chunk is read.
+    /// `variants_processed` holds the lines counted so far, while
+    /// `total_variants` is not yet known and is 0.
+    Count,
+    /// One GWAS file of the current chunk was accumulated
+    File,
+    /// A chunk of variants was finished and its results were handed off
+    Chunk,
+}
+
+/// Progress of a run, reported through `RuntimeConfig::progress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub stage: ProgressStage,
+    /// Variants in the chunks finished so far
+    pub variants_processed: usize,
+    pub total_variants: usize,
+    /// GWAS files accumulated in the current chunk
+    pub files_processed: usize,
+    pub total_files: usize,
+}
+
+/// What a run computed, returned by `run`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    /// The phenotypes used, in the order of the projection matrix
+    pub phenotypes: Vec<String>,
+    /// The GWAS file of each phenotype, in the same order
+    pub phenotype_files: Vec<String>,
+    /// Number of variants of the GWAS files
+    pub num_variants: usize,
+    /// Number of chunks computed by this run, which leaves out those
+    /// completed before resuming from a checkpoint
+    pub num_chunks: usize,
+    pub num_projections: usize,
+}
+
+/// What a timing event reports the duration of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingStage {
+    /// Reading the GWAS files of a chunk and accumulating their statistics
+    Accumulate,
+    /// Computing the final statistics of a chunk
+    FinalStats,
+}
+
+/// Timing of one stage of a chunk, reported through `RuntimeConfig::timing`
+/// and logged with the target `igwas::timing` as `key=value` pairs
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingEvent {
+    pub stage: TimingStage,
+    /// Index of the chunk among those computed by this run, from 0
+    pub chunk_index: usize,
+    /// The chunk holds variants `start_line` (inclusive) to `end_line`
+    /// (exclusive)
+    pub start_line: usize,
+    pub end_line: usize,
+    /// Rows processed by the stage: rows of GWAS results accumulated, or
+    /// rows of results computed
+    pub rows: usize,
+    pub wall_time: Duration,
+    /// Time spent reading each GWAS file, by path, for `Accumulate`; empty
+    /// for `FinalStats`
+    pub file_read_times: Vec<(String, Duration)>,
+}
+
+impl std::fmt::Display for TimingEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let stage = match self.stage {
+            TimingStage::Accumulate => "accumulate",
+            TimingStage::FinalStats => "final_stats",
+        };
+        write!(
+            f,
+            "stage={} chunk={} start_line={} end_line={} rows={} wall_ms={:.3}",
+            stage,
+            self.chunk_index,
+            self.start_line,
+            self.end_line,
+            self.rows,
+            self.wall_time.as_secs_f64() * 1e3
+        )?;
+        for (path, read_time) in &self.file_read_times {
+            write!(
+                f,
+                " read_ms[{:?}]={:.3}",
+                path,
+                read_time.as_secs_f64() * 1e3
+            )?;
+        }
+        Ok(())
     }
+}
 
-    Ok(final_gwas_paths)
+/// Log a timing event and pass it to the `timing` callback, if any
+fn report_timing(runtime_config: &RuntimeConfig, event: TimingEvent) {
+    info!(target: "igwas::timing", "timing {}", event);
+    if let Some(timing) = &runtime_config.timing {
+        timing(event);
+    }
 }
 
 pub struct RuntimeConfig {
+    /// Number of worker threads, and of threads computing the final
+    /// statistics. Zero uses all available cores.
     pub num_threads: usize,
+    /// Number of threads reading GWAS files concurrently
+    pub num_readers: usize,
     pub chunksize: usize,
-    pub compress: bool,
+    /// Make each chunk one whole chromosome, whatever its number of
+    /// variants, instead of `chunksize` variants. The chromosomes are read
+    /// from the first GWAS file (see `io::gwas::chromosome_ends`).
+    pub chunk_by_chromosome: bool,
+    /// Capacity of each of the queues between the reader, the workers, and
+    /// the updater, in chunks of single GWAS files. A full queue blocks the
+    /// stage feeding it, which bounds memory use.
     pub capacity: usize,
+    pub output: io::gwas::OutputConfig,
+    pub stats: StatsConfig,
+    /// Match variants across files by id instead of by position. The first
+    /// file defines the variants and their order; variants only found in
+    /// other files are ignored.
+    pub align_by_id: bool,
+    pub missing_variants: MissingVariantPolicy,
+    /// Read uncompressed GWAS files by memory-mapping them (see
+    /// `io::gwas::read_gwas_results_mmap`) instead of through a buffer
+    pub mmap: bool,
+    /// Number of threads parsing the rows of each GWAS file. Rows are parsed
+    /// sequentially with 1, and with more, in as many parts in parallel,
+    /// which helps with wide files (see `io::gwas::read_gwas_results_parallel`)
+    pub parse_threads: usize,
+    /// Trust that all GWAS files have the same variants in the same order,
+    /// skipping the check of their variant ids (see
+    /// `RunningSufficientStats::set_assume_aligned`)
+    pub assume_aligned: bool,
+    /// Path of a tab-separated file mapping GWAS result file paths (as
+    /// given) to phenotype names, and optionally to overrides of their
+    /// column names. Files not listed there are named after their file name.
+    pub phenotype_map: Option<String>,
+    /// Number of covariates of phenotypes that were adjusted for a different
+    /// number of covariates than `num_covar`, keyed by phenotype name
+    pub num_covar_by_phenotype: HashMap<String, usize>,
+    /// Called after each GWAS file of a chunk is accumulated and after each
+    /// chunk is finished, e.g. to drive a progress bar
+    pub progress: Option<Box<dyn Fn(ProgressEvent) + Send + Sync>>,
+    /// Called with the timing of each stage of each chunk, see `TimingEvent`
+    pub timing: Option<Box<dyn Fn(TimingEvent) + Send + Sync>>,
+    /// Path of a checkpoint saved after each chunk written by `run`. If it
+    /// exists when `run` starts, the run resumes after the chunks it records.
+    pub checkpoint: Option<String>,
+    /// Only process the variants in this genomic region, e.g.
+    /// `chr2:1-50000000` (see `io::tabix::Region`). Every GWAS file must then
+    /// be bgzipped with a tabix index, which is used to seek to the region
+    /// instead of reading the whole file.
+    pub region: Option<String>,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            num_threads: 1,
+            num_readers: 1,
+            chunksize: 100000,
+            chunk_by_chromosome: false,
+            capacity: 25,
+            output: Default::default(),
+            stats: Default::default(),
+            align_by_id: false,
+            missing_variants: Default::default(),
+            mmap: false,
+            parse_threads: 1,
+            assume_aligned: false,
+            phenotype_map: None,
+            num_covar_by_phenotype: HashMap::new(),
+            progress: None,
+            timing: None,
+            checkpoint: None,
+            region: None,
+        }
+    }
 }
 
+impl RuntimeConfig {
+    /// A builder starting from the defaults, but with one thread per
+    /// available CPU
+    pub fn builder() -> RuntimeConfigBuilder {
+        RuntimeConfigBuilder {
+            config: RuntimeConfig {
+                num_threads: std::thread::available_parallelism().map_or(1, |x| x.get()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Check that the numbers of readers and parsing threads and the chunk
+    /// size are nonzero
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.num_readers > 0, "Number of readers must be nonzero");
+        ensure!(
+            self.parse_threads > 0,
+            "Number of parsing threads must be nonzero"
+        );
+        ensure!(self.chunksize > 0, "Chunk size must be nonzero");
+        ensure!(
+            self.stats.max_neg_log_p.is_none_or(|x| x > 0.0),
+            "Maximum -log10 p-value must be positive"
+        );
+        Ok(())
+    }
+
+    /// The number of worker threads, resolving zero to the number of
+    /// available cores
+    pub fn resolved_num_threads(&self) -> usize {
+        match self.num_threads {
+            0 => std::thread::available_parallelism().map_or(1, |x| x.get()),
+            n => n,
+        }
+    }
+
+    /// A rayon thread pool with `resolved_num_threads` threads, in which the
+    /// final statistics are computed, so that they don't use more cores
+    /// than requested
+    pub fn thread_pool(&self) -> Result<rayon::ThreadPool> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.resolved_num_threads())
+            .build()
+            .context("Failed to create the thread pool")
+    }
+}
+
+/// Builds a `RuntimeConfig`, checking it with `RuntimeConfig::validate`
+pub struct RuntimeConfigBuilder {
+    config: RuntimeConfig,
+}
+
+impl RuntimeConfigBuilder {
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.config.num_threads = num_threads;
+        self
+    }
+
+    pub fn num_readers(mut self, num_readers: usize) -> Self {
+        self.config.num_readers = num_readers;
+        self
+    }
+
+    pub fn chunksize(mut self, chunksize: usize) -> Self {
+        self.config.chunksize = chunksize;
+        self
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = capacity;
+        self
+    }
+
+    pub fn output(mut self, output: io::gwas::OutputConfig) -> Self {
+        self.config.output = output;
+        self
+    }
+
+    pub fn stats(mut self, stats: StatsConfig) -> Self {
+        self.config.stats = stats;
+        self
+    }
+
+    pub fn build(self) -> Result<RuntimeConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Time spent reading each GWAS file, by path
+type ReadTimes = Vec<(String, Duration)>;
+
+/// Group the GWAS files by the file they are read from: each GWAS-VCF or
+/// wide file is read once for all of its phenotypes, and every other file
+/// on its own. Returns the indices of the GWAS files of each group, in order
+/// of first appearance.
+fn group_by_source(gwas_files: &[GwasFile]) -> Vec<Vec<usize>> {
+    let mut sources: Vec<Vec<usize>> = Vec::new();
+    let mut source_of_path: HashMap<&str, usize> = HashMap::new();
+    for (i, gwas_file) in gwas_files.iter().enumerate() {
+        if gwas_file.sample.is_none() {
+            sources.push(vec![i]);
+            continue;
+        }
+        match source_of_path.get(gwas_file.path.as_str()) {
+            Some(&k) => sources[k].push(i),
+            None => {
+                source_of_path.insert(&gwas_file.path, sources.len());
+                sources.push(vec![i]);
+            }
+        }
+    }
+    sources
+}
+
+/// Read one chunk of every GWAS file, using `num_readers` threads, each
+/// parsing the rows of a file with `parse_threads` threads. A file holding
+/// several phenotypes is parsed once for all of them (see
+/// `group_by_source`). `offsets` holds where the previous chunk stopped in
+/// each file, and is updated for the next chunk. Returns the time spent
+/// reading each file, by path.
+#[allow(clippy::too_many_arguments)]
 fn gwas_reader(
-    gwas_result_files: &[String],
+    gwas_files: &[GwasFile],
     column_names: io::gwas::ColumnSpec,
     start_line: usize,
     end_line: usize,
     num_lines: usize,
+    num_readers: usize,
+    alignment: Option<MissingVariantPolicy>,
+    mmap: bool,
+    parse_threads: usize,
+    offsets: &mut [Option<RowOffset>],
     output: Sender<(String, io::gwas::GwasResults)>,
-) -> Result<()> {
-    let n_files = gwas_result_files.len();
-    for (i, filename) in gwas_result_files.iter().enumerate() {
-        let phenotype_name = gwas_path_to_phenotype(filename);
+) -> Result<ReadTimes> {
+    let sources = group_by_source(gwas_files);
+    let n_sources = sources.len();
+    // Threads parsing the rows of each file, shared by the readers
+    let parse_pool = match parse_threads {
+        1 => None,
+        n => Some(
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(n)
+                .build()
+                .context("Failed to create the parsing thread pool")?,
+        ),
+    };
+    let offsets = Mutex::new(offsets);
+    let read_times = Mutex::new(
+        sources
+            .iter()
+            .map(|x| (gwas_files[x[0]].path.clone(), Duration::ZERO))
+            .collect::<Vec<_>>(),
+    );
+
+    let read_source = |k: usize,
+                       first_variant_ids: Option<&[String]>|
+     -> Result<Vec<(String, io::gwas::GwasResults)>> {
+        let started = Instant::now();
+        let files: Vec<&GwasFile> = sources[k].iter().map(|&i| &gwas_files[i]).collect();
+        let filename = &files[0].path;
+        let phenotype_names: Vec<String> = files.iter().map(|x| x.phenotype.clone()).collect();
         info!(
             "File {} of {}: Reading lines {} to {} of {} in {}. Interpreted phenotype name: {}",
-            i + 1,
-            n_files,
+            k + 1,
+            n_sources,
             start_line,
             end_line,
             num_lines,
             filename,
-            phenotype_name
+            phenotype_names.join(", ")
         );
 
-        let gwas_results =
-            io::gwas::read_gwas_results(filename, &column_names, start_line, end_line)
-                .with_context(|| format!("Error reading GWAS results from file: {}", &filename))
-                .unwrap();
+        // The samples of a GWAS-VCF, or column groups of a wide file, to read
+        let samples: Option<Vec<String>> = files.iter().map(|x| x.sample.clone()).collect();
+        let vcf_samples = column_names.vcf.as_ref().and(samples.as_deref());
+        let mut column_names = column_names.with_overrides(&files[0].columns);
+        if let (Some(wide), Some(samples)) = (&mut column_names.wide, &samples) {
+            wide.phenotypes = samples.clone();
+        }
+        let results = match (first_variant_ids, alignment, vcf_samples) {
+            (Some(variant_ids), Some(policy), _) => {
+                let results = match vcf_samples {
+                    Some(samples) => io::vcf::read_vcf_results_by_id(
+                        filename,
+                        &column_names,
+                        samples,
+                        variant_ids,
+                    ),
+                    None => io::gwas::read_phenotypes_by_id(filename, &column_names, variant_ids),
+                }
+                .with_context(|| format!("Error reading GWAS results from file: {}", &filename))?;
+                // The phenotypes of a file miss the same variants
+                let missing = results.first().map_or(&[][..], |x| &x.missing);
+                if let (Some(&i), MissingVariantPolicy::Error) = (missing.first(), policy) {
+                    bail!(
+                        "Variant {} from {} is missing from {} ({} missing in this chunk)",
+                        variant_ids[i],
+                        gwas_files[0].path,
+                        filename,
+                        missing.len()
+                    );
+                }
+                results
+            }
+            (_, _, Some(samples)) => {
+                io::vcf::read_vcf_results(filename, &column_names, samples, start_line, end_line)
+                    .with_context(|| {
+                        format!("Error reading GWAS results from file: {}", &filename)
+                    })?
+            }
+            _ => {
+                let i = sources[k][0];
+                let offset = offsets.lock().unwrap()[i];
+                let read = || match (mmap, parse_threads) {
+                    (true, n) => io::gwas::read_phenotypes_mmap(
+                        filename,
+                        &column_names,
+                        start_line,
+                        end_line,
+                        offset,
+                        n,
+                    ),
+                    (false, 1) => io::gwas::read_phenotypes_from(
+                        filename,
+                        &column_names,
+                        start_line,
+                        end_line,
+                        offset,
+                    ),
+                    (false, n) => io::gwas::read_phenotypes_parallel(
+                        filename,
+                        &column_names,
+                        start_line,
+                        end_line,
+                        offset,
+                        n,
+                    ),
+                };
+                let (results, offset) = match &parse_pool {
+                    Some(pool) => pool.install(read),
+                    None => read(),
+                }
+                .with_context(|| format!("Error reading GWAS results from file: {}", &filename))?;
+                offsets.lock().unwrap()[i] = offset;
+                results
+            }
+        };
+
+        for gwas_results in &results {
+            let invalid = &gwas_results.invalid;
+            if invalid.count > 0 {
+                warn!(
+                    "{} rows of lines {} to {} of {} could not be parsed and were handled as \
+                    missing values, e.g. {}",
+                    invalid.count,
+                    start_line,
+                    end_line,
+                    filename,
+                    invalid.examples.join("; ")
+                );
+            }
+        }
 
-        output.send((phenotype_name, gwas_results))?;
+        read_times.lock().unwrap()[k].1 = started.elapsed();
+        Ok(phenotype_names.into_iter().zip(results).collect())
+    };
+
+    // When aligning by id, the first file defines the variants of the chunk,
+    // so it is read before all others
+    let mut first_variant_ids = None;
+    let mut first_source = 0;
+    if alignment.is_some() {
+        let results = read_source(0, None)?;
+        first_variant_ids = results.first().map(|(_, x)| x.variant_ids.clone());
+        for results in results {
+            output.send(results)?;
+        }
+        first_source = 1;
     }
 
-    Ok(())
+    let next_source = AtomicUsize::new(first_source);
+    std::thread::scope(|scope| {
+        let readers: Vec<_> = (0..num_readers.max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    loop {
+                        let k = next_source.fetch_add(1, Ordering::Relaxed);
+                        if k >= n_sources {
+                            return Ok(());
+                        }
+                        match read_source(k, first_variant_ids.as_deref()) {
+                            Ok(results) => {
+                                for results in results {
+                                    output.send(results)?;
+                                }
+                            }
+                            Err(e) => {
+                                // Stop the other readers too
+                                next_source.store(n_sources, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        readers
+            .into_iter()
+            .try_for_each(|reader| reader.join().unwrap())
+    })?;
+    Ok(read_times.into_inner().unwrap())
 }
 
-pub struct ProcessingStats {
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "T: Float + serde::Serialize",
+        deserialize = "T: Float + serde::Deserialize<'de>"
+    ))
+)]
+pub struct ProcessingStats<T = f32> {
     pub n_variants: usize,
-    pub proj: DMatrix<f32>,
-    pub fpv: DVector<f32>,
-    pub phenotype_id_to_idx: HashMap<String, usize>,
-    pub n_covar: usize,
+    pub proj: Arc<Projection<T>>,
+    /// Magnitudes of the coefficients, if computing the heterogeneity
+    /// statistic
+    pub abs_proj: Option<Arc<Projection<T>>>,
+    /// Squared coefficients times the partial variances of the features, if
+    /// computing the effective sample size
+    pub variance_proj: Option<Arc<Projection<T>>>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::arc_dvector"))]
+    pub fpv: Arc<DVector<T>>,
+    pub phenotype_id_to_idx: Arc<HashMap<String, usize>>,
+    /// Number of covariates of each feature
+    pub n_covar: Arc<Vec<usize>>,
 }
 
-impl ProcessingStats {
+impl<T: Float> ProcessingStats<T> {
     pub fn format_update(
         &self,
         phenotype_id: &str,
         gwas_results: &GwasResults,
-    ) -> IntermediateResults {
+    ) -> IntermediateResults<T> {
         let phenotype_idx = self.phenotype_id_to_idx[phenotype_id];
 
-        let b = &gwas_results.beta_values;
-        let se = &gwas_results.se_values;
+        let b = gwas_results.beta_values.map(T::cast_f32);
+        let se = gwas_results.se_values.map(T::cast_f32);
         let ss = &gwas_results.sample_sizes;
-
-        let beta_update = b * self.proj.row(phenotype_idx);
+        let fpv = self.fpv[phenotype_idx];
+        let n_covar = self.n_covar[phenotype_idx] as i32;
 
         let mut gpv_update = DVector::zeros(self.n_variants);
-        for i in 0..self.n_variants {
-            gpv_update[i] = self.fpv[phenotype_idx]
-                / (se[i].powi(2) * (ss[i] - self.n_covar as i32 - 2) as f32 + b[i].powi(2));
+        let (beta, se) = match &gwas_results.genotype_variances {
+            None => {
+                for i in 0..self.n_variants {
+                    let dof = T::cast_f64((ss[i] - n_covar - 2) as f64);
+                    gpv_update[i] = fpv / (se[i].powi(2) * dof + b[i].powi(2));
+                }
+                (b, se)
+            }
+            Some(gv) => {
+                // With a known genotype variance v and z = beta / se, the
+                // standard error satisfies se^2 = (fpv / v - beta^2) / dof, so
+                // se = sqrt(fpv / (v * (dof + z^2))) and beta = z * se. The
+                // genotype partial variance is then v itself.
+                let gv = gv.map(T::cast_f32);
+                let mut beta = DVector::zeros(self.n_variants);
+                let mut beta_se = DVector::zeros(self.n_variants);
+                for i in 0..self.n_variants {
+                    let z = b[i] / se[i];
+                    let dof = T::cast_f64((ss[i] - n_covar - 2) as f64);
+                    beta_se[i] = (fpv / (gv[i] * (dof + z.powi(2)))).sqrt();
+                    beta[i] = z * beta_se[i];
+                    gpv_update[i] = gv[i];
+                }
+                (beta, beta_se)
+            }
+        };
+        let mut beta_update = self.proj.outer_row(&beta, phenotype_idx);
+        let mut heterogeneity = self.abs_proj.as_ref().map(|abs_proj| {
+            HeterogeneitySums::for_feature(&self.proj, abs_proj, phenotype_idx, &beta, &se, fpv)
+        });
+        let mut sample_size_sums = self
+            .variance_proj
+            .as_ref()
+            .map(|variance_proj| SampleSizeSums::for_feature(variance_proj, phenotype_idx, ss));
+
+        for &i in &gwas_results.zero_weight {
+            beta_update.row_mut(i).fill(T::zero());
+            gpv_update[i] = T::zero();
+            if let Some(sums) = &mut heterogeneity {
+                sums.clear_row(i);
+            }
+            if let Some(sums) = &mut sample_size_sums {
+                sums.clear_row(i);
+            }
         }
 
         IntermediateResults {
+            phenotype_id: phenotype_id.to_string(),
             beta_update,
             gpv_update,
             sample_sizes: gwas_results.sample_sizes.clone(),
             variant_ids: gwas_results.variant_ids.clone(),
+            missing: [gwas_results.missing.as_slice(), &gwas_results.skipped].concat(),
+            zero_weight: gwas_results.zero_weight.clone(),
+            annotations: gwas_results.annotations.clone(),
+            heterogeneity,
+            sample_size_sums,
         }
     }
 }
 
-fn process_chunk(
-    gwas_result_files: Vec<String>,
+#[allow(clippy::too_many_arguments)]
+fn process_chunk<T: Float>(
+    gwas_files: Vec<GwasFile>,
     column_names: io::gwas::ColumnSpec,
+    chunk_index: usize,
     start_line: usize,
     end_line: usize,
     num_lines: usize,
-    output_file: &str,
     runtime_config: &RuntimeConfig,
-    running: Arc<Mutex<RunningSufficientStats>>,
-) -> Result<()> {
-    let processing_stats = Arc::new(running.lock().unwrap().build_processing_stats());
+    runnings: &mut [RunningSufficientStats<T>],
+    offsets: &mut Vec<Option<RowOffset>>,
+    pool: &rayon::ThreadPool,
+) -> Result<Vec<IGwasResults>> {
+    let started = Instant::now();
+    let processing_stats: Arc<Vec<ProcessingStats<T>>> = Arc::new(
+        runnings
+            .iter()
+            .map(|running| running.build_processing_stats())
+            .collect(),
+    );
 
     let (raw_sender, raw_receiver) =
         crossbeam_channel::bounded::<(String, GwasResults)>(runtime_config.capacity);
+    // Updates are tagged with the index of their covariance matrix
     let (fmt_sender, fmt_receiver) =
-        crossbeam_channel::bounded::<IntermediateResults>(runtime_config.capacity);
-
-    let updater = std::thread::spawn({
-        let running = running.clone();
-        move || {
-            let mut running = running.lock().unwrap();
-            for intermediate_results in fmt_receiver.iter() {
-                running.update(&intermediate_results);
-            }
-        }
-    });
+        crossbeam_channel::bounded::<(usize, IntermediateResults<T>)>(runtime_config.capacity);
 
     let mut workers = Vec::new();
     for _ in 0..runtime_config.num_threads {
@@ -167,65 +899,155 @@ fn process_chunk(
         let sender = fmt_sender.clone();
         let processing_stats = processing_stats.clone();
         workers.push(std::thread::spawn(move || {
-            for (phenotype_name, gwas_results) in receiver.iter() {
-                let result = processing_stats.format_update(&phenotype_name, &gwas_results);
-                sender.send(result).unwrap();
+            for (phenotype_name, mut gwas_results) in receiver.iter() {
+                // Move the variant ids to the last update rather than copy
+                // them
+                let mut variant_ids = std::mem::take(&mut gwas_results.variant_ids);
+                for (k, stats) in processing_stats.iter().enumerate() {
+                    let mut result = stats.format_update(&phenotype_name, &gwas_results);
+                    result.variant_ids = match k + 1 == processing_stats.len() {
+                        true => std::mem::take(&mut variant_ids),
+                        false => variant_ids.clone(),
+                    };
+                    sender.send((k, result)).unwrap();
+                }
             }
         }));
     }
 
     let reader = std::thread::spawn({
-        let gwas_result_files = gwas_result_files.clone();
+        let gwas_files = gwas_files.clone();
         let column_names = column_names.clone();
-        let sender = raw_sender.clone();
-        move || {
-            gwas_reader(
-                &gwas_result_files,
+        let sender = raw_sender;
+        let num_readers = runtime_config.num_readers;
+        let alignment = runtime_config
+            .align_by_id
+            .then_some(runtime_config.missing_variants);
+        let mmap = runtime_config.mmap;
+        let parse_threads = runtime_config.parse_threads;
+        let mut offsets = std::mem::take(offsets);
+        move || -> Result<(Vec<Option<RowOffset>>, ReadTimes)> {
+            let read_times = gwas_reader(
+                &gwas_files,
                 column_names,
                 start_line,
                 end_line,
                 num_lines,
+                num_readers,
+                alignment,
+                mmap,
+                parse_threads,
+                &mut offsets,
                 sender,
-            )
+            )?;
+            Ok((offsets, read_times))
         }
     });
 
-    reader.join().unwrap()?;
-    drop(raw_sender);
+    drop(fmt_sender);
+
+    // Accumulate the updates on this thread as they arrive
+    let n_files = gwas_files.len();
+    let mut result = Ok(());
+    let mut files_processed = 0;
+    let mut rows = 0;
+    // Keep draining after an error so that the workers never block
+    for (k, intermediate_results) in fmt_receiver.iter() {
+        if result.is_ok() {
+            result = runnings[k].update(&intermediate_results);
+            // A file is done once its last update is accumulated
+            if k + 1 < runnings.len() {
+                continue;
+            }
+            files_processed += 1;
+            rows += intermediate_results.variant_ids.len();
+            if let (Ok(()), Some(progress)) = (&result, &runtime_config.progress) {
+                progress(ProgressEvent {
+                    stage: ProgressStage::File,
+                    variants_processed: start_line,
+                    total_variants: num_lines,
+                    files_processed,
+                    total_files: n_files,
+                });
+            }
+        }
+    }
+
+    let read_times;
+    (*offsets, read_times) = reader.join().unwrap()?;
     info!("Finished reading chunk, waiting for workers to finish");
 
     for worker in workers {
         worker.join().unwrap();
     }
-    drop(fmt_sender);
-
-    updater.join().unwrap();
+    result?;
+    report_timing(
+        runtime_config,
+        TimingEvent {
+            stage: TimingStage::Accumulate,
+            chunk_index,
+            start_line,
+            end_line,
+            rows,
+            wall_time: started.elapsed(),
+            file_read_times: read_times,
+        },
+    );
     info!("Finished reading chunk, computing statistics");
 
-    let final_stats = running.lock().unwrap().compute_final_stats();
-
-    info!("Writing results to file: {}", output_file);
-    let include_header = start_line == 0;
-    io::gwas::write_gwas_results(
-        final_stats,
-        output_file,
-        include_header,
-        runtime_config.compress,
-    )
-    .with_context(|| format!("Error writing GWAS results to file: {}", output_file))?;
+    let started = Instant::now();
+    let results = runnings
+        .iter_mut()
+        .map(|running| pool.install(|| running.compute_final_stats()))
+        .collect::<Result<Vec<_>>>()?;
+    report_timing(
+        runtime_config,
+        TimingEvent {
+            stage: TimingStage::FinalStats,
+            chunk_index,
+            start_line,
+            end_line,
+            rows: results.iter().map(|x| x.variant_ids.len()).sum(),
+            wall_time: started.elapsed(),
+            file_read_times: Vec::new(),
+        },
+    );
+    Ok(results)
+}
 
-    Ok(())
+/// The matrices and GWAS files of a run, loaded and checked against each other
+struct Inputs {
+    projection_matrix: io::matrix::LabeledMatrix,
+    /// The covariance matrices, each oriented to the projection matrix
+    cov_matrices: Vec<io::matrix::LabeledMatrix>,
+    /// The GWAS file of each phenotype, in the order of the matrices
+    gwas_files: Vec<GwasFile>,
+    /// Number of variants of the first GWAS file
+    num_lines: usize,
 }
 
-pub fn run(
+/// Load the matrices and check everything that can be checked without
+/// computing any statistics: the runtime config, the matrix labels, a GWAS
+/// file for every phenotype, the columns of the files, and their numbers of
+/// variants
+fn load_inputs(
     projection_matrix_path: &str,
-    covariance_matrix_path: &str,
+    covariance_matrix_paths: &[&str],
     gwas_result_files: &[String],
-    output_file: &str,
-    num_covar: usize,
-    runtime_config: RuntimeConfig,
-    column_names: io::gwas::ColumnSpec,
-) -> Result<()> {
+    runtime_config: &RuntimeConfig,
+    column_names: &io::gwas::ColumnSpec,
+) -> Result<Inputs> {
+    // A zero chunk size would never advance through the files, and zero
+    // workers or readers would never finish a chunk
+    runtime_config.validate()?;
+    if let Some(level) = runtime_config.stats.confidence_level {
+        ensure!(
+            level > 0.0 && level < 1.0,
+            "Confidence level must be between 0 and 1, got {}",
+            level
+        );
+    }
+
     let projection_matrix =
         io::matrix::read_labeled_matrix(projection_matrix_path).with_context(|| {
             format!(
@@ -234,54 +1056,755 @@ pub fn run(
             )
         })?;
 
-    let cov_matrix =
-        io::matrix::read_labeled_matrix(covariance_matrix_path).with_context(|| {
-            format!(
-                "Error reading covariance matrix: {}",
-                covariance_matrix_path
-            )
-        })?;
+    let cov_matrices = covariance_matrix_paths
+        .iter()
+        .map(|&path| {
+            io::matrix::read_labeled_matrix(path)
+                .and_then(|matrix| matrix.ensure_square().map(|_| matrix))
+                .with_context(|| format!("Error reading covariance matrix: {}", path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let cov_matrix = &cov_matrices[0];
 
     info!("Projection shape {:?}", projection_matrix.matrix.shape());
     info!("Covariance shape {:?}", cov_matrix.matrix.shape());
     info!("Covariance labels {:?}", cov_matrix.col_labels);
     info!("Projection labels {:?}", projection_matrix.row_labels);
 
-    let gwas_result_files = check_filter_inputs(
+    let phenotype_map = match &runtime_config.phenotype_map {
+        Some(filename) => read_phenotype_map(filename)?,
+        None => HashMap::new(),
+    };
+    let gwas_files = check_filter_inputs(
         &projection_matrix.row_labels,
         &cov_matrix.col_labels,
+        name_gwas_files(gwas_result_files, &phenotype_map, column_names)?,
+    )?;
+    // The statistics use the covariance matrices positionally, so take the
+    // submatrix of the projection's phenotypes, in the projection's order
+    let labels = &projection_matrix.row_labels;
+    let cov_matrices = cov_matrices
+        .iter()
+        .zip(covariance_matrix_paths)
+        .map(|(cov_matrix, path)| {
+            cov_matrix
+                .select(labels, labels)
+                .with_context(|| format!("Error reading covariance matrix: {}", path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for phenotype in runtime_config.num_covar_by_phenotype.keys() {
+        ensure!(
+            projection_matrix.row_labels.contains(phenotype),
+            "Cannot set the number of covariates of unknown phenotype {}",
+            phenotype
+        );
+    }
+    for projection_id in runtime_config.stats.projection_ids.iter().flatten() {
+        ensure!(
+            projection_matrix.col_labels.contains(projection_id),
+            "Cannot select unknown projection {}",
+            projection_id
+        );
+    }
+
+    // Catch mistyped column names before reading any data
+    ensure!(
+        column_names.vcf.is_none() || column_names.wide.is_none(),
+        "GWAS-VCF files cannot be read as wide files"
+    );
+    if column_names.vcf.is_some() {
+        io::vcf::validate_spec(column_names)?;
+    } else {
+        for gwas_file in &gwas_files {
+            io::gwas::validate_columns(&gwas_file.path, &gwas_file.column_names(column_names))?;
+        }
+    }
+
+    if let Some(region) = &column_names.region {
+        for gwas_file in &gwas_files {
+            ensure!(
+                !io::remote::is_remote(&gwas_file.path),
+                "Cannot read region {} of {}, which is not a local file",
+                region,
+                gwas_file.path
+            );
+        }
+    }
+    let num_lines = match (&column_names.vcf, &column_names.region) {
+        (None, None) => io::gwas::count_lines_with_progress(&gwas_files[0].path, &mut |lines| {
+            if let Some(progress) = &runtime_config.progress {
+                progress(ProgressEvent {
+                    stage: ProgressStage::Count,
+                    variants_processed: lines.saturating_sub(1),
+                    total_variants: 0,
+                    files_processed: 0,
+                    total_files: gwas_files.len(),
+                });
+            }
+        })?,
+        _ => io::gwas::count_variants(&gwas_files[0].path, column_names)?,
+    };
+    if !runtime_config.align_by_id {
+        // Files read by position must have the same variants, so catch
+        // truncated files before reading any chunk
+        let mut mismatched = Vec::new();
+        // A wide file or a multi-sample GWAS-VCF holds several phenotypes,
+        // so count each path once
+        let mut counted = HashSet::from([gwas_files[0].path.as_str()]);
+        for gwas_file in &gwas_files[1..] {
+            if !counted.insert(gwas_file.path.as_str()) {
+                continue;
+            }
+            let n = io::gwas::count_variants(&gwas_file.path, column_names)?;
+            if n != num_lines {
+                mismatched.push(format!("{} has {}", gwas_file.path, n));
+            }
+        }
+        ensure!(
+            mismatched.is_empty(),
+            "GWAS files have different numbers of variants: {} has {}, but {}",
+            gwas_files[0].path,
+            num_lines,
+            mismatched.join(", ")
+        );
+    }
+
+    Ok(Inputs {
+        projection_matrix,
+        cov_matrices,
+        gwas_files,
+        num_lines,
+    })
+}
+
+/// What `validate_inputs` found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputReport {
+    pub n_features: usize,
+    pub n_projections: usize,
+    /// Number of variants of the first GWAS file, which defines the output
+    /// variants
+    pub n_variants: usize,
+    /// The phenotype of each GWAS file used, and the file's path
+    pub gwas_files: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for InputReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Inputs are valid: {} phenotypes, {} projections, {} variants",
+            self.n_features, self.n_projections, self.n_variants
+        )?;
+        for (phenotype, path) in &self.gwas_files {
+            writeln!(f, "{}\t{}", phenotype, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the inputs of a run, as `run` does before computing anything, and
+/// report what was found. Nothing is computed or written.
+pub fn validate_inputs(
+    projection_matrix_path: &str,
+    covariance_matrix_path: &str,
+    gwas_result_files: &[String],
+    runtime_config: &RuntimeConfig,
+    column_names: &io::gwas::ColumnSpec,
+) -> Result<InputReport> {
+    let inputs = load_inputs(
+        projection_matrix_path,
+        &[covariance_matrix_path],
         gwas_result_files,
+        runtime_config,
+        &with_region(runtime_config, column_names.clone())?,
     )?;
+    Ok(InputReport {
+        n_features: inputs.projection_matrix.matrix.nrows(),
+        n_projections: match &runtime_config.stats.projection_ids {
+            Some(projection_ids) => projection_ids.len(),
+            None => inputs.projection_matrix.matrix.ncols(),
+        },
+        n_variants: inputs.num_lines,
+        gwas_files: inputs
+            .gwas_files
+            .into_iter()
+            .map(|x| (x.phenotype, x.path))
+            .collect(),
+    })
+}
 
-    let running = Arc::new(Mutex::new(RunningSufficientStats::new(
-        &projection_matrix,
-        &cov_matrix,
-        num_covar,
-        runtime_config.chunksize,
-    )));
+/// Compute the results chunk by chunk from variant `start_line` on, passing
+/// each chunk's results, one per covariance matrix, and the number of
+/// variants done to `on_chunk`. The GWAS files are read once for all the
+/// covariance matrices.
+#[allow(clippy::too_many_arguments)]
+fn run_chunks<F>(
+    projection_matrix_path: &str,
+    covariance_matrix_paths: &[&str],
+    gwas_result_files: &[String],
+    num_covar: usize,
+    mut runtime_config: RuntimeConfig,
+    column_names: io::gwas::ColumnSpec,
+    start_line: usize,
+    on_chunk: F,
+) -> Result<RunSummary>
+where
+    F: FnMut(Vec<IGwasResults>, usize) -> Result<()>,
+{
+    runtime_config.num_threads = runtime_config.resolved_num_threads();
+    info!("Using {} worker threads", runtime_config.num_threads);
 
-    let num_lines = io::gwas::count_lines(&gwas_result_files[0])?;
-    let mut start_line = 0;
-    let mut end_line = 0;
-    while start_line < num_lines {
-        end_line = cmp::min(num_lines, end_line + runtime_config.chunksize);
+    let column_names = with_region(&runtime_config, column_names)?;
+    let inputs = load_inputs(
+        projection_matrix_path,
+        covariance_matrix_paths,
+        gwas_result_files,
+        &runtime_config,
+        &column_names,
+    )?;
+
+    match runtime_config.stats.precision {
+        Precision::F32 => process_chunks::<f32, F>(
+            inputs,
+            num_covar,
+            &runtime_config,
+            column_names,
+            start_line,
+            on_chunk,
+        ),
+        Precision::F64 => process_chunks::<f64, F>(
+            inputs,
+            num_covar,
+            &runtime_config,
+            column_names,
+            start_line,
+            on_chunk,
+        ),
+    }
+}
+
+/// Compute the results chunk by chunk in precision `T`, with running
+/// statistics for each covariance matrix
+fn process_chunks<T: Float, F>(
+    inputs: Inputs,
+    num_covar: usize,
+    runtime_config: &RuntimeConfig,
+    column_names: io::gwas::ColumnSpec,
+    mut start_line: usize,
+    mut on_chunk: F,
+) -> Result<RunSummary>
+where
+    F: FnMut(Vec<IGwasResults>, usize) -> Result<()>,
+{
+    let Inputs {
+        projection_matrix,
+        cov_matrices,
+        gwas_files,
+        num_lines,
+    } = inputs;
+    let pool = runtime_config.thread_pool()?;
+    let mut runnings = Vec::with_capacity(cov_matrices.len());
+    for cov_matrix in &cov_matrices {
+        let mut running = pool.install(|| {
+            RunningSufficientStats::<T>::new(
+                &projection_matrix,
+                cov_matrix,
+                num_covar,
+                runtime_config.chunksize,
+                None,
+                runtime_config.stats.clone(),
+            )
+        })?;
+        for (phenotype, &n_covar) in &runtime_config.num_covar_by_phenotype {
+            running.set_num_covar(phenotype, n_covar)?;
+        }
+        running.set_assume_aligned(runtime_config.assume_aligned);
+        runnings.push(running);
+    }
+
+    let mut offsets = vec![None; gwas_files.len()];
+    if num_lines == 0 {
+        info!("GWAS files have no variants, writing only a header");
+    }
+    // The ends of the chromosomes still to process, when chunking by
+    // chromosome
+    let mut chromosome_ends = match runtime_config.chunk_by_chromosome {
+        true => {
+            let ends = io::gwas::chromosome_ends(
+                &gwas_files[0].path,
+                &gwas_files[0].column_names(&column_names),
+            )?;
+            info!("Processing {} chromosomes, one per chunk", ends.len());
+            Some(ends.into_iter().filter(move |&x| x > start_line))
+        }
+        false => None,
+    };
+    let mut end_line = start_line;
+    // Process at least one, possibly empty, chunk so that the output has a
+    // header even without variants
+    let mut first_chunk = start_line == 0;
+    let mut chunk_index = 0;
+    while start_line < num_lines || first_chunk {
+        first_chunk = false;
+        end_line = match &mut chromosome_ends {
+            Some(ends) => ends.next().unwrap_or(num_lines),
+            None => cmp::min(num_lines, end_line + runtime_config.chunksize),
+        };
 
         let new_chunksize = end_line - start_line;
-        running.lock().unwrap().clear_chunk(new_chunksize);
+        for running in &mut runnings {
+            running.clear_chunk(new_chunksize);
+        }
 
-        process_chunk(
-            gwas_result_files.clone(),
+        let results = process_chunk(
+            gwas_files.clone(),
             column_names.clone(),
+            chunk_index,
             start_line,
             end_line,
             num_lines,
-            output_file,
-            &runtime_config,
-            running.clone(),
+            runtime_config,
+            &mut runnings,
+            &mut offsets,
+            &pool,
         )?;
+        on_chunk(results, end_line)?;
+
+        if let Some(progress) = &runtime_config.progress {
+            progress(ProgressEvent {
+                stage: ProgressStage::Chunk,
+                variants_processed: end_line,
+                total_variants: num_lines,
+                files_processed: gwas_files.len(),
+                total_files: gwas_files.len(),
+            });
+        }
 
         start_line = end_line;
+        chunk_index += 1;
+    }
+
+    Ok(RunSummary {
+        phenotypes: gwas_files.iter().map(|x| x.phenotype.clone()).collect(),
+        phenotype_files: gwas_files.into_iter().map(|x| x.path).collect(),
+        num_variants: num_lines,
+        num_chunks: chunk_index,
+        num_projections: runnings[0].projection_ids().len(),
+    })
+}
+
+/// The column spec, reading only the region of the runtime config if given
+fn with_region(
+    runtime_config: &RuntimeConfig,
+    mut column_names: io::gwas::ColumnSpec,
+) -> Result<io::gwas::ColumnSpec> {
+    if let Some(region) = &runtime_config.region {
+        let region: io::tabix::Region = region.parse()?;
+        info!("Reading only the variants in region {}", region);
+        column_names.region = Some(region);
     }
+    Ok(column_names)
+}
 
-    Ok(())
+/// Compute the results in memory, without writing any files. Returns the
+/// results of each chunk of variants, in order. Summaries across chunks,
+/// like `stats::summary::TopHits`, can be built by adding each chunk.
+pub fn run_to_results(
+    projection_matrix_path: &str,
+    covariance_matrix_path: &str,
+    gwas_result_files: &[String],
+    num_covar: usize,
+    runtime_config: RuntimeConfig,
+    column_names: io::gwas::ColumnSpec,
+) -> Result<Vec<IGwasResults>> {
+    let mut all_results = Vec::new();
+    run_chunks(
+        projection_matrix_path,
+        &[covariance_matrix_path],
+        gwas_result_files,
+        num_covar,
+        runtime_config,
+        column_names,
+        0,
+        |results, _| {
+            all_results.extend(results);
+            Ok(())
+        },
+    )?;
+    Ok(all_results)
+}
+
+pub fn run(
+    projection_matrix_path: &str,
+    covariance_matrix_path: &str,
+    gwas_result_files: &[String],
+    output_file: &str,
+    num_covar: usize,
+    runtime_config: RuntimeConfig,
+    column_names: io::gwas::ColumnSpec,
+) -> Result<RunSummary> {
+    run_covariances(
+        projection_matrix_path,
+        &[(covariance_matrix_path, output_file)],
+        gwas_result_files,
+        num_covar,
+        runtime_config,
+        column_names,
+    )
+}
+
+/// Like `run`, for several covariance matrices at once, e.g. of different
+/// ancestry groups, writing the results of each to its own output file.
+/// `outputs` pairs each covariance matrix with its output. The GWAS files
+/// are read once for all of them, rather than once per matrix.
+///
+/// A checkpoint, lambda GC, and top hits each describe a single output, so
+/// they can only be used with one covariance matrix.
+pub fn run_covariances(
+    projection_matrix_path: &str,
+    outputs: &[(&str, &str)],
+    gwas_result_files: &[String],
+    num_covar: usize,
+    runtime_config: RuntimeConfig,
+    column_names: io::gwas::ColumnSpec,
+) -> Result<RunSummary> {
+    ensure!(!outputs.is_empty(), "No covariance matrix given");
+    for (i, &(_, output_file)) in outputs.iter().enumerate() {
+        ensure!(
+            !(runtime_config.output.compress && output_file.ends_with(".gz")),
+            "Output {} ends in .gz, for gzip, but --compress asks for zstd",
+            output_file
+        );
+        ensure!(
+            !(runtime_config.output.split_by_projection && output_file == io::gwas::STDOUT_PATH),
+            "Cannot write one file per projection to standard output"
+        );
+        ensure!(
+            !outputs[..i].iter().any(|&(_, other)| other == output_file),
+            "Output {} is given for more than one covariance matrix",
+            output_file
+        );
+    }
+    ensure!(
+        outputs.len() == 1
+            || (runtime_config.checkpoint.is_none()
+                && runtime_config.output.lambda_gc_file.is_none()
+                && runtime_config.output.top_hits_file.is_none()),
+        "Checkpoints, lambda GC, and top hits describe a single output, so cannot be used with \
+        more than one covariance matrix"
+    );
+    // The output of a checkpoint, which is then the only one
+    let output_file = outputs[0].1;
+
+    ensure!(
+        runtime_config.checkpoint.is_none()
+            || !(output_file == io::gwas::STDOUT_PATH
+                || runtime_config.output.split_by_projection
+                || runtime_config.output.sort_by_position),
+        "Checkpoints need results written to a single file chunk by chunk, so cannot be used \
+        with standard output, --split-by-projection, or --sort-by-position"
+    );
+
+    ensure!(
+        runtime_config.checkpoint.is_none()
+            || (runtime_config.output.lambda_gc_file.is_none()
+                && runtime_config.output.top_hits_file.is_none()),
+        "Lambda GC and top hits need the statistics of all chunks, so cannot be combined with \
+        checkpoints"
+    );
+
+    ensure!(
+        !(runtime_config.output.manifest
+            && outputs
+                .iter()
+                .any(|&(_, output)| output == io::gwas::STDOUT_PATH)),
+        "Cannot write a manifest next to standard output"
+    );
+    ensure!(
+        !runtime_config.output.manifest || cfg!(feature = "manifest"),
+        "Writing a manifest requires igwas to be built with the manifest feature"
+    );
+    let parquet = runtime_config.output.format == io::gwas::OutputFormat::Parquet;
+    ensure!(
+        !parquet || cfg!(feature = "parquet"),
+        "Parquet output requires igwas to be built with the parquet feature"
+    );
+    ensure!(
+        !parquet
+            || !(runtime_config.output.compress
+                || runtime_config.output.append
+                || runtime_config.output.split_by_projection
+                || runtime_config.checkpoint.is_some()
+                || outputs.iter().any(|&(_, output)| {
+                    output == io::gwas::STDOUT_PATH || output.ends_with(".gz")
+                })),
+        "Parquet output is compressed, and is only complete once the run finishes, so cannot be \
+        used with standard output, --compress, a .gz output, --append, --split-by-projection, \
+        or checkpoints"
+    );
+    let covariance_matrix_paths: Vec<&str> = outputs.iter().map(|&(cov, _)| cov).collect();
+    let chunksize = runtime_config.chunksize;
+    let num_threads = runtime_config.resolved_num_threads();
+    let write_manifests = |run_summary: &RunSummary| -> Result<()> {
+        for &(covariance_matrix_path, output_file) in outputs {
+            let manifest = io::manifest::Manifest {
+                igwas_version: env!("CARGO_PKG_VERSION").to_string(),
+                timestamp: std::time::SystemTime::now(),
+                projection_matrix: io::manifest::HashedFile {
+                    path: projection_matrix_path.to_string(),
+                    hash: io::checkpoint::hash_file(projection_matrix_path)?,
+                },
+                covariance_matrix: io::manifest::HashedFile {
+                    path: covariance_matrix_path.to_string(),
+                    hash: io::checkpoint::hash_file(covariance_matrix_path)?,
+                },
+                gwas_files: run_summary
+                    .phenotypes
+                    .iter()
+                    .zip(&run_summary.phenotype_files)
+                    .map(|(phenotype, path)| io::manifest::PhenotypeFile {
+                        phenotype: phenotype.clone(),
+                        path: path.clone(),
+                    })
+                    .collect(),
+                output: output_file.to_string(),
+                num_covar,
+                chunksize,
+                num_threads,
+                num_variants: run_summary.num_variants,
+                num_projections: run_summary.num_projections,
+            };
+            io::manifest::write_manifest(&manifest, &format!("{}.manifest.json", output_file))?;
+        }
+        Ok(())
+    };
+
+    let mut output_config = runtime_config.output.clone();
+    // A checkpoint must not count rows that are not yet on disk
+    output_config.sync |= runtime_config.checkpoint.is_some();
+    let mut summaries = RunSummaries::new(&output_config);
+    // The first chunk replaces any previous output, unless appending
+    let first_mode = match output_config.append {
+        true => WriteMode::Append,
+        false => WriteMode::Create,
+    };
+    // Each Parquet output stays open for the whole run, with a row group per
+    // chunk, since it cannot be reopened to append to
+    let mut parquet_writers = match parquet {
+        true => outputs
+            .iter()
+            .map(|&(_, output_file)| {
+                Ok((
+                    output_file,
+                    io::parquet::ParquetWriter::create(output_file, &output_config)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        false => Vec::new(),
+    };
+    let close_parquet_writers = |writers: Vec<(&str, io::parquet::ParquetWriter)>| {
+        writers.into_iter().try_for_each(|(output_file, writer)| {
+            writer
+                .close()
+                .with_context(|| format!("Error writing GWAS results to file: {}", output_file))
+        })
+    };
+    let mut write = |results: IGwasResults, output_file: &str, mode: WriteMode| -> Result<()> {
+        summaries.add(&results);
+        info!("Writing results to: {}", output_file);
+        if output_config.split_by_projection {
+            io::gwas::write_gwas_results_by_projection(results, output_file, mode, &output_config)
+        } else if let Some((_, writer)) = parquet_writers
+            .iter_mut()
+            .find(|(output, _)| *output == output_file)
+        {
+            writer
+                .write(&results)
+                .with_context(|| format!("Error writing GWAS results to file: {}", output_file))
+        } else {
+            io::gwas::write_gwas_results(results, output_file, mode, &output_config)
+                .with_context(|| format!("Error writing GWAS results to file: {}", output_file))
+        }
+    };
+
+    if output_config.sort_by_position {
+        // Rows of later chunks may sort before those already computed, so
+        // buffer everything and write once
+        let separator = column_names
+            .variant_id_columns
+            .as_ref()
+            .map_or(String::from(":"), |x| x.separator.clone());
+        let mut chunks: Vec<Vec<IGwasResults>> = outputs.iter().map(|_| Vec::new()).collect();
+        let run_summary = run_chunks(
+            projection_matrix_path,
+            &covariance_matrix_paths,
+            gwas_result_files,
+            num_covar,
+            runtime_config,
+            column_names,
+            0,
+            |results, _| {
+                for (chunks, results) in chunks.iter_mut().zip(results) {
+                    chunks.push(results);
+                }
+                Ok(())
+            },
+        )?;
+        for (chunks, &(_, output_file)) in chunks.into_iter().zip(outputs) {
+            let Some(results) = IGwasResults::concat(&chunks) else {
+                continue;
+            };
+            drop(chunks);
+            write(
+                results.sort_by_position(&separator)?,
+                output_file,
+                first_mode,
+            )?;
+        }
+        close_parquet_writers(parquet_writers)?;
+        summaries.write(&output_config)?;
+        if output_config.manifest {
+            write_manifests(&run_summary)?;
+        }
+        return Ok(run_summary);
+    }
+
+    let checkpoint_path = runtime_config.checkpoint.clone();
+    let mut start_line = 0;
+    let mut mode = first_mode;
+    let fingerprint = match &checkpoint_path {
+        None => None,
+        Some(checkpoint_path) => {
+            let mut input_files = vec![projection_matrix_path, covariance_matrix_paths[0]];
+            input_files.extend(gwas_result_files.iter().map(|x| x.as_str()));
+            let mut fingerprint =
+                io::checkpoint::fingerprint(output_file, runtime_config.chunksize, &input_files)?;
+            // Chunks of a region count its variants, not those of the files
+            if let Some(region) = &runtime_config.region {
+                fingerprint.push(format!("region\t{}", region));
+            }
+            if let Some(checkpoint) = io::checkpoint::read_checkpoint(checkpoint_path)? {
+                checkpoint.ensure_matches(&fingerprint, checkpoint_path)?;
+                // Drop any rows written by the interrupted chunk
+                let output = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(output_file)
+                    .with_context(|| {
+                        format!(
+                            "Cannot resume from checkpoint {}: failed to open output {}",
+                            checkpoint_path, output_file
+                        )
+                    })?;
+                let output_bytes = output.metadata()?.len();
+                ensure!(
+                    output_bytes >= checkpoint.output_bytes,
+                    "Cannot resume from checkpoint {}: output {} has {} bytes, fewer than the {} \
+                    of the chunks it records. Delete both to start over",
+                    checkpoint_path,
+                    output_file,
+                    output_bytes,
+                    checkpoint.output_bytes
+                );
+                output.set_len(checkpoint.output_bytes)?;
+                info!(
+                    "Resuming from checkpoint {} after {} variants",
+                    checkpoint_path, checkpoint.end_line
+                );
+                start_line = checkpoint.end_line;
+                mode = WriteMode::Append;
+            }
+            Some(fingerprint)
+        }
+    };
+
+    let run_summary = run_chunks(
+        projection_matrix_path,
+        &covariance_matrix_paths,
+        gwas_result_files,
+        num_covar,
+        runtime_config,
+        column_names,
+        start_line,
+        |results, end_line| {
+            for (results, &(_, output_file)) in results.into_iter().zip(outputs) {
+                write(results, output_file, mode)?;
+            }
+            mode = WriteMode::Append;
+            if let (Some(checkpoint_path), Some(fingerprint)) = (&checkpoint_path, &fingerprint) {
+                let checkpoint = io::checkpoint::Checkpoint {
+                    fingerprint: fingerprint.clone(),
+                    end_line,
+                    output_bytes: std::fs::metadata(output_file)?.len(),
+                };
+                io::checkpoint::write_checkpoint(&checkpoint, checkpoint_path)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    close_parquet_writers(parquet_writers)?;
+    // The run is complete, so there is nothing left to resume
+    if let Some(checkpoint_path) = &checkpoint_path {
+        std::fs::remove_file(checkpoint_path)?;
+    }
+    summaries.write(&output_config)?;
+    if output_config.manifest {
+        write_manifests(&run_summary)?;
+    }
+    Ok(run_summary)
+}
+
+/// Summaries of the results of all chunks, written next to the results
+struct RunSummaries {
+    inflation: Option<GenomicInflation>,
+    top_hits: Option<TopHits>,
+}
+
+impl RunSummaries {
+    fn new(config: &io::gwas::OutputConfig) -> Self {
+        RunSummaries {
+            inflation: config
+                .lambda_gc_file
+                .as_ref()
+                .map(|_| GenomicInflation::new()),
+            top_hits: config.top_hits_file.as_ref().map(|_| TopHits::new()),
+        }
+    }
+
+    fn add(&mut self, results: &IGwasResults) {
+        if let Some(inflation) = &mut self.inflation {
+            inflation.add(results);
+        }
+        if let Some(top_hits) = &mut self.top_hits {
+            top_hits.add(results);
+        }
+    }
+
+    fn write(&self, config: &io::gwas::OutputConfig) -> Result<()> {
+        if let (Some(inflation), Some(filename)) = (&self.inflation, &config.lambda_gc_file) {
+            let rows = inflation
+                .lambdas()
+                .into_iter()
+                .map(|(projection_id, lambda)| vec![projection_id, lambda.to_string()]);
+            io::gwas::write_summary(filename, &["projection_id", "lambda_gc"], rows)
+                .with_context(|| format!("Error writing lambda GC to file: {}", filename))?;
+        }
+        if let (Some(top_hits), Some(filename)) = (&self.top_hits, &config.top_hits_file) {
+            let rows = top_hits.hits().iter().map(|hit| {
+                vec![
+                    hit.projection_id.clone(),
+                    hit.variant_id.clone(),
+                    hit.neg_log_p.to_string(),
+                ]
+            });
+            io::gwas::write_summary(
+                filename,
+                &["projection_id", "variant_id", "neg_log_p"],
+                rows,
+            )
+            .with_context(|| format!("Error writing top hits to file: {}", filename))?;
+        }
+        Ok(())
+    }
 }
@@ -41,6 +41,7 @@ fn read_gwas_results() {
         beta: args.beta,
         se: args.std_error,
         sample_size: args.sample_size,
+        ..Default::default()
     };
 
     let result = igwas::io::gwas::read_gwas_results(&args.gwas_results[0], &colnames, 0, 100);
@@ -63,6 +64,7 @@ fn read_gwas_results_compressed() {
         beta: args.beta,
         se: args.std_error,
         sample_size: args.sample_size,
+        ..Default::default()
     };
 
     let result = igwas::io::gwas::read_gwas_results(&args.gwas_results[0], &colnames, 0, 100);
@@ -89,14 +91,15 @@ fn run_fn() {
         igwas::util::RuntimeConfig {
             num_threads: args.num_threads,
             chunksize: args.chunksize,
-            compress: false,
             capacity: args.num_threads,
+            ..Default::default()
         },
         igwas::io::gwas::ColumnSpec {
             variant_id: args.variant_id,
             beta: args.beta,
             se: args.std_error,
             sample_size: args.sample_size,
+            ..Default::default()
         },
     );
 
@@ -127,14 +130,15 @@ fn run_fn_compressed() {
         igwas::util::RuntimeConfig {
             num_threads: args.num_threads,
             chunksize: args.chunksize,
-            compress: false,
             capacity: args.num_threads,
+            ..Default::default()
         },
         igwas::io::gwas::ColumnSpec {
             variant_id: args.variant_id,
             beta: args.beta,
             se: args.std_error,
             sample_size: args.sample_size,
+            ..Default::default()
         },
     );
 
@@ -150,6 +154,155 @@ fn run_fn_compressed() {
     );
 }
 
+#[cfg(feature = "parquet")]
+#[test]
+fn run_fn_parquet_output() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 10, 2, 5, 3, false);
+
+    let run = |output_file: &std::path::Path, output: igwas::io::gwas::OutputConfig| {
+        igwas::util::run(
+            &args.projection_matrix,
+            &args.covariance_matrix,
+            &args.gwas_results,
+            output_file.to_str().unwrap(),
+            args.num_covar,
+            igwas::util::RuntimeConfig {
+                num_threads: args.num_threads,
+                chunksize: 4,
+                capacity: args.num_threads,
+                output,
+                ..Default::default()
+            },
+            igwas::io::gwas::ColumnSpec {
+                variant_id: args.variant_id.clone(),
+                beta: args.beta.clone(),
+                se: args.std_error.clone(),
+                sample_size: args.sample_size.clone(),
+                ..Default::default()
+            },
+        )
+    };
+    let tsv_file = path.join("igwas_results.tsv");
+    let parquet_file = path.join("igwas_results.parquet");
+    let output = igwas::io::gwas::OutputConfig {
+        raw_p_values: true,
+        ..Default::default()
+    };
+    run(&tsv_file, output.clone()).unwrap();
+    run(
+        &parquet_file,
+        igwas::io::gwas::OutputConfig {
+            format: igwas::io::gwas::OutputFormat::Parquet,
+            ..output.clone()
+        },
+    )
+    .unwrap();
+
+    // One row group per chunk of 4 variants, with the columns of the TSV
+    let reader = SerializedFileReader::try_from(parquet_file.as_path()).unwrap();
+    assert_eq!(reader.metadata().num_row_groups(), 3);
+    let names: Vec<String> = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|x| x.name().to_string())
+        .collect();
+    let mut tsv = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&tsv_file)
+        .unwrap();
+    let header = tsv.headers().unwrap().clone();
+    assert_eq!(names, header.iter().collect::<Vec<_>>());
+
+    let records: Vec<csv::StringRecord> = tsv.records().map(|x| x.unwrap()).collect();
+    let rows: Vec<parquet::record::Row> = reader
+        .get_row_iter(None)
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+    assert_eq!(rows.len(), records.len());
+    for (row, record) in rows.iter().zip(&records) {
+        assert_eq!(row.get_string(0).unwrap(), &record[0]);
+        assert_eq!(row.get_string(1).unwrap(), &record[1]);
+        // Runs may sum the files in another order, so floats can differ in
+        // their last bits
+        for i in 2..6 {
+            let expected: f32 = record[i].parse().unwrap();
+            let observed = row.get_float(i).unwrap();
+            assert!(
+                (observed - expected).abs() <= 1e-5 * expected.abs(),
+                "{} {}",
+                observed,
+                expected
+            );
+        }
+        assert_eq!(row.get_int(6).unwrap().to_string(), &record[6]);
+        let raw_p: f64 = record[7].parse().unwrap();
+        let parquet_raw_p = row.get_double(7).unwrap();
+        assert!(
+            (parquet_raw_p - raw_p).abs() <= 1e-5 * raw_p,
+            "{} {}",
+            parquet_raw_p,
+            raw_p
+        );
+    }
+
+    // The file is complete once written, so cannot be appended to
+    let err = run(
+        &parquet_file,
+        igwas::io::gwas::OutputConfig {
+            format: igwas::io::gwas::OutputFormat::Parquet,
+            append: true,
+            ..output
+        },
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("--append"));
+}
+
+#[cfg(not(feature = "parquet"))]
+#[test]
+fn parquet_output_needs_the_parquet_feature() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 10, 2, 5, 3, false);
+    let output = path.join("out.parquet");
+    let err = igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        output.to_str().unwrap(),
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: args.num_threads,
+            chunksize: args.chunksize,
+            capacity: args.num_threads,
+            output: igwas::io::gwas::OutputConfig {
+                format: igwas::io::gwas::OutputFormat::Parquet,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("parquet feature"));
+    assert!(!output.exists());
+}
+
 #[test]
 fn test_run_cli() {
     let dir = tempdir().unwrap();
@@ -263,3 +416,5901 @@ fn cli_full_compressed() {
         path.join("direct_results.csv").to_str().unwrap(),
     );
 }
+
+#[test]
+fn run_fn_gzip_matches_plaintext() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let gzip = |text: &[u8]| {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(text).unwrap();
+        encoder.finish().unwrap()
+    };
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    std::fs::write(&proj, "id,p1,p2\na.tsv.gz,0.5,1.0\nb.tsv.gz,-1.5,0.25\n").unwrap();
+    std::fs::write(
+        &cov,
+        "id,a.tsv.gz,b.tsv.gz\na.tsv.gz,1.0,0.3\nb.tsv.gz,0.3,2.0\n",
+    )
+    .unwrap();
+    let gzip_files: Vec<String> = files.iter().map(|x| format!("{}.gz", x)).collect();
+    std::fs::write(&gzip_files[0], gzip(SMALL_A.as_bytes())).unwrap();
+    // Several members, as written by bgzip, are read as one stream
+    let (head, tail) = SMALL_B.split_at(SMALL_B.find("rs3").unwrap());
+    let members = [gzip(head.as_bytes()), gzip(tail.as_bytes())].concat();
+    std::fs::write(&gzip_files[1], members).unwrap();
+
+    let colnames = Default::default();
+    for (plain, compressed) in files.iter().zip(&gzip_files) {
+        assert_eq!(
+            igwas::io::gwas::count_lines(plain).unwrap(),
+            igwas::io::gwas::count_lines(compressed).unwrap()
+        );
+        let expected = igwas::io::gwas::read_gwas_results(plain, &colnames, 1, 3).unwrap();
+        let observed = igwas::io::gwas::read_gwas_results(compressed, &colnames, 1, 3).unwrap();
+        assert_eq!(expected.variant_ids, observed.variant_ids);
+        assert_eq!(expected.beta_values, observed.beta_values);
+        assert_eq!(expected.se_values, observed.se_values);
+        assert_eq!(expected.sample_sizes, observed.sample_sizes);
+    }
+
+    // Chunks start and end within the decompressed stream
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+    let plain_dir = dir.path().join("plain");
+    std::fs::create_dir(&plain_dir).unwrap();
+    let expected = run_small_study(&plain_dir, [SMALL_A, SMALL_B], config()).unwrap();
+    let output = dir.path().join("out.tsv");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &gzip_files,
+        output.to_str().unwrap(),
+        2,
+        config(),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(read_output_records(output.to_str().unwrap()), expected);
+}
+
+#[test]
+fn read_gwas_results_zstd_matches_plaintext() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 1000, 10, 10, 10, false);
+
+    // No .zst extension, so compression must be detected from the magic bytes
+    let plain_path = &args.gwas_results[0];
+    let zstd_path = path.join("phenotype_0.tsv.compressed");
+    let plain = std::fs::read(plain_path).unwrap();
+    std::fs::write(&zstd_path, zstd::encode_all(plain.as_slice(), 0).unwrap()).unwrap();
+    let zstd_path = zstd_path.to_str().unwrap();
+
+    let colnames = igwas::io::gwas::ColumnSpec {
+        variant_id: args.variant_id,
+        beta: args.beta,
+        se: args.std_error,
+        sample_size: args.sample_size,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        igwas::io::gwas::count_lines(plain_path).unwrap(),
+        igwas::io::gwas::count_lines(zstd_path).unwrap()
+    );
+
+    let expected = igwas::io::gwas::read_gwas_results(plain_path, &colnames, 100, 300).unwrap();
+    let observed = igwas::io::gwas::read_gwas_results(zstd_path, &colnames, 100, 300).unwrap();
+    assert_eq!(expected.variant_ids, observed.variant_ids);
+    assert_eq!(expected.beta_values, observed.beta_values);
+    assert_eq!(expected.se_values, observed.se_values);
+    assert_eq!(expected.sample_sizes, observed.sample_sizes);
+}
+
+#[test]
+fn count_lines_cached() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("a.tsv");
+    std::fs::write(&path, SMALL_A).unwrap();
+    let filename = path.to_str().unwrap();
+
+    let mut reports = Vec::new();
+    let count = igwas::io::gwas::count_lines_with_progress(filename, &mut |x| reports.push(x));
+    assert_eq!(count.unwrap(), 4);
+    assert_eq!(reports, vec![5]);
+
+    // Another file of the same size and modification time is taken to be the
+    // same file, so the cached count is returned without scanning it
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+    std::fs::write(&path, SMALL_A.replacen('\n', " ", 1)).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    let mut reports = Vec::new();
+    let count = igwas::io::gwas::count_lines_with_progress(filename, &mut |x| reports.push(x));
+    assert_eq!(count.unwrap(), 4);
+    assert!(reports.is_empty());
+
+    // A changed file is scanned again
+    let lines: Vec<&str> = SMALL_A.lines().take(4).collect();
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    assert_eq!(igwas::io::gwas::count_lines(filename).unwrap(), 3);
+
+    // Compressed files are decoded to be counted, and cached all the same
+    let compressed = dir.path().join("a.tsv.zst");
+    std::fs::write(
+        &compressed,
+        zstd::encode_all(SMALL_A.as_bytes(), 0).unwrap(),
+    )
+    .unwrap();
+    let compressed = compressed.to_str().unwrap();
+    let mut reports = Vec::new();
+    let count = igwas::io::gwas::count_lines_with_progress(compressed, &mut |x| reports.push(x));
+    assert_eq!(count.unwrap(), 4);
+    assert_eq!(reports, vec![5]);
+    let mut reports = Vec::new();
+    let count = igwas::io::gwas::count_lines_with_progress(compressed, &mut |x| reports.push(x));
+    assert_eq!(count.unwrap(), 4);
+    assert!(reports.is_empty());
+}
+
+/// Write a GWAS results file given as tab-delimited text to a Parquet file
+/// with row groups of `rows_per_group` rows, with `NA` betas as nulls
+#[cfg(feature = "parquet")]
+fn write_parquet_gwas(path: &std::path::Path, text: &str, rows_per_group: usize) {
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, FloatType, Int64Type};
+    use parquet::file::writer::SerializedFileWriter;
+
+    let schema = parquet::schema::parser::parse_message_type(
+        "message gwas { REQUIRED BYTE_ARRAY ID (UTF8); OPTIONAL DOUBLE BETA; \
+        REQUIRED FLOAT SE; REQUIRED INT64 OBS_CT; }",
+    )
+    .unwrap();
+    let rows: Vec<Vec<&str>> = text
+        .lines()
+        .skip(1)
+        .map(|x| x.split('\t').collect())
+        .collect();
+    let file = std::fs::File::create(path).unwrap();
+    let mut writer =
+        SerializedFileWriter::new(file, std::sync::Arc::new(schema), Default::default()).unwrap();
+    for group in rows.chunks(rows_per_group) {
+        let column = |i: usize| group.iter().map(move |x| x[i]);
+        let mut row_group = writer.next_row_group().unwrap();
+
+        let mut ids = row_group.next_column().unwrap().unwrap();
+        let values: Vec<ByteArray> = column(0).map(ByteArray::from).collect();
+        ids.typed::<ByteArrayType>()
+            .write_batch(&values, None, None)
+            .unwrap();
+        ids.close().unwrap();
+
+        let mut betas = row_group.next_column().unwrap().unwrap();
+        let values: Vec<f64> = column(1)
+            .filter(|&x| x != "NA")
+            .map(|x| x.parse().unwrap())
+            .collect();
+        let def_levels: Vec<i16> = column(1).map(|x| (x != "NA") as i16).collect();
+        betas
+            .typed::<DoubleType>()
+            .write_batch(&values, Some(&def_levels), None)
+            .unwrap();
+        betas.close().unwrap();
+
+        let mut ses = row_group.next_column().unwrap().unwrap();
+        let values: Vec<f32> = column(2).map(|x| x.parse().unwrap()).collect();
+        ses.typed::<FloatType>()
+            .write_batch(&values, None, None)
+            .unwrap();
+        ses.close().unwrap();
+
+        let mut sample_sizes = row_group.next_column().unwrap().unwrap();
+        let values: Vec<i64> = column(3).map(|x| x.parse().unwrap()).collect();
+        sample_sizes
+            .typed::<Int64Type>()
+            .write_batch(&values, None, None)
+            .unwrap();
+        sample_sizes.close().unwrap();
+
+        row_group.close().unwrap();
+    }
+    writer.close().unwrap();
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn read_gwas_results_parquet_matches_tsv() {
+    let dir = tempdir().unwrap();
+    let tsv = dir.path().join("b.tsv");
+    std::fs::write(&tsv, SMALL_B_NA).unwrap();
+    let tsv = tsv.to_str().unwrap();
+    // Row groups of 3 rows, so that some ranges span both
+    let parquet = dir.path().join("b.parquet");
+    write_parquet_gwas(&parquet, SMALL_B_NA, 3);
+    let parquet = parquet.to_str().unwrap();
+
+    assert_eq!(igwas::io::gwas::count_lines(parquet).unwrap(), 4);
+    let column_names = igwas::io::gwas::ColumnSpec {
+        na_policy: igwas::io::gwas::NaPolicy::Skip,
+        ..Default::default()
+    };
+    igwas::io::gwas::validate_columns(parquet, &column_names).unwrap();
+    let assert_same = |expected: igwas::io::gwas::GwasResults,
+                       observed: igwas::io::gwas::GwasResults| {
+        assert_eq!(observed.variant_ids, expected.variant_ids);
+        assert_eq!(observed.beta_values, expected.beta_values);
+        assert_eq!(observed.se_values, expected.se_values);
+        assert_eq!(observed.sample_sizes, expected.sample_sizes);
+        assert_eq!(observed.skipped, expected.skipped);
+    };
+    for (start_line, end_line) in [(0, 4), (1, 3), (3, 4), (2, 10)] {
+        let read = |filename| {
+            igwas::io::gwas::read_gwas_results(filename, &column_names, start_line, end_line)
+                .unwrap()
+        };
+        assert_same(read(tsv), read(parquet));
+        let observed = igwas::io::parquet::read_gwas_results_parquet(
+            parquet,
+            &column_names,
+            start_line,
+            end_line,
+        )
+        .unwrap();
+        assert_same(read(tsv), observed);
+        let (observed, end) = igwas::io::gwas::read_gwas_results_mmap(
+            parquet,
+            &column_names,
+            start_line,
+            end_line,
+            None,
+            2,
+        )
+        .unwrap();
+        assert_same(read(tsv), observed);
+        assert_eq!(end, None);
+    }
+
+    let variant_ids = ["rs4".to_string(), "rs1".to_string(), "rs9".to_string()];
+    let read_by_id = |filename| {
+        igwas::io::gwas::read_gwas_results_by_id(filename, &column_names, &variant_ids).unwrap()
+    };
+    let expected = read_by_id(tsv);
+    let observed = read_by_id(parquet);
+    assert_eq!(observed.missing, expected.missing);
+    assert_same(expected, observed);
+
+    // A whole run in chunks of 2 rows, some of which span both row groups
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 2,
+        ..small_runtime_config()
+    };
+    let plain_dir = dir.path().join("plain");
+    std::fs::create_dir(&plain_dir).unwrap();
+    let expected = run_small_study(&plain_dir, [SMALL_A, SMALL_B], config()).unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    write_parquet_gwas(std::path::Path::new(&files[1]), SMALL_B, 3);
+    let output = dir.path().join("out.tsv");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        output.to_str().unwrap(),
+        2,
+        config(),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(read_output_records(output.to_str().unwrap()), expected);
+}
+
+#[cfg(not(feature = "parquet"))]
+#[test]
+fn read_gwas_results_parquet_needs_the_parquet_feature() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("phenotype.parquet");
+    std::fs::write(&path, b"PAR1\x15\x04\x15\x00PAR1").unwrap();
+    let filename = path.to_str().unwrap();
+
+    let colnames = igwas::io::gwas::ColumnSpec {
+        variant_id: "variant_id".to_string(),
+        beta: "beta".to_string(),
+        se: "std_error".to_string(),
+        sample_size: "sample_size".to_string(),
+        ..Default::default()
+    };
+
+    let err = igwas::io::gwas::read_gwas_results(filename, &colnames, 0, 100)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("parquet feature"), "{}", err);
+
+    let err = igwas::io::gwas::count_lines(filename).err().unwrap();
+    assert!(err.to_string().contains("parquet feature"), "{}", err);
+}
+
+fn write_npy_f8(path: &std::path::Path, matrix: &nalgebra::DMatrix<f32>) {
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        matrix.nrows(),
+        matrix.ncols()
+    );
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    for row in matrix.row_iter() {
+        for x in row.iter() {
+            bytes.extend((*x as f64).to_le_bytes());
+        }
+    }
+    std::fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn read_labeled_matrix_npy() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 10, 2, 5, 3, false);
+
+    let expected = igwas::io::matrix::read_labeled_matrix(&args.projection_matrix).unwrap();
+    write_npy_f8(&path.join("proj.npy"), &expected.matrix);
+    std::fs::write(path.join("proj.rows.txt"), expected.row_labels.join("\n")).unwrap();
+    std::fs::write(path.join("proj.cols.txt"), expected.col_labels.join("\n")).unwrap();
+
+    let observed =
+        igwas::io::matrix::read_labeled_matrix(path.join("proj.npy").to_str().unwrap()).unwrap();
+    assert_eq!(expected.row_labels, observed.row_labels);
+    assert_eq!(expected.col_labels, observed.col_labels);
+    assert_eq!(expected.matrix, observed.matrix);
+}
+
+#[test]
+fn read_labeled_matrix_npy_label_mismatch() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let matrix = nalgebra::DMatrix::<f32>::from_row_slice(2, 3, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    write_npy_f8(&path.join("m.npy"), &matrix);
+    std::fs::write(path.join("rows.txt"), "a\nb\n").unwrap();
+    std::fs::write(path.join("cols.txt"), "x\ny\n").unwrap();
+
+    let err = igwas::io::matrix::read_labeled_matrix_npy(
+        path.join("m.npy").to_str().unwrap(),
+        path.join("rows.txt").to_str().unwrap(),
+        path.join("cols.txt").to_str().unwrap(),
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("3 columns"), "{}", err);
+}
+
+/// Write a matrix given as comma-delimited text with a corner cell to a
+/// Parquet file, with the row labels in a first column named by the corner
+#[cfg(feature = "parquet")]
+fn write_parquet_matrix(path: &std::path::Path, text: &str) {
+    use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType};
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::types::Type;
+
+    let rows: Vec<Vec<&str>> = text.lines().map(|x| x.split(',').collect()).collect();
+    let fields = rows[0]
+        .iter()
+        .enumerate()
+        .map(|(j, &name)| {
+            let physical_type = match j {
+                0 => PhysicalType::BYTE_ARRAY,
+                _ => PhysicalType::DOUBLE,
+            };
+            let field = Type::primitive_type_builder(name, physical_type)
+                .with_repetition(Repetition::REQUIRED)
+                .with_logical_type((j == 0).then_some(LogicalType::String));
+            std::sync::Arc::new(field.build().unwrap())
+        })
+        .collect();
+    let schema = Type::group_type_builder("matrix")
+        .with_fields(fields)
+        .build()
+        .unwrap();
+    let file = std::fs::File::create(path).unwrap();
+    let mut writer =
+        SerializedFileWriter::new(file, std::sync::Arc::new(schema), Default::default()).unwrap();
+    let mut row_group = writer.next_row_group().unwrap();
+    for j in 0..rows[0].len() {
+        let values = rows[1..].iter().map(|x| x[j]);
+        let mut column = row_group.next_column().unwrap().unwrap();
+        match j {
+            0 => column.typed::<ByteArrayType>().write_batch(
+                &values.map(ByteArray::from).collect::<Vec<_>>(),
+                None,
+                None,
+            ),
+            _ => column.typed::<DoubleType>().write_batch(
+                &values.map(|x| x.parse().unwrap()).collect::<Vec<f64>>(),
+                None,
+                None,
+            ),
+        }
+        .unwrap();
+        column.close().unwrap();
+    }
+    row_group.close().unwrap();
+    writer.close().unwrap();
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn read_labeled_matrix_parquet_matches_csv() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let parquet_cov = dir.path().join("cov.parquet");
+    write_parquet_matrix(&parquet_cov, &std::fs::read_to_string(&cov).unwrap());
+    let parquet_cov = parquet_cov.to_str().unwrap();
+
+    let expected = igwas::io::matrix::read_labeled_matrix(&cov).unwrap();
+    let observed = igwas::io::matrix::read_labeled_matrix(parquet_cov).unwrap();
+    assert_eq!(observed.row_labels, expected.row_labels);
+    assert_eq!(observed.col_labels, expected.col_labels);
+    assert_eq!(observed.matrix, expected.matrix);
+    let summary = igwas::io::matrix::describe_labeled_matrix(parquet_cov).unwrap();
+    assert_eq!(summary.layout, igwas::io::matrix::MatrixLayout::Parquet);
+    assert!(summary.symmetric);
+
+    // Both matrices may be Parquet
+    let parquet_proj = dir.path().join("proj.parquet");
+    write_parquet_matrix(&parquet_proj, &std::fs::read_to_string(&proj).unwrap());
+    let run = |proj: &str, cov: &str, name: &str| {
+        let output = dir.path().join(name);
+        igwas::util::run(
+            proj,
+            cov,
+            &files,
+            output.to_str().unwrap(),
+            2,
+            small_runtime_config(),
+            Default::default(),
+        )
+        .unwrap();
+        read_output_records(output.to_str().unwrap())
+    };
+    assert_eq!(
+        run(parquet_proj.to_str().unwrap(), parquet_cov, "parquet.tsv"),
+        run(&proj, &cov, "csv.tsv")
+    );
+
+    // A covariance matrix must still be square
+    let non_square = dir.path().join("non_square.parquet");
+    write_parquet_matrix(&non_square, "id,a.tsv\na.tsv,1.0\nb.tsv,0.3\n");
+    let output = dir.path().join("out.tsv");
+    let err = igwas::util::run(
+        &proj,
+        non_square.to_str().unwrap(),
+        &files,
+        output.to_str().unwrap(),
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("not square: it has 2 rows and 1 columns"),
+        "{:#}",
+        err
+    );
+}
+
+#[cfg(not(feature = "parquet"))]
+#[test]
+fn read_labeled_matrix_parquet_needs_the_parquet_feature() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("covariance.parquet");
+    std::fs::write(&path, b"PAR1\x15\x04\x15\x00PAR1").unwrap();
+
+    let err = igwas::io::matrix::read_labeled_matrix(path.to_str().unwrap())
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("parquet feature"), "{}", err);
+}
+
+#[test]
+fn run_fn_covariance_labels_must_match() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    // Square, but with the columns in the other order
+    std::fs::write(&cov, "id,b.tsv,a.tsv\na.tsv,0.3,1.0\nb.tsv,2.0,0.3\n").unwrap();
+    let output = dir.path().join("out.tsv");
+    let err = igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        output.to_str().unwrap(),
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("row and column labels of the matrix differ"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn read_labeled_matrix_layouts() {
+    let dir = tempdir().unwrap();
+    let read = |name: &str, text: &str| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, text).unwrap();
+        igwas::io::matrix::read_labeled_matrix(path.to_str().unwrap())
+    };
+    let expected = nalgebra::DMatrix::<f32>::from_row_slice(2, 2, &[0.1, 0.2, 0.3, 0.4]);
+
+    // Corner cell, then the column labels
+    let corner = read("corner.csv", "_,p1,p2\nf1,0.1,0.2\nf2,0.3,0.4\n").unwrap();
+    assert_eq!(corner.row_labels, ["f1", "f2"]);
+    assert_eq!(corner.col_labels, ["p1", "p2"]);
+    assert_eq!(corner.matrix, expected);
+
+    // Column labels only
+    let header_only = read("header_only.tsv", "p1\tp2\nf1\t0.1\t0.2\nf2\t0.3\t0.4\n").unwrap();
+    assert_eq!(header_only.row_labels, ["f1", "f2"]);
+    assert_eq!(header_only.col_labels, ["p1", "p2"]);
+    assert_eq!(header_only.matrix, expected);
+
+    let err = read("no_rows.csv", "_,p1,p2\n").err().unwrap();
+    assert!(err.to_string().contains("ambiguous"), "{}", err);
+
+    let err = read("short.csv", "_,p1,p2,p3,p4\nf1,0.1,0.2\n")
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("neither"), "{}", err);
+
+    let err = read("ragged.csv", "_,p1,p2\nf1,0.1,0.2\nf2,0.3\n")
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("Row 3"), "{}", err);
+
+    let err = read("invalid.csv", "_,p1,p2\nf1,0.1,x\n").err().unwrap();
+    assert!(err.to_string().contains("Invalid value 'x'"), "{}", err);
+}
+
+#[test]
+fn read_labeled_matrix_compressed() {
+    let dir = tempdir().unwrap();
+    let text = "_,p1,p2\nf1,0.1,0.2\nf2,0.3,0.4\n";
+    let plain_path = dir.path().join("cov.csv");
+    std::fs::write(&plain_path, text).unwrap();
+    let plain = igwas::io::matrix::read_labeled_matrix(plain_path.to_str().unwrap()).unwrap();
+
+    // The delimiter still follows from the extension before .zst
+    let zstd_path = dir.path().join("cov.csv.zst");
+    std::fs::write(&zstd_path, zstd::encode_all(text.as_bytes(), 0).unwrap()).unwrap();
+    let compressed = igwas::io::matrix::read_labeled_matrix(zstd_path.to_str().unwrap()).unwrap();
+    assert_eq!(compressed.row_labels, plain.row_labels);
+    assert_eq!(compressed.col_labels, plain.col_labels);
+    assert_eq!(compressed.matrix, plain.matrix);
+
+    // Validation is unchanged
+    let ragged_path = dir.path().join("ragged.csv.zst");
+    let ragged = "_,p1,p2\nf1,0.1,0.2\nf2,0.3\n";
+    std::fs::write(
+        &ragged_path,
+        zstd::encode_all(ragged.as_bytes(), 0).unwrap(),
+    )
+    .unwrap();
+    let err = igwas::io::matrix::read_labeled_matrix(ragged_path.to_str().unwrap())
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("Row 3"), "{}", err);
+
+    // Likewise for gzip, detected from the extension or the magic bytes
+    std::fs::create_dir(dir.path().join("gzip")).unwrap();
+    for name in ["cov.csv.gz", "gzip/cov.csv"] {
+        let gzip_path = dir.path().join(name);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        std::io::Write::write_all(&mut encoder, text.as_bytes()).unwrap();
+        std::fs::write(&gzip_path, encoder.finish().unwrap()).unwrap();
+        let compressed =
+            igwas::io::matrix::read_labeled_matrix(gzip_path.to_str().unwrap()).unwrap();
+        assert_eq!(compressed.row_labels, plain.row_labels);
+        assert_eq!(compressed.col_labels, plain.col_labels);
+        assert_eq!(compressed.matrix, plain.matrix);
+    }
+}
+
+#[test]
+fn describe_labeled_matrix() {
+    use igwas::io::matrix::{describe_labeled_matrix, MatrixLayout};
+
+    let dir = tempdir().unwrap();
+    let (proj, cov, _) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+
+    let summary = describe_labeled_matrix(&cov).unwrap();
+    assert_eq!(summary.layout, MatrixLayout::CornerCell);
+    assert_eq!((summary.n_rows, summary.n_cols), (2, 2));
+    assert_eq!(summary.row_labels, ["a.tsv", "b.tsv"]);
+    assert_eq!(summary.col_labels, ["a.tsv", "b.tsv"]);
+    assert_eq!(summary.entries, [[1.0, 0.3], [0.3, 2.0]]);
+    assert!(summary.square && summary.symmetric);
+    let text = summary.to_string();
+    assert!(text.contains("Shape: 2 x 2\n"), "{}", text);
+    assert!(text.contains("Symmetric: yes\n"), "{}", text);
+
+    let summary = describe_labeled_matrix(&proj).unwrap();
+    assert_eq!(summary.col_labels, ["p1", "p2"]);
+    assert!(summary.square && !summary.symmetric);
+
+    // Only the first labels and entries are kept
+    let path = dir.path().join("wide.tsv");
+    let header: Vec<String> = (0..8).map(|j| format!("c{}", j)).collect();
+    let row: Vec<String> = (0..8).map(|j| j.to_string()).collect();
+    std::fs::write(
+        &path,
+        format!("{}\nr0\t{}\n", header.join("\t"), row.join("\t")),
+    )
+    .unwrap();
+    let summary = describe_labeled_matrix(path.to_str().unwrap()).unwrap();
+    assert_eq!(summary.layout, MatrixLayout::HeaderOnly);
+    assert_eq!((summary.n_rows, summary.n_cols), (1, 8));
+    assert_eq!(summary.col_labels, ["c0", "c1", "c2", "c3", "c4"]);
+    assert_eq!(summary.entries, [[0.0, 1.0, 2.0]]);
+    assert!(!summary.square && !summary.symmetric);
+    assert!(summary
+        .to_string()
+        .contains("Columns: c0, c1, c2, c3, c4, ...\n"));
+}
+
+fn write_small_gwas(path: &std::path::Path, delimiter: &str) {
+    let rows = [
+        ["variant_id", "beta", "std_error", "sample_size"],
+        ["rs1", "0.5", "0.1", "100"],
+        ["rs2", "-0.25", "0.2", "90"],
+    ];
+    let text: String = rows.iter().map(|x| x.join(delimiter) + "\n").collect();
+    std::fs::write(path, text).unwrap();
+}
+
+fn small_gwas_colnames() -> igwas::io::gwas::ColumnSpec {
+    igwas::io::gwas::ColumnSpec {
+        variant_id: "variant_id".to_string(),
+        beta: "beta".to_string(),
+        se: "std_error".to_string(),
+        sample_size: "sample_size".to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn read_gwas_results_detects_delimiter() {
+    let dir = tempdir().unwrap();
+    let colnames = small_gwas_colnames();
+
+    for delimiter in ["\t", ",", " "] {
+        let path = dir.path().join("phenotype.txt");
+        write_small_gwas(&path, delimiter);
+        let results =
+            igwas::io::gwas::read_gwas_results(path.to_str().unwrap(), &colnames, 0, 2).unwrap();
+        assert_eq!(results.variant_ids, vec!["rs1", "rs2"]);
+        assert_eq!(results.beta_values.as_slice(), &[0.5, -0.25]);
+        assert_eq!(results.se_values.as_slice(), &[0.1, 0.2]);
+        assert_eq!(results.sample_sizes.as_slice(), &[100, 90]);
+    }
+}
+
+#[test]
+fn read_gwas_results_delimiter_override() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("phenotype.txt");
+    write_small_gwas(&path, "|");
+
+    let colnames = small_gwas_colnames();
+    let err = igwas::io::gwas::read_gwas_results(path.to_str().unwrap(), &colnames, 0, 2)
+        .err()
+        .unwrap();
+    assert!(
+        format!("{:#}", err).contains("Could not detect the delimiter"),
+        "{:#}",
+        err
+    );
+
+    let colnames = igwas::io::gwas::ColumnSpec {
+        delimiter: Some(b'|'),
+        ..colnames
+    };
+    let results =
+        igwas::io::gwas::read_gwas_results(path.to_str().unwrap(), &colnames, 0, 2).unwrap();
+    assert_eq!(results.variant_ids, vec!["rs1", "rs2"]);
+}
+
+#[test]
+fn run_fn_comma_output() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 1000, 10, 10, 10, false);
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: args.num_threads,
+            chunksize: args.chunksize,
+            capacity: args.num_threads,
+            output: igwas::io::gwas::OutputConfig {
+                delimiter: b',',
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b',')
+        .from_path(&args.output_file)
+        .unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec![
+            "phenotype_id",
+            "variant_id",
+            "beta",
+            "std_error",
+            "t_stat",
+            "p_value",
+            "sample_size"
+        ]
+    );
+    assert!(reader.records().all(|x| x.unwrap().len() == 7));
+
+    utils::check_results(
+        path.join("igwas_results.csv").to_str().unwrap(),
+        path.join("direct_results.csv").to_str().unwrap(),
+    );
+}
+
+#[test]
+fn run_fn_gzip_output() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 10, 2, 5, 3, false);
+
+    let run = |output_file: &std::path::Path, compress: bool| {
+        igwas::util::run(
+            &args.projection_matrix,
+            &args.covariance_matrix,
+            &args.gwas_results,
+            output_file.to_str().unwrap(),
+            args.num_covar,
+            igwas::util::RuntimeConfig {
+                num_threads: args.num_threads,
+                chunksize: 4,
+                capacity: args.num_threads,
+                output: igwas::io::gwas::OutputConfig {
+                    compress,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            igwas::io::gwas::ColumnSpec {
+                variant_id: args.variant_id.clone(),
+                beta: args.beta.clone(),
+                se: args.std_error.clone(),
+                sample_size: args.sample_size.clone(),
+                ..Default::default()
+            },
+        )
+    };
+    let plain_file = path.join("igwas_results.tsv");
+    run(&plain_file, false).unwrap();
+    let gzip_file = path.join("igwas_results.tsv.gz");
+    run(&gzip_file, false).unwrap();
+
+    // Each chunk is its own gzip member, and together they decompress to the
+    // uncompressed output
+    let compressed = std::fs::read(&gzip_file).unwrap();
+    assert_eq!(compressed[..2], [0x1f, 0x8b]);
+    let mut decompressed = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::MultiGzDecoder::new(compressed.as_slice()),
+        &mut decompressed,
+    )
+    .unwrap();
+    assert_eq!(decompressed, std::fs::read_to_string(&plain_file).unwrap());
+    let mut first_member = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(compressed.as_slice()),
+        &mut first_member,
+    )
+    .unwrap();
+    assert!(first_member.len() < decompressed.len());
+
+    // zstd is asked for with --compress, so a .gz name contradicts it
+    let err = run(&path.join("both.tsv.gz"), true).err().unwrap();
+    assert!(err.to_string().contains("--compress"), "{}", err);
+    assert!(!path.join("both.tsv.gz").exists());
+}
+
+fn read_output_records(path: &str) -> Vec<csv::StringRecord> {
+    csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .unwrap()
+        .records()
+        .map(|x| x.unwrap())
+        .collect()
+}
+
+#[test]
+fn run_fn_z_scores_match_beta_se() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    std::fs::create_dir_all(path.join("beta")).unwrap();
+    std::fs::create_dir_all(path.join("z")).unwrap();
+
+    std::fs::write(
+        path.join("proj.csv"),
+        "id,p1,p2\na.tsv,0.5,1.0\nb.tsv,-1.5,0.25\n",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("cov.csv"),
+        "id,a.tsv,b.tsv\na.tsv,1.0,0.3\nb.tsv,0.3,2.0\n",
+    )
+    .unwrap();
+
+    let n = 1000;
+    let n_covar = 2;
+    let dof = (n - n_covar - 2) as f64;
+    let freqs = [0.1, 0.2, 0.3, 0.4, 0.5];
+    let phenotypes = [
+        ("a.tsv", 1.0, [1.0, -2.0, 3.5, 0.0, 5.0]),
+        ("b.tsv", 2.0, [-0.5, 4.0, 1.25, -3.0, 0.75]),
+    ];
+
+    let mut beta_files = Vec::new();
+    let mut z_files = Vec::new();
+    for (name, fpv, z_scores) in phenotypes {
+        let mut beta_text = String::from("ID\tBETA\tSE\tOBS_CT\n");
+        let mut z_text = String::from("ID\tZ\tAF\tOBS_CT\n");
+        for (i, (p, z)) in freqs.iter().zip(z_scores).enumerate() {
+            let v = 2.0 * p * (1.0 - p);
+            let se = (fpv / (v * (dof + z * z))).sqrt();
+            beta_text += &format!("rs{}\t{}\t{}\t{}\n", i, z * se, se, n);
+            z_text += &format!("rs{}\t{}\t{}\t{}\n", i, z, p, n);
+        }
+        let beta_path = path.join("beta").join(name);
+        let z_path = path.join("z").join(name);
+        std::fs::write(&beta_path, beta_text).unwrap();
+        std::fs::write(&z_path, z_text).unwrap();
+        beta_files.push(beta_path.to_str().unwrap().to_string());
+        z_files.push(z_path.to_str().unwrap().to_string());
+    }
+
+    let runtime_config = || igwas::util::RuntimeConfig {
+        num_threads: 1,
+        chunksize: 2,
+        capacity: 1,
+        ..Default::default()
+    };
+    let proj = path.join("proj.csv");
+    let cov = path.join("cov.csv");
+    let beta_output = path.join("beta_results.tsv");
+    let z_output = path.join("z_results.tsv");
+
+    igwas::util::run(
+        proj.to_str().unwrap(),
+        cov.to_str().unwrap(),
+        &beta_files,
+        beta_output.to_str().unwrap(),
+        n_covar,
+        runtime_config(),
+        Default::default(),
+    )
+    .unwrap();
+    igwas::util::run(
+        proj.to_str().unwrap(),
+        cov.to_str().unwrap(),
+        &z_files,
+        z_output.to_str().unwrap(),
+        n_covar,
+        runtime_config(),
+        igwas::io::gwas::ColumnSpec {
+            z_score: Some("Z".to_string()),
+            allele_freq: Some("AF".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let expected = read_output_records(beta_output.to_str().unwrap());
+    let observed = read_output_records(z_output.to_str().unwrap());
+    assert_eq!(expected.len(), 10);
+    assert_eq!(expected.len(), observed.len());
+    for (e, o) in expected.iter().zip(observed.iter()) {
+        assert_eq!(&e[0], &o[0]);
+        assert_eq!(&e[1], &o[1]);
+        for j in 2..6 {
+            let (e, o): (f32, f32) = (e[j].parse().unwrap(), o[j].parse().unwrap());
+            assert!((e - o).abs() <= 1e-4 * e.abs().max(1.0), "{} vs {}", e, o);
+        }
+    }
+}
+
+#[test]
+fn raw_pvalue_from_t_stat() {
+    use igwas::stats::sumstats::{compute_neg_log_pvalue, format_pvalue};
+    use statrs::distribution::{ContinuousCDF, StudentsT};
+
+    let dof = 1000;
+    let t_dist = StudentsT::new(0.0, 1.0, dof as f64).unwrap();
+    for t in [0.5_f32, 1.96, 5.0, 12.0] {
+        let expected = 2.0 * t_dist.cdf(-t as f64);
+        let observed: f64 = format_pvalue(compute_neg_log_pvalue(t, dof))
+            .parse()
+            .unwrap();
+        assert!(
+            ((observed - expected) / expected).abs() < 1e-5,
+            "t = {}: {} vs {}",
+            t,
+            observed,
+            expected
+        );
+    }
+
+    // Far below the smallest f32 (and f64) value
+    assert_eq!(format_pvalue(400.0), "1e-400");
+    assert_eq!(format_pvalue(400.5), "3.1622777e-401");
+    assert_eq!(format_pvalue(f32::INFINITY), "0");
+}
+
+#[test]
+fn log_tail_pvalue_reference() {
+    use igwas::stats::sumstats::{compute_neg_log_pvalue_with, PValueMethod};
+
+    // -log10 of I_x(dof / 2, 1 / 2), x = dof / (dof + t^2), from mpmath
+    let reference = [
+        (40.0, 1000, 208.979686287006),
+        (40.0, 100000, 346.382342562464),
+        (-45.0, 5000, 370.868480724505),
+        (10.0, 50, 12.7937859472599),
+        (2.0, 30, 1.26260819254477),
+    ];
+    for (t, dof, expected) in reference {
+        let observed: f64 = compute_neg_log_pvalue_with(t, dof, PValueMethod::LogTail);
+        assert!(
+            ((observed - expected) / expected).abs() < 1e-9,
+            "t = {}, dof = {}: expected {}, got {}",
+            t,
+            dof,
+            expected,
+            observed
+        );
+    }
+
+    // The CDF underflows where the log-space tail does not
+    let cdf: f64 = compute_neg_log_pvalue_with(40.0, 100000, PValueMethod::Cdf);
+    assert_eq!(cdf, f64::INFINITY);
+    let f32_tail: f32 = compute_neg_log_pvalue_with(40.0, 100000, PValueMethod::LogTail);
+    assert!((f32_tail - 346.38234).abs() < 1e-3);
+}
+
+#[test]
+fn normal_pvalue_continuity_and_tail() {
+    use igwas::stats::sumstats::{
+        compute_neg_log_pvalue_normal, compute_neg_log_pvalue_with, PValueMethod,
+    };
+
+    // Just below and above the default threshold of 10000 dof. The t tail
+    // is heavier, so the gap grows slowly with |t|
+    for t in [0.5, 1.0, 3.0, 5.0, 8.0] {
+        for method in [PValueMethod::Cdf, PValueMethod::LogTail] {
+            let below: f64 = compute_neg_log_pvalue_with(t, 10000, method);
+            let above: f64 = compute_neg_log_pvalue_normal(t, method);
+            assert!(
+                ((below - above) / below).abs() < 5e-3,
+                "t = {}: {} below the threshold, {} above",
+                t,
+                below,
+                above
+            );
+        }
+    }
+
+    // -log10 erfc(z / sqrt(2)), from mpmath
+    let reference = [
+        (1.0, 0.498515545827989),
+        (-3.0, 2.56866904026539),
+        (5.0, 6.24161567672667),
+        (8.0, 14.9051125553532),
+        (40.0, 349.135976463682),
+    ];
+    for (z, expected) in reference {
+        let observed: f64 = compute_neg_log_pvalue_normal(z, PValueMethod::LogTail);
+        assert!(
+            ((observed - expected) / expected).abs() < 1e-9,
+            "z = {}: expected {}, got {}",
+            z,
+            expected,
+            observed
+        );
+    }
+    let cdf: f64 = compute_neg_log_pvalue_normal(40.0, PValueMethod::Cdf);
+    assert_eq!(cdf, f64::INFINITY);
+}
+
+#[test]
+fn write_gwas_results_raw_pvalues() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("results.tsv");
+    let results = igwas::io::gwas::IGwasResults {
+        projection_ids: vec!["p1".to_string(), "p1".to_string()],
+        variant_ids: vec!["rs1".to_string(), "rs2".to_string()],
+        beta_values: nalgebra::DVector::from_vec(vec![0.1, 0.2]),
+        se_values: nalgebra::DVector::from_vec(vec![0.01, 0.02]),
+        t_stat_values: nalgebra::DVector::from_vec(vec![10.0, 10.0]),
+        p_values: nalgebra::DVector::from_vec(vec![2.0, 50.0]),
+        sample_sizes: nalgebra::DVector::from_vec(vec![100, 100]),
+        ci_lower: None,
+        ci_upper: None,
+        annotations: Default::default(),
+        heterogeneity: None,
+        effective_sample_size: None,
+    };
+    let config = igwas::io::gwas::OutputConfig {
+        raw_p_values: true,
+        ..Default::default()
+    };
+    igwas::io::gwas::write_gwas_results(
+        results,
+        path.to_str().unwrap(),
+        igwas::io::gwas::WriteMode::Create,
+        &config,
+    )
+    .unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&path)
+        .unwrap();
+    assert_eq!(reader.headers().unwrap().get(7), Some("raw_p_value"));
+    let raw: Vec<String> = reader
+        .records()
+        .map(|x| x.unwrap()[7].to_string())
+        .collect();
+    assert_eq!(raw, vec!["0.01", "1e-50"]);
+}
+
+#[test]
+fn write_gwas_results_min_neg_log_p() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("results.tsv");
+    let results = igwas::io::gwas::IGwasResults {
+        projection_ids: vec!["p1".to_string(), "p1".to_string(), "p2".to_string()],
+        variant_ids: vec!["rs1".to_string(), "rs2".to_string(), "rs1".to_string()],
+        beta_values: nalgebra::DVector::from_vec(vec![0.1, 0.2, 0.3]),
+        se_values: nalgebra::DVector::from_vec(vec![0.01, 0.02, 0.03]),
+        t_stat_values: nalgebra::DVector::from_vec(vec![10.0, 10.0, 10.0]),
+        p_values: nalgebra::DVector::from_vec(vec![2.0, 8.0, f32::NAN]),
+        sample_sizes: nalgebra::DVector::from_vec(vec![100, 100, 100]),
+        ci_lower: None,
+        ci_upper: None,
+        annotations: Default::default(),
+        heterogeneity: None,
+        effective_sample_size: None,
+    };
+    let config = igwas::io::gwas::OutputConfig {
+        min_neg_log_p: Some(7.3),
+        ..Default::default()
+    };
+    igwas::io::gwas::write_gwas_results(
+        results,
+        path.to_str().unwrap(),
+        igwas::io::gwas::WriteMode::Create,
+        &config,
+    )
+    .unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&path)
+        .unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec![
+            "phenotype_id",
+            "variant_id",
+            "beta",
+            "std_error",
+            "t_stat",
+            "p_value",
+            "sample_size"
+        ]
+    );
+    let rows: Vec<(String, String)> = reader
+        .records()
+        .map(|x| x.unwrap())
+        .map(|x| (x[0].to_string(), x[1].to_string()))
+        .collect();
+    assert_eq!(rows, vec![("p1".to_string(), "rs2".to_string())]);
+}
+
+#[test]
+fn t_critical_value_reference() {
+    use igwas::stats::sumstats::compute_t_critical_value;
+
+    // Reference quantiles of the t-distribution
+    assert!((compute_t_critical_value(0.95, 10) - 2.228_139).abs() < 1e-5);
+    assert!((compute_t_critical_value(0.99, 10) - 3.169_273).abs() < 1e-5);
+    assert!((compute_t_critical_value(0.95, 1_000_000) - 1.959_966).abs() < 1e-5);
+    assert!(compute_t_critical_value(0.95, 0).is_nan());
+    assert!(compute_t_critical_value(0.95, -3).is_nan());
+}
+
+#[test]
+fn run_fn_confidence_intervals() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 100, 10, 10, 3, false);
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: args.num_threads,
+            chunksize: 30,
+            capacity: args.num_threads,
+            stats: igwas::stats::running::StatsConfig {
+                confidence_level: Some(0.95),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&args.output_file)
+        .unwrap();
+    assert_eq!(reader.headers().unwrap().get(7), Some("ci_lower"));
+    assert_eq!(reader.headers().unwrap().get(8), Some("ci_upper"));
+
+    // 100 samples and 10 covariates give 88 degrees of freedom
+    let t_975_88 = 1.987_29;
+    let mut n_rows = 0;
+    for record in reader.records() {
+        let record = record.unwrap();
+        let field = |i: usize| record[i].parse::<f32>().unwrap();
+        let (beta, se, lower, upper) = (field(2), field(3), field(7), field(8));
+        assert!(((upper + lower) / 2.0 - beta).abs() < 1e-4);
+        assert!(((upper - lower) / (2.0 * se) - t_975_88).abs() < 1e-3);
+        n_rows += 1;
+    }
+    assert_eq!(n_rows, 300);
+}
+
+#[test]
+fn run_fn_confidence_intervals_zero_dof() {
+    let dir = tempdir().unwrap();
+    let config = igwas::util::RuntimeConfig {
+        stats: igwas::stats::running::StatsConfig {
+            confidence_level: Some(0.95),
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    // 4 samples less 2 covariates and the 2 fitted terms leave rs2 no
+    // degrees of freedom
+    let a = SMALL_A.replace("rs2\t-0.2\t0.1\t100", "rs2\t-0.2\t0.1\t4");
+    let b = SMALL_B.replace("rs2\t0.1\t0.15\t90", "rs2\t0.1\t0.15\t4");
+    let records = run_small_study(dir.path(), [&a, &b], config).unwrap();
+
+    assert_eq!(records.len(), 8);
+    for record in &records {
+        let field = |i: usize| record[i].parse::<f32>().unwrap();
+        let (lower, upper) = (field(7), field(8));
+        match &record[1] {
+            "rs2" => assert!(lower.is_nan() && upper.is_nan(), "{:?}", record),
+            _ => assert!(lower.is_finite() && upper.is_finite(), "{:?}", record),
+        }
+    }
+}
+
+#[test]
+fn run_to_results_matches_run() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 100, 10, 10, 3, false);
+
+    let runtime_config = || igwas::util::RuntimeConfig {
+        num_threads: args.num_threads,
+        chunksize: 30,
+        capacity: args.num_threads,
+        ..Default::default()
+    };
+    let column_names = || igwas::io::gwas::ColumnSpec {
+        variant_id: args.variant_id.clone(),
+        beta: args.beta.clone(),
+        se: args.std_error.clone(),
+        sample_size: args.sample_size.clone(),
+        ..Default::default()
+    };
+
+    let chunks = igwas::util::run_to_results(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        args.num_covar,
+        runtime_config(),
+        column_names(),
+    )
+    .unwrap();
+    assert_eq!(chunks.len(), 4);
+    assert!(!std::path::Path::new(&args.output_file).exists());
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        runtime_config(),
+        column_names(),
+    )
+    .unwrap();
+
+    let records = read_output_records(&args.output_file);
+    let mut i = 0;
+    for chunk in &chunks {
+        for j in 0..chunk.variant_ids.len() {
+            let record = &records[i];
+            assert_eq!(&record[0], chunk.projection_ids[j]);
+            assert_eq!(&record[1], chunk.variant_ids[j]);
+            // Workers finish in any order, so sums may differ in the last bits
+            assert!((record[2].parse::<f32>().unwrap() - chunk.beta_values[j]).abs() < 1e-5);
+            assert!((record[3].parse::<f32>().unwrap() - chunk.se_values[j]).abs() < 1e-5);
+            assert_eq!(record[6].parse::<i32>().unwrap(), chunk.sample_sizes[j]);
+            i += 1;
+        }
+    }
+    assert_eq!(i, records.len());
+    assert_eq!(i, 300);
+}
+
+#[test]
+fn run_fn_multi_chunk_row_count() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (n_variants, n_projections) = (95, 4);
+    let args = utils::setup_test(path, 100, n_variants, 10, 10, n_projections, false);
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: args.num_threads,
+            chunksize: 20,
+            capacity: args.num_threads,
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let records = read_output_records(&args.output_file);
+    assert_eq!(records.len(), n_projections * n_variants);
+
+    let mut seen = std::collections::HashSet::new();
+    for record in &records {
+        assert!(seen.insert((record[0].to_string(), record[1].to_string())));
+    }
+}
+
+#[test]
+fn run_fn_corrupt_file_error() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 50, 2, 5, 3, false);
+
+    // Corrupt a beta value in the middle of one file
+    let corrupt_path = &args.gwas_results[2];
+    let text = std::fs::read_to_string(corrupt_path).unwrap();
+    let mut lines: Vec<String> = text.lines().map(|x| x.to_string()).collect();
+    let mut fields: Vec<&str> = lines[20].split('\t').collect();
+    fields[2] = "not_a_number";
+    lines[20] = fields.join("\t");
+    std::fs::write(corrupt_path, lines.join("\n") + "\n").unwrap();
+
+    let err = igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: args.num_threads,
+            chunksize: args.chunksize,
+            capacity: args.num_threads,
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .err()
+    .unwrap();
+
+    let message = format!("{:#}", err);
+    assert!(
+        err.to_string().contains(corrupt_path.as_str()),
+        "{}",
+        message
+    );
+    assert!(message.contains("not_a_number"), "{}", message);
+}
+
+/// Write a two-feature, two-projection study with the given GWAS file contents
+fn write_small_study(dir: &std::path::Path, files: [&str; 2]) -> (String, String, Vec<String>) {
+    let proj = dir.join("proj.csv");
+    let cov = dir.join("cov.csv");
+    std::fs::write(&proj, "id,p1,p2\na.tsv,0.5,1.0\nb.tsv,-1.5,0.25\n").unwrap();
+    std::fs::write(&cov, "id,a.tsv,b.tsv\na.tsv,1.0,0.3\nb.tsv,0.3,2.0\n").unwrap();
+
+    let mut gwas_paths = Vec::new();
+    for (name, text) in ["a.tsv", "b.tsv"].into_iter().zip(files) {
+        let gwas_path = dir.join(name);
+        std::fs::write(&gwas_path, text).unwrap();
+        gwas_paths.push(gwas_path.to_str().unwrap().to_string());
+    }
+    (
+        proj.to_str().unwrap().to_string(),
+        cov.to_str().unwrap().to_string(),
+        gwas_paths,
+    )
+}
+
+fn small_runtime_config() -> igwas::util::RuntimeConfig {
+    igwas::util::RuntimeConfig {
+        num_threads: 1,
+        chunksize: 10,
+        capacity: 1,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn run_fn_misaligned_variants_error() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj, cov, files) = write_small_study(
+        path,
+        [
+            "ID\tBETA\tSE\tOBS_CT\nrs1\t0.1\t0.1\t100\nrs2\t0.2\t0.1\t100\nrs3\t0.3\t0.1\t100\n",
+            "ID\tBETA\tSE\tOBS_CT\nrs1\t0.1\t0.1\t100\nrs3\t0.3\t0.1\t100\nrs2\t0.2\t0.1\t100\n",
+        ],
+    );
+
+    let err = igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        path.join("out.tsv").to_str().unwrap(),
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .err()
+    .unwrap()
+    .to_string();
+
+    assert!(err.contains("Mismatched variant ids"), "{}", err);
+    assert!(err.contains("at index 1"), "{}", err);
+    assert!(err.contains("a.tsv") || err.contains("b.tsv"), "{}", err);
+    assert!(
+        err.contains("expected rs2 but found rs3") || err.contains("expected rs3 but found rs2"),
+        "{}",
+        err
+    );
+}
+
+const SMALL_A: &str = "ID\tBETA\tSE\tOBS_CT\n\
+    rs1\t0.1\t0.1\t100\nrs2\t-0.2\t0.1\t100\nrs3\t0.3\t0.2\t100\nrs4\t0.05\t0.1\t100\n";
+const SMALL_B: &str = "ID\tBETA\tSE\tOBS_CT\n\
+    rs1\t0.2\t0.1\t90\nrs2\t0.1\t0.15\t90\nrs3\t-0.3\t0.1\t90\nrs4\t0.0\t0.1\t90\n";
+
+fn run_small_study(
+    path: &std::path::Path,
+    files: [&str; 2],
+    config: igwas::util::RuntimeConfig,
+) -> anyhow::Result<Vec<csv::StringRecord>> {
+    run_small_study_with(path, files, config, Default::default())
+}
+
+fn run_small_study_with(
+    path: &std::path::Path,
+    files: [&str; 2],
+    config: igwas::util::RuntimeConfig,
+    column_names: igwas::io::gwas::ColumnSpec,
+) -> anyhow::Result<Vec<csv::StringRecord>> {
+    let (proj, cov, gwas_files) = write_small_study(path, files);
+    let output = path.join("out.tsv");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &gwas_files,
+        output.to_str().unwrap(),
+        2,
+        config,
+        column_names,
+    )?;
+    Ok(read_output_records(output.to_str().unwrap()))
+}
+
+#[test]
+fn run_fn_chi_square() {
+    let dir = tempdir().unwrap();
+    let config = igwas::util::RuntimeConfig {
+        output: igwas::io::gwas::OutputConfig {
+            chi_square: true,
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(dir.path().join("out.tsv"))
+        .unwrap();
+    assert_eq!(reader.headers().unwrap().get(7), Some("chi_square"));
+    let records: Vec<csv::StringRecord> = reader.records().map(|x| x.unwrap()).collect();
+    assert_eq!(records.len(), 8);
+    for record in records {
+        let t_stat: f32 = record[4].parse().unwrap();
+        let chi_square: f32 = record[7].parse().unwrap();
+        assert_eq!(chi_square, t_stat.powi(2));
+    }
+}
+
+#[test]
+fn run_fn_align_by_id_shuffled() {
+    let dir = tempdir().unwrap();
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        align_by_id: true,
+        ..small_runtime_config()
+    };
+
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config()).unwrap();
+
+    // Same rows in a different order, plus a variant absent from the first file
+    let shuffled_b = "ID\tBETA\tSE\tOBS_CT\n\
+        rs4\t0.0\t0.1\t90\nrs9\t1.0\t0.1\t90\nrs2\t0.1\t0.15\t90\nrs1\t0.2\t0.1\t90\nrs3\t-0.3\t0.1\t90\n";
+    let observed = run_small_study(dir.path(), [SMALL_A, shuffled_b], config()).unwrap();
+
+    assert_eq!(expected.len(), 8);
+    assert_eq!(expected, observed);
+}
+
+#[test]
+fn run_fn_align_by_id_missing_variants() {
+    let dir = tempdir().unwrap();
+    let missing_b = "ID\tBETA\tSE\tOBS_CT\n\
+        rs4\t0.0\t0.1\t90\nrs1\t0.2\t0.1\t90\nrs3\t-0.3\t0.1\t90\n";
+
+    let err = run_small_study(
+        dir.path(),
+        [SMALL_A, missing_b],
+        igwas::util::RuntimeConfig {
+            align_by_id: true,
+            ..small_runtime_config()
+        },
+    )
+    .err()
+    .unwrap()
+    .to_string();
+    assert!(err.contains("Variant rs2"), "{}", err);
+    assert!(err.contains("b.tsv"), "{}", err);
+
+    let expected = run_small_study(
+        dir.path(),
+        [SMALL_A, SMALL_B],
+        igwas::util::RuntimeConfig {
+            align_by_id: true,
+            ..small_runtime_config()
+        },
+    )
+    .unwrap();
+    let observed = run_small_study(
+        dir.path(),
+        [SMALL_A, missing_b],
+        igwas::util::RuntimeConfig {
+            align_by_id: true,
+            missing_variants: igwas::util::MissingVariantPolicy::Skip,
+            ..small_runtime_config()
+        },
+    )
+    .unwrap();
+
+    let expected: Vec<_> = expected.into_iter().filter(|x| &x[1] != "rs2").collect();
+    assert_eq!(expected.len(), 6);
+    assert_eq!(expected, observed);
+}
+
+#[test]
+fn read_gwas_results_from_offsets() {
+    let dir = tempdir().unwrap();
+    let args = utils::setup_test(dir.path(), 100, 1000, 10, 1, 1, false);
+    let colnames = igwas::io::gwas::ColumnSpec {
+        variant_id: args.variant_id,
+        beta: args.beta,
+        se: args.std_error,
+        sample_size: args.sample_size,
+        ..Default::default()
+    };
+
+    // CRLF line endings use two bytes per terminator
+    let crlf_path = dir.path().join("crlf.tsv");
+    let text = std::fs::read_to_string(&args.gwas_results[0]).unwrap();
+    std::fs::write(&crlf_path, text.replace('\n', "\r\n")).unwrap();
+
+    for path in [args.gwas_results[0].as_str(), crlf_path.to_str().unwrap()] {
+        let mut offset = None;
+        for start in (0..1000).step_by(300) {
+            let end = std::cmp::min(start + 300, 1000);
+            let expected = igwas::io::gwas::read_gwas_results(path, &colnames, start, end).unwrap();
+            let (observed, next) =
+                igwas::io::gwas::read_gwas_results_from(path, &colnames, start, end, offset)
+                    .unwrap();
+            assert_eq!(expected.variant_ids, observed.variant_ids);
+            assert_eq!(expected.beta_values, observed.beta_values);
+            assert_eq!(expected.se_values, observed.se_values);
+            assert_eq!(expected.sample_sizes, observed.sample_sizes);
+            assert_eq!(next.unwrap().line, end);
+            offset = next;
+        }
+    }
+}
+
+#[test]
+fn read_gwas_results_from_compressed_has_no_offset() {
+    let dir = tempdir().unwrap();
+    let args = utils::setup_test(dir.path(), 100, 1000, 10, 1, 1, true);
+    let colnames = igwas::io::gwas::ColumnSpec {
+        variant_id: args.variant_id,
+        beta: args.beta,
+        se: args.std_error,
+        sample_size: args.sample_size,
+        ..Default::default()
+    };
+    let path = &args.gwas_results[0];
+
+    let expected = igwas::io::gwas::read_gwas_results(path, &colnames, 300, 600).unwrap();
+    let (observed, next) =
+        igwas::io::gwas::read_gwas_results_from(path, &colnames, 300, 600, None).unwrap();
+    assert_eq!(expected.variant_ids, observed.variant_ids);
+    assert!(next.is_none());
+}
+
+#[test]
+fn run_fn_capacity_one() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 250, 10, 20, 5, false);
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: 4,
+            chunksize: 250,
+            capacity: 1,
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    utils::check_results(
+        path.join("igwas_results.csv").to_str().unwrap(),
+        path.join("direct_results.csv").to_str().unwrap(),
+    );
+}
+
+#[test]
+fn run_fn_parallel_readers() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 250, 10, 20, 5, false);
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: 2,
+            num_readers: 4,
+            chunksize: 250,
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    utils::check_results(
+        path.join("igwas_results.csv").to_str().unwrap(),
+        path.join("direct_results.csv").to_str().unwrap(),
+    );
+}
+
+#[test]
+fn run_fn_parallel_readers_align_by_id() {
+    let dir = tempdir().unwrap();
+    let shuffled_b = "ID\tBETA\tSE\tOBS_CT\n\
+        rs3\t-0.3\t0.1\t90\nrs1\t0.2\t0.1\t90\nrs4\t0.0\t0.1\t90\nrs2\t0.1\t0.15\t90\n";
+
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    let observed = run_small_study(
+        dir.path(),
+        [SMALL_A, shuffled_b],
+        igwas::util::RuntimeConfig {
+            num_readers: 2,
+            align_by_id: true,
+            ..small_runtime_config()
+        },
+    )
+    .unwrap();
+    assert_eq!(expected, observed);
+}
+
+/// Accumulate one large and many small updates of beta, which f32 rounds
+/// badly, and return the final beta estimate
+fn accumulate_small_updates<T: igwas::stats::sumstats::Float>(
+    config: igwas::stats::running::StatsConfig,
+) -> f32 {
+    let n_features = 2000;
+    let labels: Vec<String> = (0..n_features).map(|i| format!("y{}", i)).collect();
+    let proj = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p".to_string()],
+        matrix: nalgebra::DMatrix::from_element(n_features, 1, 1000.0),
+    };
+    let cov = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: labels.clone(),
+        matrix: nalgebra::DMatrix::identity(n_features, n_features),
+    };
+
+    let mut running =
+        igwas::stats::running::RunningSufficientStats::<T>::new(&proj, &cov, 0, 1, None, config)
+            .unwrap();
+    for (i, label) in labels.iter().enumerate() {
+        let beta = if i == 0 { 1000.0 } else { 0.001 };
+        running
+            .update(&igwas::io::gwas::IntermediateResults {
+                phenotype_id: label.clone(),
+                variant_ids: vec!["rs1".to_string()],
+                beta_update: nalgebra::DMatrix::from_element(1, 1, T::cast_f64(beta)),
+                gpv_update: nalgebra::DVector::from_element(1, T::cast_f64(1.0)),
+                sample_sizes: nalgebra::DVector::from_element(1, 1000),
+                missing: Vec::new(),
+                zero_weight: Vec::new(),
+                annotations: Default::default(),
+                heterogeneity: None,
+                sample_size_sums: None,
+            })
+            .unwrap();
+    }
+    running.compute_final_stats().unwrap().beta_values[0]
+}
+
+#[test]
+fn f64_precision_reduces_drift() {
+    let expected = 1000.0 + 1999.0 * 0.001;
+    let error_f32 = (accumulate_small_updates::<f32>(Default::default()) as f64 - expected).abs();
+    let error_f64 = (accumulate_small_updates::<f64>(Default::default()) as f64 - expected).abs();
+
+    assert!(error_f32 > 1e-2, "f32 error {}", error_f32);
+    // Only the final conversion to f32 remains
+    assert!(error_f64 < 1e-4, "f64 error {}", error_f64);
+}
+
+#[test]
+fn run_fn_f64_precision() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 250, 10, 20, 5, false);
+
+    igwas::util::run(
+        &args.projection_matrix,
+        &args.covariance_matrix,
+        &args.gwas_results,
+        &args.output_file,
+        args.num_covar,
+        igwas::util::RuntimeConfig {
+            num_threads: 2,
+            chunksize: 250,
+            stats: igwas::stats::running::StatsConfig {
+                precision: igwas::stats::running::Precision::F64,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        igwas::io::gwas::ColumnSpec {
+            variant_id: args.variant_id,
+            beta: args.beta,
+            se: args.std_error,
+            sample_size: args.sample_size,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    utils::check_results(
+        path.join("igwas_results.csv").to_str().unwrap(),
+        path.join("direct_results.csv").to_str().unwrap(),
+    );
+}
+
+#[test]
+fn compensated_summation_reduces_drift() {
+    let expected = 1000.0 + 1999.0 * 0.001;
+    let naive = accumulate_small_updates::<f32>(Default::default());
+    let compensated = accumulate_small_updates::<f32>(igwas::stats::running::StatsConfig {
+        compensated_summation: true,
+        ..Default::default()
+    });
+    let reference = accumulate_small_updates::<f64>(Default::default());
+
+    let error_naive = (naive as f64 - reference as f64).abs();
+    let error_compensated = (compensated as f64 - reference as f64).abs();
+    assert!(
+        error_compensated * 100.0 < error_naive,
+        "naive error {}, compensated error {}",
+        error_naive,
+        error_compensated
+    );
+    assert!((compensated as f64 - expected).abs() < 1e-4);
+}
+
+#[test]
+fn run_fn_compensated_summation() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 250, 10, 20, 5, false);
+
+    // Several chunks, so that the compensation is reset between chunks
+    let run = |compensated_summation: bool| {
+        igwas::util::run_to_results(
+            &args.projection_matrix,
+            &args.covariance_matrix,
+            &args.gwas_results,
+            args.num_covar,
+            igwas::util::RuntimeConfig {
+                chunksize: 100,
+                stats: igwas::stats::running::StatsConfig {
+                    compensated_summation,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            igwas::io::gwas::ColumnSpec {
+                variant_id: args.variant_id.clone(),
+                beta: args.beta.clone(),
+                se: args.std_error.clone(),
+                sample_size: args.sample_size.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let naive = run(false);
+    let compensated = run(true);
+    assert_eq!(naive.len(), 3);
+    for (a, b) in naive.iter().zip(&compensated) {
+        assert_eq!(a.variant_ids, b.variant_ids);
+        for (x, y) in a.beta_values.iter().zip(b.beta_values.iter()) {
+            assert!((x - y).abs() < 1e-5, "{} vs {}", x, y);
+        }
+        for (x, y) in a.se_values.iter().zip(b.se_values.iter()) {
+            assert!((x - y).abs() < 1e-5, "{} vs {}", x, y);
+        }
+    }
+}
+
+#[test]
+fn run_fn_progress_events() {
+    use igwas::util::{ProgressEvent, ProgressStage};
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir().unwrap();
+    let events: Arc<Mutex<Vec<ProgressEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 3,
+        progress: Some(Box::new({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        })),
+        ..small_runtime_config()
+    };
+    run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+
+    let events = events.lock().unwrap();
+    let file_event = |variants_processed, files_processed| ProgressEvent {
+        stage: ProgressStage::File,
+        variants_processed,
+        total_variants: 4,
+        files_processed,
+        total_files: 2,
+    };
+    let chunk_event = |variants_processed| ProgressEvent {
+        stage: ProgressStage::Chunk,
+        variants_processed,
+        total_variants: 4,
+        files_processed: 2,
+        total_files: 2,
+    };
+    let count_event = ProgressEvent {
+        stage: ProgressStage::Count,
+        variants_processed: 4,
+        total_variants: 0,
+        files_processed: 0,
+        total_files: 2,
+    };
+    assert_eq!(
+        *events,
+        vec![
+            count_event,
+            file_event(0, 1),
+            file_event(0, 2),
+            chunk_event(3),
+            file_event(3, 1),
+            file_event(3, 2),
+            chunk_event(4),
+        ]
+    );
+}
+
+#[test]
+fn validate_columns_lists_missing_columns() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("gwas.csv");
+    write_small_gwas(&path, ",");
+    let path = path.to_str().unwrap();
+
+    igwas::io::gwas::validate_columns(path, &small_gwas_colnames()).unwrap();
+
+    let colnames = igwas::io::gwas::ColumnSpec {
+        beta: "BETA".to_string(),
+        se: "SE".to_string(),
+        ..small_gwas_colnames()
+    };
+    let err = igwas::io::gwas::validate_columns(path, &colnames)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains(r#"Missing columns ["BETA", "SE"]"#), "{}", err);
+    assert!(err.contains("gwas.csv"), "{}", err);
+    assert!(
+        err.contains(r#"["variant_id", "beta", "std_error", "sample_size"]"#),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_missing_column_error() {
+    let dir = tempdir().unwrap();
+    // The second file lacks the sample size column, and has unparsable data
+    // that would only be reached without the preflight check
+    let text_b = "ID\tBETA\tSE\tN\nrs1\tx\t0.1\t90\n";
+    let err = run_small_study(dir.path(), [SMALL_A, text_b], small_runtime_config())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains(r#"Missing columns ["OBS_CT"]"#), "{}", err);
+    assert!(err.contains("b.tsv"), "{}", err);
+}
+
+#[test]
+fn run_fn_phenotype_map() {
+    let dir = tempdir().unwrap();
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+
+    // Two files with the same name, in different directories
+    let (proj, cov, _) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let mut gwas_files = Vec::new();
+    for (subdir, text) in ["x", "y"].into_iter().zip([SMALL_A, SMALL_B]) {
+        std::fs::create_dir(dir.path().join(subdir)).unwrap();
+        let path = dir.path().join(subdir).join("gwas.tsv");
+        std::fs::write(&path, text).unwrap();
+        gwas_files.push(path.to_str().unwrap().to_string());
+    }
+    let output = dir.path().join("mapped.tsv");
+    let run = |phenotype_map: Option<String>| {
+        igwas::util::run(
+            &proj,
+            &cov,
+            &gwas_files,
+            output.to_str().unwrap(),
+            2,
+            igwas::util::RuntimeConfig {
+                phenotype_map,
+                ..small_runtime_config()
+            },
+            Default::default(),
+        )
+    };
+
+    let err = run(None).unwrap_err().to_string();
+    assert!(err.contains("Multiple GWAS files"), "{}", err);
+
+    let map_path = dir.path().join("phenotypes.tsv");
+    std::fs::write(
+        &map_path,
+        format!("{}\ta.tsv\n{}\tb.tsv\n", gwas_files[0], gwas_files[1]),
+    )
+    .unwrap();
+    run(Some(map_path.to_str().unwrap().to_string())).unwrap();
+    assert_eq!(expected, read_output_records(output.to_str().unwrap()));
+}
+
+#[test]
+fn run_fn_phenotype_map_column_names() {
+    let dir = tempdir().unwrap();
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+
+    // The second file, from another provider, names its columns differently
+    let renamed = SMALL_B.replacen("ID\tBETA\tSE\tOBS_CT", "rsid\tb\tSE\tN", 1);
+    let (proj, cov, gwas_files) = write_small_study(dir.path(), [SMALL_A, &renamed]);
+    let output = dir.path().join("mapped.tsv");
+    let map_path = dir.path().join("phenotypes.tsv");
+    let run = |map: &str| {
+        std::fs::write(&map_path, map).unwrap();
+        igwas::util::run(
+            &proj,
+            &cov,
+            &gwas_files,
+            output.to_str().unwrap(),
+            2,
+            igwas::util::RuntimeConfig {
+                phenotype_map: Some(map_path.to_str().unwrap().to_string()),
+                ..small_runtime_config()
+            },
+            Default::default(),
+        )
+    };
+
+    let err = format!("{:#}", run("").unwrap_err());
+    assert!(err.contains("rsid"), "{}", err);
+
+    run(&format!(
+        "{}\tb.tsv\tvariant-id=rsid\tbeta=b\tsample-size=N\n",
+        gwas_files[1]
+    ))
+    .unwrap();
+    assert_eq!(expected, read_output_records(output.to_str().unwrap()));
+
+    let err = format!(
+        "{:#}",
+        run(&format!("{}\tb.tsv\tid=rsid\n", gwas_files[1])).unwrap_err()
+    );
+    assert!(err.contains("Unknown column id"), "{}", err);
+}
+
+#[test]
+fn run_fn_duplicate_phenotype_error() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, mut gwas_files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    std::fs::create_dir(dir.path().join("other")).unwrap();
+    let duplicate = dir.path().join("other").join("a.tsv");
+    std::fs::write(&duplicate, SMALL_A).unwrap();
+    gwas_files.push(duplicate.to_str().unwrap().to_string());
+
+    let err = igwas::util::run(
+        &proj,
+        &cov,
+        &gwas_files,
+        dir.path().join("out.tsv").to_str().unwrap(),
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(
+        err.contains(&format!(
+            "Multiple GWAS files provided for phenotype a.tsv: {} and {}",
+            gwas_files[0], gwas_files[2]
+        )),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_unmatched_phenotypes_error() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, gwas_files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let run = |gwas_files: &[String]| {
+        igwas::util::run(
+            &proj,
+            &cov,
+            gwas_files,
+            dir.path().join("out.tsv").to_str().unwrap(),
+            2,
+            small_runtime_config(),
+            Default::default(),
+        )
+        .unwrap_err()
+        .to_string()
+    };
+
+    // Only missing files: list every missing phenotype
+    let err = run(&[]);
+    assert!(
+        err.contains(r#"No GWAS result file provided for phenotypes ["a.tsv", "b.tsv"]"#),
+        "{}",
+        err
+    );
+
+    // A missing phenotype and a file matching no phenotype are reported together
+    let renamed = dir.path().join("b_results.tsv");
+    std::fs::rename(&gwas_files[1], &renamed).unwrap();
+    let renamed = renamed.to_str().unwrap().to_string();
+    let err = run(&[gwas_files[0].clone(), renamed.clone()]);
+    assert!(
+        err.contains(&format!(
+            r#"No GWAS result file provided for phenotypes ["b.tsv"], while files ["{}"] match no phenotype"#,
+            renamed
+        )),
+        "{}",
+        err
+    );
+}
+
+const SMALL_B_NA: &str = "ID\tBETA\tSE\tOBS_CT\n\
+    rs1\t0.2\t0.1\t90\nrs2\tNA\t0.15\t90\nrs3\t-0.3\t0.1\t90\nrs4\t0.0\t0.1\t90\n";
+
+fn run_small_study_na(
+    path: &std::path::Path,
+    na_policy: igwas::io::gwas::NaPolicy,
+    config: igwas::util::RuntimeConfig,
+) -> anyhow::Result<Vec<csv::StringRecord>> {
+    run_small_study_with(
+        path,
+        [SMALL_A, SMALL_B_NA],
+        config,
+        igwas::io::gwas::ColumnSpec {
+            na_policy,
+            ..Default::default()
+        },
+    )
+}
+
+#[test]
+fn run_fn_na_policy_error() {
+    let dir = tempdir().unwrap();
+    let err = run_small_study_na(
+        dir.path(),
+        igwas::io::gwas::NaPolicy::Error,
+        small_runtime_config(),
+    )
+    .unwrap_err();
+    let message = format!("{:#}", err);
+    assert!(message.contains("b.tsv"), "{}", message);
+    assert!(
+        message.contains("Missing value for variant rs2 on line 3"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn run_fn_na_policy_skip() {
+    let dir = tempdir().unwrap();
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    let expected: Vec<_> = expected.into_iter().filter(|x| &x[1] != "rs2").collect();
+
+    for align_by_id in [false, true] {
+        let config = igwas::util::RuntimeConfig {
+            align_by_id,
+            ..small_runtime_config()
+        };
+        let observed =
+            run_small_study_na(dir.path(), igwas::io::gwas::NaPolicy::Skip, config).unwrap();
+        assert_eq!(expected, observed);
+    }
+}
+
+#[test]
+fn run_fn_na_policy_zero_weight() {
+    let dir = tempdir().unwrap();
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    for align_by_id in [false, true] {
+        let config = igwas::util::RuntimeConfig {
+            align_by_id,
+            ..small_runtime_config()
+        };
+        let observed =
+            run_small_study_na(dir.path(), igwas::io::gwas::NaPolicy::ZeroWeight, config).unwrap();
+        assert_eq!(expected.len(), observed.len());
+
+        for (e, o) in expected.iter().zip(&observed) {
+            if &o[1] != "rs2" {
+                assert_eq!(e, o);
+                continue;
+            }
+            // Only a.tsv contributes, with beta -0.2 and sample size 100
+            let proj_coef = if &o[0] == "p1" { 0.5 } else { 1.0 };
+            let beta: f32 = o[2].parse().unwrap();
+            assert!((beta - proj_coef * -0.2).abs() < 1e-6, "{:?}", o);
+            assert_eq!(&o[6], "100");
+            let se: f32 = o[3].parse().unwrap();
+            assert!(se.is_finite() && se > 0.0, "{:?}", o);
+        }
+    }
+}
+
+#[test]
+fn read_gwas_results_invalid_as_missing() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("gwas.tsv");
+    let mut text = String::from("ID\tBETA\tSE\tOBS_CT\n");
+    for i in 0..20 {
+        let row = match i % 4 {
+            1 => format!("rs{}\tabc\t0.1\t90\n", i),
+            2 if i < 10 => format!("rs{}\t0.1\t0.1\t9O\n", i),
+            _ => format!("rs{}\t0.1\t0.1\t90\n", i),
+        };
+        text.push_str(&row);
+    }
+    std::fs::write(&path, text).unwrap();
+    let path = path.to_str().unwrap();
+    let read = |na_policy, invalid_as_missing| {
+        let column_names = igwas::io::gwas::ColumnSpec {
+            na_policy,
+            invalid_as_missing,
+            ..Default::default()
+        };
+        igwas::io::gwas::read_gwas_results(path, &column_names, 0, usize::MAX)
+    };
+
+    // Invalid values are errors unless allowed, and under the error policy
+    let err = format!(
+        "{:#}",
+        read(igwas::io::gwas::NaPolicy::Skip, false).err().unwrap()
+    );
+    assert!(err.contains("Invalid value 'abc' on line 3"), "{}", err);
+    assert!(read(igwas::io::gwas::NaPolicy::Error, true).is_err());
+
+    // Rows 1, 5, 9, 13, and 17 have an invalid beta, rows 2 and 6 an invalid
+    // sample size
+    let expected_rows = vec![1, 2, 5, 6, 9, 13, 17];
+    let results = read(igwas::io::gwas::NaPolicy::Skip, true).unwrap();
+    assert_eq!(results.variant_ids.len(), 20);
+    assert_eq!(results.skipped, expected_rows);
+    assert_eq!(results.invalid.count, 7);
+    assert_eq!(results.invalid.examples.len(), 5);
+    assert!(results.invalid.examples[1].contains("Invalid value '9O' on line 4"));
+
+    let results = read(igwas::io::gwas::NaPolicy::ZeroWeight, true).unwrap();
+    assert_eq!(results.zero_weight, expected_rows);
+    assert_eq!(results.invalid.count, 7);
+
+    // Parts read in parallel add up their counts
+    let column_names = igwas::io::gwas::ColumnSpec {
+        na_policy: igwas::io::gwas::NaPolicy::Skip,
+        invalid_as_missing: true,
+        ..Default::default()
+    };
+    let (results, _) =
+        igwas::io::gwas::read_gwas_results_parallel(path, &column_names, 0, 20, None, 4).unwrap();
+    assert_eq!(results.skipped, expected_rows);
+    assert_eq!(results.invalid.count, 7);
+    assert_eq!(results.invalid.examples.len(), 5);
+}
+
+#[test]
+fn run_fn_num_covar_by_phenotype() {
+    use igwas::stats::sumstats::compute_neg_log_pvalue;
+
+    let dir = tempdir().unwrap();
+    let uniform = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    let config = igwas::util::RuntimeConfig {
+        num_covar_by_phenotype: [("b.tsv".to_string(), 50)].into_iter().collect(),
+        ..small_runtime_config()
+    };
+    let observed = run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+
+    // dof = min(100 - 2 - 2, 90 - 2 - 50) with per-phenotype covariates,
+    // and min(100, 90) - 2 - 2 otherwise
+    let check_dof = |records: &[csv::StringRecord], dof: i32| {
+        for record in records {
+            let t_stat: f32 = record[4].parse().unwrap();
+            assert_eq!(
+                compute_neg_log_pvalue(t_stat, dof).to_string(),
+                &record[5],
+                "{:?}",
+                record
+            );
+        }
+    };
+    check_dof(&uniform, 86);
+    check_dof(&observed, 38);
+    assert_ne!(uniform[0][3], observed[0][3]);
+
+    let config = igwas::util::RuntimeConfig {
+        num_covar_by_phenotype: [("c.tsv".to_string(), 5)].into_iter().collect(),
+        ..small_runtime_config()
+    };
+    let err = run_small_study(dir.path(), [SMALL_A, SMALL_B], config)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("unknown phenotype c.tsv"), "{}", err);
+}
+
+#[test]
+fn run_fn_composite_variant_ids() {
+    let dir = tempdir().unwrap();
+    let composite = |text: &str| -> String {
+        let mut lines = text.lines();
+        let header = lines
+            .next()
+            .unwrap()
+            .replacen("ID", "CHR\tPOS\tREF\tALT", 1);
+        let rows = lines.map(|line| {
+            let (id, rest) = line.split_once('\t').unwrap();
+            let position = &id[2..];
+            format!("1\t{}00\tA\tG\t{}", position, rest)
+        });
+        std::iter::once(header)
+            .chain(rows)
+            .map(|x| x + "\n")
+            .collect()
+    };
+    let single = |text: &str| -> String {
+        text.lines()
+            .map(|line| match line.split_once('\t') {
+                Some(("ID", _)) => line.to_string(),
+                Some((id, rest)) => format!("1_{}00_A_G\t{}", &id[2..], rest),
+                None => unreachable!(),
+            })
+            .map(|x| x + "\n")
+            .collect()
+    };
+    let column_names = igwas::io::gwas::ColumnSpec {
+        variant_id_columns: Some(igwas::io::gwas::VariantIdColumns {
+            chromosome: "CHR".to_string(),
+            position: "POS".to_string(),
+            ref_allele: "REF".to_string(),
+            alt_allele: "ALT".to_string(),
+            separator: "_".to_string(),
+        }),
+        ..Default::default()
+    };
+
+    let expected = run_small_study(
+        dir.path(),
+        [&single(SMALL_A), &single(SMALL_B)],
+        small_runtime_config(),
+    )
+    .unwrap();
+    assert_eq!(&expected[0][1], "1_100_A_G");
+
+    for align_by_id in [false, true] {
+        let observed = run_small_study_with(
+            dir.path(),
+            [&composite(SMALL_A), &composite(SMALL_B)],
+            igwas::util::RuntimeConfig {
+                align_by_id,
+                ..small_runtime_config()
+            },
+            column_names.clone(),
+        )
+        .unwrap();
+        assert_eq!(expected, observed);
+    }
+}
+
+/// Append effect allele and frequency columns to a small study file
+fn with_annotations(text: &str, alleles: [&str; 4]) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => format!("{}\tEA\tEAF\n", line),
+            i => format!("{}\t{}\t0.{}\n", line, alleles[i - 1], i),
+        })
+        .collect()
+}
+
+fn annotation_columns() -> igwas::io::gwas::ColumnSpec {
+    igwas::io::gwas::ColumnSpec {
+        effect_allele: Some("EA".to_string()),
+        effect_allele_freq: Some("EAF".to_string()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn run_fn_annotations_repeated_per_projection() {
+    let dir = tempdir().unwrap();
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    let alleles = ["A", "C", "G", "T"];
+    let observed = run_small_study_with(
+        dir.path(),
+        [
+            &with_annotations(SMALL_A, alleles),
+            &with_annotations(SMALL_B, alleles),
+        ],
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            ..small_runtime_config()
+        },
+        annotation_columns(),
+    )
+    .unwrap();
+
+    let output = dir.path().join("out.tsv");
+    let header = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&output)
+        .unwrap()
+        .headers()
+        .unwrap()
+        .clone();
+    assert_eq!(&header[7], "effect_allele");
+    assert_eq!(&header[8], "effect_allele_freq");
+
+    // Rows are ordered by chunk, then projection, then variant
+    let mut sorted = observed.clone();
+    sorted.sort_by(|x, y| (&x[0], &x[1]).cmp(&(&y[0], &y[1])));
+    assert_eq!(sorted.len(), 8);
+    for (i, row) in sorted.iter().enumerate() {
+        let variant = i % 4;
+        assert_eq!(&row[0], if i < 4 { "p1" } else { "p2" });
+        assert_eq!(&row[1], format!("rs{}", variant + 1));
+        assert_eq!(&row[7], alleles[variant]);
+        assert_eq!(&row[8], format!("0.{}", variant + 1));
+    }
+    let mut expected = expected;
+    expected.sort_by(|x, y| (&x[0], &x[1]).cmp(&(&y[0], &y[1])));
+    for (x, y) in expected.iter().zip(&sorted) {
+        assert_eq!(x, &y.iter().take(7).collect::<csv::StringRecord>());
+    }
+}
+
+#[test]
+fn run_fn_annotation_mismatch() {
+    let dir = tempdir().unwrap();
+    let files = [
+        with_annotations(SMALL_A, ["A", "C", "G", "T"]),
+        with_annotations(SMALL_B, ["A", "C", "T", "T"]),
+    ];
+    let run = |annotation_mismatch| {
+        run_small_study_with(
+            dir.path(),
+            [&files[0], &files[1]],
+            igwas::util::RuntimeConfig {
+                num_threads: 2,
+                stats: igwas::stats::running::StatsConfig {
+                    annotation_mismatch,
+                    ..Default::default()
+                },
+                ..small_runtime_config()
+            },
+            annotation_columns(),
+        )
+    };
+
+    let err = run(igwas::stats::running::AnnotationPolicy::Error)
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.contains("Mismatched effect_allele of variant rs3"),
+        "{}",
+        err
+    );
+
+    // The values of a.tsv, the first phenotype of the projection matrix, are kept
+    let records = run(igwas::stats::running::AnnotationPolicy::TakeFirst).unwrap();
+    let rs3: Vec<&str> = records
+        .iter()
+        .filter(|x| &x[1] == "rs3")
+        .map(|x| &x[7])
+        .collect();
+    assert_eq!(rs3, vec!["G", "G"]);
+}
+
+#[test]
+fn run_fn_sort_by_position() {
+    let dir = tempdir().unwrap();
+    let ids = ["chr2:100:A:G", "1:200:C:T", "X:5:G:A", "1:30:T:C"];
+    let with_ids = |text: &str| -> String {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| match i {
+                0 => format!("{}\n", line),
+                i => format!("{}\t{}\n", ids[i - 1], line.split_once('\t').unwrap().1),
+            })
+            .collect()
+    };
+    let records = run_small_study(
+        dir.path(),
+        [&with_ids(SMALL_A), &with_ids(SMALL_B)],
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            output: igwas::io::gwas::OutputConfig {
+                sort_by_position: true,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        },
+    )
+    .unwrap();
+
+    let observed: Vec<(&str, &str)> = records.iter().map(|x| (&x[0], &x[1])).collect();
+    let mut expected = Vec::new();
+    for projection in ["p1", "p2"] {
+        for id in ["1:30:T:C", "1:200:C:T", "chr2:100:A:G", "X:5:G:A"] {
+            expected.push((projection, id));
+        }
+    }
+    assert_eq!(observed, expected);
+
+    let err = run_small_study(
+        dir.path(),
+        [SMALL_A, SMALL_B],
+        igwas::util::RuntimeConfig {
+            output: igwas::io::gwas::OutputConfig {
+                sort_by_position: true,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        },
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("Cannot sort by position"), "{}", err);
+}
+
+#[test]
+fn run_fn_split_by_projection() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let combined = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+
+    let output_dir = dir.path().join("out");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        output_dir.to_str().unwrap(),
+        2,
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            output: igwas::io::gwas::OutputConfig {
+                split_by_projection: true,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        },
+        Default::default(),
+    )
+    .unwrap();
+
+    let mut names: Vec<String> = std::fs::read_dir(&output_dir)
+        .unwrap()
+        .map(|x| x.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["p1.tsv", "p2.tsv"]);
+
+    for projection in ["p1", "p2"] {
+        let path = output_dir.join(format!("{}.tsv", projection));
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.matches("phenotype_id").count(), 1);
+        let records = read_output_records(path.to_str().unwrap());
+        let expected: Vec<&csv::StringRecord> =
+            combined.iter().filter(|x| &x[0] == projection).collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records.iter().collect::<Vec<_>>(), expected);
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NdjsonRow {
+    variant_id: String,
+    projection_id: String,
+    beta: Option<f32>,
+    se: Option<f32>,
+    t_stat: Option<f32>,
+    neg_log_p: Option<f32>,
+    n: i64,
+}
+
+#[test]
+fn run_fn_ndjson_output() {
+    let dir = tempdir().unwrap();
+    let records = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+
+    let output = dir.path().join("out.ndjson");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        output.to_str().unwrap(),
+        2,
+        igwas::util::RuntimeConfig {
+            output: igwas::io::gwas::OutputConfig {
+                format: igwas::io::gwas::OutputFormat::Ndjson,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        },
+        Default::default(),
+    )
+    .unwrap();
+
+    let text = std::fs::read_to_string(&output).unwrap();
+    let rows: Vec<NdjsonRow> = text
+        .lines()
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    assert_eq!(rows.len(), records.len());
+    let parse = |x: &str| x.parse::<f32>().ok().filter(|x| x.is_finite());
+    for (row, record) in rows.iter().zip(&records) {
+        assert_eq!(row.projection_id, &record[0]);
+        assert_eq!(row.variant_id, &record[1]);
+        assert_eq!(row.beta, parse(&record[2]));
+        assert_eq!(row.se, parse(&record[3]));
+        assert_eq!(row.t_stat, parse(&record[4]));
+        assert_eq!(row.neg_log_p, parse(&record[5]));
+        assert_eq!(row.n.to_string(), &record[6]);
+    }
+}
+
+#[test]
+fn cli_output_to_stdout() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let expected_path = dir.path().join("expected.tsv");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        expected_path.to_str().unwrap(),
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .unwrap();
+
+    // Several chunks, with logging enabled
+    let mut cmd = Command::cargo_bin("igwas").unwrap();
+    cmd.arg("-p")
+        .arg(&proj)
+        .arg("-c")
+        .arg(&cov)
+        .arg("-g")
+        .args(&files)
+        .arg("-o")
+        .arg("-")
+        .arg("--num-covar")
+        .arg("2")
+        .arg("--chunksize")
+        .arg("3");
+    let output = cmd.assert().success().get_output().clone();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let records: Vec<csv::StringRecord> = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(stdout.as_bytes())
+        .records()
+        .map(|x| x.unwrap())
+        .collect();
+    let mut expected = read_output_records(expected_path.to_str().unwrap());
+    let mut observed = records;
+    let key = |x: &csv::StringRecord| (x[0].to_string(), x[1].to_string());
+    expected.sort_by_key(key);
+    observed.sort_by_key(key);
+    assert_eq!(observed, expected);
+    assert_eq!(stdout.matches("phenotype_id").count(), 1);
+    assert!(!String::from_utf8(output.stderr).unwrap().is_empty());
+}
+
+#[test]
+fn runtime_config_builder() {
+    let config = igwas::util::RuntimeConfig::builder().build().unwrap();
+    assert!(config.num_threads >= 1);
+    assert_eq!(config.chunksize, 100000);
+
+    let config = igwas::util::RuntimeConfig::builder()
+        .num_threads(3)
+        .chunksize(10)
+        .build()
+        .unwrap();
+    assert_eq!((config.num_threads, config.chunksize), (3, 10));
+
+    let err =
+        |builder: igwas::util::RuntimeConfigBuilder| builder.build().err().unwrap().to_string();
+    let builder = igwas::util::RuntimeConfig::builder;
+    assert_eq!(builder().num_threads(0).build().unwrap().num_threads, 0);
+    assert!(err(builder().num_readers(0)).contains("Number of readers"));
+    assert!(err(builder().chunksize(0)).contains("Chunk size"));
+}
+
+#[test]
+fn run_fn_zero_chunksize_error() {
+    let dir = tempdir().unwrap();
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 0,
+        ..small_runtime_config()
+    };
+    let err = run_small_study(dir.path(), [SMALL_A, SMALL_B], config)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("Chunk size must be nonzero"), "{}", err);
+}
+
+#[test]
+fn run_fn_zero_threads_uses_available_cores() {
+    let config = igwas::util::RuntimeConfig {
+        num_threads: 0,
+        ..small_runtime_config()
+    };
+    assert!(config.resolved_num_threads() > 0);
+    assert_eq!(small_runtime_config().resolved_num_threads(), 1);
+
+    let dir = tempdir().unwrap();
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    let records = run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+    assert_eq!(expected, records);
+}
+
+#[test]
+fn run_fn_header_only_input() {
+    let dir = tempdir().unwrap();
+    let header = "ID\tBETA\tSE\tOBS_CT\n";
+    let records = run_small_study(dir.path(), [header, header], small_runtime_config()).unwrap();
+    assert!(records.is_empty());
+
+    let text = std::fs::read_to_string(dir.path().join("out.tsv")).unwrap();
+    assert_eq!(
+        text,
+        "phenotype_id\tvariant_id\tbeta\tstd_error\tt_stat\tp_value\tsample_size\n"
+    );
+
+    let err = run_small_study(dir.path(), ["", ""], small_runtime_config())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("Missing columns"), "{}", err);
+}
+
+#[test]
+fn run_fn_unequal_line_counts_error() {
+    let dir = tempdir().unwrap();
+    let truncated: String = SMALL_B
+        .lines()
+        .take(3)
+        .map(|x| format!("{}\n", x))
+        .collect();
+    let err = run_small_study(dir.path(), [SMALL_A, &truncated], small_runtime_config())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("different numbers of variants"), "{}", err);
+    assert!(err.contains("a.tsv has 4"), "{}", err);
+    assert!(err.contains("b.tsv has 2"), "{}", err);
+}
+
+/// A two-sample GWAS-VCF with the statistics of `SMALL_A` and `SMALL_B`
+fn small_gwas_vcf() -> String {
+    let mut text = String::from(
+        "##fileformat=VCFv4.2\n\
+        ##FORMAT=<ID=ES,Number=A,Type=Float,Description=\"Effect size estimate\">\n\
+        ##FORMAT=<ID=SE,Number=A,Type=Float,Description=\"Standard error\">\n\
+        ##FORMAT=<ID=SS,Number=A,Type=Float,Description=\"Sample size\">\n\
+        #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tstudy_a\tstudy_b\n",
+    );
+    let rows = |x: &'static str| x.lines().skip(1).map(|x| x.split('\t').collect::<Vec<_>>());
+    for (i, (a, b)) in rows(SMALL_A).zip(rows(SMALL_B)).enumerate() {
+        text.push_str(&format!(
+            "1\t{}\t{}\tA\tG\t.\tPASS\tAF=0.2\tES:SE:SS\t{}:{}:{}\t{}:{}:{}\n",
+            100 * (i + 1),
+            a[0],
+            a[1],
+            a[2],
+            a[3],
+            b[1],
+            b[2],
+            b[3]
+        ));
+    }
+    text
+}
+
+/// Write `SMALL_A` and `SMALL_B` as the files `study_a` and `study_b`,
+/// with matrices labeled by these names
+fn write_named_study(path: &std::path::Path) {
+    std::fs::write(
+        path.join("proj.csv"),
+        "id,p1,p2\nstudy_a,0.5,1.0\nstudy_b,-1.5,0.25\n",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("cov.csv"),
+        "id,study_a,study_b\nstudy_a,1.0,0.3\nstudy_b,0.3,2.0\n",
+    )
+    .unwrap();
+    std::fs::write(path.join("study_a"), SMALL_A).unwrap();
+    std::fs::write(path.join("study_b"), SMALL_B).unwrap();
+}
+
+/// Run the study of `write_named_study` on the given files in `path`
+fn run_named_study(
+    path: &std::path::Path,
+    files: &[&str],
+    column_names: igwas::io::gwas::ColumnSpec,
+    align_by_id: bool,
+) -> Vec<csv::StringRecord> {
+    run_named_study_with(
+        path,
+        files,
+        column_names,
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            align_by_id,
+            ..small_runtime_config()
+        },
+    )
+}
+
+fn run_named_study_with(
+    path: &std::path::Path,
+    files: &[&str],
+    column_names: igwas::io::gwas::ColumnSpec,
+    config: igwas::util::RuntimeConfig,
+) -> Vec<csv::StringRecord> {
+    let files: Vec<String> = files
+        .iter()
+        .map(|x| path.join(x).to_str().unwrap().to_string())
+        .collect();
+    let output = path.join("out.tsv");
+    igwas::util::run(
+        path.join("proj.csv").to_str().unwrap(),
+        path.join("cov.csv").to_str().unwrap(),
+        &files,
+        output.to_str().unwrap(),
+        2,
+        config,
+        column_names,
+    )
+    .unwrap();
+    read_output_records(output.to_str().unwrap())
+}
+
+/// Files read for each chunk of a run of `run_named_study_with`, by name
+fn named_study_files_read(
+    path: &std::path::Path,
+    files: &[&str],
+    column_names: igwas::io::gwas::ColumnSpec,
+) -> Vec<Vec<String>> {
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 3,
+        timing: Some(Box::new({
+            let events = events.clone();
+            move |event: igwas::util::TimingEvent| {
+                if event.stage == igwas::util::TimingStage::Accumulate {
+                    events.lock().unwrap().push(
+                        event
+                            .file_read_times
+                            .iter()
+                            .map(|(x, _)| x.rsplit('/').next().unwrap().to_string())
+                            .collect(),
+                    );
+                }
+            }
+        })),
+        ..small_runtime_config()
+    };
+    run_named_study_with(path, files, column_names, config);
+    let events = events.lock().unwrap().clone();
+    events
+}
+
+#[test]
+fn count_variants_gwas_vcf_cached() {
+    use igwas::io::gwas::{count_lines, count_variants};
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("studies.vcf");
+    let mut text = small_gwas_vcf();
+    std::fs::write(&path, &text).unwrap();
+    let filename = path.to_str().unwrap();
+    let column_names = igwas::io::gwas::ColumnSpec {
+        vcf: Some(Default::default()),
+        ..Default::default()
+    };
+    assert_eq!(count_variants(filename, &column_names).unwrap(), 4);
+
+    // Joining the last two records keeps the size, and with the same
+    // modification time the cached count of records is returned
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+    let last = text.trim_end().rfind('\n').unwrap();
+    text.replace_range(last..last + 1, " ");
+    std::fs::write(&path, &text).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    assert_eq!(count_variants(filename, &column_names).unwrap(), 4);
+
+    // Counting its lines is not the same count, so it scans the file
+    assert_eq!(count_lines(filename).unwrap(), 7);
+}
+
+#[test]
+fn run_fn_gwas_vcf() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    write_named_study(path);
+    std::fs::write(path.join("studies.vcf"), small_gwas_vcf()).unwrap();
+
+    let expected = run_named_study(path, &["study_a", "study_b"], Default::default(), false);
+    for align_by_id in [false, true] {
+        let observed = run_named_study(
+            path,
+            &["studies.vcf"],
+            igwas::io::gwas::ColumnSpec {
+                vcf: Some(Default::default()),
+                ..Default::default()
+            },
+            align_by_id,
+        );
+        assert_eq!(expected, observed);
+    }
+
+    std::fs::write(path.join("studies.vcf"), windows_text(&small_gwas_vcf())).unwrap();
+    let observed = run_named_study(
+        path,
+        &["studies.vcf"],
+        igwas::io::gwas::ColumnSpec {
+            vcf: Some(Default::default()),
+            ..Default::default()
+        },
+        false,
+    );
+    assert_eq!(expected, observed);
+
+    // Each chunk reads the file once for both of its samples
+    let files_read = named_study_files_read(
+        path,
+        &["studies.vcf"],
+        igwas::io::gwas::ColumnSpec {
+            vcf: Some(Default::default()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(files_read, vec![vec!["studies.vcf"]; 2]);
+}
+
+#[test]
+fn run_fn_wide_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    write_named_study(path);
+    let mut wide = String::from(
+        "ID\tBETA_study_a\tSE_study_a\tOBS_CT_study_a\tBETA_study_b\tSE_study_b\tOBS_CT_study_b\n",
+    );
+    for (a, b) in SMALL_A.lines().zip(SMALL_B.lines()).skip(1) {
+        let (id, a) = a.split_once('\t').unwrap();
+        wide.push_str(&format!(
+            "{}\t{}\t{}\n",
+            id,
+            a,
+            b.split_once('\t').unwrap().1
+        ));
+    }
+    std::fs::write(path.join("wide.tsv"), wide).unwrap();
+    let column_names = igwas::io::gwas::ColumnSpec {
+        wide: Some(igwas::io::gwas::WideColumns {
+            phenotypes: vec!["study_a".to_string(), "study_b".to_string()],
+            separator: "_".to_string(),
+        }),
+        ..Default::default()
+    };
+
+    let results = igwas::io::gwas::read_wide_gwas_results(
+        path.join("wide.tsv").to_str().unwrap(),
+        &column_names,
+        0,
+        4,
+    )
+    .unwrap();
+    let phenotypes: Vec<&str> = results.iter().map(|(x, _)| x.as_str()).collect();
+    assert_eq!(phenotypes, vec!["study_a", "study_b"]);
+    assert_eq!(results[1].1.beta_values.as_slice(), &[0.2, 0.1, -0.3, 0.0]);
+    assert_eq!(results[1].1.sample_sizes.as_slice(), &[90; 4]);
+
+    let expected = run_named_study(path, &["study_a", "study_b"], Default::default(), false);
+    for align_by_id in [false, true] {
+        let observed = run_named_study(path, &["wide.tsv"], column_names.clone(), align_by_id);
+        assert_eq!(expected, observed);
+    }
+    for (mmap, parse_threads) in [(true, 1), (false, 2)] {
+        let config = igwas::util::RuntimeConfig {
+            chunksize: 3,
+            mmap,
+            parse_threads,
+            ..small_runtime_config()
+        };
+        let observed = run_named_study_with(path, &["wide.tsv"], column_names.clone(), config);
+        assert_eq!(expected, observed);
+    }
+
+    // Each chunk reads the file once for both of its phenotypes
+    let files_read = named_study_files_read(path, &["wide.tsv"], column_names);
+    assert_eq!(files_read, vec![vec!["wide.tsv"]; 2]);
+}
+
+#[test]
+fn validate_inputs_report() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj, cov, files) = write_small_study(path, [SMALL_A, SMALL_B]);
+    let validate = |files: &[String], column_names: igwas::io::gwas::ColumnSpec| {
+        igwas::util::validate_inputs(&proj, &cov, files, &small_runtime_config(), &column_names)
+    };
+
+    let report = validate(&files, Default::default()).unwrap();
+    assert_eq!(
+        report,
+        igwas::util::InputReport {
+            n_features: 2,
+            n_projections: 2,
+            n_variants: 4,
+            gwas_files: vec![
+                ("a.tsv".to_string(), files[0].clone()),
+                ("b.tsv".to_string(), files[1].clone()),
+            ],
+        }
+    );
+    assert!(report.to_string().starts_with("Inputs are valid"));
+    assert!(!path.join("out.tsv").exists());
+
+    let err = validate(&files[..1], Default::default())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("No GWAS result file provided"), "{}", err);
+
+    let err = validate(
+        &files,
+        igwas::io::gwas::ColumnSpec {
+            beta: "EFFECT".to_string(),
+            ..Default::default()
+        },
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(err.contains("Missing columns [\"EFFECT\"]"), "{}", err);
+
+    let truncated: String = SMALL_B
+        .lines()
+        .take(4)
+        .map(|x| format!("{}\n", x))
+        .collect();
+    std::fs::write(&files[1], truncated).unwrap();
+    let err = validate(&files, Default::default())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("different numbers of variants"), "{}", err);
+}
+
+#[test]
+fn cli_check() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj, cov, files) = write_small_study(path, [SMALL_A, SMALL_B]);
+    let output = path.join("out.tsv");
+
+    let mut cmd = Command::cargo_bin("igwas").unwrap();
+    cmd.arg("-p")
+        .arg(&proj)
+        .arg("-c")
+        .arg(&cov)
+        .arg("-g")
+        .args(&files)
+        .arg("-o")
+        .arg(&output)
+        .arg("--num-covar")
+        .arg("2")
+        .arg("--check")
+        .arg("--quiet");
+    let stdout = cmd.assert().success().get_output().stdout.clone();
+    let stdout = String::from_utf8(stdout).unwrap();
+    assert!(
+        stdout.contains("2 phenotypes, 2 projections, 4 variants"),
+        "{}",
+        stdout
+    );
+    assert!(!output.exists());
+}
+
+#[test]
+fn run_fn_shuffled_covariance_labels() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let expected = run_small_study(path, [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+
+    // The same covariance matrix, with the phenotypes in the other order
+    let cov = path.join("cov.csv");
+    std::fs::write(&cov, "id,b.tsv,a.tsv\nb.tsv,2.0,0.3\na.tsv,0.3,1.0\n").unwrap();
+    let output = path.join("out.tsv");
+    let files: Vec<String> = ["a.tsv", "b.tsv"]
+        .iter()
+        .map(|x| path.join(x).to_str().unwrap().to_string())
+        .collect();
+    let run = || {
+        igwas::util::run(
+            path.join("proj.csv").to_str().unwrap(),
+            cov.to_str().unwrap(),
+            &files,
+            output.to_str().unwrap(),
+            2,
+            small_runtime_config(),
+            Default::default(),
+        )
+    };
+    run().unwrap();
+    assert_eq!(expected, read_output_records(output.to_str().unwrap()));
+
+    std::fs::write(&cov, "id,b.tsv,c.tsv\nb.tsv,2.0,0.3\nc.tsv,0.3,1.0\n").unwrap();
+    let err = run().unwrap_err().to_string();
+    assert!(
+        err.contains("no labels for phenotypes [\"a.tsv\"]"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_superset_covariance() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let expected = run_small_study(path, [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+
+    // An extra phenotype c.tsv, correlated with the others
+    std::fs::write(
+        path.join("cov.csv"),
+        "id,c.tsv,a.tsv,b.tsv\nc.tsv,5.0,0.7,-0.4\na.tsv,0.7,1.0,0.3\nb.tsv,-0.4,0.3,2.0\n",
+    )
+    .unwrap();
+    let files: Vec<String> = ["a.tsv", "b.tsv"]
+        .iter()
+        .map(|x| path.join(x).to_str().unwrap().to_string())
+        .collect();
+    let output = path.join("out.tsv");
+    igwas::util::run(
+        path.join("proj.csv").to_str().unwrap(),
+        path.join("cov.csv").to_str().unwrap(),
+        &files,
+        output.to_str().unwrap(),
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(expected, read_output_records(output.to_str().unwrap()));
+}
+
+#[test]
+fn run_fn_sparse_projection() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    // Each projection loads on some of the three phenotypes
+    std::fs::write(
+        path.join("proj.csv"),
+        "id,p1,p2,p3\na.tsv,1.0,0.0,0.5\nb.tsv,0.0,-1.5,0.0\nc.tsv,0.0,0.25,2.0\n",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("cov.csv"),
+        "id,a.tsv,b.tsv,c.tsv\na.tsv,1.0,0.3,0.1\nb.tsv,0.3,2.0,-0.2\nc.tsv,0.1,-0.2,1.5\n",
+    )
+    .unwrap();
+    let mut files = Vec::new();
+    for (name, text) in [
+        ("a.tsv", SMALL_A),
+        ("b.tsv", SMALL_B),
+        ("c.tsv", SMALL_B_NA),
+    ] {
+        std::fs::write(path.join(name), text).unwrap();
+        files.push(path.join(name).to_str().unwrap().to_string());
+    }
+
+    let run = |sparse_projection: bool| {
+        let output = path.join(format!("out_{}.tsv", sparse_projection));
+        igwas::util::run(
+            path.join("proj.csv").to_str().unwrap(),
+            path.join("cov.csv").to_str().unwrap(),
+            &files,
+            output.to_str().unwrap(),
+            2,
+            igwas::util::RuntimeConfig {
+                stats: igwas::stats::running::StatsConfig {
+                    sparse_projection,
+                    ..Default::default()
+                },
+                ..small_runtime_config()
+            },
+            igwas::io::gwas::ColumnSpec {
+                na_policy: igwas::io::gwas::NaPolicy::ZeroWeight,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        read_output_records(output.to_str().unwrap())
+    };
+    let dense = run(false);
+    let sparse = run(true);
+    assert_eq!(dense.len(), 12);
+    assert_eq!(dense, sparse);
+}
+
+#[test]
+fn projection_quadratic_form_diagonal() {
+    use igwas::stats::running::Projection;
+
+    // Mostly zero coefficients, so that the sparse path is exercised too
+    let proj =
+        nalgebra::DMatrix::<f32>::new_random(30, 200).map(|x| if x < 0.7 { 0.0 } else { x - 0.5 });
+    let a = nalgebra::DMatrix::<f64>::new_random(30, 30);
+    let cov = &a * a.transpose();
+
+    let proj_f64 = proj.map(|x| x as f64);
+    let expected = (proj_f64.transpose() * &cov * &proj_f64).diagonal();
+    for sparse in [false, true] {
+        let ppv = Projection::<f64>::new(&proj, sparse).quadratic_form_diagonal(&cov);
+        assert_eq!(ppv.len(), 200);
+        for (x, y) in ppv.iter().zip(expected.iter()) {
+            assert!((x - y).abs() <= 1e-10 * y.abs().max(1.0), "{} != {}", x, y);
+        }
+    }
+}
+
+#[test]
+fn processing_stats_share_projection() {
+    let labels = vec!["a".to_string(), "b".to_string()];
+    let proj = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p1".to_string(), "p2".to_string()],
+        matrix: nalgebra::DMatrix::from_row_slice(2, 2, &[0.5, 1.0, -1.5, 0.25]),
+    };
+    let cov = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: labels,
+        matrix: nalgebra::DMatrix::identity(2, 2),
+    };
+    let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+        &proj,
+        &cov,
+        0,
+        10,
+        None,
+        Default::default(),
+    )
+    .unwrap();
+
+    let first = running.build_processing_stats();
+    running.clear_chunk(5);
+    let second = running.build_processing_stats();
+    assert!(std::sync::Arc::ptr_eq(&first.proj, &second.proj));
+    assert!(std::sync::Arc::ptr_eq(&first.fpv, &second.fpv));
+    assert!(std::sync::Arc::ptr_eq(
+        &first.phenotype_id_to_idx,
+        &second.phenotype_id_to_idx
+    ));
+    assert_eq!(second.n_variants, 5);
+
+    // Changing the covariates of a feature leaves earlier stats untouched
+    running.set_num_covar("b", 3).unwrap();
+    let third = running.build_processing_stats();
+    assert_eq!(*first.n_covar, vec![0, 0]);
+    assert_eq!(*third.n_covar, vec![0, 3]);
+}
+
+#[test]
+fn run_fn_resume_from_checkpoint() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let checkpoint = path.join("checkpoint.txt").to_str().unwrap().to_string();
+    let config = |chunksize: usize| igwas::util::RuntimeConfig {
+        chunksize,
+        checkpoint: Some(checkpoint.clone()),
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(path, [SMALL_A, SMALL_B], config(2)).unwrap();
+    assert_eq!(expected.len(), 8);
+    assert!(!path.join("checkpoint.txt").exists());
+
+    // Interrupt the run once the first of its two chunks is written, and
+    // leave part of a second chunk in the output
+    let interrupt = || {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            run_small_study(
+                path,
+                [SMALL_A, SMALL_B],
+                igwas::util::RuntimeConfig {
+                    progress: Some(Box::new(|event| {
+                        if event.stage == igwas::util::ProgressStage::Chunk {
+                            panic!("interrupted");
+                        }
+                    })),
+                    ..config(2)
+                },
+            )
+        }));
+        assert!(result.is_err());
+        assert_eq!(
+            read_output_records(path.join("out.tsv").to_str().unwrap()).len(),
+            4
+        );
+        let mut output = std::fs::OpenOptions::new()
+            .append(true)
+            .open(path.join("out.tsv"))
+            .unwrap();
+        std::io::Write::write_all(&mut output, b"p1\trs3\t0.1").unwrap();
+    };
+    interrupt();
+    assert!(path.join("checkpoint.txt").exists());
+
+    let resumed = run_small_study(path, [SMALL_A, SMALL_B], config(2)).unwrap();
+    assert_eq!(expected, resumed);
+    assert!(!path.join("checkpoint.txt").exists());
+
+    // Resuming with a different chunk size is refused
+    interrupt();
+    let err = run_small_study(path, [SMALL_A, SMALL_B], config(3)).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("different parameters or inputs"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_sync_output_after_crash() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let mut config = small_runtime_config();
+    config.chunksize = 2;
+    config.output.sync = true;
+    let expected = run_small_study(path, [SMALL_A, SMALL_B], config).unwrap();
+
+    // A crash between the two chunks leaves a valid output with exactly the
+    // rows of the first
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut config = small_runtime_config();
+        config.chunksize = 2;
+        config.output.sync = true;
+        config.progress = Some(Box::new(|event| {
+            if event.stage == igwas::util::ProgressStage::Chunk {
+                panic!("interrupted");
+            }
+        }));
+        run_small_study(path, [SMALL_A, SMALL_B], config)
+    }));
+    assert!(result.is_err());
+    let records = read_output_records(path.join("out.tsv").to_str().unwrap());
+    assert_eq!(expected.len(), 8);
+    assert_eq!(records, expected[..4]);
+
+    // Resuming from a checkpoint that counts more output than is on disk is
+    // refused, rather than padding the output
+    let checkpoint = path.join("checkpoint.txt").to_str().unwrap().to_string();
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 2,
+        checkpoint: Some(checkpoint.clone()),
+        ..small_runtime_config()
+    };
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        run_small_study(
+            path,
+            [SMALL_A, SMALL_B],
+            igwas::util::RuntimeConfig {
+                progress: Some(Box::new(|event| {
+                    if event.stage == igwas::util::ProgressStage::Chunk {
+                        panic!("interrupted");
+                    }
+                })),
+                ..config()
+            },
+        )
+    }));
+    assert!(result.is_err());
+    let output = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path.join("out.tsv"))
+        .unwrap();
+    output.set_len(10).unwrap();
+    let err = run_small_study(path, [SMALL_A, SMALL_B], config()).unwrap_err();
+    assert!(format!("{:#}", err).contains("fewer than the"), "{:#}", err);
+}
+
+#[test]
+fn runtime_config_thread_pool_size() {
+    for num_threads in [1, 3] {
+        let config = igwas::util::RuntimeConfig {
+            num_threads,
+            ..small_runtime_config()
+        };
+        let pool = config.thread_pool().unwrap();
+        assert_eq!(pool.current_num_threads(), num_threads);
+        // Parallel loops run inside the pool only use its threads
+        let max_index = pool.install(|| {
+            use rayon::prelude::*;
+            (0..1000)
+                .into_par_iter()
+                .map(|_| rayon::current_thread_index().unwrap())
+                .max()
+                .unwrap()
+        });
+        assert!(max_index < num_threads);
+    }
+
+    let config = igwas::util::RuntimeConfig {
+        num_threads: 0,
+        ..small_runtime_config()
+    };
+    assert_eq!(
+        config.thread_pool().unwrap().current_num_threads(),
+        config.resolved_num_threads()
+    );
+}
+
+#[test]
+fn engine_matches_run() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj, cov, files) = write_small_study(path, [SMALL_A, SMALL_B]);
+    let expected = igwas::util::run_to_results(
+        &proj,
+        &cov,
+        &files,
+        2,
+        small_runtime_config(),
+        Default::default(),
+    )
+    .unwrap();
+    let expected = igwas::io::gwas::IGwasResults::concat(&expected).unwrap();
+
+    let proj = igwas::io::matrix::read_labeled_matrix(&proj).unwrap();
+    let cov = igwas::io::matrix::read_labeled_matrix(&cov).unwrap();
+    let column_names = Default::default();
+    let mut engine = igwas::engine::IGwasEngine::<f32>::new(&proj, &cov, 2).unwrap();
+    for (phenotype, file) in ["a.tsv", "b.tsv"].iter().zip(&files) {
+        let gwas_results =
+            igwas::io::gwas::read_gwas_results(file, &column_names, 0, usize::MAX).unwrap();
+        engine.ingest(phenotype, &gwas_results).unwrap();
+    }
+    let err = engine.ingest(
+        "a.tsv",
+        &igwas::io::gwas::GwasResults::new(vec![], vec![], vec![], vec![]),
+    );
+    assert!(err.err().unwrap().to_string().contains("already ingested"));
+    let results = engine.finish().unwrap();
+    assert_eq!(results.variant_ids, expected.variant_ids);
+    assert_eq!(results.projection_ids, expected.projection_ids);
+    assert_eq!(results.beta_values, expected.beta_values);
+    assert_eq!(results.se_values, expected.se_values);
+    assert_eq!(results.p_values, expected.p_values);
+
+    // Finishing starts over, so nothing has been ingested yet
+    let err = engine.finish().err().unwrap().to_string();
+    assert!(err.contains("No results were ingested"), "{}", err);
+}
+
+#[test]
+fn running_stats_non_square_covariance_error() {
+    let labels = vec!["a".to_string(), "b".to_string()];
+    let proj = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p1".to_string()],
+        matrix: nalgebra::DMatrix::from_element(2, 1, 1.0),
+    };
+    let cov = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        matrix: nalgebra::DMatrix::from_element(2, 3, 0.5),
+    };
+    let err = igwas::stats::running::RunningSufficientStats::<f32>::new(
+        &proj,
+        &cov,
+        0,
+        10,
+        None,
+        Default::default(),
+    )
+    .err()
+    .unwrap()
+    .to_string();
+    assert_eq!(
+        err,
+        "Covariance matrix has wrong shape, expected 2 x 2, got 2 x 3"
+    );
+}
+
+#[test]
+fn running_stats_covariance_orientation() {
+    use igwas::io::matrix::LabeledMatrix;
+
+    let labels: Vec<String> = ["a", "b", "c"].map(String::from).to_vec();
+    let proj = LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p1".to_string(), "p2".to_string()],
+        matrix: nalgebra::DMatrix::from_row_slice(3, 2, &[0.5, 1.0, -1.5, 0.25, 1.0, 0.0]),
+    };
+    // Slightly asymmetric, so that a transpose is not the same matrix
+    let matrix =
+        nalgebra::DMatrix::from_row_slice(3, 3, &[1.0, 0.3, 0.1, 0.31, 2.0, -0.2, 0.1, -0.21, 1.5]);
+    let oriented = LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: labels.clone(),
+        matrix: matrix.clone(),
+    };
+    // Features along the columns first, in another order
+    let order = [2, 0, 1];
+    let transposed = LabeledMatrix {
+        row_labels: order.map(|i| labels[i].clone()).to_vec(),
+        col_labels: order.map(|i| labels[i].clone()).to_vec(),
+        matrix: nalgebra::DMatrix::from_fn(3, 3, |i, j| matrix[(order[j], order[i])]),
+    };
+
+    let compute = |cov: &LabeledMatrix| {
+        let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+            &proj,
+            cov,
+            0,
+            2,
+            None,
+            Default::default(),
+        )?;
+        for (phenotype, beta, gpv) in [("a", 0.1, 1.0), ("b", -0.2, 0.5), ("c", 0.3, 2.0)] {
+            running.update(&igwas::io::gwas::IntermediateResults {
+                phenotype_id: phenotype.to_string(),
+                variant_ids: vec!["rs1".to_string(), "rs2".to_string()],
+                beta_update: nalgebra::DMatrix::from_fn(2, 2, |i, j| {
+                    beta * proj.matrix[(labels.iter().position(|x| x == phenotype).unwrap(), j)]
+                        * (i + 1) as f32
+                }),
+                gpv_update: nalgebra::DVector::from_element(2, gpv),
+                sample_sizes: nalgebra::DVector::from_element(2, 100),
+                missing: Vec::new(),
+                zero_weight: Vec::new(),
+                annotations: Default::default(),
+                heterogeneity: None,
+                sample_size_sums: None,
+            })?;
+        }
+        running.compute_final_stats()
+    };
+
+    let expected = compute(&oriented).unwrap();
+    let observed = compute(&transposed).unwrap();
+    assert_eq!(expected.projection_ids, observed.projection_ids);
+    assert_eq!(expected.beta_values, observed.beta_values);
+    for (x, y) in [
+        (&expected.se_values, &observed.se_values),
+        (&expected.t_stat_values, &observed.t_stat_values),
+        (&expected.p_values, &observed.p_values),
+    ] {
+        for (x, y) in x.iter().zip(y.iter()) {
+            assert!((x - y).abs() <= 1e-5 * x.abs().max(1.0), "{} vs {}", x, y);
+        }
+    }
+
+    // A matrix of other features is an error rather than used as it is
+    let other = LabeledMatrix {
+        row_labels: ["a", "b", "d"].map(String::from).to_vec(),
+        col_labels: ["a", "b", "d"].map(String::from).to_vec(),
+        matrix,
+    };
+    let err = compute(&other).err().unwrap();
+    assert!(
+        format!("{:#}", err).contains("No row labeled c"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn running_stats_too_few_features_error() {
+    let labels = vec!["a".to_string(), "b".to_string()];
+    let proj = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p1".to_string()],
+        matrix: nalgebra::DMatrix::from_element(2, 1, 1.0),
+    };
+    let cov = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: labels,
+        matrix: nalgebra::DMatrix::identity(2, 2),
+    };
+    let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+        &proj,
+        &cov,
+        0,
+        1,
+        None,
+        Default::default(),
+    )
+    .unwrap();
+    let gwas_results =
+        igwas::io::gwas::GwasResults::new(vec!["rs1".to_string()], vec![0.1], vec![0.1], vec![100]);
+    let update = running
+        .build_processing_stats()
+        .format_update("a", &gwas_results);
+    running.update(&update).unwrap();
+
+    let err = running.compute_final_stats().err().unwrap().to_string();
+    assert_eq!(err, "Too few features seen. Expected 2, got 1");
+}
+
+#[test]
+fn running_stats_save_and_load_state() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj_path, cov_path, files) = write_small_study(path, [SMALL_A, SMALL_B]);
+    let proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    let cov = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    let column_names = Default::default();
+    let gwas_results: Vec<_> = files
+        .iter()
+        .map(|x| igwas::io::gwas::read_gwas_results(x, &column_names, 0, usize::MAX).unwrap())
+        .collect();
+
+    for (compensated_summation, effective_sample_size) in [(false, false), (true, true)] {
+        let new_stats = || {
+            let config = igwas::stats::running::StatsConfig {
+                compensated_summation,
+                effective_sample_size,
+                ..Default::default()
+            };
+            let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+                &proj, &cov, 2, 4, None, config,
+            )
+            .unwrap();
+            running.clear_chunk(4);
+            running
+        };
+        let add = |running: &mut igwas::stats::running::RunningSufficientStats, i: usize| {
+            let phenotype = ["a.tsv", "b.tsv"][i];
+            let update = running
+                .build_processing_stats()
+                .format_update(phenotype, &gwas_results[i]);
+            running.update(&update).unwrap();
+        };
+
+        let mut expected = new_stats();
+        add(&mut expected, 0);
+        add(&mut expected, 1);
+        let expected = expected.compute_final_stats().unwrap();
+
+        // Save after the first feature, then add the second to the state
+        let state = path.join("state.bin");
+        let state = state.to_str().unwrap();
+        let mut running = new_stats();
+        add(&mut running, 0);
+        running.save_state(state).unwrap();
+
+        let mut resumed = new_stats();
+        resumed.load_state(state).unwrap();
+        add(&mut resumed, 1);
+        let results = resumed.compute_final_stats().unwrap();
+        assert_eq!(results.variant_ids, expected.variant_ids);
+        assert_eq!(results.beta_values, expected.beta_values);
+        assert_eq!(results.se_values, expected.se_values);
+        assert_eq!(results.p_values, expected.p_values);
+        assert_eq!(
+            results.effective_sample_size,
+            expected.effective_sample_size
+        );
+    }
+
+    // The state can only be loaded with the same projection
+    let mut other_proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    other_proj.matrix[(0, 0)] = 2.0;
+    let mut other = igwas::stats::running::RunningSufficientStats::<f32>::new(
+        &other_proj,
+        &cov,
+        2,
+        4,
+        None,
+        Default::default(),
+    )
+    .unwrap();
+    let err = other
+        .load_state(path.join("state.bin").to_str().unwrap())
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("saved with different"), "{}", err);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj_path, cov_path, files) = write_small_study(path, [SMALL_A, SMALL_B]);
+
+    // GwasResults
+    let column_names = igwas::io::gwas::ColumnSpec {
+        na_policy: igwas::io::gwas::NaPolicy::Skip,
+        ..Default::default()
+    };
+    std::fs::write(&files[1], SMALL_B_NA).unwrap();
+    let gwas_results =
+        igwas::io::gwas::read_gwas_results(&files[1], &column_names, 0, usize::MAX).unwrap();
+    let json = serde_json::to_string(&gwas_results).unwrap();
+    let decoded: igwas::io::gwas::GwasResults = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded.variant_ids, gwas_results.variant_ids);
+    assert_eq!(decoded.beta_values, gwas_results.beta_values);
+    assert_eq!(decoded.se_values, gwas_results.se_values);
+    assert_eq!(decoded.sample_sizes, gwas_results.sample_sizes);
+    assert_eq!(decoded.genotype_variances, gwas_results.genotype_variances);
+    assert_eq!(decoded.skipped, vec![1]);
+    assert_eq!(decoded.annotations, gwas_results.annotations);
+
+    // IGwasResults, with confidence intervals
+    std::fs::write(&files[1], SMALL_B).unwrap();
+    let config = igwas::util::RuntimeConfig {
+        stats: igwas::stats::running::StatsConfig {
+            confidence_level: Some(0.95),
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    let results =
+        igwas::util::run_to_results(&proj_path, &cov_path, &files, 2, config, Default::default())
+            .unwrap()
+            .remove(0);
+    let json = serde_json::to_string(&results).unwrap();
+    let decoded: igwas::io::gwas::IGwasResults = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded.projection_ids, results.projection_ids);
+    assert_eq!(decoded.variant_ids, results.variant_ids);
+    assert_eq!(decoded.beta_values, results.beta_values);
+    assert_eq!(decoded.se_values, results.se_values);
+    assert_eq!(decoded.t_stat_values, results.t_stat_values);
+    assert_eq!(decoded.p_values, results.p_values);
+    assert_eq!(decoded.sample_sizes, results.sample_sizes);
+    assert!(decoded.ci_lower.is_some());
+    assert_eq!(decoded.ci_lower, results.ci_lower);
+    assert_eq!(decoded.ci_upper, results.ci_upper);
+
+    // ProcessingStats, with dense and sparse projections
+    let proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    let cov = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    for sparse_projection in [false, true] {
+        let config = igwas::stats::running::StatsConfig {
+            sparse_projection,
+            ..Default::default()
+        };
+        let running = igwas::stats::running::RunningSufficientStats::<f64>::new(
+            &proj, &cov, 2, 4, None, config,
+        )
+        .unwrap();
+        let stats = running.build_processing_stats();
+        let json = serde_json::to_string(&stats).unwrap();
+        let decoded: igwas::util::ProcessingStats<f64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.n_variants, stats.n_variants);
+        assert_eq!(decoded.fpv, stats.fpv);
+        assert_eq!(decoded.phenotype_id_to_idx, stats.phenotype_id_to_idx);
+        assert_eq!(decoded.n_covar, stats.n_covar);
+        let values = nalgebra::DVector::from_vec(vec![1.0, -2.0]);
+        for row in 0..2 {
+            assert_eq!(
+                decoded.proj.outer_row(&values, row),
+                stats.proj.outer_row(&values, row)
+            );
+        }
+    }
+
+    // Values must match the shape
+    let json = serde_json::to_string(&gwas_results).unwrap();
+    let tampered = json.replacen("\"nrows\":4", "\"nrows\":5", 1);
+    assert_ne!(json, tampered);
+    let err = serde_json::from_str::<igwas::io::gwas::GwasResults>(&tampered)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string()
+            .contains("expected 5 x 1 = 5 values, found 4"),
+        "{}",
+        err
+    );
+}
+
+fn results_with_t_stats(
+    projection_ids: &[&str],
+    t_stats: Vec<f32>,
+) -> igwas::io::gwas::IGwasResults {
+    let n = t_stats.len();
+    igwas::io::gwas::IGwasResults {
+        projection_ids: projection_ids.iter().map(|x| x.to_string()).collect(),
+        variant_ids: (0..n).map(|i| format!("rs{}", i)).collect(),
+        beta_values: nalgebra::DVector::from_element(n, 0.1),
+        se_values: nalgebra::DVector::from_element(n, 0.01),
+        t_stat_values: nalgebra::DVector::from_vec(t_stats),
+        p_values: nalgebra::DVector::from_element(n, 1.0),
+        sample_sizes: nalgebra::DVector::from_element(n, 100),
+        ci_lower: None,
+        ci_upper: None,
+        annotations: Default::default(),
+        heterogeneity: None,
+        effective_sample_size: None,
+    }
+}
+
+#[test]
+fn genomic_inflation_known_lambda() {
+    // Chi-square statistics 0, 0.002, ..., 2.0 have median 1.0, split over
+    // two chunks
+    let t_stats: Vec<f32> = (0..=1000).map(|k| (k as f32 * 0.002).sqrt()).collect();
+    let (first, second) = t_stats.split_at(400);
+    let mut inflation = igwas::stats::summary::GenomicInflation::new();
+    inflation.add(&results_with_t_stats(&["p1"; 400], first.to_vec()));
+    let mut second = second.to_vec();
+    second.push(f32::NAN);
+    inflation.add(&results_with_t_stats(&["p1"; 602], second));
+    // A projection whose statistics are all undefined is left out
+    inflation.add(&results_with_t_stats(&["p2"], vec![f32::NAN]));
+
+    let lambdas = inflation.lambdas();
+    assert_eq!(lambdas.len(), 1);
+    assert_eq!(lambdas[0].0, "p1");
+    let expected = 1.0 / 0.454_936_4;
+    assert!((lambdas[0].1 - expected).abs() < 0.003 * expected);
+}
+
+#[test]
+fn run_fn_lambda_gc() {
+    let dir = tempdir().unwrap();
+    let lambda_path = dir.path().join("lambda.tsv");
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 3,
+        output: igwas::io::gwas::OutputConfig {
+            lambda_gc_file: Some(lambda_path.to_str().unwrap().to_string()),
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    let records = run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&lambda_path)
+        .unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec!["projection_id", "lambda_gc"]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(|x| x.unwrap()).collect();
+    assert_eq!(rows.len(), 2);
+    for (row, projection) in rows.iter().zip(["p1", "p2"]) {
+        assert_eq!(&row[0], projection);
+        // The median of an even number of statistics lies between the
+        // middle two, up to the histogram's bin width
+        let mut chi2: Vec<f32> = records
+            .iter()
+            .filter(|x| &x[0] == projection)
+            .map(|x| x[4].parse::<f32>().unwrap().powi(2))
+            .collect();
+        chi2.sort_by(f32::total_cmp);
+        let median = row[1].parse::<f32>().unwrap() * 0.454_936_4;
+        let mid = chi2.len() / 2;
+        assert!(median >= chi2[mid - 1] - 1e-3 && median <= chi2[mid] + 1e-3);
+    }
+}
+
+#[test]
+fn run_fn_lambda_gc_with_checkpoint_error() {
+    let dir = tempdir().unwrap();
+    let config = igwas::util::RuntimeConfig {
+        checkpoint: Some(
+            dir.path()
+                .join("run.checkpoint")
+                .to_str()
+                .unwrap()
+                .to_string(),
+        ),
+        output: igwas::io::gwas::OutputConfig {
+            lambda_gc_file: Some(dir.path().join("lambda.tsv").to_str().unwrap().to_string()),
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    let result = run_small_study(dir.path(), [SMALL_A, SMALL_B], config);
+    assert!(result
+        .err()
+        .unwrap()
+        .to_string()
+        .contains("cannot be combined with checkpoints"));
+}
+
+#[test]
+fn run_fn_top_hits_match_brute_force() {
+    let dir = tempdir().unwrap();
+    let top_hits_path = dir.path().join("top_hits.tsv");
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 3,
+        output: igwas::io::gwas::OutputConfig {
+            top_hits_file: Some(top_hits_path.to_str().unwrap().to_string()),
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    let records = run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&top_hits_path)
+        .unwrap();
+    assert_eq!(
+        reader.headers().unwrap(),
+        vec!["projection_id", "variant_id", "neg_log_p"]
+    );
+    let rows: Vec<csv::StringRecord> = reader.records().map(|x| x.unwrap()).collect();
+    assert_eq!(rows.len(), 2);
+    for (row, projection) in rows.iter().zip(["p1", "p2"]) {
+        let best = records
+            .iter()
+            .filter(|x| &x[0] == projection)
+            .max_by(|x, y| {
+                let x: f32 = x[5].parse().unwrap();
+                x.total_cmp(&y[5].parse().unwrap())
+            })
+            .unwrap();
+        assert_eq!(&row[0], projection);
+        assert_eq!(&row[1], &best[1]);
+        let expected: f32 = best[5].parse().unwrap();
+        assert!((row[2].parse::<f32>().unwrap() - expected).abs() <= 1e-5 * expected);
+    }
+}
+
+#[test]
+fn top_hits_across_chunks() {
+    let mut top_hits = igwas::stats::summary::TopHits::new();
+    let mut first = results_with_t_stats(&["p1", "p2", "p1"], vec![1.0; 3]);
+    first.p_values = nalgebra::DVector::from_vec(vec![2.0, f32::NAN, 5.0]);
+    let mut second = results_with_t_stats(&["p1", "p2", "p2"], vec![1.0; 3]);
+    second.p_values = nalgebra::DVector::from_vec(vec![5.0, 1.0, 3.0]);
+    top_hits.add(&first);
+    top_hits.add(&second);
+
+    let hits: Vec<(&str, &str, f32)> = top_hits
+        .hits()
+        .iter()
+        .map(|x| (x.projection_id.as_str(), x.variant_id.as_str(), x.neg_log_p))
+        .collect();
+    // Ties keep the first variant seen
+    assert_eq!(hits, vec![("p1", "rs2", 5.0), ("p2", "rs2", 3.0)]);
+}
+
+#[test]
+fn running_stats_feature_weights() {
+    let dir = tempdir().unwrap();
+    let (proj_path, cov_path, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    let cov = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    let column_names = Default::default();
+    let gwas_results: Vec<_> = files
+        .iter()
+        .map(|x| igwas::io::gwas::read_gwas_results(x, &column_names, 0, usize::MAX).unwrap())
+        .collect();
+    let compute = |proj: &igwas::io::matrix::LabeledMatrix, weights: Option<Vec<f32>>| {
+        let weights = weights.map(nalgebra::DVector::from_vec);
+        let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+            proj,
+            &cov,
+            2,
+            4,
+            weights.as_ref(),
+            Default::default(),
+        )
+        .unwrap();
+        for (phenotype, results) in ["a.tsv", "b.tsv"].into_iter().zip(&gwas_results) {
+            let update = running
+                .build_processing_stats()
+                .format_update(phenotype, results);
+            running.update(&update).unwrap();
+        }
+        running.compute_final_stats().unwrap()
+    };
+    let unweighted = compute(&proj, None);
+
+    // Unit weights reproduce the unweighted results
+    let ones = compute(&proj, Some(vec![1.0, 1.0]));
+    assert_eq!(ones.beta_values, unweighted.beta_values);
+    assert_eq!(ones.se_values, unweighted.se_values);
+    assert_eq!(ones.p_values, unweighted.p_values);
+
+    // Uniform weights scale beta and its standard error alike, leaving the
+    // test statistics unchanged
+    let twos = compute(&proj, Some(vec![2.0, 2.0]));
+    for i in 0..unweighted.beta_values.len() {
+        assert!((twos.beta_values[i] - 2.0 * unweighted.beta_values[i]).abs() < 1e-5);
+        assert!((twos.se_values[i] - 2.0 * unweighted.se_values[i]).abs() < 1e-5);
+        assert!((twos.t_stat_values[i] - unweighted.t_stat_values[i]).abs() < 1e-4);
+        assert!((twos.p_values[i] - unweighted.p_values[i]).abs() < 1e-4);
+    }
+
+    // Other weights are the same as scaling the rows of the projection
+    let mut scaled = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    scaled.matrix.row_mut(0).scale_mut(3.0);
+    scaled.matrix.row_mut(1).scale_mut(0.5);
+    let weighted = compute(&proj, Some(vec![3.0, 0.5]));
+    let expected = compute(&scaled, None);
+    assert_eq!(weighted.beta_values, expected.beta_values);
+    assert_eq!(weighted.se_values, expected.se_values);
+
+    let err = igwas::stats::running::RunningSufficientStats::<f32>::new(
+        &proj,
+        &cov,
+        2,
+        4,
+        Some(&nalgebra::DVector::from_vec(vec![1.0])),
+        Default::default(),
+    )
+    .err()
+    .unwrap()
+    .to_string();
+    assert_eq!(
+        err,
+        "Expected 2 feature weights, one per row of the projection matrix, got 1"
+    );
+}
+
+#[test]
+fn running_stats_non_finite_matrices() {
+    let dir = tempdir().unwrap();
+    let (proj_path, cov_path, _) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let new = |proj: &igwas::io::matrix::LabeledMatrix, cov: &igwas::io::matrix::LabeledMatrix| {
+        igwas::stats::running::RunningSufficientStats::<f32>::new(
+            proj,
+            cov,
+            2,
+            4,
+            None,
+            Default::default(),
+        )
+        .map(|_| ())
+    };
+
+    let mut proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    let mut cov = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    assert!(new(&proj, &cov).is_ok());
+
+    cov.matrix[(1, 0)] = f32::NAN;
+    let err = new(&proj, &cov).err().unwrap();
+    assert!(
+        err.to_string().contains(
+            "covariance matrix has a non-finite value, NaN, in row b.tsv and column a.tsv"
+        ),
+        "{}",
+        err
+    );
+
+    cov.matrix[(1, 0)] = 0.3;
+    proj.matrix[(0, 1)] = f32::INFINITY;
+    let err = new(&proj, &cov).err().unwrap();
+    assert!(
+        err.to_string()
+            .contains("projection matrix has a non-finite value, inf, in row a.tsv and column p2"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn running_stats_asymmetric_covariance() {
+    let dir = tempdir().unwrap();
+    let (proj_path, cov_path, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    let symmetric = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    let mut asymmetric = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    asymmetric.matrix[(1, 0)] = 0.3004;
+    let column_names = Default::default();
+    let gwas_results: Vec<_> = files
+        .iter()
+        .map(|x| igwas::io::gwas::read_gwas_results(x, &column_names, 0, usize::MAX).unwrap())
+        .collect();
+    let compute = |cov: &igwas::io::matrix::LabeledMatrix,
+                   symmetrize_covariance: bool,
+                   max_covariance_asymmetry: Option<f32>| {
+        let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+            &proj,
+            cov,
+            2,
+            4,
+            None,
+            igwas::stats::running::StatsConfig {
+                symmetrize_covariance,
+                max_covariance_asymmetry,
+                ..Default::default()
+            },
+        )?;
+        for (phenotype, results) in ["a.tsv", "b.tsv"].into_iter().zip(&gwas_results) {
+            let update = running
+                .build_processing_stats()
+                .format_update(phenotype, results);
+            running.update(&update)?;
+        }
+        running.compute_final_stats()
+    };
+
+    // The asymmetry is 0.0004 / sqrt(1 * 2)
+    let (asymmetry, i, j) = igwas::stats::running::covariance_asymmetry(&asymmetric.matrix);
+    assert!(
+        (asymmetry - 0.0004 / 2f32.sqrt()).abs() < 1e-6,
+        "{}",
+        asymmetry
+    );
+    assert_eq!((i, j), (1, 0));
+    assert_eq!(
+        igwas::stats::running::covariance_asymmetry(&symmetric.matrix).0,
+        0.0
+    );
+
+    // Symmetrizing uses the mean of 0.3 and 0.3004. The partial variances
+    // of the projections are quadratic forms, which do not change, so the
+    // results are the same either way up to rounding.
+    let mut mean = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    mean.matrix[(0, 1)] = 0.3002;
+    mean.matrix[(1, 0)] = 0.3002;
+    let expected = compute(&mean, false, None).unwrap();
+    let symmetrized = compute(&asymmetric, true, None).unwrap();
+    assert_eq!(symmetrized.beta_values, expected.beta_values);
+    assert_eq!(symmetrized.se_values, expected.se_values);
+    let unchanged = compute(&asymmetric, false, None).unwrap();
+    for (x, y) in unchanged.se_values.iter().zip(expected.se_values.iter()) {
+        assert!((x - y).abs() < 1e-6 * y.abs(), "{} != {}", x, y);
+    }
+
+    // Too much asymmetry is an error, whether or not it is symmetrized
+    for symmetrize_covariance in [false, true] {
+        let err = compute(&asymmetric, symmetrize_covariance, Some(1e-4))
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("between rows b.tsv and a.tsv"),
+            "{}",
+            err
+        );
+    }
+    assert!(compute(&asymmetric, false, Some(1e-3)).is_ok());
+    assert!(compute(&symmetric, false, Some(0.0)).is_ok());
+}
+
+#[test]
+fn running_stats_normalized_projections() {
+    let dir = tempdir().unwrap();
+    let (proj_path, cov_path, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let proj = igwas::io::matrix::read_labeled_matrix(&proj_path).unwrap();
+    let cov = igwas::io::matrix::read_labeled_matrix(&cov_path).unwrap();
+    let column_names = Default::default();
+    let gwas_results: Vec<_> = files
+        .iter()
+        .map(|x| igwas::io::gwas::read_gwas_results(x, &column_names, 0, usize::MAX).unwrap())
+        .collect();
+    let compute = |normalize_projections: bool, restore_projection_scale: bool| {
+        let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+            &proj,
+            &cov,
+            2,
+            4,
+            None,
+            igwas::stats::running::StatsConfig {
+                confidence_level: Some(0.95),
+                heterogeneity: true,
+                normalize_projections,
+                restore_projection_scale,
+                ..Default::default()
+            },
+        )?;
+        for (phenotype, results) in ["a.tsv", "b.tsv"].into_iter().zip(&gwas_results) {
+            let update = running
+                .build_processing_stats()
+                .format_update(phenotype, results);
+            running.update(&update)?;
+        }
+        let scales = running.projection_scales().cloned();
+        running.compute_final_stats().map(|x| (x, scales))
+    };
+    let (original, scales) = compute(false, false).unwrap();
+    assert!(scales.is_none());
+
+    // Columns (0.5, -1.5) and (1.0, 0.25) of the projection matrix
+    let (normalized, scales) = compute(true, false).unwrap();
+    let scales = scales.unwrap();
+    let expected = [2.5f32.sqrt(), 1.0625f32.sqrt()];
+    for (scale, expected) in scales.iter().zip(expected) {
+        assert!((scale - expected).abs() < 1e-6);
+    }
+
+    let (restored, _) = compute(true, true).unwrap();
+    let close = |x: f32, y: f32| (x - y).abs() <= 1e-5 * (1.0 + y.abs());
+    for i in 0..original.beta_values.len() {
+        let scale = scales[i / 4];
+        // Normalizing scales beta and its standard error, but not the test
+        // statistics
+        assert!(close(
+            normalized.beta_values[i] * scale,
+            original.beta_values[i]
+        ));
+        assert!(close(
+            normalized.se_values[i] * scale,
+            original.se_values[i]
+        ));
+        assert!(close(
+            normalized.t_stat_values[i],
+            original.t_stat_values[i]
+        ));
+        assert!(close(normalized.p_values[i], original.p_values[i]));
+
+        // Restoring the scale reproduces the original results
+        assert!(close(restored.beta_values[i], original.beta_values[i]));
+        assert!(close(restored.se_values[i], original.se_values[i]));
+        assert!(close(restored.t_stat_values[i], original.t_stat_values[i]));
+        assert!(close(restored.p_values[i], original.p_values[i]));
+        for (x, y) in [
+            (&restored.ci_lower, &original.ci_lower),
+            (&restored.ci_upper, &original.ci_upper),
+            (&restored.heterogeneity, &original.heterogeneity),
+        ] {
+            assert!(close(x.as_ref().unwrap()[i], y.as_ref().unwrap()[i]));
+        }
+    }
+
+    let err = compute(false, true).err().unwrap().to_string();
+    assert_eq!(
+        err,
+        "Restoring the projection scale requires normalizing the projections"
+    );
+}
+
+#[test]
+fn run_fn_projection_subset() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    std::fs::write(
+        &proj,
+        "id,p1,p2,p3,p4\na.tsv,0.5,1.0,-0.3,2.0\nb.tsv,-1.5,0.25,0.8,0.1\n",
+    )
+    .unwrap();
+    let run = |projection_ids: Option<Vec<String>>, name: &str| {
+        let output = dir.path().join(name);
+        let config = igwas::util::RuntimeConfig {
+            chunksize: 3,
+            stats: igwas::stats::running::StatsConfig {
+                projection_ids,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        };
+        igwas::util::run(
+            &proj,
+            &cov,
+            &files,
+            output.to_str().unwrap(),
+            2,
+            config,
+            Default::default(),
+        )
+        .map(|_| read_output_records(output.to_str().unwrap()))
+    };
+    let full = run(None, "full.tsv").unwrap();
+    let subset = run(Some(vec!["p4".to_string(), "p2".to_string()]), "subset.tsv").unwrap();
+
+    // Each chunk has the rows of the selected projections, in the order given
+    let expected: Vec<&csv::StringRecord> = full
+        .chunks(4 * 3)
+        .flat_map(|chunk| {
+            let n_variants = chunk.len() / 4;
+            chunk[3 * n_variants..]
+                .iter()
+                .chain(&chunk[n_variants..2 * n_variants])
+        })
+        .collect();
+    assert_eq!(subset.len(), 8);
+    assert_eq!(subset.iter().collect::<Vec<_>>(), expected);
+
+    let err = run(Some(vec!["p5".to_string()]), "unknown.tsv")
+        .err()
+        .unwrap()
+        .to_string();
+    assert_eq!(err, "Cannot select unknown projection p5");
+}
+
+#[test]
+fn run_fn_append_output() {
+    let dir = tempdir().unwrap();
+    let first = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    assert_eq!(first.len(), 8);
+
+    // A second run, e.g. of another chromosome, appended to the first
+    let append_config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        output: igwas::io::gwas::OutputConfig {
+            append: true,
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    let combined = run_small_study(dir.path(), [SMALL_A, SMALL_B], append_config()).unwrap();
+    assert_eq!(combined.len(), 16);
+    assert_eq!(&combined[..8], &first[..]);
+    let text = std::fs::read_to_string(dir.path().join("out.tsv")).unwrap();
+    assert_eq!(text.matches("phenotype_id").count(), 1);
+
+    // Without --append, the output is replaced
+    let replaced = run_small_study(dir.path(), [SMALL_A, SMALL_B], small_runtime_config()).unwrap();
+    assert_eq!(replaced, first);
+
+    // Appending needs an existing file
+    std::fs::remove_file(dir.path().join("out.tsv")).unwrap();
+    let err = run_small_study(dir.path(), [SMALL_A, SMALL_B], append_config())
+        .err()
+        .unwrap();
+    assert!(format!("{:#}", err).contains("Cannot append to"));
+}
+
+#[test]
+fn run_fn_timing_events() {
+    use igwas::util::{TimingEvent, TimingStage};
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir().unwrap();
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config()).unwrap();
+    let events: Arc<Mutex<Vec<TimingEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let timed_config = igwas::util::RuntimeConfig {
+        timing: Some(Box::new({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        })),
+        ..config()
+    };
+    let records = run_small_study(dir.path(), [SMALL_A, SMALL_B], timed_config).unwrap();
+    assert_eq!(records, expected);
+
+    // Two stages of each of two chunks, of 3 and 1 variants
+    let events = events.lock().unwrap();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|x| (x.stage, x.chunk_index, x.start_line, x.end_line, x.rows))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (TimingStage::Accumulate, 0, 0, 3, 6),
+            (TimingStage::FinalStats, 0, 0, 3, 6),
+            (TimingStage::Accumulate, 1, 3, 4, 2),
+            (TimingStage::FinalStats, 1, 3, 4, 2),
+        ]
+    );
+    for event in events.iter() {
+        let files: Vec<&str> = event
+            .file_read_times
+            .iter()
+            .map(|(path, _)| path.rsplit('/').next().unwrap())
+            .collect();
+        match event.stage {
+            TimingStage::Accumulate => assert_eq!(files, vec!["a.tsv", "b.tsv"]),
+            TimingStage::FinalStats => assert!(files.is_empty()),
+        }
+    }
+    assert!(events[0]
+        .to_string()
+        .starts_with("stage=accumulate chunk=0 start_line=0 end_line=3 rows=6 wall_ms="));
+}
+
+#[test]
+fn read_gwas_results_mmap_matches_buffered() {
+    let dir = tempdir().unwrap();
+    let args = utils::setup_test(dir.path(), 100, 1000, 10, 1, 1, false);
+    let colnames = igwas::io::gwas::ColumnSpec {
+        variant_id: args.variant_id,
+        beta: args.beta,
+        se: args.std_error,
+        sample_size: args.sample_size,
+        ..Default::default()
+    };
+
+    // CRLF line endings, and a last line without a terminator
+    let text = std::fs::read_to_string(&args.gwas_results[0]).unwrap();
+    let crlf_path = dir.path().join("crlf.tsv");
+    std::fs::write(&crlf_path, text.replace('\n', "\r\n")).unwrap();
+    let unterminated_path = dir.path().join("unterminated.tsv");
+    std::fs::write(&unterminated_path, text.trim_end()).unwrap();
+
+    for path in [
+        args.gwas_results[0].as_str(),
+        crlf_path.to_str().unwrap(),
+        unterminated_path.to_str().unwrap(),
+    ] {
+        let mut offset = None;
+        for start in (0..1000).step_by(300) {
+            let end = std::cmp::min(start + 300, 1000);
+            let (expected, expected_next) =
+                igwas::io::gwas::read_gwas_results_from(path, &colnames, start, end, None).unwrap();
+            // Both with the offset of the previous chunk and from the top
+            for start_offset in [offset, None] {
+                let (observed, next) = igwas::io::gwas::read_gwas_results_mmap(
+                    path,
+                    &colnames,
+                    start,
+                    end,
+                    start_offset,
+                    1,
+                )
+                .unwrap();
+                assert_eq!(expected.variant_ids, observed.variant_ids);
+                assert_eq!(expected.beta_values, observed.beta_values);
+                assert_eq!(expected.se_values, observed.se_values);
+                assert_eq!(expected.sample_sizes, observed.sample_sizes);
+                // With CRLF, the buffered reader stops between the \r and the \n
+                assert_eq!(next.unwrap().line, expected_next.unwrap().line);
+                offset = next;
+            }
+        }
+    }
+
+    let compressed = utils::setup_test(&dir.path().join("zst"), 100, 10, 10, 1, 1, true);
+    let err = igwas::io::gwas::read_gwas_results_mmap(
+        &compressed.gwas_results[0],
+        &colnames,
+        0,
+        10,
+        None,
+        1,
+    )
+    .err()
+    .unwrap()
+    .to_string();
+    assert!(
+        err.starts_with("Cannot memory-map compressed file"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_mmap() {
+    let dir = tempdir().unwrap();
+    let config = |mmap| igwas::util::RuntimeConfig {
+        chunksize: 3,
+        mmap,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(false)).unwrap();
+    let observed = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(true)).unwrap();
+    assert_eq!(observed, expected);
+}
+
+/// A file as written on Windows, with a byte order mark and CRLF line
+/// endings
+fn windows_text(text: &str) -> String {
+    format!("\u{feff}{}", text.replace('\n', "\r\n"))
+}
+
+#[test]
+fn read_gwas_results_bom_crlf() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("gwas.tsv");
+    std::fs::write(&path, SMALL_B).unwrap();
+    let expected = igwas::io::gwas::read_gwas_results(
+        path.to_str().unwrap(),
+        &Default::default(),
+        0,
+        usize::MAX,
+    )
+    .unwrap();
+
+    // The last field of the header and rows is the sample size, which would
+    // keep a stray carriage return
+    let column_names = igwas::io::gwas::ColumnSpec {
+        effect_allele: Some("A1".to_string()),
+        ..Default::default()
+    };
+    let text = SMALL_B
+        .lines()
+        .enumerate()
+        .map(|(i, x)| format!("{}\t{}\n", x, if i == 0 { "A1" } else { "A" }))
+        .collect::<String>();
+    for (text, column_names) in [
+        (windows_text(SMALL_B), Default::default()),
+        (windows_text(&text), column_names),
+    ] {
+        std::fs::write(&path, text).unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(igwas::io::gwas::count_lines(path).unwrap(), 4);
+        igwas::io::gwas::validate_columns(path, &column_names).unwrap();
+        let observed =
+            igwas::io::gwas::read_gwas_results(path, &column_names, 0, usize::MAX).unwrap();
+        if let Some(alleles) = &observed.annotations.effect_allele {
+            assert!(alleles.iter().all(|x| x == "A"));
+        }
+        assert_eq!(observed.variant_ids, expected.variant_ids);
+        assert_eq!(observed.beta_values, expected.beta_values);
+        assert_eq!(observed.se_values, expected.se_values);
+        assert_eq!(observed.sample_sizes, expected.sample_sizes);
+    }
+}
+
+#[test]
+fn run_fn_bom_crlf() {
+    let dir = tempdir().unwrap();
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config).unwrap();
+    let windows_b = windows_text(SMALL_B);
+    for (mmap, parse_threads, align_by_id) in [
+        (false, 1, false),
+        (false, 2, false),
+        (true, 1, false),
+        (false, 1, true),
+    ] {
+        let config = igwas::util::RuntimeConfig {
+            chunksize: 3,
+            mmap,
+            parse_threads,
+            align_by_id,
+            ..small_runtime_config()
+        };
+        let observed = run_small_study(dir.path(), [SMALL_A, &windows_b], config).unwrap();
+        assert_eq!(
+            observed, expected,
+            "{} {} {}",
+            mmap, parse_threads, align_by_id
+        );
+    }
+
+    // A phenotype map written on Windows
+    let (proj, cov, _) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let mut gwas_files = Vec::new();
+    for (name, text) in ["x.tsv", "y.tsv"].into_iter().zip([SMALL_A, SMALL_B]) {
+        let path = dir.path().join(name);
+        std::fs::write(&path, text).unwrap();
+        gwas_files.push(path.to_str().unwrap().to_string());
+    }
+    let map_path = dir.path().join("phenotypes.tsv");
+    let map = format!("{}\ta.tsv\n{}\tb.tsv\n", gwas_files[0], gwas_files[1]);
+    std::fs::write(&map_path, windows_text(&map)).unwrap();
+    let output = dir.path().join("mapped.tsv");
+    igwas::util::run(
+        &proj,
+        &cov,
+        &gwas_files,
+        output.to_str().unwrap(),
+        2,
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            phenotype_map: Some(map_path.to_str().unwrap().to_string()),
+            ..small_runtime_config()
+        },
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(read_output_records(output.to_str().unwrap()), expected);
+}
+
+#[test]
+fn run_fn_whitespace_delimited() {
+    let dir = tempdir().unwrap();
+    let config = |mmap, parse_threads, align_by_id| igwas::util::RuntimeConfig {
+        chunksize: 3,
+        mmap,
+        parse_threads,
+        align_by_id,
+        ..small_runtime_config()
+    };
+    let expected =
+        run_small_study(dir.path(), [SMALL_A, SMALL_B], config(false, 1, false)).unwrap();
+
+    // Columns right-aligned with runs of spaces, as written by Plink 1.9
+    let aligned: String = SMALL_B
+        .lines()
+        .map(|line| {
+            let fields: Vec<String> = line.split('\t').map(|x| format!("{:>8}", x)).collect();
+            format!("{}  \n", fields.join("   "))
+        })
+        .collect();
+    let column_names = || igwas::io::gwas::ColumnSpec {
+        whitespace_delimited: true,
+        ..Default::default()
+    };
+    for (mmap, parse_threads, align_by_id) in [
+        (false, 1, false),
+        (false, 2, false),
+        (true, 1, false),
+        (false, 1, true),
+    ] {
+        let observed = run_small_study_with(
+            dir.path(),
+            [SMALL_A, &aligned],
+            config(mmap, parse_threads, align_by_id),
+            column_names(),
+        )
+        .unwrap();
+        assert_eq!(
+            observed, expected,
+            "{} {} {}",
+            mmap, parse_threads, align_by_id
+        );
+    }
+
+    // Split by single spaces, the aligned rows have varying numbers of
+    // empty fields
+    let err = format!(
+        "{:#}",
+        run_small_study(dir.path(), [SMALL_A, &aligned], config(false, 1, false)).unwrap_err()
+    );
+    assert!(err.contains("but the previous record has"), "{}", err);
+}
+
+#[test]
+fn read_gwas_results_parallel_matches_sequential() {
+    let dir = tempdir().unwrap();
+    let column_names = igwas::io::gwas::ColumnSpec {
+        na_policy: igwas::io::gwas::NaPolicy::ZeroWeight,
+        effect_allele: Some("A1".to_string()),
+        ..Default::default()
+    };
+    // Missing values scattered through the file, to check that their
+    // indices are carried across the parts
+    let mut text = String::from("ID\tA1\tBETA\tSE\tOBS_CT\n");
+    for i in 0..1000 {
+        let beta = if i % 37 == 5 {
+            "NA".to_string()
+        } else {
+            format!("{}", (i as f32 - 500.0) / 997.0)
+        };
+        text.push_str(&format!("rs{}\tA\t{}\t0.1\t{}\n", i, beta, 900 + i % 17));
+    }
+    let path = dir.path().join("gwas.tsv");
+    std::fs::write(&path, text).unwrap();
+    let path = path.to_str().unwrap();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(4)
+        .build()
+        .unwrap();
+    for n_parts in [1, 2, 3, 7, 64, 5000] {
+        let mut parallel_offset = None;
+        let mut mmap_offset = None;
+        for start in (0..1000).step_by(300) {
+            let end = std::cmp::min(start + 300, 1000);
+            let (expected, expected_next) =
+                igwas::io::gwas::read_gwas_results_from(path, &column_names, start, end, None)
+                    .unwrap();
+            let (parallel, parallel_next) = pool.install(|| {
+                igwas::io::gwas::read_gwas_results_parallel(
+                    path,
+                    &column_names,
+                    start,
+                    end,
+                    parallel_offset,
+                    n_parts,
+                )
+                .unwrap()
+            });
+            let (mapped, mmap_next) = pool.install(|| {
+                igwas::io::gwas::read_gwas_results_mmap(
+                    path,
+                    &column_names,
+                    start,
+                    end,
+                    mmap_offset,
+                    n_parts,
+                )
+                .unwrap()
+            });
+            for observed in [&parallel, &mapped] {
+                assert_eq!(observed.variant_ids, expected.variant_ids);
+                assert_eq!(observed.beta_values, expected.beta_values);
+                assert_eq!(observed.se_values, expected.se_values);
+                assert_eq!(observed.sample_sizes, expected.sample_sizes);
+                assert_eq!(observed.zero_weight, expected.zero_weight);
+                assert_eq!(observed.annotations, expected.annotations);
+            }
+            assert!(!expected.zero_weight.is_empty());
+            assert_eq!(parallel_next, expected_next);
+            assert_eq!(mmap_next, expected_next);
+            parallel_offset = parallel_next;
+            mmap_offset = mmap_next;
+        }
+    }
+}
+
+#[test]
+fn run_fn_parse_threads() {
+    let dir = tempdir().unwrap();
+    let config = |mmap, parse_threads| igwas::util::RuntimeConfig {
+        chunksize: 3,
+        mmap,
+        parse_threads,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(false, 1)).unwrap();
+    for mmap in [false, true] {
+        let observed = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(mmap, 3)).unwrap();
+        assert_eq!(observed, expected);
+    }
+    let err = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(false, 0))
+        .err()
+        .unwrap()
+        .to_string();
+    assert_eq!(err, "Number of parsing threads must be nonzero");
+}
+
+#[test]
+fn run_fn_assume_aligned() {
+    let dir = tempdir().unwrap();
+    let config = |assume_aligned| igwas::util::RuntimeConfig {
+        chunksize: 3,
+        assume_aligned,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(false)).unwrap();
+    let observed = run_small_study(dir.path(), [SMALL_A, SMALL_B], config(true)).unwrap();
+    assert_eq!(observed, expected);
+}
+
+#[test]
+fn run_fn_chromosome_filter() {
+    let dir = tempdir().unwrap();
+    let ids = ["1:100:A:G", "chrX:200:C:T", "1:300:G:A", "2:5:T:C"];
+    let with_ids = |text: &str| -> String {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| match i {
+                0 => format!("{}\tCHR\n", line),
+                i => {
+                    let id = ids[i - 1];
+                    let rest = line.split_once('\t').unwrap().1;
+                    format!("{}\t{}\t{}\n", id, rest, id.split(':').next().unwrap())
+                }
+            })
+            .collect()
+    };
+    let files = [with_ids(SMALL_A), with_ids(SMALL_B)];
+    let run = |chromosome_filter, chromosome_column| {
+        run_small_study_with(
+            dir.path(),
+            [&files[0], &files[1]],
+            igwas::util::RuntimeConfig {
+                chunksize: 3,
+                ..small_runtime_config()
+            },
+            igwas::io::gwas::ColumnSpec {
+                chromosome_filter,
+                chromosome_column,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    };
+    let all = run(None, None);
+
+    // From the variant id, and from a column, with or without a chr prefix
+    for (chromosomes, column) in [(["1"], None), (["chr1"], Some(String::from("CHR")))] {
+        let records = run(
+            Some(igwas::io::gwas::ChromosomeFilter {
+                chromosomes: chromosomes.map(String::from).to_vec(),
+            }),
+            column,
+        );
+        let expected: Vec<&csv::StringRecord> =
+            all.iter().filter(|x| x[1].starts_with("1:")).collect();
+        assert_eq!(expected.len(), 4);
+        assert_eq!(records.iter().collect::<Vec<_>>(), expected);
+    }
+}
+
+#[test]
+fn run_fn_min_maf() {
+    let dir = tempdir().unwrap();
+    let with_freqs = |text: &str, freqs: [&str; 4]| -> String {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| match i {
+                0 => format!("{}\tFREQ\n", line),
+                i => format!("{}\t{}\n", line, freqs[i - 1]),
+            })
+            .collect()
+    };
+    // rs2 is rare in the first file only, and rs3 in the second only
+    let files = [
+        with_freqs(SMALL_A, ["0.3", "0.99", "0.2", "0.5"]),
+        with_freqs(SMALL_B, ["0.3", "0.5", "0.004", "0.6"]),
+    ];
+    let run = |min_maf, allele_freq: Option<&str>| {
+        run_small_study_with(
+            dir.path(),
+            [&files[0], &files[1]],
+            igwas::util::RuntimeConfig {
+                chunksize: 3,
+                ..small_runtime_config()
+            },
+            igwas::io::gwas::ColumnSpec {
+                allele_freq: allele_freq.map(String::from),
+                min_maf,
+                ..Default::default()
+            },
+        )
+    };
+    let all = run(None, Some("FREQ")).unwrap();
+    let filtered = run(Some(0.05), Some("FREQ")).unwrap();
+    let expected: Vec<&csv::StringRecord> = all
+        .iter()
+        .filter(|x| &x[1] == "rs1" || &x[1] == "rs4")
+        .collect();
+    assert_eq!(expected.len(), 4);
+    assert_eq!(filtered.iter().collect::<Vec<_>>(), expected);
+
+    let err = format!("{:#}", run(Some(0.05), None).unwrap_err());
+    assert!(
+        err.contains("allele frequency column is required"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_returns_summary() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    // Phenotypes are reported in the order of the projection matrix
+    std::fs::write(
+        &proj,
+        "id,p1,p2,p3\nb.tsv,-1.5,0.25,1.0\na.tsv,0.5,1.0,0.0\n",
+    )
+    .unwrap();
+    let output = dir.path().join("out.tsv");
+    let summary = igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        output.to_str().unwrap(),
+        2,
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            ..small_runtime_config()
+        },
+        Default::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        summary,
+        igwas::util::RunSummary {
+            phenotypes: vec!["b.tsv".to_string(), "a.tsv".to_string()],
+            phenotype_files: vec![files[1].clone(), files[0].clone()],
+            num_variants: 4,
+            num_chunks: 2,
+            num_projections: 3,
+        }
+    );
+    assert_eq!(read_output_records(output.to_str().unwrap()).len(), 4 * 3);
+}
+
+#[test]
+fn run_fn_multiple_covariances() {
+    use igwas::util::{ProgressEvent, ProgressStage};
+    use std::sync::{Arc, Mutex};
+
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let (proj, cov, files) = write_small_study(path, [SMALL_A, SMALL_B]);
+    let cov2 = path.join("cov2.csv").to_str().unwrap().to_string();
+    std::fs::write(&cov2, "id,b.tsv,a.tsv\nb.tsv,0.8,-0.2\na.tsv,-0.2,1.5\n").unwrap();
+    let output = |name: &str| path.join(name).to_str().unwrap().to_string();
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+
+    // Each covariance matrix on its own
+    let mut expected = Vec::new();
+    for (cov, name) in [(&cov, "single1.tsv"), (&cov2, "single2.tsv")] {
+        igwas::util::run(
+            &proj,
+            cov,
+            &files,
+            &output(name),
+            2,
+            config(),
+            Default::default(),
+        )
+        .unwrap();
+        expected.push(read_output_records(&output(name)));
+    }
+    assert_ne!(expected[0], expected[1]);
+
+    // Both at once, reading each file once per chunk
+    let events: Arc<Mutex<Vec<ProgressEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let summary = igwas::util::run_covariances(
+        &proj,
+        &[(&cov, &output("out1.tsv")), (&cov2, &output("out2.tsv"))],
+        &files,
+        2,
+        igwas::util::RuntimeConfig {
+            progress: Some(Box::new({
+                let events = events.clone();
+                move |event| events.lock().unwrap().push(event)
+            })),
+            ..config()
+        },
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(summary.num_chunks, 2);
+    let files_read = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|x| x.stage == ProgressStage::File)
+        .count();
+    assert_eq!(files_read, 2 * 2);
+    assert_eq!(read_output_records(&output("out1.tsv")), expected[0]);
+    assert_eq!(read_output_records(&output("out2.tsv")), expected[1]);
+
+    // The same from the command line
+    let mut cmd = Command::cargo_bin("igwas").unwrap();
+    cmd.args(["-p", &proj, "-c", &cov, "-o", &output("cli1.tsv")])
+        .args(["--extra-covariance", &cov2, &output("cli2.tsv")])
+        .arg("-g")
+        .args(&files)
+        .args(["--num-covar", "2", "--chunksize", "3", "--quiet"]);
+    cmd.assert().success();
+    assert_eq!(read_output_records(&output("cli1.tsv")), expected[0]);
+    assert_eq!(read_output_records(&output("cli2.tsv")), expected[1]);
+
+    let run = |outputs: &[(&str, &str)], config| {
+        igwas::util::run_covariances(&proj, outputs, &files, 2, config, Default::default())
+    };
+    let err = run(
+        &[(&cov, &output("same.tsv")), (&cov2, &output("same.tsv"))],
+        config(),
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("more than one covariance matrix"),
+        "{:#}",
+        err
+    );
+    let err = run(
+        &[(&cov, &output("out1.tsv")), (&cov2, &output("out2.tsv"))],
+        igwas::util::RuntimeConfig {
+            checkpoint: Some(output("checkpoint.txt")),
+            ..config()
+        },
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("describe a single output"),
+        "{:#}",
+        err
+    );
+}
+
+#[cfg(feature = "manifest")]
+#[test]
+fn run_fn_manifest() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    // Phenotype files are listed in the order of the projection matrix
+    std::fs::write(&proj, "id,p1,p2\nb.tsv,-1.5,0.25\na.tsv,0.5,1.0\n").unwrap();
+    let output = dir.path().join("out.tsv");
+    let run = |manifest| {
+        igwas::util::run(
+            &proj,
+            &cov,
+            &files,
+            output.to_str().unwrap(),
+            2,
+            igwas::util::RuntimeConfig {
+                chunksize: 3,
+                num_threads: 2,
+                output: igwas::io::gwas::OutputConfig {
+                    manifest,
+                    ..Default::default()
+                },
+                ..small_runtime_config()
+            },
+            Default::default(),
+        )
+    };
+
+    // The manifest is opt-in
+    run(false).unwrap();
+    let manifest_path = dir.path().join("out.tsv.manifest.json");
+    assert!(!manifest_path.exists());
+
+    run(true).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+    assert_eq!(manifest["igwas_version"], env!("CARGO_PKG_VERSION"));
+    let timestamp = manifest["timestamp"].as_str().unwrap();
+    assert!(humantime::parse_rfc3339(timestamp).is_ok(), "{}", timestamp);
+    assert_eq!(manifest["projection_matrix"]["path"], proj.as_str());
+    assert_eq!(manifest["covariance_matrix"]["path"], cov.as_str());
+    for key in ["projection_matrix", "covariance_matrix"] {
+        let hash = manifest[key]["fnv1a_64"].as_str().unwrap();
+        assert_eq!(hash.len(), 16);
+        assert!(hash.chars().all(|x| x.is_ascii_hexdigit()));
+    }
+    assert_ne!(
+        manifest["projection_matrix"]["fnv1a_64"],
+        manifest["covariance_matrix"]["fnv1a_64"]
+    );
+    assert_eq!(
+        manifest["gwas_files"],
+        serde_json::json!([
+            {"phenotype": "b.tsv", "path": files[1]},
+            {"phenotype": "a.tsv", "path": files[0]},
+        ])
+    );
+    assert_eq!(manifest["output"], output.to_str().unwrap());
+    assert_eq!(manifest["num_covar"], 2);
+    assert_eq!(manifest["chunksize"], 3);
+    assert_eq!(manifest["num_threads"], 2);
+    assert_eq!(manifest["num_variants"], 4);
+    assert_eq!(manifest["num_projections"], 2);
+
+    // A changed matrix changes its hash
+    let hash = manifest["covariance_matrix"]["fnv1a_64"].clone();
+    std::fs::write(&cov, "id,a.tsv,b.tsv\na.tsv,1.0,0.2\nb.tsv,0.2,2.0\n").unwrap();
+    run(true).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+    assert_ne!(manifest["covariance_matrix"]["fnv1a_64"], hash);
+}
+
+#[cfg(not(feature = "manifest"))]
+#[test]
+fn manifest_needs_the_manifest_feature() {
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    let output = dir.path().join("out.tsv");
+    let err = igwas::util::run(
+        &proj,
+        &cov,
+        &files,
+        output.to_str().unwrap(),
+        2,
+        igwas::util::RuntimeConfig {
+            output: igwas::io::gwas::OutputConfig {
+                manifest: true,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        },
+        Default::default(),
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("manifest feature"));
+    assert!(!output.exists());
+}
+
+#[test]
+fn run_fn_quoted_csv_fields() {
+    let dir = tempdir().unwrap();
+    // Ids holding the delimiter are quoted, as are some header names
+    let quoted = |text: &str| -> String {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                match i {
+                    0 => format!(
+                        "\"{}\",{},\"{}\",{}\n",
+                        fields[0], fields[1], fields[2], fields[3]
+                    ),
+                    _ => format!("\"{},x\",{}\n", fields[0], fields[1..].join(",")),
+                }
+            })
+            .collect()
+    };
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config()).unwrap();
+    let observed =
+        run_small_study(dir.path(), [&quoted(SMALL_A), &quoted(SMALL_B)], config()).unwrap();
+
+    assert_eq!(observed.len(), expected.len());
+    for (observed, expected) in observed.iter().zip(&expected) {
+        assert_eq!(&observed[1], format!("{},x", &expected[1]));
+        assert_eq!(
+            observed.iter().skip(2).collect::<Vec<_>>(),
+            expected.iter().skip(2).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn run_fn_odds_ratios() {
+    let dir = tempdir().unwrap();
+    // Exponentiate the betas, and scale the standard errors to the odds
+    // ratio scale if `se_of_or`
+    let to_odds_ratios = |text: &str, se_of_or: bool| -> String {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                if i == 0 {
+                    return format!("{}\n", line);
+                }
+                let odds_ratio = fields[1].parse::<f64>().unwrap().exp();
+                let se = fields[2].parse::<f64>().unwrap();
+                let se = if se_of_or { se * odds_ratio } else { se };
+                format!("{}\t{}\t{}\t{}\n", fields[0], odds_ratio, se, fields[3])
+            })
+            .collect()
+    };
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config()).unwrap();
+
+    for (effect_scale, se_of_or) in [
+        (igwas::io::gwas::EffectScale::OddsRatio, false),
+        (igwas::io::gwas::EffectScale::OddsRatioSe, true),
+    ] {
+        let observed = run_small_study_with(
+            dir.path(),
+            [
+                &to_odds_ratios(SMALL_A, se_of_or),
+                &to_odds_ratios(SMALL_B, se_of_or),
+            ],
+            config(),
+            igwas::io::gwas::ColumnSpec {
+                effect_scale,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(observed.len(), expected.len());
+        for (observed, expected) in observed.iter().zip(&expected) {
+            assert_eq!(&observed[1], &expected[1]);
+            for j in 2..6 {
+                let observed: f64 = observed[j].parse().unwrap();
+                let expected: f64 = expected[j].parse().unwrap();
+                assert!(
+                    (observed - expected).abs() < 1e-4,
+                    "{} {}",
+                    observed,
+                    expected
+                );
+            }
+        }
+    }
+
+    let err = run_small_study_with(
+        dir.path(),
+        [SMALL_A, SMALL_B],
+        config(),
+        igwas::io::gwas::ColumnSpec {
+            effect_scale: igwas::io::gwas::EffectScale::OddsRatio,
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("Invalid odds ratio -0.2 on line 3"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn neg_log_pvalue_degenerate_statistics() {
+    use igwas::stats::sumstats::{
+        compute_neg_log_pvalue_normal, compute_neg_log_pvalue_with, PValueMethod,
+    };
+
+    for method in [PValueMethod::Cdf, PValueMethod::LogTail] {
+        for t in [f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(compute_neg_log_pvalue_with(t, 100, method), f64::INFINITY);
+            assert_eq!(compute_neg_log_pvalue_normal(t, method), f64::INFINITY);
+        }
+        assert!(compute_neg_log_pvalue_with(f64::NAN, 100, method).is_nan());
+        assert!(compute_neg_log_pvalue_normal(f64::NAN, method).is_nan());
+        // No degrees of freedom, e.g. fewer samples than covariates
+        assert!(compute_neg_log_pvalue_with(2.0f64, 0, method).is_nan());
+        assert!(compute_neg_log_pvalue_with(2.0f64, -3, method).is_nan());
+    }
+}
+
+#[test]
+fn running_stats_degenerate_standard_errors() {
+    let labels = vec!["y".to_string()];
+    let proj = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p".to_string()],
+        matrix: nalgebra::DMatrix::from_element(1, 1, 1.0),
+    };
+    let cov = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: labels,
+        matrix: nalgebra::DMatrix::from_element(1, 1, 1.0),
+    };
+    // With a unit phenotype partial variance, the residual variance is
+    // 1 / gpv - beta^2: zero, negative, zero with a zero beta, and positive
+    let compute = |max_neg_log_p| {
+        let config = igwas::stats::running::StatsConfig {
+            max_neg_log_p,
+            normal_dof_threshold: None,
+            ..Default::default()
+        };
+        let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+            &proj, &cov, 0, 4, None, config,
+        )
+        .unwrap();
+        running
+            .update(&igwas::io::gwas::IntermediateResults {
+                phenotype_id: "y".to_string(),
+                variant_ids: ["rs1", "rs2", "rs3", "rs4"].map(String::from).to_vec(),
+                beta_update: nalgebra::DMatrix::from_vec(4, 1, vec![1.0, 1.5, 0.0, 0.5]),
+                gpv_update: nalgebra::DVector::from_vec(vec![1.0, 1.0, f32::INFINITY, 1.0]),
+                sample_sizes: nalgebra::DVector::from_element(4, 100),
+                missing: Vec::new(),
+                zero_weight: Vec::new(),
+                annotations: Default::default(),
+                heterogeneity: None,
+                sample_size_sums: None,
+            })
+            .unwrap();
+        running.compute_final_stats().unwrap()
+    };
+
+    let results = compute(None);
+    assert_eq!(results.se_values[0], 0.0);
+    assert_eq!(results.se_values[1], 0.0);
+    assert_eq!(results.t_stat_values[0], f32::INFINITY);
+    assert_eq!(results.t_stat_values[1], f32::INFINITY);
+    assert_eq!(results.p_values[0], f32::INFINITY);
+    assert_eq!(results.se_values[2], 0.0);
+    assert!(results.t_stat_values[2].is_nan());
+    assert!(results.p_values[2].is_nan());
+    assert!(results.se_values[3] > 0.0);
+    let finite_p = results.p_values[3];
+    assert!(finite_p.is_finite() && finite_p < 50.0);
+
+    let capped = compute(Some(50.0));
+    assert_eq!(capped.p_values[0], 50.0);
+    assert_eq!(capped.p_values[1], 50.0);
+    assert!(capped.p_values[2].is_nan());
+    assert_eq!(capped.p_values[3], finite_p);
+}
+
+#[test]
+fn running_stats_max_neg_log_p_extreme_t() {
+    use igwas::stats::sumstats::{format_pvalue, PValueMethod};
+
+    let labels = vec!["y".to_string()];
+    let proj = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: vec!["p".to_string()],
+        matrix: nalgebra::DMatrix::from_element(1, 1, 1.0),
+    };
+    let cov = igwas::io::matrix::LabeledMatrix {
+        row_labels: labels.clone(),
+        col_labels: labels,
+        matrix: nalgebra::DMatrix::from_element(1, 1, 1.0),
+    };
+    // A residual variance of 1 - 0.999^2 over a million samples gives a
+    // finite t-statistic in the tens of thousands, whose p-value underflows
+    let compute = |max_neg_log_p, pvalue_method, normal_dof_threshold| {
+        let config = igwas::stats::running::StatsConfig {
+            max_neg_log_p,
+            pvalue_method,
+            normal_dof_threshold,
+            ..Default::default()
+        };
+        let mut running = igwas::stats::running::RunningSufficientStats::<f32>::new(
+            &proj, &cov, 0, 2, None, config,
+        )
+        .unwrap();
+        running
+            .update(&igwas::io::gwas::IntermediateResults {
+                phenotype_id: "y".to_string(),
+                variant_ids: ["rs1", "rs2"].map(String::from).to_vec(),
+                beta_update: nalgebra::DMatrix::from_vec(2, 1, vec![0.999, 0.001]),
+                gpv_update: nalgebra::DVector::from_element(2, 1.0),
+                sample_sizes: nalgebra::DVector::from_element(2, 1_000_000),
+                missing: Vec::new(),
+                zero_weight: Vec::new(),
+                annotations: Default::default(),
+                heterogeneity: None,
+                sample_size_sums: None,
+            })
+            .unwrap();
+        running.compute_final_stats().unwrap()
+    };
+
+    let uncapped = compute(None, PValueMethod::Cdf, None);
+    assert!(uncapped.t_stat_values[0].is_finite() && uncapped.t_stat_values[0] > 1e4);
+    assert_eq!(uncapped.p_values[0], f32::INFINITY);
+    let modest_p = uncapped.p_values[1];
+    assert!(modest_p < 300.0);
+
+    // The cap applies whichever way the p-value is computed, and leaves
+    // smaller values alone
+    for (method, threshold) in [
+        (PValueMethod::Cdf, None),
+        (PValueMethod::LogTail, None),
+        (PValueMethod::Cdf, Some(10000)),
+        (PValueMethod::LogTail, Some(10000)),
+    ] {
+        let capped = compute(Some(300.0), method, threshold);
+        assert_eq!(capped.t_stat_values[0], uncapped.t_stat_values[0]);
+        assert_eq!(capped.p_values[0], 300.0, "{:?} {:?}", method, threshold);
+        assert!((capped.p_values[1] - modest_p).abs() < 1e-3);
+    }
+    // Raw p-values are written from the capped value
+    assert_eq!(format_pvalue(300.0), "1e-300");
+}
+
+#[test]
+fn run_fn_negative_residual_variance() {
+    utils::capture_warnings();
+    let dir = tempdir().unwrap();
+    let (proj, cov, files) = write_small_study(dir.path(), [SMALL_A, SMALL_B]);
+    // A covariance beyond the variances, so that the phenotype partial
+    // variance of p2 is negative
+    std::fs::write(&cov, "id,a.tsv,b.tsv\na.tsv,1.0,-3.0\nb.tsv,-3.0,2.0\n").unwrap();
+    let run = |max_negative_variance_fraction| {
+        let output = dir.path().join("out.tsv");
+        igwas::util::run(
+            &proj,
+            &cov,
+            &files,
+            output.to_str().unwrap(),
+            2,
+            igwas::util::RuntimeConfig {
+                stats: igwas::stats::running::StatsConfig {
+                    max_negative_variance_fraction,
+                    ..Default::default()
+                },
+                ..small_runtime_config()
+            },
+            Default::default(),
+        )
+        .map(|_| read_output_records(output.to_str().unwrap()))
+    };
+
+    let records = run(None).unwrap();
+    assert!(utils::captured_warnings()
+        .iter()
+        .any(|x| x.starts_with("4 of 8 results had a negative residual variance")));
+    // Taken as zero, so the standard errors of p2 are zero
+    for record in &records {
+        assert_eq!(&record[3] == "0", &record[0] == "p2", "{:?}", record);
+    }
+
+    let err = run(Some(0.25)).unwrap_err().to_string();
+    assert!(
+        err.contains("more than the maximum fraction 0.25"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn run_fn_heterogeneity() {
+    let dir = tempdir().unwrap();
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        stats: igwas::stats::running::StatsConfig {
+            heterogeneity: true,
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    // The second feature has twice the partial variance of the first, so
+    // betas scaled by sqrt(2) are the same standardized effects. Only rs3
+    // has opposite effects in the two files.
+    let a = "ID\tBETA\tSE\tOBS_CT\n\
+        rs1\t0.1\t0.1\t100\nrs2\t-0.2\t0.1\t100\nrs3\t0.3\t0.1\t100\nrs4\t0.05\t0.1\t100\n";
+    let b = format!(
+        "ID\tBETA\tSE\tOBS_CT\nrs1\t{}\t0.1\t100\nrs2\t{}\t0.1\t100\nrs3\t{}\t0.1\t100\n\
+        rs4\t{}\t0.1\t100\n",
+        0.1 * 2f64.sqrt(),
+        -0.2 * 2f64.sqrt(),
+        -0.3 * 2f64.sqrt(),
+        0.05 * 2f64.sqrt()
+    );
+    let records = run_small_study(dir.path(), [a, &b], config()).unwrap();
+    let header = std::fs::read_to_string(dir.path().join("out.tsv")).unwrap();
+    assert!(header
+        .lines()
+        .next()
+        .unwrap()
+        .ends_with("\theterogeneity_q"));
+
+    assert_eq!(records.len(), 8);
+    for record in &records {
+        let q: f64 = record[7].parse().unwrap();
+        // p2 loads on both features with the same sign, so the opposite
+        // effects of rs3 disagree: Q = 13.5 - 15^2 / 150 = 12. p1 loads on
+        // them with opposite signs, so the other variants disagree instead.
+        let expected_large = (&record[0] == "p2") == (&record[1] == "rs3");
+        if expected_large {
+            assert!(q > 0.1, "{:?}", record);
+        } else {
+            assert!(q.abs() < 1e-3, "{:?}", record);
+        }
+        if &record[0] == "p2" && &record[1] == "rs3" {
+            assert!((q - 12.0).abs() < 1e-3, "{}", q);
+        }
+    }
+
+    // Without the option, there is no column
+    let records = run_small_study(dir.path(), [a, &b], small_runtime_config()).unwrap();
+    assert_eq!(records[0].len(), 7);
+}
+
+#[test]
+fn run_fn_effective_sample_size() {
+    let dir = tempdir().unwrap();
+    let config = igwas::util::RuntimeConfig {
+        chunksize: 3,
+        stats: igwas::stats::running::StatsConfig {
+            effective_sample_size: true,
+            ..Default::default()
+        },
+        ..small_runtime_config()
+    };
+    let column_names = igwas::io::gwas::ColumnSpec {
+        na_policy: igwas::io::gwas::NaPolicy::ZeroWeight,
+        ..Default::default()
+    };
+    // b.tsv contributes nothing to rs4
+    let a = "ID\tBETA\tSE\tOBS_CT\n\
+        rs1\t0.1\t0.1\t100\nrs2\t-0.2\t0.1\t100\nrs3\t0.3\t0.1\t100\nrs4\t0.05\t0.1\t100\n";
+    let b = "ID\tBETA\tSE\tOBS_CT\n\
+        rs1\t0.2\t0.1\t200\nrs2\t0.1\t0.1\t50\nrs3\t-0.1\t0.1\t100\nrs4\tNA\t0.1\t100\n";
+    let records = run_small_study_with(dir.path(), [a, b], config, column_names).unwrap();
+    let header = std::fs::read_to_string(dir.path().join("out.tsv")).unwrap();
+    assert!(header
+        .lines()
+        .next()
+        .unwrap()
+        .ends_with("\teffective_sample_size"));
+
+    // Features are weighted by c^2 times their partial variance (1 and 2):
+    // 0.25 and 4.5 in p1, 1 and 0.125 in p2
+    let expected = |projection: &str, n_a: f64, n_b: Option<f64>| {
+        let (w_a, w_b) = match projection {
+            "p1" => (0.25, 4.5),
+            _ => (1.0, 0.125),
+        };
+        match n_b {
+            Some(n_b) => (w_a + w_b) / (w_a / n_a + w_b / n_b),
+            None => n_a,
+        }
+    };
+    assert_eq!(records.len(), 8);
+    for record in &records {
+        let n_b = match &record[1] {
+            "rs1" => Some(200.0),
+            "rs2" => Some(50.0),
+            "rs3" => Some(100.0),
+            _ => None,
+        };
+        let observed: f64 = record[7].parse().unwrap();
+        let expected = expected(&record[0], 100.0, n_b);
+        assert!(
+            (observed - expected).abs() < 1e-3,
+            "{:?}: expected {}",
+            record,
+            expected
+        );
+    }
+}
+
+#[test]
+fn run_fn_sample_size_policy() {
+    use igwas::stats::running::SampleSizePolicy;
+
+    let dir = tempdir().unwrap();
+    // b.tsv contributes nothing to rs4
+    let a = "ID\tBETA\tSE\tOBS_CT\n\
+        rs1\t0.1\t0.1\t100\nrs2\t-0.2\t0.1\t100\nrs3\t0.3\t0.1\t100\nrs4\t0.05\t0.1\t100\n";
+    let b = "ID\tBETA\tSE\tOBS_CT\n\
+        rs1\t0.2\t0.1\t200\nrs2\t0.1\t0.1\t51\nrs3\t-0.1\t0.1\t100\nrs4\tNA\t0.1\t100\n";
+    let run = |sample_size_policy| {
+        let config = igwas::util::RuntimeConfig {
+            stats: igwas::stats::running::StatsConfig {
+                sample_size_policy,
+                ..Default::default()
+            },
+            ..small_runtime_config()
+        };
+        let column_names = igwas::io::gwas::ColumnSpec {
+            na_policy: igwas::io::gwas::NaPolicy::ZeroWeight,
+            ..Default::default()
+        };
+        run_small_study_with(dir.path(), [a, b], config, column_names).unwrap()
+    };
+    let sample_sizes = |records: &[csv::StringRecord]| -> Vec<i32> {
+        records
+            .iter()
+            .filter(|x| &x[0] == "p1")
+            .map(|x| x[6].parse().unwrap())
+            .collect()
+    };
+
+    let min = run(SampleSizePolicy::Min);
+    assert_eq!(min.len(), 8);
+    assert_eq!(sample_sizes(&min), [100, 51, 100, 100]);
+    assert_eq!(
+        sample_sizes(&run(SampleSizePolicy::Max)),
+        [200, 100, 100, 100]
+    );
+    assert_eq!(
+        sample_sizes(&run(SampleSizePolicy::Mean)),
+        [150, 76, 100, 100]
+    );
+    assert_eq!(
+        sample_sizes(&run(SampleSizePolicy::First)),
+        [100, 100, 100, 100]
+    );
+    let sum = run(SampleSizePolicy::Sum);
+    assert_eq!(sample_sizes(&sum), [300, 151, 200, 100]);
+
+    // The degrees of freedom follow: with 2 covariates, 96 for the smallest
+    // sample size of rs1 and 96 + 196 for the sum, so the standard error
+    // shrinks by sqrt(292 / 96)
+    let se = |records: &[csv::StringRecord]| -> f64 { records[0][3].parse().unwrap() };
+    assert_eq!(&min[0][1], "rs1");
+    let ratio = se(&min) / se(&sum);
+    assert!((ratio - (292.0f64 / 96.0).sqrt()).abs() < 1e-4, "{}", ratio);
+}
+
+#[test]
+fn run_fn_chunk_by_chromosome() {
+    let dir = tempdir().unwrap();
+    let with_ids = |text: &str, ids: [&str; 4]| -> String {
+        text.lines()
+            .enumerate()
+            .map(|(i, line)| match i {
+                0 => format!("{}\n", line),
+                i => format!("{}\t{}\n", ids[i - 1], line.split_once('\t').unwrap().1),
+            })
+            .collect()
+    };
+    let ids = ["1:100:A:G", "1:300:G:A", "2:5:T:C", "X:200:C:T"];
+    let files = [with_ids(SMALL_A, ids), with_ids(SMALL_B, ids)];
+    let all = run_small_study(dir.path(), [&files[0], &files[1]], small_runtime_config()).unwrap();
+
+    // Write each chunk to a file of its own, named after its chromosome
+    let (proj, cov, gwas_files) = write_small_study(dir.path(), [&files[0], &files[1]]);
+    let chunks = igwas::util::run_to_results(
+        &proj,
+        &cov,
+        &gwas_files,
+        2,
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            chunk_by_chromosome: true,
+            ..small_runtime_config()
+        },
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(chunks.len(), 3);
+    let mut records = Vec::new();
+    for chunk in chunks {
+        let chromosome = chunk.variant_ids[0].split(':').next().unwrap().to_string();
+        let path = dir.path().join(format!("chr{}.tsv", chromosome));
+        igwas::io::gwas::write_gwas_results(
+            chunk,
+            path.to_str().unwrap(),
+            igwas::io::gwas::WriteMode::Create,
+            &Default::default(),
+        )
+        .unwrap();
+        let chunk_records = read_output_records(path.to_str().unwrap());
+        assert!(chunk_records
+            .iter()
+            .all(|x| x[1].starts_with(&format!("{}:", chromosome))));
+        records.extend(chunk_records);
+    }
+    let key = |x: &csv::StringRecord| (x[0].to_string(), x[1].to_string());
+    let mut expected = all.clone();
+    expected.sort_by_key(key);
+    records.sort_by_key(key);
+    assert_eq!(records, expected);
+
+    // The variants of a chromosome must be together
+    let ids = ["1:100:A:G", "2:5:T:C", "1:300:G:A", "X:200:C:T"];
+    let err = run_small_study(
+        dir.path(),
+        [&with_ids(SMALL_A, ids), &with_ids(SMALL_B, ids)],
+        igwas::util::RuntimeConfig {
+            chunk_by_chromosome: true,
+            ..small_runtime_config()
+        },
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("chromosome 1 are not together"));
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn run_fn_http_range_requests() {
+    let dir = tempdir().unwrap();
+    let write_gwas = |seed: f64| -> String {
+        let mut text = String::from("ID\tBETA\tSE\tOBS_CT\n");
+        for i in 0..10000 {
+            let beta = ((i as f64 + seed) * 0.37).sin() * 0.1;
+            text.push_str(&format!("rs{}\t{}\t0.1\t100\n", i, beta));
+        }
+        text
+    };
+    let files = [write_gwas(0.0), write_gwas(1.0)];
+    let (proj, cov, gwas_files) = write_small_study(dir.path(), [&files[0], &files[1]]);
+    let server = utils::MockServer::start(dir.path());
+    let urls: Vec<String> = ["a.tsv", "b.tsv"]
+        .iter()
+        .map(|x| format!("{}/{}", server.address, x))
+        .collect();
+
+    let run = |gwas_files: &[String], output: &str| {
+        let output = dir.path().join(output);
+        igwas::util::run(
+            &proj,
+            &cov,
+            gwas_files,
+            output.to_str().unwrap(),
+            2,
+            igwas::util::RuntimeConfig {
+                chunksize: 3000,
+                ..small_runtime_config()
+            },
+            Default::default(),
+        )
+        .unwrap();
+        read_output_records(output.to_str().unwrap())
+    };
+    assert_eq!(run(&urls, "http.tsv"), run(&gwas_files, "local.tsv"));
+
+    // With the line index cached, a chunk fetches only a small part of the file
+    let bytes_before = server
+        .bytes_served
+        .load(std::sync::atomic::Ordering::SeqCst);
+    let remote =
+        igwas::io::gwas::read_gwas_results(&urls[0], &Default::default(), 5000, 5100).unwrap();
+    let local = igwas::io::gwas::read_gwas_results(&gwas_files[0], &Default::default(), 5000, 5100)
+        .unwrap();
+    let bytes_served = server
+        .bytes_served
+        .load(std::sync::atomic::Ordering::SeqCst)
+        - bytes_before;
+    assert_eq!(remote.variant_ids, local.variant_ids);
+    assert_eq!(remote.beta_values, local.beta_values);
+    assert!(bytes_served < files[0].len() as u64 / 4, "{}", bytes_served);
+}
+
+#[cfg(not(feature = "http"))]
+#[test]
+fn http_urls_need_the_http_feature() {
+    let err = igwas::io::gwas::count_lines("https://example.com/a.tsv").unwrap_err();
+    assert!(format!("{:#}", err).contains("http feature"));
+}
+
+#[cfg(feature = "s3")]
+#[test]
+fn run_fn_s3_objects() {
+    let dir = tempdir().unwrap();
+    let config = || igwas::util::RuntimeConfig {
+        chunksize: 3,
+        ..small_runtime_config()
+    };
+    let expected = run_small_study(dir.path(), [SMALL_A, SMALL_B], config()).unwrap();
+
+    // Objects of a bucket are files of its directory
+    let objects = dir.path().join("bucket").join("gwas");
+    std::fs::create_dir_all(&objects).unwrap();
+    std::fs::write(objects.join("a.tsv"), SMALL_A).unwrap();
+    std::fs::write(objects.join("b.tsv"), SMALL_B).unwrap();
+    let server = utils::MockServer::start(dir.path());
+    std::env::set_var("AWS_ENDPOINT_URL", &server.address);
+    std::env::set_var("AWS_ACCESS_KEY_ID", "test");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "test");
+    std::env::set_var("AWS_REGION", "us-east-1");
+
+    // Phenotypes are named after the basenames of the keys
+    let output = dir.path().join("s3.tsv");
+    igwas::util::run(
+        dir.path().join("proj.csv").to_str().unwrap(),
+        dir.path().join("cov.csv").to_str().unwrap(),
+        &[
+            String::from("s3://bucket/gwas/a.tsv"),
+            String::from("s3://bucket/gwas/b.tsv"),
+        ],
+        output.to_str().unwrap(),
+        2,
+        config(),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(read_output_records(output.to_str().unwrap()), expected);
+
+    // Once the lines are indexed, a range of rows fetches only a small part
+    // of the object
+    let mut text = String::from("ID\tBETA\tSE\tOBS_CT\n");
+    for i in 0..10000 {
+        text.push_str(&format!(
+            "rs{}\t{}\t0.1\t100\n",
+            i,
+            (i as f64 * 0.37).sin() * 0.1
+        ));
+    }
+    std::fs::write(objects.join("c.tsv"), &text).unwrap();
+    let key = "s3://bucket/gwas/c.tsv";
+    assert_eq!(igwas::io::gwas::count_lines(key).unwrap(), 10000);
+    let bytes_before = server
+        .bytes_served
+        .load(std::sync::atomic::Ordering::SeqCst);
+    let remote = igwas::io::gwas::read_gwas_results(key, &Default::default(), 5000, 5100).unwrap();
+    let bytes_served = server
+        .bytes_served
+        .load(std::sync::atomic::Ordering::SeqCst)
+        - bytes_before;
+    assert_eq!(remote.variant_ids.len(), 100);
+    assert_eq!(remote.variant_ids[0], "rs5000");
+    assert!(bytes_served < text.len() as u64 / 4, "{}", bytes_served);
+}
+
+#[cfg(not(feature = "s3"))]
+#[test]
+fn s3_paths_need_the_s3_feature() {
+    let err = igwas::io::gwas::count_lines("s3://bucket/a.tsv").unwrap_err();
+    assert!(format!("{:#}", err).contains("s3 feature"));
+}
+
+#[test]
+fn run_fn_gwas_vcf_region() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    write_named_study(path);
+    utils::write_bgzip_tabix(&path.join("studies.vcf.gz"), &small_gwas_vcf(), 1, 2, 0, 2);
+
+    // Positions 200 and 300, of rs2 and rs3
+    let expected: Vec<csv::StringRecord> =
+        run_named_study(path, &["study_a", "study_b"], Default::default(), false)
+            .into_iter()
+            .filter(|x| ["rs2", "rs3"].contains(&&x[1]))
+            .collect();
+    let observed = run_named_study_with(
+        path,
+        &["studies.vcf.gz"],
+        igwas::io::gwas::ColumnSpec {
+            vcf: Some(Default::default()),
+            ..Default::default()
+        },
+        igwas::util::RuntimeConfig {
+            chunksize: 3,
+            region: Some(String::from("1:150-350")),
+            ..small_runtime_config()
+        },
+    );
+    assert_eq!(expected, observed);
+}
+
+#[test]
+fn run_fn_region() {
+    let dir = tempdir().unwrap();
+    let path = dir.path();
+    let args = utils::setup_test(path, 100, 60, 2, 3, 2, false);
+
+    // Put the variants on two chromosomes, 20 kb apart so that the region
+    // spans several windows of the index, in blocks of 4 rows
+    std::fs::create_dir_all(path.join("indexed")).unwrap();
+    let mut indexed_files = Vec::new();
+    let mut positions = Vec::new();
+    for gwas_file in &args.gwas_results {
+        let text = std::fs::read_to_string(gwas_file).unwrap();
+        let mut lines = text.lines();
+        let mut located = format!("chrom\tpos\t{}\n", lines.next().unwrap());
+        positions.clear();
+        for (i, line) in lines.enumerate() {
+            let (chromosome, position) = match i < 30 {
+                true => ("chr1", 1 + 20000 * i),
+                false => ("chr2", 1 + 20000 * (i - 30)),
+            };
+            located.push_str(&format!("{}\t{}\t{}\n", chromosome, position, line));
+            let variant_id = line.split('\t').nth(1).unwrap().to_string();
+            positions.push((variant_id, chromosome, position));
+        }
+        let indexed = path
+            .join("indexed")
+            .join(std::path::Path::new(gwas_file).file_name().unwrap());
+        utils::write_bgzip_tabix(&indexed, &located, 1, 2, 1, 4);
+        indexed_files.push(indexed.to_str().unwrap().to_string());
+    }
+
+    let run = |files: &[String], output: &str, config: igwas::util::RuntimeConfig| {
+        igwas::util::run(
+            &args.projection_matrix,
+            &args.covariance_matrix,
+            files,
+            output,
+            args.num_covar,
+            igwas::util::RuntimeConfig {
+                num_threads: args.num_threads,
+                chunksize: 4,
+                capacity: args.num_threads,
+                ..config
+            },
+            igwas::io::gwas::ColumnSpec {
+                variant_id: args.variant_id.clone(),
+                beta: args.beta.clone(),
+                se: args.std_error.clone(),
+                sample_size: args.sample_size.clone(),
+                ..Default::default()
+            },
+        )
+    };
+    let read_records = |output: &std::path::Path| -> Vec<csv::StringRecord> {
+        csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_path(output)
+            .unwrap()
+            .records()
+            .map(|x| x.unwrap())
+            .collect()
+    };
+    let all_output = path.join("all.tsv");
+    run(
+        &args.gwas_results,
+        all_output.to_str().unwrap(),
+        Default::default(),
+    )
+    .unwrap();
+    let in_region: Vec<&str> = positions
+        .iter()
+        .filter(|(_, chromosome, position)| {
+            *chromosome == "chr2" && (100000..=300000).contains(position)
+        })
+        .map(|(id, _, _)| id.as_str())
+        .collect();
+    assert_eq!(in_region.len(), 10);
+    // Chunks start at the region, so its rows are in another order
+    let sorted = |mut records: Vec<csv::StringRecord>| {
+        records.sort_by(|x, y| (&x[0], &x[1]).cmp(&(&y[0], &y[1])));
+        records
+    };
+    let expected = sorted(
+        read_records(&all_output)
+            .into_iter()
+            .filter(|x| in_region.contains(&&x[1]))
+            .collect(),
+    );
+
+    let region_output = path.join("region.tsv");
+    for parse_threads in [1, 2] {
+        let summary = run(
+            &indexed_files,
+            region_output.to_str().unwrap(),
+            igwas::util::RuntimeConfig {
+                parse_threads,
+                region: Some(String::from("chr2:100,000-300000")),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(summary.num_variants, 10);
+        assert_eq!(sorted(read_records(&region_output)), expected);
+    }
+
+    // A chromosome without variants leaves only the header
+    run(
+        &indexed_files,
+        region_output.to_str().unwrap(),
+        igwas::util::RuntimeConfig {
+            region: Some(String::from("chr3")),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert!(read_records(&region_output).is_empty());
+
+    // A file without an index cannot be read for a region
+    std::fs::remove_file(format!("{}.tbi", indexed_files[1])).unwrap();
+    let err = run(
+        &indexed_files,
+        region_output.to_str().unwrap(),
+        igwas::util::RuntimeConfig {
+            region: Some(String::from("chr2:100000-300000")),
+            ..Default::default()
+        },
+    )
+    .unwrap_err();
+    assert!(format!("{:#}", err).contains("has no tabix index"));
+}
@@ -7,6 +7,127 @@ use serde::{Deserialize, Serialize};
 
 use igwas::{stats::sumstats::compute_neg_log_pvalue, InputArguments};
 
+/// Logger keeping the messages of warnings and errors, see `captured_warnings`
+struct CaptureLogger;
+
+static CAPTURED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+static LOGGER: CaptureLogger = CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            CAPTURED.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Start capturing the warnings logged by every test of this binary
+pub fn capture_warnings() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+    });
+}
+
+/// Warnings logged since `capture_warnings` was first called, by any test
+pub fn captured_warnings() -> Vec<String> {
+    CAPTURED.lock().unwrap().clone()
+}
+
+/// Minimal HTTP server of the files of a directory, answering range
+/// requests, on a local port. It also serves as an S3 endpoint with
+/// path-style requests, whose buckets are subdirectories.
+#[cfg(any(feature = "http", feature = "s3"))]
+pub struct MockServer {
+    pub address: String,
+    /// Bytes of file contents served so far
+    pub bytes_served: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[cfg(any(feature = "http", feature = "s3"))]
+impl MockServer {
+    pub fn start(directory: &Path) -> MockServer {
+        use std::io::{BufRead, Write};
+        use std::sync::atomic::Ordering;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let bytes_served = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let directory = directory.to_path_buf();
+        let counter = bytes_served.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("range") {
+                            let (start, end) = value
+                                .trim()
+                                .strip_prefix("bytes=")
+                                .unwrap()
+                                .split_once('-')
+                                .unwrap();
+                            range = Some((
+                                start.parse::<usize>().unwrap(),
+                                end.parse::<usize>().unwrap(),
+                            ));
+                        }
+                    }
+                }
+                // Leave out the query, e.g. the x-id of S3 requests
+                let path = request.split_whitespace().nth(1).unwrap();
+                let path = path.split('?').next().unwrap();
+                let Ok(data) = std::fs::read(directory.join(path.trim_start_matches('/'))) else {
+                    write!(
+                        stream,
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    )
+                    .unwrap();
+                    continue;
+                };
+                let (status, body, content_range) = match range {
+                    Some((start, end)) => (
+                        "206 Partial Content",
+                        &data[start..=end],
+                        format!("Content-Range: bytes {}-{}/{}\r\n", start, end, data.len()),
+                    ),
+                    None => ("200 OK", &data[..], String::new()),
+                };
+                counter.fetch_add(body.len() as u64, Ordering::SeqCst);
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+                    status,
+                    body.len(),
+                    content_range
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        MockServer {
+            address,
+            bytes_served,
+        }
+    }
+}
+
 pub struct TestData {
     pub variant_ids: Vec<String>,
     pub phenotype_ids: Vec<String>,
@@ -279,6 +400,7 @@ pub fn setup_test(
     );
 
     InputArguments {
+        command: None,
         projection_matrix: dir
             .join("projection_matrix.csv")
             .to_str()
@@ -293,13 +415,74 @@ pub fn setup_test(
         output_file: dir.join("igwas_results.csv").to_str().unwrap().to_string(),
         num_covar: n_covariates,
         chunksize: n_variants,
+        chunk_by_chromosome: false,
+        region: None,
         variant_id: "variant_id".to_string(),
         beta: "beta".to_string(),
         std_error: "std_error".to_string(),
         sample_size: "sample_size".to_string(),
+        z_score: None,
+        allele_freq: None,
+        effect_allele: None,
+        effect_allele_freq: None,
+        wide_phenotypes: None,
+        wide_separator: "_".to_string(),
+        gwas_vcf: false,
+        vcf_fields: vec!["ES".to_string(), "SE".to_string(), "SS".to_string()],
+        effect_scale: igwas::io::gwas::EffectScale::Beta,
+        chromosomes: None,
+        chromosome_column: None,
+        min_maf: None,
+        annotation_mismatch: igwas::stats::running::AnnotationPolicy::TakeFirst,
+        na_policy: igwas::io::gwas::NaPolicy::Error,
+        invalid_as_missing: false,
+        variant_id_columns: None,
+        variant_id_separator: ":".to_string(),
+        delimiter: None,
+        whitespace_delimited: false,
         num_threads: 2,
+        num_readers: 1,
         capacity: 10,
         compress: false,
+        output_format: igwas::io::gwas::OutputFormat::Delimited,
+        output_delimiter: b'\t',
+        raw_p_values: false,
+        chi_square: false,
+        sort_by_position: false,
+        min_neg_log_p: None,
+        split_by_projection: false,
+        append: false,
+        sync_output: false,
+        extra_covariance: Vec::new(),
+        lambda_gc_file: None,
+        top_hits_file: None,
+        manifest: false,
+        checkpoint: None,
+        confidence_intervals: false,
+        confidence_level: 0.95,
+        precision: igwas::stats::running::Precision::F32,
+        pvalue_method: igwas::stats::sumstats::PValueMethod::Cdf,
+        normal_dof_threshold: 10000,
+        max_neg_log_p: None,
+        max_negative_variance_fraction: None,
+        heterogeneity: false,
+        effective_sample_size: false,
+        sample_size_policy: igwas::stats::running::SampleSizePolicy::Min,
+        symmetrize_covariance: false,
+        max_covariance_asymmetry: None,
+        normalize_projections: false,
+        restore_projection_scale: false,
+        sparse_projection: false,
+        projections: None,
+        compensated_summation: false,
+        phenotype_map: None,
+        num_covar_file: None,
+        align_by_id: false,
+        missing_variants: igwas::util::MissingVariantPolicy::Error,
+        mmap: false,
+        parse_threads: 1,
+        assume_aligned: false,
+        check: false,
         quiet: true,
     }
 }
@@ -355,3 +538,132 @@ pub fn check_results(direct_results_path: &str, indirect_results_path: &str) {
         );
     }
 }
+
+/// Write `text` to `path` compressed with BGZF, as bgzip does, in blocks of
+/// `lines_per_block` lines, and index it as `tabix` would at `<path>.tbi`,
+/// reading the chromosome and position from the 1-based columns `col_seq`
+/// and `col_beg` and skipping lines starting with `#` and the first `skip`
+pub fn write_bgzip_tabix(
+    path: &Path,
+    text: &str,
+    col_seq: usize,
+    col_beg: usize,
+    skip: usize,
+    lines_per_block: usize,
+) {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut compressed = Vec::new();
+    // Virtual offset of the start of each line, and of the end of the file
+    let mut offsets = Vec::new();
+    for block in lines.chunks(lines_per_block) {
+        let mut in_block = 0;
+        for line in block {
+            offsets.push(((compressed.len() as u64) << 16) | in_block);
+            in_block += line.len() as u64;
+        }
+        let data = block.concat();
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data.as_bytes()).unwrap();
+        let deflated = encoder.finish().unwrap();
+        let mut crc = flate2::Crc::new();
+        crc.update(data.as_bytes());
+        let block_size = 18 + deflated.len() + 8;
+        compressed.extend([
+            0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+        ]);
+        compressed.extend(((block_size - 1) as u16).to_le_bytes());
+        compressed.extend(&deflated);
+        compressed.extend(crc.sum().to_le_bytes());
+        compressed.extend((data.len() as u32).to_le_bytes());
+    }
+    offsets.push((compressed.len() as u64) << 16);
+    // The empty block marking the end of the file
+    compressed.extend([
+        0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0,
+        0, 0, 0, 0,
+    ]);
+    std::fs::write(path, compressed).unwrap();
+
+    fn bin(start: u64, end: u64) -> u32 {
+        let end = end - 1;
+        for (first_bin, shift) in [(4681, 14), (585, 17), (73, 20), (9, 23), (1, 26)] {
+            if start >> shift == end >> shift {
+                return first_bin + (start >> shift) as u32;
+            }
+        }
+        0
+    }
+    type Reference = (HashMap<u32, Vec<(u64, u64)>>, Vec<u64>);
+    let mut names: Vec<String> = Vec::new();
+    let mut references: Vec<Reference> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if i < skip || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        let chromosome = fields[col_seq - 1];
+        let start: u64 = fields[col_beg - 1].parse::<u64>().unwrap() - 1;
+        if names.last().map(|x| x.as_str()) != Some(chromosome) {
+            names.push(chromosome.to_string());
+            references.push(Default::default());
+        }
+        let (bins, windows) = references.last_mut().unwrap();
+        let chunks = bins.entry(bin(start, start + 1)).or_default();
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 == offsets[i] => chunk.1 = offsets[i + 1],
+            _ => chunks.push((offsets[i], offsets[i + 1])),
+        }
+        let window = (start >> 14) as usize;
+        if windows.len() <= window {
+            // Windows without rows start where the next row does
+            windows.resize(window + 1, offsets[i]);
+        }
+    }
+
+    let mut index = Vec::new();
+    index.extend(b"TBI\x01");
+    let i32s = |index: &mut Vec<u8>, values: &[i32]| {
+        for value in values {
+            index.extend(value.to_le_bytes());
+        }
+    };
+    let names: Vec<u8> = names.iter().flat_map(|x| x.bytes().chain([0])).collect();
+    i32s(
+        &mut index,
+        &[
+            references.len() as i32,
+            0,
+            col_seq as i32,
+            col_beg as i32,
+            0,
+            b'#' as i32,
+            skip as i32,
+            names.len() as i32,
+        ],
+    );
+    index.extend(names);
+    for (bins, windows) in references {
+        i32s(&mut index, &[bins.len() as i32]);
+        for (bin, chunks) in bins {
+            i32s(&mut index, &[bin as i32, chunks.len() as i32]);
+            for (start, end) in chunks {
+                index.extend(start.to_le_bytes());
+                index.extend(end.to_le_bytes());
+            }
+        }
+        i32s(&mut index, &[windows.len() as i32]);
+        for offset in windows {
+            index.extend(offset.to_le_bytes());
+        }
+    }
+    let mut encoder = flate2::write::GzEncoder::new(
+        std::fs::File::create(format!("{}.tbi", path.to_str().unwrap())).unwrap(),
+        flate2::Compression::default(),
+    );
+    encoder.write_all(&index).unwrap();
+    encoder.finish().unwrap();
+}